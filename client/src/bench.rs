@@ -0,0 +1,114 @@
+use core::error::Error;
+use std::time::{Duration, Instant};
+
+use opencv::core::Mat;
+
+use crate::{
+    Frame,
+    frame::{AsciiStyle, ColorDepth, DEFAULT_ASCII_RAMP, FrameFormat, RenderMode},
+    frame_generator::{CameraTestMode, FrameGenerator},
+};
+
+/// Terminal size the benchmark renders into, about a full-screen window.
+pub const BENCH_RENDER_SIZE: (i32, i32) = (160, 48);
+
+/// Average per-frame timings from [`run_bench`].
+pub struct BenchStats {
+    pub iterations: u32,
+    pub encode: Duration,
+    pub decode: Duration,
+    pub render: Duration,
+    pub encoded_bytes: usize,
+    pub fps: f64,
+}
+
+/// Pushes `iterations` test-pattern frames through encode, decode and ASCII rendering,
+/// the same path a frame takes from one user's camera to another's terminal, minus the
+/// network. `iterations` must be nonzero.
+pub fn run_bench(
+    iterations: u32,
+    send_resolution: (i32, i32),
+    quality: f32,
+    color_enabled: bool,
+) -> Result<BenchStats, Box<dyn Error + Send + Sync>> {
+    if iterations == 0 {
+        return Err("Benchmark needs at least one iteration".into());
+    }
+
+    let (send_width, send_height) = send_resolution;
+    let (render_width, render_height) = BENCH_RENDER_SIZE;
+    let style = AsciiStyle {
+        color_enabled,
+        color_depth: if color_enabled {
+            ColorDepth::TrueColor
+        } else {
+            ColorDepth::None
+        },
+        render_mode: RenderMode::Ascii,
+        ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+        dither: false,
+        color_fill: false,
+    };
+
+    let mut mat = Mat::default();
+    let mut ascii_buffer = String::with_capacity(50000);
+    let mut encode = Duration::ZERO;
+    let mut decode = Duration::ZERO;
+    let mut render = Duration::ZERO;
+    let mut encoded_bytes = 0;
+
+    let started = Instant::now();
+
+    for i in 0..iterations {
+        FrameGenerator::generate_frame(&CameraTestMode::SpiralTunnel, i as i32, &mut mat)?;
+        let frame = Frame::from_mat(&mat, send_width, send_height, false)?;
+
+        let step_started = Instant::now();
+        let bytes = frame.to_bytes(quality, FrameFormat::Rgb);
+        encode += step_started.elapsed();
+        encoded_bytes += bytes.len();
+
+        let step_started = Instant::now();
+        let decoded = Frame::from_bytes(&bytes)?;
+        decode += step_started.elapsed();
+
+        let step_started = Instant::now();
+        ascii_buffer.clear();
+        decoded.to_ascii_with_buffer(&style, render_width, render_height, &mut ascii_buffer)?;
+        render += step_started.elapsed();
+    }
+
+    let elapsed = started.elapsed();
+
+    Ok(BenchStats {
+        iterations,
+        encode: encode / iterations,
+        decode: decode / iterations,
+        render: render / iterations,
+        encoded_bytes: encoded_bytes / iterations as usize,
+        fps: iterations as f64 / elapsed.as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_reports_averages_over_every_iteration() {
+        let stats = run_bench(5, (64, 48), 50.0, true).unwrap();
+
+        assert_eq!(stats.iterations, 5);
+        assert!(stats.encoded_bytes > 0);
+        assert!(stats.fps.is_finite() && stats.fps > 0.0);
+
+        // Each frame spends at least its measured steps, so the rate can't beat them
+        let per_frame = stats.encode + stats.decode + stats.render;
+        assert!(stats.fps <= 1.0 / per_frame.as_secs_f64());
+    }
+
+    #[test]
+    fn bench_refuses_zero_iterations() {
+        assert!(run_bench(0, (64, 48), 50.0, false).is_err());
+    }
+}
@@ -1,55 +1,164 @@
 use core::error::Error;
 use crossterm::{
     cursor::{self, Hide, Show},
-    event::{Event, KeyCode, KeyModifiers},
+    event::{Event, KeyCode, KeyEventKind, KeyModifiers},
     execute,
     terminal::{
         self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
         enable_raw_mode,
     },
 };
-use std::{io::stdout, time::Duration};
+use opencv::core::{Mat, MatTraitConst};
+use std::{fmt, io::stdout, time::Duration};
 use tokio::{
-    net::{TcpStream, UdpSocket},
-    sync::watch::{self, Sender},
+    net::UdpSocket,
+    sync::{
+        mpsc,
+        watch::{self, Sender},
+    },
     time::Instant,
 };
 
+#[cfg(feature = "record")]
+use crate::recorder::record_loop;
 use crate::{
+    call_stats::CallStats,
     camera::Camera,
-    frame::{Frame, combine_frames_with_buffers, detect_true_color},
-    renderer::Renderer,
-    udp_handler::{udp_listener_loop, udp_send_loop},
+    chat::ChatLog,
+    frame::{
+        AsciiStyle, Frame, FrameFormat, RemoteStream, combine_frames_with_buffers,
+        count_visible_chars_fast, save_snapshot, truncate_to_width,
+    },
+    frame_generator::FrameGenerator,
+    media_crypto::{MEDIA_CRYPTO_OVERHEAD, MediaCipher},
+    renderer::{FrameSink, Renderer, ResizeDebouncer},
+    udp_handler::{
+        MediaStream, SendSettings, feedback_channel, negotiate_chunk_size, udp_listener_loop,
+        udp_send_loop,
+    },
 };
 use crossterm::event::{self};
-use shared::StreamID;
+use shared::control_stream::ControlStream;
+use shared::protocol_error::ProtocolError;
 use shared::received_tcp_command::ReceivedTcpCommand;
 use shared::tcp_command::TcpCommand;
 use shared::tcp_command_id::TcpCommandId;
+use shared::tcp_command_reader::TcpCommandReader;
+use shared::{MediaKey, StreamID};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
-const SEND_WIDTH: i32 = 96;
-const SEND_HEIGHT: i32 = 54;
 const MAX_TERMINAL_WIDTH: u16 = 384;
 const MAX_TERMINAL_HEIGHT: u16 = 216;
 const MAX_COLOR_TERMINAL_WIDTH: u16 = 201;
 const MAX_COLOR_TERMINAL_HEIGHT: u16 = 113;
-const TARGET_FPS: u64 = 30;
-const FRAME_DURATION: Duration = Duration::from_millis(1000 / TARGET_FPS);
+const OWN_TILE_LABEL: &str = "You";
+const PAUSED_TILE_LABEL: &str = "You (paused)";
+const DECODE_ERROR_TILE_SUFFIX: &str = " (decode error)";
+const HUD_TOGGLE_KEY: char = 'i';
+const PAUSE_TOGGLE_KEY: char = 'p';
+const SNAPSHOT_KEY: char = 's';
+const MIRROR_TOGGLE_KEY: char = 'm';
+const STREAM_STATUS_KEY: char = 'b';
+const CAMERA_SWITCH_KEY: char = 'c';
+const CHAT_KEY: char = 't';
+const KICK_CHAT_COMMAND: &str = "/kick ";
+const SERVER_CHAT_SENDER: &str = "server";
+const SNAPSHOT_CHAT_SENDER: &str = "snapshot";
+const CAMERA_OFFLINE_THRESHOLD: u32 = 10;
+const CAMERA_REOPEN_INTERVAL: Duration = Duration::from_secs(2);
+const RECORDING_INTERVAL: Duration = Duration::from_millis(100);
+#[cfg(feature = "record")]
+const RECORDING_QUEUE_SIZE: usize = 30;
+#[cfg(feature = "record")]
+const RECORDING_CHAT_SENDER: &str = "record";
+
+#[derive(Debug)]
+pub struct ConnectionLost;
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server closed connection.")
+    }
+}
+
+impl Error for ConnectionLost {}
+
+/// Everything about a call that comes from the user's settings rather than the server.
+#[derive(Clone)]
+pub struct CallSettings {
+    pub extra_camera_indices: Vec<i32>,
+    pub send_resolution: (i32, i32),
+    pub capture_fps: u32,
+    pub render_fps: u32,
+    pub style: AsciiStyle,
+    pub quality: f32,
+    pub fec_enabled: bool,
+    pub mtu: Option<usize>,
+    pub max_upload_kbps: Option<u32>,
+    pub mono_transport: bool,
+    pub mirror: bool,
+    pub mirror_self_only: bool,
+    pub idle_timeout: Option<Duration>,
+    pub record_path: Option<String>,
+}
+
+/// State shared by the tasks of a running call.
+#[derive(Clone)]
+struct CallState {
+    sid_to_frame_map: Arc<Mutex<HashMap<StreamID, RemoteStream>>>,
+    call_stats: Arc<Mutex<CallStats>>,
+    chat_log: Arc<Mutex<ChatLog>>,
+    hud_enabled: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    mirror_enabled: Arc<AtomicBool>,
+    cancel_token: CancellationToken,
+}
+
+/// Tracks how long it has been since the last key press, for `--idle-timeout`.
+struct IdleTracker {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl IdleTracker {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn record_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    fn is_idle(&self) -> bool {
+        self.timeout
+            .is_some_and(|timeout| self.last_activity.elapsed() >= timeout)
+    }
+}
 
 pub struct CallInterface;
 
 impl CallInterface {
     pub async fn run(
         full_sid: &[u8],
-        tcp_stream: &mut TcpStream,
+        media_key: Option<MediaKey>,
+        tcp_stream: &mut dyn ControlStream,
         udp_stream: UdpSocket,
         camera_index: i32,
-        color_enabled: bool,
+        settings: &CallSettings,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let media_cipher = media_key.map(|media_key| MediaCipher::new(&media_key));
+        let mut chunk_size = negotiate_chunk_size(&udp_stream, full_sid, settings.mtu).await;
+        if media_cipher.is_some() {
+            chunk_size = chunk_size.saturating_sub(MEDIA_CRYPTO_OVERHEAD).max(1);
+        }
+
         println!("Starting camera ASCII feed... Press Ctrl+C to exit");
 
         let mut stdout = stdout();
@@ -74,10 +183,27 @@ impl CallInterface {
             );
         });
 
-        let cancel_token = CancellationToken::new();
+        let state = CallState {
+            sid_to_frame_map: Arc::new(Mutex::new(HashMap::new())),
+            call_stats: Arc::new(Mutex::new(CallStats::new())),
+            chat_log: Arc::new(Mutex::new(ChatLog::new())),
+            hud_enabled: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            mirror_enabled: Arc::new(AtomicBool::new(
+                settings.mirror || settings.mirror_self_only,
+            )),
+            cancel_token: CancellationToken::new(),
+        };
+        let cancel_token = state.cancel_token.clone();
 
-        let sid_to_frame_map = Arc::new(Mutex::new(HashMap::new()));
-        let udp_stream = Arc::new(udp_stream);
+        let media = MediaStream {
+            udp_stream: Arc::new(udp_stream),
+            full_sid: full_sid.to_vec(),
+            media_cipher,
+        };
+        let (feedback_tx, feedback_rx) = feedback_channel();
+        let (outgoing_command_tx, outgoing_command_rx) = mpsc::unbounded_channel();
+        let (terminal_resize_tx, terminal_resize_rx) = mpsc::unbounded_channel();
 
         let (camera_frame_channel_tx, camera_frame_channel_rx) = watch::channel(Frame {
             width: 0,
@@ -86,32 +212,91 @@ impl CallInterface {
         });
 
         let mut udp_listener_loop_task = tokio::spawn(udp_listener_loop(
-            udp_stream.clone(),
-            sid_to_frame_map.clone(),
+            media.clone(),
+            state.sid_to_frame_map.clone(),
+            feedback_tx,
+            state.call_stats.clone(),
             cancel_token.clone(),
         ));
 
+        let send_settings = SendSettings {
+            quality: settings.quality,
+            fec_enabled: settings.fec_enabled,
+            chunk_size,
+            max_upload_kbps: settings.max_upload_kbps,
+            frame_format: if settings.mono_transport {
+                FrameFormat::Mono
+            } else {
+                FrameFormat::Rgb
+            },
+        };
+
         let mut udp_send_loop_task = tokio::spawn(udp_send_loop(
-            udp_stream,
+            media,
             camera_frame_channel_tx.subscribe(),
-            full_sid.to_vec(),
+            send_settings,
+            feedback_rx,
+            state.call_stats.clone(),
+            state.paused.clone(),
             cancel_token.clone(),
         ));
 
+        #[cfg(feature = "record")]
+        let (recording_tx, recording_task) = match settings.record_path.clone() {
+            Some(output_dir) => {
+                let (tx, rx) = mpsc::channel(RECORDING_QUEUE_SIZE);
+                let chat_log = state.chat_log.clone();
+                let recording_cancel_token = cancel_token.clone();
+                let task = tokio::spawn(async move {
+                    let result = record_loop(output_dir, rx, recording_cancel_token).await;
+                    if let Err(e) = &result {
+                        let notice = format!("Recording stopped: {}", e);
+                        chat_log.lock().await.push(RECORDING_CHAT_SENDER, &notice);
+                    }
+                    result
+                });
+                (Some(tx), Some(task))
+            }
+            None => (None, None),
+        };
+        #[cfg(not(feature = "record"))]
+        let (recording_tx, recording_task) = (
+            None,
+            None::<tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>>,
+        );
+
+        let mut render_settings = settings.clone();
+        render_settings.style.color_depth = render_settings.style.color_depth.resolve();
+
         let mut render_loop_task = tokio::spawn(render_loop(
             camera_frame_channel_rx,
-            sid_to_frame_map.clone(),
-            color_enabled,
-            cancel_token.clone(),
+            state.clone(),
+            render_settings,
+            recording_tx,
+            terminal_resize_rx,
+            terminal::size().unwrap_or_default(),
+            Renderer::new(),
         ));
 
+        let snapshot_frame_rx = camera_frame_channel_tx.subscribe();
+        let (camera_index_tx, camera_index_rx) = watch::channel(camera_index);
+
         let mut camera_loop_task = tokio::spawn(camera_loop(
             camera_frame_channel_tx,
-            camera_index,
+            camera_index_rx,
+            settings.clone(),
+            state.mirror_enabled.clone(),
             cancel_token.clone(),
         ));
 
-        let mut user_input_loop_task = tokio::spawn(user_input_loop(cancel_token.clone()));
+        let mut user_input_loop_task = tokio::spawn(user_input_loop(
+            snapshot_frame_rx,
+            state.clone(),
+            camera_index_tx,
+            outgoing_command_tx,
+            terminal_resize_tx,
+            settings.idle_timeout,
+        ));
 
         let result = tokio::select! {
             result = &mut user_input_loop_task => result?,
@@ -119,12 +304,12 @@ impl CallInterface {
             result = &mut render_loop_task => result?,
             result = &mut udp_listener_loop_task => result?,
             result = &mut udp_send_loop_task => result?,
-            result = tcp_loop(tcp_stream, sid_to_frame_map.clone(), cancel_token.clone()) => result
+            result = tcp_loop(tcp_stream, &state, outgoing_command_rx) => result
         };
 
         cancel_token.cancel();
 
-        let cleanup_tasks = [
+        let cleanup_tasks = vec![
             user_input_loop_task,
             camera_loop_task,
             render_loop_task,
@@ -139,25 +324,115 @@ impl CallInterface {
             }
         }
 
-        result
+        // The recorder flushes queued frames after cancellation, so its errors come last
+        let recording_result = match recording_task {
+            Some(task) => tokio::time::timeout(cleanup_timeout, task).await.ok(),
+            None => None,
+        };
+
+        match recording_result {
+            Some(Ok(Err(e))) if result.is_ok() => Err(format!("Recording failed: {}", e).into()),
+            _ => result,
+        }
     }
 }
 
+/// Stitches a frame from the primary camera and one from each of `extra_cameras` side by
+/// side. Each tile is scaled to `height`, keeping its aspect ratio, so cameras with
+/// different resolutions line up. An extra camera that fails to deliver a frame is shown
+/// as the offline placeholder.
+async fn tile_camera_frames(
+    primary: &Mat,
+    extra_cameras: &mut [Camera],
+    height: i32,
+    mirror: bool,
+) -> Result<Frame, Box<dyn Error + Send + Sync>> {
+    let mut tiles = vec![tile_from_mat(primary, height)?];
+
+    for camera in extra_cameras.iter_mut() {
+        match camera.get_frame().await {
+            Ok(mat) => tiles.push(tile_from_mat(mat, height)?),
+            Err(_) => {
+                let mut placeholder = Mat::default();
+                FrameGenerator::generate_offline_frame(&mut placeholder)?;
+                tiles.push(tile_from_mat(&placeholder, height)?);
+            }
+        }
+    }
+
+    let frame = Frame::tile_horizontally(&tiles);
+    Ok(if mirror { frame.mirrored() } else { frame })
+}
+
+fn tile_from_mat(mat: &Mat, height: i32) -> opencv::Result<Frame> {
+    let width = (mat.cols() as i64 * height as i64 / mat.rows().max(1) as i64).max(1) as i32;
+    Frame::from_mat(mat, width, height, false)
+}
+
 async fn camera_loop(
     camera_frame_channel_tx: Sender<Frame>,
-    camera_index: i32,
+    mut camera_index_rx: watch::Receiver<i32>,
+    settings: CallSettings,
+    mirror_enabled: Arc<AtomicBool>,
     cancel_token: CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut camera = Camera::new(camera_index)?;
+    let camera = Camera::new(*camera_index_rx.borrow_and_update())?;
+
+    capture_frames(
+        camera,
+        camera_frame_channel_tx,
+        camera_index_rx,
+        settings,
+        mirror_enabled,
+        cancel_token,
+    )
+    .await
+}
+
+/// Sends frames from `camera` until cancelled, switching to the camera whose index comes
+/// in on `camera_index_rx`. While the camera keeps failing, it shows the offline
+/// placeholder and tries reopening the camera every `CAMERA_REOPEN_INTERVAL`.
+async fn capture_frames(
+    mut camera: Camera,
+    camera_frame_channel_tx: Sender<Frame>,
+    mut camera_index_rx: watch::Receiver<i32>,
+    settings: CallSettings,
+    mirror_enabled: Arc<AtomicBool>,
+    cancel_token: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (send_width, send_height) = settings.send_resolution;
+    let mirror_self_only = settings.mirror_self_only;
+    let frame_duration = Duration::from_secs(1) / settings.capture_fps;
+    let mut extra_cameras = settings
+        .extra_camera_indices
+        .into_iter()
+        .map(Camera::new)
+        .collect::<Result<Vec<_>, _>>()?;
     let mut last_frame_time = Instant::now();
+    let mut consecutive_failures = 0;
+    let mut last_reopen_attempt = Instant::now();
 
     loop {
         tokio::select! {
             _ = cancel_token.cancelled() => break,
-            _ = tokio::time::sleep_until(last_frame_time + FRAME_DURATION) => {
+            Ok(()) = camera_index_rx.changed() => {
+                let camera_index = *camera_index_rx.borrow_and_update();
+                match tokio::task::spawn_blocking(move || Camera::new(camera_index)).await? {
+                    Ok(new_camera) => camera = new_camera,
+                    Err(e) => eprintln!("Could not switch to camera {}: {}", camera_index, e),
+                }
+            }
+            _ = tokio::time::sleep_until(last_frame_time + frame_duration) => {
                 match camera.get_frame().await {
                     Ok(mat) => {
-                        match Frame::from_mat(&mat, SEND_WIDTH, SEND_HEIGHT) {
+                        consecutive_failures = 0;
+                        let mirror = mirror_enabled.load(Ordering::Relaxed) && !mirror_self_only;
+                        let frame: Result<Frame, Box<dyn Error + Send + Sync>> = if extra_cameras.is_empty() {
+                            Frame::from_mat(mat, send_width, send_height, mirror).map_err(Into::into)
+                        } else {
+                            tile_camera_frames(mat, &mut extra_cameras, send_height, mirror).await
+                        };
+                        match frame {
                             Ok(frame) => {
                                 if camera_frame_channel_tx.receiver_count() > 0 {
                                     let _ = camera_frame_channel_tx.send(frame);
@@ -170,7 +445,30 @@ async fn camera_loop(
                         }
                     }
                     Err(e) => {
-                        eprintln!("Camera error: {}", e);
+                        consecutive_failures += 1;
+
+                        if consecutive_failures == 1 {
+                            eprintln!("Camera error: {}", e);
+                        }
+
+                        if consecutive_failures == CAMERA_OFFLINE_THRESHOLD {
+                            eprintln!("Camera offline, retrying every {:?}", CAMERA_REOPEN_INTERVAL);
+                            let mut placeholder = Mat::default();
+                            if FrameGenerator::generate_offline_frame(&mut placeholder).is_ok()
+                                && let Ok(frame) = Frame::from_mat(&placeholder, send_width, send_height, false)
+                            {
+                                let _ = camera_frame_channel_tx.send(frame);
+                            }
+                        }
+
+                        if consecutive_failures >= CAMERA_OFFLINE_THRESHOLD && last_reopen_attempt.elapsed() >= CAMERA_REOPEN_INTERVAL {
+                            last_reopen_attempt = Instant::now();
+                            let camera_index = *camera_index_rx.borrow();
+                            if let Ok(new_camera) = tokio::task::spawn_blocking(move || Camera::new(camera_index)).await? {
+                                camera = new_camera;
+                            }
+                        }
+
                         tokio::time::sleep(Duration::from_millis(100)).await;
                     }
                 }
@@ -181,68 +479,79 @@ async fn camera_loop(
     Ok(())
 }
 
-async fn render_loop(
+async fn render_loop<S: FrameSink>(
     mut camera_frame_channel_rx: watch::Receiver<Frame>,
-    sid_to_frame_map: Arc<Mutex<HashMap<StreamID, Option<Frame>>>>,
-    color_enabled: bool,
-    cancel_token: CancellationToken,
+    state: CallState,
+    settings: CallSettings,
+    recording_tx: Option<mpsc::Sender<Vec<Frame>>>,
+    mut terminal_resize_rx: mpsc::UnboundedReceiver<(u16, u16)>,
+    initial_terminal_size: (u16, u16),
+    mut sink: S,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let style = settings.style;
+    let color_enabled = style.color_enabled;
+    let mirror_self_only = settings.mirror_self_only;
+
     let mut last_content = String::new();
-    let mut renderer = Renderer::new();
-    let true_color = detect_true_color();
+    let mut resize_debouncer = ResizeDebouncer::new(initial_terminal_size);
 
     let mut ascii_buffer = String::with_capacity(50000);
     let mut temp_buffers = Vec::with_capacity(10);
     let mut last_terminal_size = (0, 0);
+    let mut last_recording_time = Instant::now();
+    let render_frame_duration = Duration::from_secs(1) / settings.render_fps;
+    let mut last_render_time = Instant::now();
 
     loop {
         tokio::select! {
-            _ = cancel_token.cancelled() => break,
+            _ = state.cancel_token.cancelled() => break,
+            Some(terminal_size) = terminal_resize_rx.recv() => resize_debouncer.resize(terminal_size),
+            // Redraw at the settled size even if no new frame comes in, e.g. while the camera is stalled
+            _ = tokio::time::sleep_until(resize_debouncer.settles_at().map_or_else(Instant::now, Instant::from_std)), if resize_debouncer.is_resizing() => {
+                camera_frame_channel_rx.mark_changed();
+            }
             result = camera_frame_channel_rx.changed() => {
-                if let Err(_) = result {
+                if result.is_err() {
                     break;
                 }
 
-                if let Ok(terminal_size) = terminal::size() {
+                // Frames that arrive before the next render slot are coalesced into the latest one
+                tokio::time::sleep_until(last_render_time + render_frame_duration).await;
+                last_render_time = Instant::now();
 
-                    let constrained_terminal_size = match color_enabled {
-                        true => (terminal_size.0.min(MAX_COLOR_TERMINAL_WIDTH), terminal_size.1.min(MAX_COLOR_TERMINAL_HEIGHT)),
-                        false => (terminal_size.0.min(MAX_TERMINAL_WIDTH), terminal_size.1.min(MAX_TERMINAL_HEIGHT))
-                    };
+                let terminal_size = resize_debouncer.size();
+                if !resize_debouncer.is_resizing() {
 
                     let size_changed = terminal_size != last_terminal_size;
                     last_terminal_size = terminal_size;
 
                     let frame = camera_frame_channel_rx.borrow().clone();
-                    let mut frames = Vec::with_capacity(10);
-                    frames.push(frame);
+                    let tiles = call_tiles(&state, frame, mirror_self_only).await;
 
+                    if let Some(tx) = &recording_tx
+                        && last_recording_time.elapsed() >= RECORDING_INTERVAL
                     {
-                        let frame_map = sid_to_frame_map.lock().await;
-                        for frame_option in frame_map.values() {
-                            if let Some(frame) = frame_option {
-                                frames.push(frame.clone());
-                            }
-                        }
+                        last_recording_time = Instant::now();
+                        let _ = tx.try_send(tiles.frames.clone());
                     }
 
-                    combine_frames_with_buffers(
-                        &frames,
-                        constrained_terminal_size.0,
-                        constrained_terminal_size.1,
-                        terminal_size.0,
-                        terminal_size.1,
-                        color_enabled,
-                        true_color,
-                        &mut ascii_buffer,
+                    let content = compose_frame(
+                        &state,
+                        &tiles,
+                        &style,
+                        terminal_size,
+                        std::mem::take(&mut ascii_buffer),
                         &mut temp_buffers,
-                    );
+                    )
+                    .await;
 
-                    if ascii_buffer != last_content || size_changed {
-                        if let Err(e) = renderer.update_terminal(&ascii_buffer, terminal_size.0, terminal_size.1, color_enabled) {
+                    if content != last_content || size_changed {
+                        if let Err(e) = sink.draw(&content, terminal_size.0, terminal_size.1, color_enabled) {
                             eprintln!("Render error: {}", e);
                         }
-                        std::mem::swap(&mut last_content, &mut ascii_buffer);
+                        ascii_buffer = std::mem::replace(&mut last_content, content);
+                    } else {
+                        ascii_buffer = content;
                     }
                 }
             }
@@ -252,24 +561,207 @@ async fn render_loop(
     Ok(())
 }
 
+/// The frames of one composited view, own camera first, with the label and the sender of
+/// each remote tile.
+struct CallTiles {
+    frames: Vec<Frame>,
+    labels: Vec<String>,
+    usernames: HashMap<StreamID, String>,
+}
+
+/// Collects the tiles to show: the local frame, or its paused placeholder, followed by every
+/// remote stream that has a frame, with a placeholder for streams that failed to decode.
+async fn call_tiles(state: &CallState, frame: Frame, mirror_self_only: bool) -> CallTiles {
+    let mut frames = Vec::with_capacity(10);
+    let mut labels = Vec::with_capacity(10);
+    let mut usernames = HashMap::new();
+
+    if state.paused.load(Ordering::Relaxed) {
+        let placeholder = vec![0; (frame.width * frame.height * 3) as usize];
+        frames.push(Frame {
+            data: Arc::new(placeholder),
+            ..frame
+        });
+        labels.push(PAUSED_TILE_LABEL.to_string());
+    } else if mirror_self_only && state.mirror_enabled.load(Ordering::Relaxed) {
+        frames.push(frame.mirrored());
+        labels.push(OWN_TILE_LABEL.to_string());
+    } else {
+        frames.push(frame);
+        labels.push(OWN_TILE_LABEL.to_string());
+    }
+
+    let frame_map = state.sid_to_frame_map.lock().await;
+    for (sid, remote_stream) in frame_map.iter() {
+        usernames.insert(*sid, remote_stream.username.clone());
+        if remote_stream.decode_error {
+            let (width, height) = remote_stream
+                .frame
+                .as_ref()
+                .map_or((frames[0].width, frames[0].height), |frame| {
+                    (frame.width, frame.height)
+                });
+            let placeholder = vec![0; (width * height * 3) as usize];
+            frames.push(Frame {
+                width,
+                height,
+                data: Arc::new(placeholder),
+            });
+            labels.push(format!(
+                "{}{}",
+                remote_stream.username, DECODE_ERROR_TILE_SUFFIX
+            ));
+        } else if let Some(frame) = &remote_stream.frame {
+            frames.push(frame.clone());
+            labels.push(remote_stream.username.clone());
+        }
+    }
+
+    CallTiles {
+        frames,
+        labels,
+        usernames,
+    }
+}
+
+/// Lays `tiles` out for `terminal_size` and draws the HUD and chat over them, reusing
+/// `ascii_buffer` for the result. Every [`FrameSink`] gets exactly this text.
+async fn compose_frame(
+    state: &CallState,
+    tiles: &CallTiles,
+    style: &AsciiStyle,
+    terminal_size: (u16, u16),
+    mut ascii_buffer: String,
+    temp_buffers: &mut Vec<String>,
+) -> String {
+    let constrained_terminal_size = match style.color_enabled {
+        true => (
+            terminal_size.0.min(MAX_COLOR_TERMINAL_WIDTH),
+            terminal_size.1.min(MAX_COLOR_TERMINAL_HEIGHT),
+        ),
+        false => (
+            terminal_size.0.min(MAX_TERMINAL_WIDTH),
+            terminal_size.1.min(MAX_TERMINAL_HEIGHT),
+        ),
+    };
+
+    combine_frames_with_buffers(
+        &tiles.frames,
+        &tiles.labels,
+        constrained_terminal_size,
+        terminal_size,
+        style,
+        &mut ascii_buffer,
+        temp_buffers,
+    );
+
+    {
+        let mut stats = state.call_stats.lock().await;
+        stats.record_frame_rendered();
+        stats.update();
+
+        if state.hud_enabled.load(Ordering::Relaxed) {
+            let hud = format_hud(&stats, &tiles.usernames);
+            overlay_line(&mut ascii_buffer, 0, &hud, terminal_size.0);
+        }
+    }
+
+    let chat_lines = state.chat_log.lock().await.visible_lines();
+    let line_count = ascii_buffer.matches('\n').count() + 1;
+    let first_chat_line = line_count.saturating_sub(chat_lines.len());
+
+    for (i, line) in chat_lines.iter().enumerate() {
+        overlay_line(
+            &mut ascii_buffer,
+            first_chat_line + i,
+            line,
+            terminal_size.0,
+        );
+    }
+
+    ascii_buffer
+}
+
+fn format_hud(stats: &CallStats, usernames: &HashMap<StreamID, String>) -> String {
+    let mut hud = format!(
+        "FPS {:.1} | In {:.1} KB/s | Out {:.1} KB/s",
+        stats.render_fps, stats.inbound_kbps, stats.outbound_kbps
+    );
+
+    if let Some(quality) = stats.send_quality {
+        hud.push_str(&format!(" | Quality {:.0}", quality));
+    }
+
+    if stats.upload_drop_fps > 0.0 {
+        hud.push_str(&format!(" | Capped {:.1} fps", stats.upload_drop_fps));
+    }
+
+    for (sid, fps) in &stats.stream_fps {
+        let username = usernames.get(sid).map(String::as_str).unwrap_or("?");
+        hud.push_str(&format!(" | {} {:.1} fps", username, fps));
+    }
+
+    for (sid, bound) in &stats.stream_bindings {
+        let sid_hex: String = sid.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let status = if *bound { "bound" } else { "not bound" };
+        hud.push_str(&format!(" | Stream {} {}", sid_hex, status));
+    }
+
+    hud
+}
+
+fn overlay_line(content: &mut String, line_index: usize, status: &str, width: u16) {
+    let line_start = match line_index {
+        0 => 0,
+        _ => match content.match_indices('\n').nth(line_index - 1) {
+            Some((newline, _)) => newline + 1,
+            None => return,
+        },
+    };
+    let line_end = content[line_start..]
+        .find('\n')
+        .map_or(content.len(), |end| line_start + end);
+
+    let status = truncate_to_width(status, width as usize);
+    let padding = " ".repeat((width as usize).saturating_sub(count_visible_chars_fast(&status)));
+
+    content.replace_range(
+        line_start..line_end,
+        &format!("\x1b[0m{}{}", status, padding),
+    );
+}
+
 async fn tcp_loop(
-    tcp_stream: &mut TcpStream,
-    sid_to_frame_string_map: Arc<Mutex<HashMap<StreamID, Option<Frame>>>>,
-    cancel_token: CancellationToken,
+    tcp_stream: &mut dyn ControlStream,
+    state: &CallState,
+    mut outgoing_command_rx: mpsc::UnboundedReceiver<TcpCommand>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sid_to_frame_string_map = &state.sid_to_frame_map;
+    let chat_log = &state.chat_log;
+    let call_stats = &state.call_stats;
+    let cancel_token = &state.cancel_token;
+    let mut command_reader = TcpCommandReader::new();
+
     loop {
         tokio::select! {
-            result = TcpCommand::read_from_stream(tcp_stream) => {
+            Some(command) = outgoing_command_rx.recv() => {
+                command.write_to_stream(tcp_stream).await?;
+            }
+            result = command_reader.read_from_stream(tcp_stream) => {
                 match result {
                     Ok(ReceivedTcpCommand::EOF) => {
-                        return Err("Server closed connection.".into());
+                        return Err(Box::new(ConnectionLost));
                     }
                     Ok(ReceivedTcpCommand::Command(command)) => {
                         match command {
-                            TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, sid_bytes) => {
-                                if let Ok(sid) = sid_bytes[..].try_into() {
+                            TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, payload) => {
+                                let sid_len = StreamID::default().len();
+                                if payload.len() >= sid_len
+                                    && let Ok(sid) = payload[..sid_len].try_into()
+                                {
+                                    let username = String::from_utf8_lossy(&payload[sid_len..]).to_string();
                                     let mut map = sid_to_frame_string_map.lock().await;
-                                    map.insert(sid, None);
+                                    map.entry(sid).or_default().username = username;
                                 }
                             }
                             TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid_bytes) => {
@@ -278,12 +770,46 @@ async fn tcp_loop(
                                     map.remove(&sid);
                                 }
                             }
+                            TcpCommand::StringList(TcpCommandId::ChatMessage, payload) => {
+                                if let [sender, text] = payload.as_slice() {
+                                    chat_log.lock().await.push(sender, text);
+                                }
+                            }
+                            TcpCommand::Bytes(TcpCommandId::MyStreamStatus, payload) => {
+                                let sid_len = StreamID::default().len();
+                                let stream_bindings = payload
+                                    .chunks_exact(sid_len + 1)
+                                    .filter_map(|entry| Some((entry[..sid_len].try_into().ok()?, entry[sid_len] != 0)))
+                                    .collect();
+                                call_stats.lock().await.stream_bindings = stream_bindings;
+                            }
+                            TcpCommand::StringList(TcpCommandId::RoomRenamed, payload) => {
+                                if let [old_name, new_name] = payload.as_slice() {
+                                    let notice = format!("Room '{}' was renamed to '{}'", old_name, new_name);
+                                    chat_log.lock().await.push(SERVER_CHAT_SENDER, &notice);
+                                }
+                            }
+                            TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+                                let error = ProtocolError::from_response(&error).to_string();
+                                chat_log.lock().await.push(SERVER_CHAT_SENDER, &error);
+                            }
+                            TcpCommand::Simple(TcpCommandId::YouWereKicked) => {
+                                return Err("You were kicked from the room.".into());
+                            }
+                            TcpCommand::Simple(TcpCommandId::ServerShuttingDown) => {
+                                return Err("The server is shutting down.".into());
+                            }
+                            TcpCommand::Simple(TcpCommandId::Ping) => {
+                                TcpCommand::Simple(TcpCommandId::Pong)
+                                    .write_to_stream(tcp_stream)
+                                    .await?;
+                            }
                             _ => {}
                         }
                     }
                     Err(e) => {
                         eprintln!("TCP error: {}", e);
-                        return Err(e);
+                        return Err(Box::new(ConnectionLost));
                     }
                 }
             },
@@ -295,24 +821,132 @@ async fn tcp_loop(
 }
 
 async fn user_input_loop(
-    cancel_token: CancellationToken,
+    camera_frame_channel_rx: watch::Receiver<Frame>,
+    state: CallState,
+    camera_index_tx: watch::Sender<i32>,
+    outgoing_command_tx: mpsc::UnboundedSender<TcpCommand>,
+    terminal_resize_tx: mpsc::UnboundedSender<(u16, u16)>,
+    idle_timeout: Option<Duration>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let CallState {
+        sid_to_frame_map,
+        chat_log,
+        hud_enabled,
+        paused,
+        mirror_enabled,
+        cancel_token,
+        ..
+    } = state;
     let mut interval = tokio::time::interval(Duration::from_millis(16));
+    let mut idle_tracker = IdleTracker::new(idle_timeout);
 
     loop {
         tokio::select! {
             _ = cancel_token.cancelled() => break,
             _ = interval.tick() => {
+                // Leaving through the same break as Ctrl+C tears the call down and sends LeaveRoom
+                if idle_tracker.is_idle() {
+                    break;
+                }
+
                 if event::poll(Duration::from_millis(0)).unwrap_or(false) {
-                    match event::read() {
-                        Ok(Event::Key(key_event)) => {
+                    let event = event::read();
+
+                    if let Ok(Event::Key(_)) = &event {
+                        idle_tracker.record_activity();
+                    }
+
+                    if let Ok(Event::Key(key_event)) = &event {
+                        let is_ctrl_c = key_event.code == KeyCode::Char('c')
+                            && key_event.modifiers.contains(KeyModifiers::CONTROL);
+
+                        if key_event.kind == KeyEventKind::Press && !is_ctrl_c {
+                            let mut chat = chat_log.lock().await;
+                            if chat.is_typing() {
+                                if let Some(message) = chat.handle_key(key_event.code) {
+                                    let command = match message.strip_prefix(KICK_CHAT_COMMAND) {
+                                        Some(target_username) => TcpCommand::String(TcpCommandId::KickUser, target_username.trim().to_string()),
+                                        None => TcpCommand::StringList(TcpCommandId::ChatMessage, vec![message]),
+                                    };
+                                    let _ = outgoing_command_tx.send(command);
+                                }
+                                continue;
+                            }
+                        }
+                    }
+
+                    match event {
+                        Ok(Event::Key(key_event))
                             if key_event.code == KeyCode::Char('c')
-                                && key_event.modifiers.contains(KeyModifiers::CONTROL)
+                                && key_event.modifiers.contains(KeyModifiers::CONTROL) =>
+                        {
+                            break;
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(HUD_TOGGLE_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            hud_enabled.fetch_xor(true, Ordering::Relaxed);
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(STREAM_STATUS_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            hud_enabled.store(true, Ordering::Relaxed);
+                            let _ = outgoing_command_tx.send(TcpCommand::Simple(TcpCommandId::GetMyStreamStatus));
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(PAUSE_TOGGLE_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            paused.fetch_xor(true, Ordering::Relaxed);
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(MIRROR_TOGGLE_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            mirror_enabled.fetch_xor(true, Ordering::Relaxed);
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(CAMERA_SWITCH_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            let available_cameras = tokio::task::spawn_blocking(Camera::list_available_cameras).await?;
+                            let current = camera_index_tx.borrow().to_string();
+                            let next = available_cameras
+                                .iter()
+                                .position(|camera| *camera == current)
+                                .map(|idx| (idx + 1) % available_cameras.len())
+                                .unwrap_or(0);
+
+                            if let Ok(next_index) = available_cameras[next].parse() {
+                                let _ = camera_index_tx.send(next_index);
+                            }
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(CHAT_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            chat_log.lock().await.start_typing();
+                        }
+                        Ok(Event::Key(key_event))
+                            if key_event.code == KeyCode::Char(SNAPSHOT_KEY)
+                                && key_event.kind == KeyEventKind::Press =>
+                        {
+                            let mut frames = vec![camera_frame_channel_rx.borrow().clone()];
                             {
-                                break;
+                                let frame_map = sid_to_frame_map.lock().await;
+                                frames.extend(frame_map.values().filter_map(|remote_stream| remote_stream.frame.clone()));
                             }
+
+                            let notice = match tokio::task::spawn_blocking(move || save_snapshot(&frames)).await? {
+                                Ok(path) => format!("Saved to {}", path),
+                                Err(e) => format!("Could not save: {}", e),
+                            };
+                            chat_log.lock().await.push(SNAPSHOT_CHAT_SENDER, &notice);
                         }
-                        Ok(Event::Resize(_, _)) => {
+                        Ok(Event::Resize(width, height)) => {
+                            let _ = terminal_resize_tx.send((width, height));
                         }
                         _ => {}
                     }
@@ -323,3 +957,423 @@ async fn user_input_loop(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::{
+        camera::{MAX_USER_CAMERAS, TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH},
+        frame::{ColorDepth, DEFAULT_ASCII_RAMP, RenderMode},
+        renderer::WriterRenderer,
+    };
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    const CAPTURE_FPS: u32 = 30;
+
+    fn test_settings() -> CallSettings {
+        CallSettings {
+            extra_camera_indices: Vec::new(),
+            send_resolution: (TEST_FRAME_WIDTH, TEST_FRAME_HEIGHT),
+            capture_fps: CAPTURE_FPS,
+            render_fps: CAPTURE_FPS,
+            style: AsciiStyle {
+                color_enabled: false,
+                color_depth: ColorDepth::None,
+                render_mode: RenderMode::Ascii,
+                ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+                dither: false,
+                color_fill: false,
+            },
+            quality: 75.0,
+            fec_enabled: false,
+            mtu: None,
+            max_upload_kbps: None,
+            mono_transport: false,
+            mirror: false,
+            mirror_self_only: false,
+            idle_timeout: None,
+            record_path: None,
+        }
+    }
+
+    struct CameraLoop {
+        frames: watch::Receiver<Frame>,
+        camera_index_tx: watch::Sender<i32>,
+        cancel_token: CancellationToken,
+        task: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+    }
+
+    impl CameraLoop {
+        fn spawn(camera_index: i32) -> Self {
+            Self::spawn_with(
+                Camera::new(camera_index).unwrap(),
+                camera_index,
+                test_settings(),
+            )
+        }
+
+        /// Runs on `camera`, reopening and switching by index like a real call.
+        fn spawn_with(camera: Camera, camera_index: i32, settings: CallSettings) -> Self {
+            let (frame_tx, frames) = watch::channel(Frame {
+                width: 0,
+                height: 0,
+                data: Arc::new(Vec::new()),
+            });
+            let (camera_index_tx, camera_index_rx) = watch::channel(camera_index);
+            let cancel_token = CancellationToken::new();
+            let task = tokio::spawn(capture_frames(
+                camera,
+                frame_tx,
+                camera_index_rx,
+                settings,
+                Arc::new(AtomicBool::new(false)),
+                cancel_token.clone(),
+            ));
+
+            Self {
+                frames,
+                camera_index_tx,
+                cancel_token,
+                task,
+            }
+        }
+
+        /// Counts the frames that arrive within `duration`.
+        async fn count_frames(&mut self, duration: Duration) -> usize {
+            let deadline = Instant::now() + duration;
+            let mut frames = 0;
+            while let Ok(Ok(())) = tokio::time::timeout_at(deadline, self.frames.changed()).await {
+                frames += 1;
+            }
+            frames
+        }
+
+        async fn stop(self) {
+            self.cancel_token.cancel();
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_tracker_fires_after_the_timeout_unless_reset() {
+        let mut tracker = IdleTracker::new(Some(IDLE_TIMEOUT));
+
+        tokio::time::advance(IDLE_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(!tracker.is_idle());
+
+        tracker.record_activity();
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(!tracker.is_idle());
+
+        tokio::time::advance(IDLE_TIMEOUT).await;
+        assert!(tracker.is_idle());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_tracker_without_timeout_never_fires() {
+        let tracker = IdleTracker::new(None);
+
+        tokio::time::advance(Duration::from_secs(24 * 60 * 60)).await;
+        assert!(!tracker.is_idle());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pattern_frames_flow_at_the_capture_rate() {
+        let mut camera_loop = CameraLoop::spawn(MAX_USER_CAMERAS);
+
+        let frames = camera_loop.count_frames(Duration::from_secs(2)).await;
+        let frame = camera_loop.frames.borrow().clone();
+        camera_loop.stop().await;
+
+        let expected = 2 * CAPTURE_FPS as usize;
+        assert!(
+            (expected - 3..=expected).contains(&frames),
+            "{} frames in 2s",
+            frames
+        );
+        assert_eq!(
+            (frame.width, frame.height),
+            (TEST_FRAME_WIDTH, TEST_FRAME_HEIGHT)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn capture_rates_above_30_fps_are_not_capped() {
+        const HIGH_CAPTURE_FPS: u32 = 60;
+        let settings = CallSettings {
+            capture_fps: HIGH_CAPTURE_FPS,
+            ..test_settings()
+        };
+        let mut camera_loop = CameraLoop::spawn_with(
+            Camera::new(MAX_USER_CAMERAS).unwrap(),
+            MAX_USER_CAMERAS,
+            settings,
+        );
+
+        let frames = camera_loop.count_frames(Duration::from_secs(2)).await;
+        camera_loop.stop().await;
+
+        let expected = 2 * HIGH_CAPTURE_FPS as usize;
+        assert!(
+            (expected - 6..=expected).contains(&frames),
+            "{} frames in 2s",
+            frames
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn swapping_cameras_keeps_frames_coming() {
+        const COLOR_BARS_CAMERA: i32 = MAX_USER_CAMERAS + 3;
+        let mut camera_loop = CameraLoop::spawn(MAX_USER_CAMERAS);
+        assert!(camera_loop.count_frames(Duration::from_millis(500)).await > 0);
+
+        camera_loop.camera_index_tx.send(COLOR_BARS_CAMERA).unwrap();
+        let frames = camera_loop.count_frames(Duration::from_secs(1)).await;
+        let frame = camera_loop.frames.borrow().clone();
+        camera_loop.stop().await;
+
+        assert!(
+            frames >= CAPTURE_FPS as usize - 3,
+            "{} frames in 1s",
+            frames
+        );
+        // The leftmost bar is gray and the rightmost blue
+        let last_pixel = (TEST_FRAME_WIDTH as usize - 1) * 3;
+        assert_eq!(frame.data[..3], [191, 191, 191]);
+        assert_eq!(frame.data[last_pixel..last_pixel + 3], [0, 0, 191]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_camera_shows_the_placeholder_until_frames_return() {
+        use opencv::core::{CV_8UC3, MatExprTraitConst, MatTrait, Vec3b};
+
+        let mut gray = Mat::zeros(TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH, CV_8UC3)
+            .unwrap()
+            .to_mat()
+            .unwrap();
+        for y in 0..gray.rows() {
+            for x in 0..gray.cols() {
+                *gray.at_2d_mut::<Vec3b>(y, x).unwrap() = Vec3b::from([90; 3]);
+            }
+        }
+
+        let (captured_tx, captured_rx) = mpsc::channel(2 * CAMERA_OFFLINE_THRESHOLD as usize);
+        for _ in 0..CAMERA_OFFLINE_THRESHOLD {
+            captured_tx
+                .send(Err("Empty frame captured".to_string()))
+                .await
+                .unwrap();
+        }
+        for _ in 0..CAMERA_OFFLINE_THRESHOLD {
+            captured_tx.send(Ok(gray.clone())).await.unwrap();
+        }
+
+        let mut camera_loop = CameraLoop::spawn_with(
+            Camera::from_frames(captured_rx),
+            MAX_USER_CAMERAS,
+            test_settings(),
+        );
+
+        camera_loop.frames.changed().await.unwrap();
+        let placeholder = camera_loop.frames.borrow_and_update().clone();
+        assert!(
+            placeholder
+                .data
+                .iter()
+                .all(|&value| value == 0 || value == 255)
+        );
+
+        camera_loop.frames.changed().await.unwrap();
+        let recovered = camera_loop.frames.borrow_and_update().clone();
+        assert!(recovered.data.iter().all(|&value| value == 90));
+
+        camera_loop.stop().await;
+        drop(captured_tx);
+    }
+
+    fn test_state() -> CallState {
+        CallState {
+            sid_to_frame_map: Arc::new(Mutex::new(HashMap::new())),
+            call_stats: Arc::new(Mutex::new(CallStats::new())),
+            chat_log: Arc::new(Mutex::new(ChatLog::new())),
+            hud_enabled: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            mirror_enabled: Arc::new(AtomicBool::new(false)),
+            cancel_token: CancellationToken::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_faster_than_the_render_rate_are_coalesced() {
+        const RENDER_FPS: u32 = 10;
+        let state = test_state();
+        let settings = CallSettings {
+            render_fps: RENDER_FPS,
+            ..test_settings()
+        };
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![128; 12]),
+        };
+        let (frame_tx, frame_rx) = watch::channel(frame.clone());
+        let (_terminal_resize_tx, terminal_resize_rx) = mpsc::unbounded_channel();
+
+        let render_task = tokio::spawn(render_loop(
+            frame_rx,
+            state.clone(),
+            settings,
+            None,
+            terminal_resize_rx,
+            (80, 24),
+            Renderer::with_writer(std::io::sink()),
+        ));
+
+        for _ in 0..CAPTURE_FPS * 3 {
+            tokio::time::sleep(Duration::from_secs(1) / CAPTURE_FPS).await;
+            frame_tx.send_replace(frame.clone());
+        }
+        state.cancel_token.cancel();
+        render_task.await.unwrap().unwrap();
+
+        let render_fps = state.call_stats.lock().await.render_fps;
+        assert!(
+            (RENDER_FPS as f32 * 0.8..=RENDER_FPS as f32 * 1.1).contains(&render_fps),
+            "Rendered at {} fps",
+            render_fps
+        );
+    }
+
+    /// Collects what the renderer writes so a test can read it after the render loop ends.
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Widths of the text runs between escape sequences, one list per screen clear.
+    fn printed_widths_per_clear(output: &str) -> Vec<Vec<usize>> {
+        let mut screens = Vec::new();
+        for screen in output.split("\x1b[2J").skip(1) {
+            let mut widths = Vec::new();
+            let mut chars = screen.chars().peekable();
+            let mut run = 0;
+            while let Some(c) = chars.next() {
+                if c == '\x1b' {
+                    widths.push(run);
+                    run = 0;
+                    // Skip to the final byte of the sequence
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            break;
+                        }
+                    }
+                } else {
+                    run += 1;
+                }
+            }
+            widths.push(run);
+            widths.retain(|width| *width > 0);
+            screens.push(widths);
+        }
+        screens
+    }
+
+    #[tokio::test]
+    async fn resize_is_rendered_at_the_new_terminal_size() {
+        let state = test_state();
+        let writer = SharedWriter::default();
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![128; 12]),
+        };
+        let (frame_tx, frame_rx) = watch::channel(frame.clone());
+        let (terminal_resize_tx, terminal_resize_rx) = mpsc::unbounded_channel();
+
+        let render_task = tokio::spawn(render_loop(
+            frame_rx,
+            state.clone(),
+            test_settings(),
+            None,
+            terminal_resize_rx,
+            (40, 10),
+            Renderer::with_writer(writer.clone()),
+        ));
+
+        frame_tx.send_replace(frame.clone());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // No new frame follows, so the redraw comes from the resize settling
+        terminal_resize_tx.send((20, 6)).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        state.cancel_token.cancel();
+        render_task.await.unwrap().unwrap();
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let screens = printed_widths_per_clear(&output);
+        assert_eq!(screens.len(), 2, "Expected a clear per size");
+        assert_eq!(screens[0].iter().max(), Some(&40));
+        assert_eq!(screens[1].iter().max(), Some(&20));
+    }
+
+    #[tokio::test]
+    async fn writer_renderer_gets_the_composited_call_view() {
+        let state = test_state();
+        state.hud_enabled.store(true, Ordering::Relaxed);
+        state.chat_log.lock().await.push("alice", "hello there");
+        let writer = SharedWriter::default();
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![128; 12]),
+        };
+        let (frame_tx, frame_rx) = watch::channel(frame.clone());
+        let (_terminal_resize_tx, terminal_resize_rx) = mpsc::unbounded_channel();
+
+        let render_task = tokio::spawn(render_loop(
+            frame_rx,
+            state.clone(),
+            test_settings(),
+            None,
+            terminal_resize_rx,
+            // Narrow enough that the tile's label row sits clear of the chat row
+            (20, 20),
+            WriterRenderer::new(writer.clone()),
+        ));
+
+        frame_tx.send_replace(frame);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        state.cancel_token.cancel();
+        render_task.await.unwrap().unwrap();
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let rows: Vec<&str> = output.lines().collect();
+        assert!(rows[0].contains("FPS"), "HUD missing: {:?}", rows);
+        assert!(
+            rows.iter().any(|row| row.contains(OWN_TILE_LABEL)),
+            "{:?}",
+            rows
+        );
+        assert!(
+            rows.iter().any(|row| row.contains("alice: hello")),
+            "Chat missing: {:?}",
+            rows
+        );
+        // Full frames only, with no cursor hiding or screen clears
+        assert!(!output.contains("\x1b[2J") && !output.contains("\x1b[?25l"));
+    }
+}
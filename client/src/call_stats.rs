@@ -0,0 +1,130 @@
+use std::{collections::HashMap, time::Duration};
+
+use shared::StreamID;
+use tokio::time::Instant;
+
+const STATS_WINDOW: Duration = Duration::from_secs(1);
+
+pub struct CallStats {
+    bytes_sent: u64,
+    bytes_received: u64,
+    frames_rendered: u32,
+    frames_received: HashMap<StreamID, u32>,
+    upload_drops: u32,
+    window_start: Instant,
+    pub render_fps: f32,
+    pub inbound_kbps: f32,
+    pub outbound_kbps: f32,
+    pub stream_fps: HashMap<StreamID, f32>,
+    /// The server's last answer on whether it has a UDP address for each of our streams.
+    pub stream_bindings: Vec<(StreamID, bool)>,
+    /// WebP quality our video is currently sent at, after adapting to packet loss.
+    pub send_quality: Option<f32>,
+    /// Frames per second left unsent to stay under `--max-upload-kbps`.
+    pub upload_drop_fps: f32,
+}
+
+impl Default for CallStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CallStats {
+    pub fn new() -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            frames_rendered: 0,
+            frames_received: HashMap::new(),
+            upload_drops: 0,
+            window_start: Instant::now(),
+            render_fps: 0.0,
+            inbound_kbps: 0.0,
+            outbound_kbps: 0.0,
+            stream_fps: HashMap::new(),
+            stream_bindings: Vec::new(),
+            send_quality: None,
+            upload_drop_fps: 0.0,
+        }
+    }
+
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+    }
+
+    pub fn record_frame_received(&mut self, sid: StreamID) {
+        *self.frames_received.entry(sid).or_insert(0) += 1;
+    }
+
+    pub fn record_frame_rendered(&mut self) {
+        self.frames_rendered += 1;
+    }
+
+    pub fn record_upload_drop(&mut self) {
+        self.upload_drops += 1;
+    }
+
+    pub fn update(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < STATS_WINDOW {
+            return;
+        }
+
+        let seconds = elapsed.as_secs_f32();
+        self.render_fps = self.frames_rendered as f32 / seconds;
+        self.inbound_kbps = self.bytes_received as f32 / 1024.0 / seconds;
+        self.outbound_kbps = self.bytes_sent as f32 / 1024.0 / seconds;
+        self.upload_drop_fps = self.upload_drops as f32 / seconds;
+        self.stream_fps = self
+            .frames_received
+            .drain()
+            .map(|(sid, frames)| (sid, frames as f32 / seconds))
+            .collect();
+
+        self.bytes_sent = 0;
+        self.bytes_received = 0;
+        self.frames_rendered = 0;
+        self.upload_drops = 0;
+        self.window_start = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_cover_the_whole_window_and_then_reset() {
+        let mut stats = CallStats::new();
+        let sid = [1; 4];
+
+        for _ in 0..20 {
+            stats.record_frame_rendered();
+            stats.record_frame_received(sid);
+            stats.record_received(512);
+        }
+        stats.record_sent(4096);
+
+        // Nothing is computed until the window has passed
+        tokio::time::advance(Duration::from_millis(500)).await;
+        stats.update();
+        assert_eq!(stats.render_fps, 0.0);
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        stats.update();
+        assert_eq!(stats.render_fps, 10.0);
+        assert_eq!(stats.stream_fps[&sid], 10.0);
+        assert_eq!(stats.inbound_kbps, 5.0);
+        assert_eq!(stats.outbound_kbps, 2.0);
+
+        tokio::time::advance(STATS_WINDOW).await;
+        stats.update();
+        assert_eq!(stats.render_fps, 0.0);
+        assert!(stats.stream_fps.is_empty());
+    }
+}
@@ -1,16 +1,18 @@
 use opencv::{
     core::{
-        CV_8UC1, LogLevel, Mat, MatExprTraitConst, MatTraitConst, flip, get_log_level,
-        set_log_level,
+        CV_8UC1, LogLevel, Mat, MatExprTraitConst, MatTraitConst, get_log_level, set_log_level,
+    },
+    videoio::{
+        CAP_ANY, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst, get_backend_name,
+        get_camera_backends,
     },
-    videoio::{CAP_ANY, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst},
 };
 use std::error::Error;
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use std::result::Result;
 use strum::IntoEnumIterator;
-use tokio::time::Instant;
+use tokio::{sync::mpsc, time::Instant};
 
 pub const MAX_USER_CAMERAS: i32 = 10;
 
@@ -31,11 +33,10 @@ pub struct Camera {
     capture: CameraCapture,
     frame: Mat,
     start_time: Instant,
-    last_frame_time: Instant,
 }
 
 enum CameraCapture {
-    Real(VideoCapture),
+    Real(mpsc::Receiver<Result<Mat, String>>),
     Test(CameraTestMode),
 }
 
@@ -50,48 +51,38 @@ impl Camera {
                     capture: CameraCapture::Test(mode),
                     frame,
                     start_time,
-                    last_frame_time: Instant::now(),
                 });
             }
         }
 
-        let cam = VideoCapture::new(camera_index, CAP_ANY)?;
+        let cam = VideoCapture::new(camera_index, CAP_ANY)
+            .map_err(|e| open_camera_error(camera_index, Some(&e.to_string())))?;
         if !cam.is_opened()? {
-            return Err(format!("Could not open camera at index {}", camera_index).into());
+            return Err(open_camera_error(camera_index, None).into());
         }
 
         Ok(Self {
-            capture: CameraCapture::Real(cam),
+            capture: CameraCapture::Real(spawn_capture_thread(cam)),
             frame,
             start_time,
-            last_frame_time: Instant::now(),
         })
     }
 
-    pub async fn get_frame(&mut self) -> Result<&Mat, Box<dyn Error + Send + Sync>> {
-        let target_frame_duration = std::time::Duration::from_millis(33);
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_frame_time);
-
-        if elapsed < target_frame_duration {
-            tokio::time::sleep(target_frame_duration - elapsed).await;
+    /// A camera that delivers whatever comes in on `frames`, errors included.
+    #[cfg(test)]
+    pub(crate) fn from_frames(frames: mpsc::Receiver<Result<Mat, String>>) -> Self {
+        Self {
+            capture: CameraCapture::Real(frames),
+            frame: Mat::default(),
+            start_time: Instant::now(),
         }
+    }
 
-        self.last_frame_time = Instant::now();
-
+    /// Returns the next frame without pacing, so callers capture at their own rate.
+    pub async fn get_frame(&mut self) -> Result<&Mat, Box<dyn Error + Send + Sync>> {
         match &mut self.capture {
-            CameraCapture::Real(video_capture) => {
-                let mut flipped = Mat::default();
-                video_capture.read(&mut flipped)?;
-
-                if flipped.empty() {
-                    return Err("Empty frame captured".into());
-                }
-
-                if let Err(e) = flip(&flipped, &mut self.frame, 1) {
-                    eprintln!("Flip failed, using original frame: {}", e);
-                    self.frame = flipped;
-                }
+            CameraCapture::Real(frame_rx) => {
+                self.frame = frame_rx.recv().await.ok_or("Camera capture stopped")??;
 
                 Ok(&self.frame)
             }
@@ -117,10 +108,10 @@ impl Camera {
             let mut available = vec!["0".to_string()];
 
             for i in 1..MAX_USER_CAMERAS {
-                if let Ok(cam) = VideoCapture::new(i, CAP_ANY) {
-                    if cam.is_opened().unwrap_or(false) {
-                        available.push(format!("{}", i));
-                    }
+                if let Ok(cam) = VideoCapture::new(i, CAP_ANY)
+                    && cam.is_opened().unwrap_or(false)
+                {
+                    available.push(format!("{}", i));
                 }
             }
 
@@ -153,6 +144,92 @@ impl Camera {
     }
 }
 
+fn open_camera_error(camera_index: i32, opencv_error: Option<&str>) -> String {
+    let mut message = format!("Could not open camera at index {}", camera_index);
+
+    if let Some(opencv_error) = opencv_error {
+        message.push_str(&format!(": {}", opencv_error));
+    }
+
+    let backends = camera_backend_names();
+    if backends.is_empty() {
+        message.push_str(" (OpenCV was built without any camera backends)");
+    } else {
+        message.push_str(&format!(" (camera backends: {})", backends.join(", ")));
+    }
+
+    if let Some(hint) = camera_device_hint(camera_index) {
+        message.push_str(". ");
+        message.push_str(&hint);
+    }
+
+    message
+}
+
+fn camera_backend_names() -> Vec<String> {
+    match get_camera_backends() {
+        Ok(backends) => backends
+            .iter()
+            .filter_map(|backend| get_backend_name(backend).ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn camera_device_hint(camera_index: i32) -> Option<String> {
+    let path = format!("/dev/video{}", camera_index);
+
+    let hint = match File::open(&path) {
+        Ok(_) => format!(
+            "{} exists and is readable, it may be in use by another application",
+            path
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => format!("{} does not exist", path),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => format!(
+            "{} is not readable, check that your user is in the 'video' group",
+            path
+        ),
+        Err(e) => format!("{} could not be opened: {}", path, e),
+    };
+
+    Some(hint)
+}
+
+#[cfg(target_os = "macos")]
+fn camera_device_hint(_camera_index: i32) -> Option<String> {
+    Some(
+        "Check that your terminal is allowed to use the camera in System Settings > Privacy & Security > Camera"
+            .to_string(),
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn camera_device_hint(_camera_index: i32) -> Option<String> {
+    None
+}
+
+fn spawn_capture_thread(mut video_capture: VideoCapture) -> mpsc::Receiver<Result<Mat, String>> {
+    let (frame_tx, frame_rx) = mpsc::channel(1);
+
+    std::thread::spawn(move || {
+        loop {
+            let mut frame = Mat::default();
+            let result = match video_capture.read(&mut frame) {
+                Ok(_) if frame.empty() => Err("Empty frame captured".to_string()),
+                Ok(_) => Ok(frame),
+                Err(e) => Err(e.to_string()),
+            };
+
+            if frame_tx.blocking_send(result).is_err() {
+                break;
+            }
+        }
+    });
+
+    frame_rx
+}
+
 #[cfg(unix)]
 fn silence_stderr<F: FnOnce() -> T, T>(f: F) -> T {
     let devnull = File::open("/dev/null").unwrap();
@@ -0,0 +1,93 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crossterm::event::KeyCode;
+use shared::{MAX_CHAT_MESSAGE_LENGTH, sanitize_chat_message};
+use tokio::time::Instant;
+
+const MAX_CHAT_HISTORY: usize = 50;
+const VISIBLE_CHAT_MESSAGES: usize = 5;
+const CHAT_MESSAGE_LIFETIME: Duration = Duration::from_secs(15);
+
+struct ChatEntry {
+    sender: String,
+    text: String,
+    received_at: Instant,
+}
+
+pub struct ChatLog {
+    messages: VecDeque<ChatEntry>,
+    input: Option<String>,
+}
+
+impl ChatLog {
+    pub fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            input: None,
+        }
+    }
+
+    pub fn push(&mut self, sender: &str, text: &str) {
+        if self.messages.len() == MAX_CHAT_HISTORY {
+            self.messages.pop_front();
+        }
+
+        self.messages.push_back(ChatEntry {
+            sender: sanitize_chat_message(sender),
+            text: sanitize_chat_message(text),
+            received_at: Instant::now(),
+        });
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.input.is_some()
+    }
+
+    pub fn start_typing(&mut self) {
+        self.input = Some(String::new());
+    }
+
+    pub fn handle_key(&mut self, key_code: KeyCode) -> Option<String> {
+        let input = self.input.as_mut()?;
+
+        match key_code {
+            KeyCode::Char(c) if input.chars().count() < MAX_CHAT_MESSAGE_LENGTH => {
+                input.push(c);
+            }
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => {
+                self.input = None;
+            }
+            KeyCode::Enter => {
+                let message = sanitize_chat_message(&self.input.take().unwrap_or_default());
+                if !message.is_empty() {
+                    return Some(message);
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    pub fn visible_lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .messages
+            .iter()
+            .filter(|entry| entry.received_at.elapsed() < CHAT_MESSAGE_LIFETIME)
+            .map(|entry| format!("{}: {}", entry.sender, entry.text))
+            .collect();
+
+        if lines.len() > VISIBLE_CHAT_MESSAGES {
+            lines.drain(..lines.len() - VISIBLE_CHAT_MESSAGES);
+        }
+
+        if let Some(input) = &self.input {
+            lines.push(format!("> {}_", input));
+        }
+
+        lines
+    }
+}
@@ -61,34 +61,34 @@ impl CliDisplay {
         println!();
     }
 
+    pub fn print_room_members(room_name: &str, members: &[String], current_username: &str) {
+        let content = if members.is_empty() {
+            vec!["(room is empty)".to_string()]
+        } else {
+            members
+                .iter()
+                .map(|member| {
+                    if member == current_username {
+                        format!("- {} (you)", member)
+                    } else {
+                        format!("- {}", member)
+                    }
+                })
+                .collect()
+        };
+        draw_box(&format!("Members of '{}'", room_name), &content);
+        println!();
+    }
+
     pub fn print_camera_list(camera_list: &[String], current_camera_index: i32) {
         let mut content = if camera_list.is_empty() {
             vec!["(no cameras available)".to_string()]
         } else {
             camera_list
                 .iter()
-                .map(|r| {
-                    if r == "0" {
-                        "- 0 (main camera)".to_string()
-                    } else if let Ok(index) = r.parse::<i32>() {
-                        let test_camera_start = MAX_USER_CAMERAS as i32;
-                        let test_camera_end =
-                            test_camera_start + CameraTestMode::iter().count() as i32;
-
-                        if index >= test_camera_start && index < test_camera_end {
-                            let test_index = (index - test_camera_start) as usize;
-                            let test_mode = CameraTestMode::iter().nth(test_index);
-                            if let Some(mode) = test_mode {
-                                format!("- {} (test camera: {})", r, mode.to_string())
-                            } else {
-                                format!("- {} (test camera: unknown)", r)
-                            }
-                        } else {
-                            format!("- {}", r)
-                        }
-                    } else {
-                        format!("- {}", r)
-                    }
+                .map(|r| match r.parse().ok().and_then(camera_description) {
+                    Some(description) => format!("- {} ({})", r, description),
+                    None => format!("- {}", r),
                 })
                 .collect::<Vec<_>>()
         };
@@ -104,6 +104,31 @@ impl CliDisplay {
         println!();
     }
 
+    pub fn print_status(
+        server_addr: &str,
+        current_user: &str,
+        camera_index: i32,
+        tls_enabled: bool,
+    ) {
+        print!(
+            "{}",
+            status_box(server_addr, current_user, camera_index, tls_enabled)
+        );
+        println!();
+    }
+
+    pub fn print_connected_json(server_addr: &str, username: &str) {
+        Self::print_json(&serde_json::json!({
+            "type": "connected",
+            "server": server_addr,
+            "user": username,
+        }));
+    }
+
+    pub fn print_json(value: &serde_json::Value) {
+        println!("{}", value);
+    }
+
     pub fn print_prompt() {
         print!("> ");
         stdout().flush().unwrap();
@@ -112,10 +137,14 @@ impl CliDisplay {
     pub fn print_command_help() {
         println!("\nAvailable Commands:");
         println!("    - list users|rooms|cameras   : Lists users, rooms, or available cameras");
+        println!("    - list members <room>        : Lists the users inside a room");
         println!("    - switch camera [index]      : Switches to camera at index");
-        println!("    - create room <string>       : Creates a new room");
+        println!("    - create room <string> [pass]: Creates a room, optionally with a password");
+        println!("      [--max <users>]            : Limits how many users can join the room");
         println!("    - delete room <string>       : Deletes a room");
-        println!("    - join room <string>         : Joins a specific room");
+        println!("    - rename room <string> <new> : Renames a room you created");
+        println!("    - join room <string> [pass]  : Joins a specific room");
+        println!("    - status                     : Shows your user, server, and camera");
         println!("    - help                       : Displays a list of available commands");
         println!("    - exit                       : Quits the application\n");
     }
@@ -126,6 +155,24 @@ impl CliDisplay {
     }
 }
 
+/// What a camera index refers to, for indices other than plain user cameras.
+fn camera_description(index: i32) -> Option<String> {
+    let test_camera_start = MAX_USER_CAMERAS;
+    let test_camera_end = test_camera_start + CameraTestMode::iter().count() as i32;
+
+    if index == 0 {
+        Some("main camera".to_string())
+    } else if index >= test_camera_start && index < test_camera_end {
+        let test_index = (index - test_camera_start) as usize;
+        match CameraTestMode::iter().nth(test_index) {
+            Some(mode) => Some(format!("test camera: {}", mode)),
+            None => Some("test camera: unknown".to_string()),
+        }
+    } else {
+        None
+    }
+}
+
 fn draw_double_box(title: &str, lines: &[String]) {
     let width = lines
         .iter()
@@ -142,7 +189,40 @@ fn draw_double_box(title: &str, lines: &[String]) {
     println!("╚{}╝", "═".repeat(width + 2));
 }
 
+/// The `status` box: who you are, where you're connected, and which camera is selected.
+fn status_box(
+    server_addr: &str,
+    current_user: &str,
+    camera_index: i32,
+    tls_enabled: bool,
+) -> String {
+    let camera = match camera_description(camera_index) {
+        Some(description) => format!("{} ({})", camera_index, description),
+        None => camera_index.to_string(),
+    };
+
+    let lines = vec![
+        format!("User: {}", current_user),
+        format!("Server: {}", server_addr),
+        format!("Camera: {}", camera),
+        format!(
+            "Connection: {}",
+            if tls_enabled {
+                "Connected (TLS)"
+            } else {
+                "Connected"
+            }
+        ),
+    ];
+
+    format_box("Status", &lines)
+}
+
 fn draw_box(title: &str, lines: &[String]) {
+    print!("{}", format_box(title, lines));
+}
+
+fn format_box(title: &str, lines: &[String]) -> String {
     let left_padding = 2;
     let right_padding = 2;
 
@@ -166,13 +246,13 @@ fn draw_box(title: &str, lines: &[String]) {
 
     let bottom_border = format!("╰{:─<width$}╯", "", width = border_fill_width + 2);
 
-    println!("\n{}", top_border);
+    let mut output = format!("\n{}\n", top_border);
 
-    println!("│ {:width$} │", "", width = border_fill_width);
+    output += &format!("│ {:width$} │\n", "", width = border_fill_width);
 
     for line in lines {
-        println!(
-            "│ {:<width$} │",
+        output += &format!(
+            "│ {:<width$} │\n",
             format!(
                 "{}{}{}",
                 " ".repeat(left_padding),
@@ -183,5 +263,36 @@ fn draw_box(title: &str, lines: &[String]) {
         );
     }
 
-    println!("{}", bottom_border);
+    output += &format!("{}\n", bottom_border);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_box_contains_the_user_and_camera() {
+        let status = status_box("127.0.0.1:8080", "alice", MAX_USER_CAMERAS, true);
+        let camera_line = format!(
+            "Camera: {} (test camera: {})",
+            MAX_USER_CAMERAS,
+            CameraTestMode::iter().next().unwrap()
+        );
+
+        let rows: Vec<&str> = status.lines().collect();
+        assert!(rows[1].contains(" Status "));
+        for expected in ["User: alice", &camera_line, "Connection: Connected (TLS)"] {
+            assert!(
+                rows.iter().any(|row| row.contains(expected)),
+                "No {:?} in {}",
+                expected,
+                status
+            );
+        }
+
+        // Every row of the box is as wide as the borders
+        let width = rows[1].chars().count();
+        assert!(rows[1..].iter().all(|row| row.chars().count() == width));
+    }
 }
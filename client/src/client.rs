@@ -1,81 +1,919 @@
 use core::error::Error;
 
 use shared::{
-    TCP_PORT, UDP_PORT, received_tcp_command::ReceivedTcpCommand, tcp_command::TcpCommand,
-    tcp_command_id::TcpCommandId,
+    LIST_COMPRESSION_FEATURE, SessionToken, TCP_PORT, UDP_PORT, control_stream::ControlStream,
+    format_host_port, protocol_error::ProtocolError, received_tcp_command::ReceivedTcpCommand,
+    tcp_command::TcpCommand, tcp_command_id::TcpCommandId, user_list_label,
+};
+use std::{fmt, time::Duration};
+
+use tokio::{
+    io::duplex,
+    net::{TcpStream, UdpSocket},
+    time::timeout,
 };
-use tokio::net::{TcpStream, UdpSocket};
 
 use crate::{
-    call_interface::CallInterface, cli_display::CliDisplay, pre_call_interface::PreCallInterface,
+    call_interface::{CallInterface, CallSettings, ConnectionLost},
+    cli_display::CliDisplay,
+    frame::{AsciiStyle, ColorDepth, DEFAULT_ASCII_RAMP, RenderMode},
+    loopback::{self, LOOPBACK_CONTROL_BUFFER_SIZE},
+    media_crypto::split_call_payload,
+    pre_call_interface::PreCallInterface,
+    tls::connect_tls,
 };
 
-pub struct Client;
+const RESUME_ATTEMPTS: u32 = 10;
+const RESUME_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_SEND_RESOLUTION: (i32, i32) = (96, 54);
+const DEFAULT_FPS: u32 = 30;
+const DEFAULT_QUALITY: f32 = 75.0;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_CONNECT_RETRIES: u32 = 3;
+const CONNECT_BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(8);
+pub const MIN_RESOLUTION: (i32, i32) = (16, 9);
+pub const MAX_RESOLUTION: (i32, i32) = (384, 216);
+pub const MAX_FPS: u32 = 120;
+
+/// A [`Client`] setting that is out of range, caught before anything connects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    InvalidFps(u32),
+    InvalidResolution(i32, i32),
+    MalformedResolution(String),
+    InvalidAsciiRamp,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidFps(fps) => {
+                write!(f, "FPS must be between 1 and {}, got {}", MAX_FPS, fps)
+            }
+            ClientError::InvalidResolution(width, height) => write!(
+                f,
+                "Resolution must be between {}x{} and {}x{}, got {}x{}",
+                MIN_RESOLUTION.0,
+                MIN_RESOLUTION.1,
+                MAX_RESOLUTION.0,
+                MAX_RESOLUTION.1,
+                width,
+                height
+            ),
+            ClientError::MalformedResolution(resolution) => write!(
+                f,
+                "Resolution must be formatted as WIDTHxHEIGHT, e.g. 96x54, got '{}'",
+                resolution
+            ),
+            ClientError::InvalidAsciiRamp => write!(
+                f,
+                "ASCII ramp must be a non-empty string of printable ASCII characters"
+            ),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+pub fn validate_fps(fps: u32) -> Result<u32, ClientError> {
+    match fps {
+        1..=MAX_FPS => Ok(fps),
+        _ => Err(ClientError::InvalidFps(fps)),
+    }
+}
+
+pub fn validate_resolution((width, height): (i32, i32)) -> Result<(i32, i32), ClientError> {
+    if !(MIN_RESOLUTION.0..=MAX_RESOLUTION.0).contains(&width)
+        || !(MIN_RESOLUTION.1..=MAX_RESOLUTION.1).contains(&height)
+    {
+        return Err(ClientError::InvalidResolution(width, height));
+    }
+
+    Ok((width, height))
+}
+
+/// Parses a `WIDTHxHEIGHT` resolution and checks it is in range.
+pub fn parse_resolution(resolution: &str) -> Result<(i32, i32), ClientError> {
+    let malformed = || ClientError::MalformedResolution(resolution.to_string());
+
+    let (width, height) = resolution.split_once(['x', 'X']).ok_or_else(malformed)?;
+    let width = width.parse().map_err(|_| malformed())?;
+    let height = height.parse().map_err(|_| malformed())?;
+
+    validate_resolution((width, height))
+}
+
+/// Accepts a ramp of printable ASCII characters, since rendering indexes it byte by byte.
+pub fn parse_ascii_ramp(ramp: &str) -> Result<String, ClientError> {
+    if ramp.is_empty() || !ramp.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return Err(ClientError::InvalidAsciiRamp);
+    }
+
+    Ok(ramp.to_string())
+}
+
+/// Connects to a WeSFU server and runs the interactive pre-call and call interfaces.
+///
+/// Built with [`Client::new`] and configured through the chained setters, which
+/// default to the same values as the `client` CLI.
+pub struct Client {
+    server_addr: String,
+    tcp_port: u16,
+    udp_port: u16,
+    username: String,
+    display_name: Option<String>,
+    camera_index: i32,
+    call_settings: CallSettings,
+    tls_enabled: bool,
+    ca_path: Option<String>,
+    join_room: Option<String>,
+    join_password: Option<String>,
+    create_room: bool,
+    json_output: bool,
+    connect_timeout: Duration,
+    connect_retries: u32,
+    loopback: bool,
+}
 
 impl Client {
-    pub async fn run(
-        server_addr: &str,
-        username: &str,
-        camera_index: &mut i32,
-        color_enabled: bool,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let server_tcp_addr = format!("{}:{}", server_addr, TCP_PORT);
-        let server_udp_addr = format!("{}:{}", server_addr, UDP_PORT);
-
-        let mut tcp_stream = TcpStream::connect(server_tcp_addr).await?;
-
-        perform_handshake(&mut tcp_stream, username).await?;
-        CliDisplay::print_connected_message(server_addr, username);
-
-        let call_info_option =
-            PreCallInterface::run(&mut tcp_stream, username, camera_index).await?;
-
-        match call_info_option {
-            Some(full_sid) => {
-                let udp_stream = UdpSocket::bind("0.0.0.0:0").await?;
-                udp_stream.connect(&server_udp_addr).await?;
-
-                if let Err(e) = CallInterface::run(
-                    &full_sid,
-                    &mut tcp_stream,
-                    udp_stream,
-                    *camera_index,
-                    color_enabled,
+    pub fn new(server_addr: &str, username: &str) -> Self {
+        Self {
+            server_addr: server_addr.to_string(),
+            tcp_port: TCP_PORT,
+            udp_port: UDP_PORT,
+            username: username.to_string(),
+            display_name: None,
+            camera_index: 0,
+            call_settings: CallSettings {
+                extra_camera_indices: Vec::new(),
+                send_resolution: DEFAULT_SEND_RESOLUTION,
+                capture_fps: DEFAULT_FPS,
+                render_fps: DEFAULT_FPS,
+                style: AsciiStyle {
+                    color_enabled: false,
+                    color_depth: ColorDepth::Auto,
+                    render_mode: RenderMode::Ascii,
+                    ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+                    dither: false,
+                    color_fill: false,
+                },
+                quality: DEFAULT_QUALITY,
+                fec_enabled: false,
+                mtu: None,
+                max_upload_kbps: None,
+                mono_transport: false,
+                mirror: false,
+                mirror_self_only: false,
+                idle_timeout: None,
+                record_path: None,
+            },
+            tls_enabled: false,
+            ca_path: None,
+            join_room: None,
+            join_password: None,
+            create_room: false,
+            json_output: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            loopback: false,
+        }
+    }
+
+    pub fn tcp_port(mut self, tcp_port: u16) -> Self {
+        self.tcp_port = tcp_port;
+        self
+    }
+
+    pub fn udp_port(mut self, udp_port: u16) -> Self {
+        self.udp_port = udp_port;
+        self
+    }
+
+    /// Name shown to other users in lists and on video tiles instead of the username.
+    /// Unlike the username, it doesn't have to be unique and may contain spaces.
+    pub fn display_name(mut self, display_name: Option<&str>) -> Self {
+        self.display_name = display_name.map(str::to_string);
+        self
+    }
+
+    /// Camera index to start with; indices from `camera::MAX_USER_CAMERAS` up select test patterns.
+    pub fn camera_index(mut self, camera_index: i32) -> Self {
+        self.camera_index = camera_index;
+        self
+    }
+
+    /// Further cameras to tile to the right of the main one in a single stream. Switching
+    /// cameras during a call only changes the main one.
+    pub fn extra_camera_indices(mut self, extra_camera_indices: Vec<i32>) -> Self {
+        self.call_settings.extra_camera_indices = extra_camera_indices;
+        self
+    }
+
+    /// Width and height, in pixels, of the frames sent to other users.
+    pub fn send_resolution(mut self, send_resolution: (i32, i32)) -> Self {
+        self.call_settings.send_resolution = send_resolution;
+        self
+    }
+
+    /// How many frames per second to capture and send.
+    pub fn capture_fps(mut self, capture_fps: u32) -> Self {
+        self.call_settings.capture_fps = capture_fps;
+        self
+    }
+
+    /// Upper bound on terminal redraws per second, independent of the capture rate.
+    pub fn render_fps(mut self, render_fps: u32) -> Self {
+        self.call_settings.render_fps = render_fps;
+        self
+    }
+
+    pub fn color_enabled(mut self, color_enabled: bool) -> Self {
+        self.call_settings.style.color_enabled = color_enabled;
+        self
+    }
+
+    /// Palette used for colored output; `ColorDepth::Auto` picks truecolor or 256 colors
+    /// from the terminal environment.
+    pub fn color_depth(mut self, color_depth: ColorDepth) -> Self {
+        self.call_settings.style.color_depth = color_depth;
+        self
+    }
+
+    pub fn render_mode(mut self, render_mode: RenderMode) -> Self {
+        self.call_settings.style.render_mode = render_mode;
+        self
+    }
+
+    /// Characters used for ASCII rendering, ordered from darkest to brightest.
+    pub fn ascii_ramp(mut self, ascii_ramp: &str) -> Self {
+        self.call_settings.style.ascii_ramp = ascii_ramp.to_string();
+        self
+    }
+
+    /// Dither luma before picking ASCII characters, trading speed for smoother gradients.
+    pub fn dither(mut self, dither: bool) -> Self {
+        self.call_settings.style.dither = dither;
+        self
+    }
+
+    /// Paint each ASCII cell's background in its pixel color, so glyph gaps don't show
+    /// the terminal background. Only applies when color is enabled.
+    pub fn color_fill(mut self, color_fill: bool) -> Self {
+        self.call_settings.style.color_fill = color_fill;
+        self
+    }
+
+    /// WebP encoding quality, from 0 to 100.
+    pub fn quality(mut self, quality: f32) -> Self {
+        self.call_settings.quality = quality;
+        self
+    }
+
+    /// Sends XOR parity chunks so single lost chunks can be recovered.
+    pub fn fec_enabled(mut self, fec_enabled: bool) -> Self {
+        self.call_settings.fec_enabled = fec_enabled;
+        self
+    }
+
+    /// Path MTU used to size UDP chunks. When unset, the path to the server is probed at
+    /// the start of each call.
+    pub fn mtu(mut self, mtu: Option<usize>) -> Self {
+        self.call_settings.mtu = mtu;
+        self
+    }
+
+    /// Hard ceiling on video upload. Frames that would exceed it are dropped, deltas first.
+    pub fn max_upload_kbps(mut self, max_upload_kbps: Option<u32>) -> Self {
+        self.call_settings.max_upload_kbps = max_upload_kbps;
+        self
+    }
+
+    /// Sends grayscale frames to reduce bandwidth.
+    pub fn mono_transport(mut self, mono_transport: bool) -> Self {
+        self.call_settings.mono_transport = mono_transport;
+        self
+    }
+
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.call_settings.mirror = mirror;
+        self
+    }
+
+    /// Mirrors the local preview only, leaving the transmitted frames untouched.
+    pub fn mirror_self_only(mut self, mirror_self_only: bool) -> Self {
+        self.call_settings.mirror_self_only = mirror_self_only;
+        self
+    }
+
+    /// Connects to the control channel over TLS, trusting `ca_path` if given.
+    pub fn tls(mut self, tls_enabled: bool, ca_path: Option<&str>) -> Self {
+        self.tls_enabled = tls_enabled;
+        self.ca_path = ca_path.map(str::to_string);
+        self
+    }
+
+    /// Leaves the call after this long without a key press. `None` stays in the call.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.call_settings.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Directory to record calls into. Ignored unless built with the `record` feature.
+    pub fn record_path(mut self, record_path: Option<String>) -> Self {
+        self.call_settings.record_path = record_path;
+        self
+    }
+
+    /// Joins `room_name` right after connecting instead of showing the interactive menu,
+    /// creating the room first if `create` is set and it doesn't exist yet.
+    pub fn join(mut self, room_name: &str, password: Option<&str>, create: bool) -> Self {
+        self.join_room = Some(room_name.to_string());
+        self.join_password = password.map(str::to_string);
+        self.create_room = create;
+        self
+    }
+
+    /// Prints menu output as one JSON object per line instead of boxed tables.
+    pub fn json_output(mut self, json_output: bool) -> Self {
+        self.json_output = json_output;
+        self
+    }
+
+    /// How long each connection attempt, including the handshake, may take before it is abandoned.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How many times to retry a failed connection attempt, with exponential backoff.
+    pub fn connect_retries(mut self, connect_retries: u32) -> Self {
+        self.connect_retries = connect_retries;
+        self
+    }
+
+    /// Skips the server and plays the local camera back as a remote participant, running
+    /// it through the same encode, chunk, decode and render path as a real call.
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.loopback = loopback;
+        self
+    }
+
+    /// Checks the settings the CLI also range-checks, since the setters store them as given.
+    pub fn validate(&self) -> Result<(), ClientError> {
+        validate_resolution(self.call_settings.send_resolution)?;
+        validate_fps(self.call_settings.capture_fps)?;
+        validate_fps(self.call_settings.render_fps)?;
+        parse_ascii_ramp(&self.call_settings.style.ascii_ramp)?;
+
+        Ok(())
+    }
+
+    /// Runs until the user quits, taking over the terminal while in a call. Fails with a
+    /// [`ClientError`] before connecting if [`Client::validate`] does.
+    pub async fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.validate()?;
+
+        let server_addr = self.server_addr.as_str();
+        let username = self.username.as_str();
+        let display_name = self.display_name.as_deref();
+        let tcp_port = self.tcp_port;
+        let udp_port = self.udp_port;
+        let camera_index = &mut self.camera_index;
+        let call_settings = &self.call_settings;
+        let tls_enabled = self.tls_enabled;
+        let ca_path = self.ca_path.as_deref();
+        let json_output = self.json_output;
+        let connect_timeout = self.connect_timeout;
+        let connect_retries = self.connect_retries;
+
+        if self.loopback {
+            let (mut control_stream, control_far_end) = duplex(LOOPBACK_CONTROL_BUFFER_SIZE);
+            let control_task =
+                tokio::spawn(loopback::run_control(control_far_end, username.to_string()));
+
+            let reflector_socket = UdpSocket::bind("127.0.0.1:0").await?;
+            let reflector_addr = reflector_socket.local_addr()?;
+            let reflector_task = tokio::spawn(loopback::run_reflector(reflector_socket));
+
+            let udp_stream = UdpSocket::bind("127.0.0.1:0").await?;
+            udp_stream.connect(reflector_addr).await?;
+
+            let call_result = CallInterface::run(
+                &loopback::loopback_full_sid(),
+                None,
+                &mut control_stream,
+                udp_stream,
+                *camera_index,
+                call_settings,
+            )
+            .await;
+
+            control_task.abort();
+            reflector_task.abort();
+
+            if let Err(e) = call_result {
+                eprintln!("Call Error: {}", e);
+            }
+
+            return Ok(());
+        }
+
+        let server_tcp_addr = format_host_port(server_addr, tcp_port);
+        let server_udp_addr = format_host_port(server_addr, udp_port);
+
+        let (mut tcp_stream, server_is_ipv6, session_token) = connect_with_retries(
+            &server_tcp_addr,
+            server_addr,
+            tls_enabled,
+            ca_path,
+            username,
+            connect_timeout,
+            connect_retries,
+        )
+        .await?;
+
+        if let Some(display_name) = display_name {
+            PreCallInterface::set_display_name(tcp_stream.as_mut(), display_name).await?;
+        }
+
+        if json_output {
+            CliDisplay::print_connected_json(server_addr, username);
+        } else {
+            CliDisplay::print_connected_message(server_addr, username);
+        }
+
+        let call_info_option = match &self.join_room {
+            Some(room_name) => Some(
+                PreCallInterface::join_directly(
+                    tcp_stream.as_mut(),
+                    room_name,
+                    self.join_password.as_deref(),
+                    self.create_room,
+                    json_output,
+                )
+                .await?,
+            ),
+            None => {
+                let current_user = user_list_label(username, display_name);
+                PreCallInterface::run(
+                    tcp_stream.as_mut(),
+                    &server_tcp_addr,
+                    tls_enabled,
+                    &current_user,
+                    camera_index,
+                    json_output,
+                )
+                .await?
+            }
+        };
+
+        let mut call_payload = match call_info_option {
+            Some(call_payload) => call_payload,
+            None => return Ok(()),
+        };
+
+        loop {
+            let udp_bind_addr = if server_is_ipv6 {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let udp_stream = UdpSocket::bind(udp_bind_addr).await?;
+            udp_stream.connect(&server_udp_addr).await?;
+
+            let (full_sid, media_key) =
+                split_call_payload(&call_payload).ok_or("Unexpected payload length from server")?;
+
+            let call_result = CallInterface::run(
+                &full_sid,
+                media_key,
+                tcp_stream.as_mut(),
+                udp_stream,
+                *camera_index,
+                call_settings,
+            )
+            .await;
+
+            match call_result {
+                Err(e) if e.downcast_ref::<ConnectionLost>().is_some() => {
+                    eprintln!("Connection lost, trying to resume the call...");
+
+                    match resume_session(
+                        &server_tcp_addr,
+                        server_addr,
+                        tls_enabled,
+                        ca_path,
+                        &session_token,
+                        connect_timeout,
+                    )
+                    .await
+                    {
+                        Ok((resumed_stream, resumed_payload)) => {
+                            tcp_stream = resumed_stream;
+                            call_payload = resumed_payload;
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("Could not resume the call: {}", e);
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Call Error: {}", e),
+                Ok(()) => {}
+            }
+
+            break;
+        }
+
+        TcpCommand::Simple(TcpCommandId::LeaveRoom)
+            .write_to_stream(tcp_stream.as_mut())
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn connect_with_retries(
+    server_tcp_addr: &str,
+    server_addr: &str,
+    tls_enabled: bool,
+    ca_path: Option<&str>,
+    username: &str,
+    connect_timeout: Duration,
+    connect_retries: u32,
+) -> Result<(Box<dyn ControlStream>, bool, SessionToken), Box<dyn Error + Send + Sync>> {
+    let attempts = connect_retries + 1;
+    let mut backoff = CONNECT_BACKOFF_INITIAL;
+    let mut request_compression = true;
+    let mut attempt = 1;
+
+    loop {
+        let error: Box<dyn Error + Send + Sync> = match connect(
+            server_tcp_addr,
+            server_addr,
+            tls_enabled,
+            ca_path,
+            connect_timeout,
+        )
+        .await
+        {
+            Ok((mut tcp_stream, server_is_ipv6)) => {
+                match timeout(
+                    connect_timeout,
+                    perform_handshake(tcp_stream.as_mut(), username, request_compression),
                 )
                 .await
                 {
-                    eprintln!("Call Error: {}", e);
+                    Ok(Ok(session_token)) => {
+                        return Ok((tcp_stream, server_is_ipv6, session_token));
+                    }
+                    // Servers that don't understand HelloWithFeatures say so, so reconnect
+                    // straight away with the plain hello. A bare hang-up is a real failure.
+                    Ok(Err(e)) if request_compression && is_unsupported_command(e.as_ref()) => {
+                        request_compression = false;
+                        continue;
+                    }
+                    // The server answered, so retrying would get the same response
+                    Ok(Err(e)) if !is_connection_error(e.as_ref()) => return Err(e),
+                    Ok(Err(e)) => e,
+                    Err(_) => "Timed out waiting for the server handshake".into(),
                 }
+            }
+            Err(e) => e,
+        };
+
+        if attempt == attempts {
+            return Err(format!(
+                "Could not connect to {} after {} attempt{}: {}",
+                server_tcp_addr,
+                attempts,
+                if attempts == 1 { "" } else { "s" },
+                error
+            )
+            .into());
+        }
 
-                TcpCommand::Simple(TcpCommandId::LeaveRoom)
-                    .write_to_stream(&mut tcp_stream)
-                    .await?;
+        eprintln!(
+            "Connection attempt {}/{} failed: {}. Retrying in {:.1}s...",
+            attempt,
+            attempts,
+            error,
+            backoff.as_secs_f32()
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(CONNECT_BACKOFF_MAX);
+        attempt += 1;
+    }
+}
+
+fn is_unsupported_command(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::UnsupportedCommand(_))
+    )
+}
+
+fn is_connection_error(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    error.is::<std::io::Error>() || error.is::<ConnectionLost>()
+}
+
+async fn connect(
+    server_tcp_addr: &str,
+    server_addr: &str,
+    tls_enabled: bool,
+    ca_path: Option<&str>,
+    connect_timeout: Duration,
+) -> Result<(Box<dyn ControlStream>, bool), Box<dyn Error + Send + Sync>> {
+    let tcp_stream = timeout(connect_timeout, TcpStream::connect(server_tcp_addr))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", server_tcp_addr))??;
+    let server_is_ipv6 = tcp_stream.peer_addr()?.is_ipv6();
+
+    let tcp_stream: Box<dyn ControlStream> = if tls_enabled {
+        Box::new(
+            timeout(
+                connect_timeout,
+                connect_tls(tcp_stream, server_addr, ca_path),
+            )
+            .await
+            .map_err(|_| "Timed out during the TLS handshake")??,
+        )
+    } else {
+        Box::new(tcp_stream)
+    };
+
+    Ok((tcp_stream, server_is_ipv6))
+}
+
+async fn resume_session(
+    server_tcp_addr: &str,
+    server_addr: &str,
+    tls_enabled: bool,
+    ca_path: Option<&str>,
+    session_token: &SessionToken,
+    connect_timeout: Duration,
+) -> Result<(Box<dyn ControlStream>, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let mut last_error: Box<dyn Error + Send + Sync> = "No resume attempts were made".into();
+
+    for _ in 0..RESUME_ATTEMPTS {
+        let mut tcp_stream = match connect(
+            server_tcp_addr,
+            server_addr,
+            tls_enabled,
+            ca_path,
+            connect_timeout,
+        )
+        .await
+        {
+            Ok((tcp_stream, _)) => tcp_stream,
+            Err(e) => {
+                last_error = e;
+                tokio::time::sleep(RESUME_RETRY_INTERVAL).await;
+                continue;
             }
-            None => return Ok(()),
         };
 
-        Ok(())
+        TcpCommand::Bytes(TcpCommandId::Resume, session_token.to_vec())
+            .write_to_stream(tcp_stream.as_mut())
+            .await?;
+
+        let received_command = match TcpCommand::read_from_stream(tcp_stream.as_mut()).await? {
+            ReceivedTcpCommand::EOF => {
+                return Err("Unexpected EOF from server during resume".into());
+            }
+            ReceivedTcpCommand::Command(command) => command,
+        };
+
+        return match received_command {
+            TcpCommand::Bytes(TcpCommandId::ResumeSuccess, full_sid) => {
+                if split_call_payload(&full_sid).is_none() {
+                    return Err("Unexpected payload length from server during resume".into());
+                }
+
+                Ok((tcp_stream, full_sid))
+            }
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+                Err(Box::new(ProtocolError::from_response(&error)))
+            }
+            _ => Err("Invalid command from server during resume".into()),
+        };
     }
+
+    Err(last_error)
 }
 
 pub async fn perform_handshake(
-    tcp_stream: &mut TcpStream,
+    tcp_stream: &mut dyn ControlStream,
     username: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    TcpCommand::String(TcpCommandId::HelloFromClient, username.to_string())
-        .write_to_stream(tcp_stream)
-        .await?;
+    request_compression: bool,
+) -> Result<SessionToken, Box<dyn Error + Send + Sync>> {
+    let hello = if request_compression {
+        TcpCommand::StringList(
+            TcpCommandId::HelloWithFeatures,
+            vec![username.to_string(), LIST_COMPRESSION_FEATURE.to_string()],
+        )
+    } else {
+        TcpCommand::String(TcpCommandId::HelloFromClient, username.to_string())
+    };
+    hello.write_to_stream(tcp_stream).await?;
 
     let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
 
     let received_command = match received_command_option {
-        ReceivedTcpCommand::EOF => return Err("Unexpected EOF from server during handshake".into()),
+        ReceivedTcpCommand::EOF => return Err(Box::new(ConnectionLost)),
         ReceivedTcpCommand::Command(command) => command,
     };
 
     match received_command {
-        TcpCommand::Simple(TcpCommandId::HelloFromServer) => Ok(()),
-        TcpCommand::String(TcpCommandId::ErrorResponse, error) => Err(error.into()),
+        TcpCommand::Bytes(TcpCommandId::HelloFromServer, session_token) => session_token
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid session token from server during handshake".into()),
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            Err(Box::new(ProtocolError::from_response(&error)))
+        }
         _ => Err("Invalid command from server during handshake".into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const TEST_CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+    async fn connect_to(server_tcp_addr: &str, connect_retries: u32) -> String {
+        let result = connect_with_retries(
+            server_tcp_addr,
+            "127.0.0.1",
+            false,
+            None,
+            "alice",
+            TEST_CONNECT_TIMEOUT,
+            connect_retries,
+        )
+        .await;
+
+        match result {
+            Ok(_) => panic!("Connected to {} unexpectedly", server_tcp_addr),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_port_fails_after_its_retries_instead_of_hanging() {
+        let closed_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let started = Instant::now();
+        let error = connect_to(&closed_addr, 1).await;
+
+        assert!(error.contains("after 2 attempts"), "{}", error);
+        assert!(started.elapsed() < CONNECT_BACKOFF_INITIAL + TEST_CONNECT_TIMEOUT * 2);
+    }
+
+    #[tokio::test]
+    async fn silent_server_times_out_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_tcp_addr = listener.local_addr().unwrap().to_string();
+
+        // Accepts the connection and never answers the hello
+        let _silent_server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let started = Instant::now();
+        let error = connect_to(&server_tcp_addr, 0).await;
+
+        assert!(
+            error.contains("Timed out waiting for the server handshake"),
+            "{}",
+            error
+        );
+        assert!(started.elapsed() < TEST_CONNECT_TIMEOUT * 3);
+    }
+
+    #[tokio::test]
+    async fn unsupported_hello_falls_back_to_the_plain_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_tcp_addr = listener.local_addr().unwrap().to_string();
+
+        // Refuses HelloWithFeatures the way the server does, and accepts the plain hello
+        let _server = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let response = match TcpCommand::read_from_stream(&mut stream).await.unwrap() {
+                    ReceivedTcpCommand::Command(TcpCommand::String(
+                        TcpCommandId::HelloFromClient,
+                        _,
+                    )) => TcpCommand::Bytes(
+                        TcpCommandId::HelloFromServer,
+                        SessionToken::default().to_vec(),
+                    ),
+                    command => {
+                        ProtocolError::UnsupportedCommand(format!("{:?}", command)).to_response()
+                    }
+                };
+                response.write_to_stream(&mut stream).await.unwrap();
+            }
+        });
+
+        let (_, _, session_token) = connect_with_retries(
+            &server_tcp_addr,
+            "127.0.0.1",
+            false,
+            None,
+            "alice",
+            TEST_CONNECT_TIMEOUT,
+            0,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(session_token, SessionToken::default());
+    }
+
+    #[tokio::test]
+    async fn server_hanging_up_on_the_hello_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_tcp_addr = listener.local_addr().unwrap().to_string();
+
+        // Reads the hello and closes the connection without answering
+        let server = tokio::spawn(async move {
+            let mut hellos = Vec::new();
+            while let Ok(Ok((mut stream, _))) =
+                timeout(TEST_CONNECT_TIMEOUT, listener.accept()).await
+            {
+                if let ReceivedTcpCommand::Command(command) =
+                    TcpCommand::read_from_stream(&mut stream).await.unwrap()
+                {
+                    hellos.push(command);
+                }
+            }
+            hellos
+        });
+
+        let error = connect_to(&server_tcp_addr, 0).await;
+        let hellos = server.await.unwrap();
+
+        assert!(error.contains("after 1 attempt"), "{}", error);
+        assert!(
+            matches!(
+                hellos.as_slice(),
+                [TcpCommand::StringList(TcpCommandId::HelloWithFeatures, _)]
+            ),
+            "{:?}",
+            hellos
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_fps_is_rejected_before_connecting() {
+        let error = Client::new("127.0.0.1", "alice")
+            .capture_fps(0)
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ClientError>(),
+            Some(&ClientError::InvalidFps(0))
+        );
+
+        let client = Client::new("127.0.0.1", "alice").render_fps(0);
+        assert_eq!(client.validate(), Err(ClientError::InvalidFps(0)));
+    }
+
+    #[test]
+    fn empty_or_non_ascii_ramps_are_rejected() {
+        for ramp in ["", " .:é#"] {
+            let client = Client::new("127.0.0.1", "alice").ascii_ramp(ramp);
+            assert_eq!(client.validate(), Err(ClientError::InvalidAsciiRamp));
+        }
+
+        assert!(Client::new("127.0.0.1", "alice").validate().is_ok());
+    }
+
+    #[test]
+    fn resolutions_parse_within_range() {
+        assert_eq!(parse_resolution("96x54"), Ok((96, 54)));
+        assert_eq!(
+            parse_resolution("8x8"),
+            Err(ClientError::InvalidResolution(8, 8))
+        );
+        assert_eq!(
+            parse_resolution("96"),
+            Err(ClientError::MalformedResolution("96".to_string()))
+        );
+    }
+}
@@ -2,6 +2,77 @@ use libwebp_sys::*;
 use opencv::{core::AlgorithmHint, prelude::*};
 use std::ptr;
 use std::sync::Arc;
+use unicode_width::UnicodeWidthChar;
+
+pub const DEFAULT_ASCII_RAMP: &str = " .'`^\",_-|\\/*rxz%@$B";
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum RenderMode {
+    Ascii,
+    Braille,
+    #[value(name = "halfblock")]
+    HalfBlock,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ColorDepth {
+    Auto,
+    #[value(name = "truecolor")]
+    TrueColor,
+    #[value(name = "256")]
+    Ansi256,
+    #[value(name = "16")]
+    Ansi16,
+    None,
+}
+
+impl ColorDepth {
+    pub fn resolve(self) -> Self {
+        match self {
+            ColorDepth::Auto if detect_true_color() => ColorDepth::TrueColor,
+            ColorDepth::Auto => ColorDepth::Ansi256,
+            color_depth => color_depth,
+        }
+    }
+}
+
+/// How frames are turned into terminal text.
+#[derive(Clone, Debug)]
+pub struct AsciiStyle {
+    pub color_enabled: bool,
+    pub color_depth: ColorDepth,
+    pub render_mode: RenderMode,
+    pub ascii_ramp: String,
+    pub dither: bool,
+    pub color_fill: bool,
+}
+
+const FRAME_HEADER_LEN: usize = 13;
+const MIN_CELL_WIDTH: u16 = 8;
+const MIN_CELL_HEIGHT: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameFormat {
+    Rgb,
+    Mono,
+}
+
+impl FrameFormat {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameFormat::Rgb => 0,
+            FrameFormat::Mono => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match byte {
+            0 => Ok(FrameFormat::Rgb),
+            1 => Ok(FrameFormat::Mono),
+            _ => Err(format!("Unknown frame format {}", byte).into()),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Frame {
@@ -10,10 +81,19 @@ pub struct Frame {
     pub data: Arc<Vec<u8>>,
 }
 
+#[derive(Clone, Default)]
+pub struct RemoteStream {
+    pub username: String,
+    pub frame: Option<Frame>,
+    /// Set after several frames in a row from this stream failed to decode, cleared by the
+    /// next frame that decodes.
+    pub decode_error: bool,
+}
+
 impl Frame {
-    pub fn from_mat(mat: &Mat, width: i32, height: i32) -> opencv::Result<Self> {
+    pub fn from_mat(mat: &Mat, width: i32, height: i32, mirror: bool) -> opencv::Result<Self> {
         use opencv::{
-            core::{Mat, Size},
+            core::{Mat, Size, flip},
             imgproc::{COLOR_BGR2RGB, INTER_LINEAR, cvt_color, resize},
         };
 
@@ -26,6 +106,12 @@ impl Frame {
             AlgorithmHint::ALGO_HINT_ACCURATE,
         )?;
 
+        if mirror {
+            let mut mirrored = Mat::default();
+            flip(&rgb, &mut mirrored, 1)?;
+            rgb = mirrored;
+        }
+
         let mut resized = Mat::default();
         resize(
             &rgb,
@@ -51,16 +137,25 @@ impl Frame {
         })
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self, quality: f32, format: FrameFormat) -> Vec<u8> {
+        let mono_data;
+        let pixels = match format {
+            FrameFormat::Rgb => self.data.as_slice(),
+            FrameFormat::Mono => {
+                mono_data = to_grayscale(&self.data);
+                mono_data.as_slice()
+            }
+        };
+
         let mut output_ptr: *mut u8 = ptr::null_mut();
 
         let output_size = unsafe {
             WebPEncodeRGB(
-                self.data.as_ptr(),
+                pixels.as_ptr(),
                 self.width,
                 self.height,
                 self.width * 3,
-                75.0,
+                quality,
                 &mut output_ptr,
             )
         };
@@ -71,7 +166,8 @@ impl Frame {
 
         let compressed = unsafe { Vec::from_raw_parts(output_ptr, output_size, output_size) };
 
-        let mut buf = Vec::with_capacity(12 + compressed.len());
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + compressed.len());
+        buf.push(format.to_byte());
         buf.extend(&self.width.to_le_bytes());
         buf.extend(&self.height.to_le_bytes());
         buf.extend(&(compressed.len() as u32).to_le_bytes());
@@ -81,24 +177,34 @@ impl Frame {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        if bytes.len() < 12 {
+        Self::from_bytes_into(bytes, Vec::new())
+    }
+
+    /// Like [`Frame::from_bytes`], but decodes into `buffer`, reusing its allocation when it
+    /// is already large enough.
+    pub fn from_bytes_into(
+        bytes: &[u8],
+        mut buffer: Vec<u8>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        if bytes.len() < FRAME_HEADER_LEN {
             return Err("Too short to decode Frame".into());
         }
 
-        let stored_width = i32::from_le_bytes(bytes[0..4].try_into()?);
-        let stored_height = i32::from_le_bytes(bytes[4..8].try_into()?);
-        let compressed_len = u32::from_le_bytes(bytes[8..12].try_into()?) as usize;
+        let format = FrameFormat::from_byte(bytes[0])?;
+        let stored_width = i32::from_le_bytes(bytes[1..5].try_into()?);
+        let stored_height = i32::from_le_bytes(bytes[5..9].try_into()?);
+        let compressed_len = u32::from_le_bytes(bytes[9..13].try_into()?) as usize;
 
-        if bytes.len() < 12 + compressed_len {
+        if bytes.len() < FRAME_HEADER_LEN + compressed_len {
             return Err("Not enough bytes for compressed data".into());
         }
 
-        let compressed = &bytes[12..12 + compressed_len];
+        let compressed = &bytes[FRAME_HEADER_LEN..FRAME_HEADER_LEN + compressed_len];
         let mut out_width = 0;
         let mut out_height = 0;
 
-        let decoded_ptr = unsafe {
-            WebPDecodeRGB(
+        let has_info = unsafe {
+            WebPGetInfo(
                 compressed.as_ptr(),
                 compressed_len,
                 &mut out_width,
@@ -106,30 +212,89 @@ impl Frame {
             )
         };
 
-        if decoded_ptr.is_null() {
+        if has_info == 0 {
             return Err("WebP decoding failed".into());
         }
 
         if out_width != stored_width || out_height != stored_height {
-            unsafe { libc::free(decoded_ptr as *mut libc::c_void) };
             return Err("Decoded dimensions do not match stored values".into());
         }
 
-        let pixel_count = out_width * out_height * 3;
-        let data =
-            unsafe { Vec::from_raw_parts(decoded_ptr, pixel_count as usize, pixel_count as usize) };
+        let stride = out_width * 3;
+        buffer.clear();
+        buffer.resize((stride * out_height) as usize, 0);
+
+        let decoded_ptr = unsafe {
+            WebPDecodeRGBInto(
+                compressed.as_ptr(),
+                compressed_len,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                stride,
+            )
+        };
+
+        if decoded_ptr.is_null() {
+            return Err("WebP decoding failed".into());
+        }
+
+        if format == FrameFormat::Mono {
+            grayscale_in_place(&mut buffer);
+        }
 
         Ok(Self {
             width: out_width,
             height: out_height,
-            data: Arc::new(data),
+            data: Arc::new(buffer),
         })
     }
 
+    pub fn mirrored(&self) -> Self {
+        let row_len = self.width as usize * 3;
+        let mut data = Vec::with_capacity(self.data.len());
+
+        for row in self.data.chunks_exact(row_len.max(1)) {
+            for pixel in row.chunks_exact(3).rev() {
+                data.extend_from_slice(pixel);
+            }
+        }
+
+        Self {
+            width: self.width,
+            height: self.height,
+            data: Arc::new(data),
+        }
+    }
+
+    /// Places `tiles` side by side, left to right, into one frame. All tiles must share a
+    /// height; the result is as wide as their widths combined.
+    pub fn tile_horizontally(tiles: &[Frame]) -> Self {
+        let height = tiles.first().map_or(0, |tile| tile.height);
+        assert!(
+            tiles.iter().all(|tile| tile.height == height),
+            "Tiles must share a height"
+        );
+
+        let width = tiles.iter().map(|tile| tile.width).sum();
+        let mut data = Vec::with_capacity((width * height * 3) as usize);
+
+        for row in 0..height as usize {
+            for tile in tiles {
+                let row_len = tile.width as usize * 3;
+                data.extend_from_slice(&tile.data[row * row_len..(row + 1) * row_len]);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            data: Arc::new(data),
+        }
+    }
+
     pub fn to_ascii_with_buffer(
         &self,
-        color_enabled: bool,
-        true_color: bool,
+        style: &AsciiStyle,
         width: i32,
         height: i32,
         buffer: &mut String,
@@ -139,53 +304,99 @@ impl Frame {
             imgproc::{INTER_LINEAR, resize},
         };
 
-        const ASCII_CHARS: &[u8] = b" .'`^\",_-|\\/*rxz%@$B";
         const COLOR_ASCII_CHARS: &[u8] = b" `'.,-^~:;!*+=cr?%$S#@";
         const TRUE_COLOR_ASCII_CHARS: &[u8] = b" ,:;lll$$$$&&&&&#####";
 
+        let &AsciiStyle {
+            color_enabled,
+            color_depth,
+            render_mode,
+            ref ascii_ramp,
+            dither,
+            color_fill,
+        } = style;
+
         let expected = self.width * self.height * 3;
         if self.data.len() != expected as usize {
             return Err("Frame data size mismatch".into());
         }
 
-        let base = Mat::from_slice(self.data.as_ref())?;
-        let mat: opencv::boxed_ref::BoxedRef<'_, Mat> = base.reshape(3, self.height)?;
-        let mut resized = Mat::default();
-        resize(
-            &mat,
-            &mut resized,
-            Size::new(width, height),
-            0.0,
-            0.0,
-            INTER_LINEAR,
-        )?;
+        let (pixel_width, pixel_height) = match render_mode {
+            RenderMode::Ascii => (width, height),
+            RenderMode::Braille => (width * 2, height * 4),
+            RenderMode::HalfBlock => (width, height * 2),
+        };
+
+        let resized;
+        let resized_data: &[u8] = if (pixel_width, pixel_height) == (self.width, self.height) {
+            self.data.as_slice()
+        } else {
+            let base = Mat::from_slice(self.data.as_ref())?;
+            let mat: opencv::boxed_ref::BoxedRef<'_, Mat> = base.reshape(3, self.height)?;
+            let mut resized_mat = Mat::default();
+            resize(
+                &mat,
+                &mut resized_mat,
+                Size::new(pixel_width, pixel_height),
+                0.0,
+                0.0,
+                INTER_LINEAR,
+            )?;
+
+            resized = resized_mat;
+            resized.data_bytes()?
+        };
 
-        let resized_data = resized.data_bytes()?;
+        if render_mode == RenderMode::Braille {
+            write_braille(
+                resized_data,
+                color_enabled,
+                color_depth,
+                width,
+                height,
+                buffer,
+            );
+            return Ok(());
+        }
+
+        if render_mode == RenderMode::HalfBlock {
+            write_half_block(resized_data, color_depth, width, height, buffer);
+            return Ok(());
+        }
 
         buffer.clear();
         let capacity = if color_enabled {
-            if true_color {
-                (width * height * 25 + height) as usize
-            } else {
-                (width * height * 12 + height) as usize
-            }
+            let cell_size = match (color_depth == ColorDepth::TrueColor, color_fill) {
+                (true, true) => 44,
+                (true, false) => 25,
+                (false, true) => 24,
+                (false, false) => 12,
+            };
+            (width * height * cell_size + height) as usize
         } else {
             (width * height + height) as usize
         };
         buffer.reserve(capacity);
 
-        let ascii_chars = if color_enabled && true_color {
+        let ascii_chars = if color_enabled && color_depth == ColorDepth::TrueColor {
             TRUE_COLOR_ASCII_CHARS
         } else if color_enabled {
             COLOR_ASCII_CHARS
         } else {
-            ASCII_CHARS
+            ascii_ramp.as_bytes()
         };
         let ascii_len = ascii_chars.len() - 1;
 
+        let dithered_indices = if dither {
+            Some(dither_ramp_indices(resized_data, width as usize, ascii_len))
+        } else {
+            None
+        };
+
         for row in 0..height {
             for col in 0..width {
-                let idx = (row * width + col) as usize * 3;
+                let pixel = (row * width + col) as usize;
+                let idx = pixel * 3;
                 if idx + 2 >= resized_data.len() {
                     continue;
                 }
@@ -194,20 +405,18 @@ impl Frame {
                 let g = resized_data[idx + 1];
                 let b = resized_data[idx + 2];
 
-                let gray = ((r as u32 * 77 + g as u32 * 150 + b as u32 * 29) >> 8) as u8;
-
-                let ascii_index = (gray as usize * ascii_len) / 255;
+                let ascii_index = match &dithered_indices {
+                    Some(indices) => indices[pixel],
+                    None => (luminance(r, g, b) as usize * ascii_len) / 255,
+                };
                 let c = ascii_chars[ascii_index] as char;
 
                 if color_enabled {
-                    if true_color {
-                        use std::fmt::Write;
-                        let _ = write!(buffer, "\x1b[38;2;{};{};{}m{}", r, g, b, c);
-                    } else {
-                        let color_code = rgb_to_ansi256_fast(r, g, b);
-                        use std::fmt::Write;
-                        let _ = write!(buffer, "\x1b[38;5;{}m{}", color_code, c);
+                    if color_fill {
+                        write_background(buffer, color_depth, r / 2, g / 2, b / 2);
                     }
+                    write_foreground(buffer, color_depth, r, g, b);
+                    buffer.push(c);
                 } else {
                     buffer.push(c);
                 }
@@ -224,14 +433,204 @@ impl Frame {
     }
 }
 
+fn write_braille(
+    pixels: &[u8],
+    color_enabled: bool,
+    color_depth: ColorDepth,
+    width: i32,
+    height: i32,
+    buffer: &mut String,
+) {
+    const BRAILLE_BASE: u32 = 0x2800;
+    const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+    let pixel_width = width as usize * 2;
+    let pixel_count = pixels.len() / 3;
+    let total_gray: usize = pixels
+        .chunks_exact(3)
+        .map(|px| luminance(px[0], px[1], px[2]) as usize)
+        .sum();
+    let threshold = (total_gray / pixel_count.max(1)) as u8;
+
+    buffer.clear();
+    let capacity = if color_enabled {
+        (width * height * 28 + height) as usize
+    } else {
+        (width * height * 3 + height) as usize
+    };
+    buffer.reserve(capacity);
+
+    for row in 0..height as usize {
+        for col in 0..width as usize {
+            let mut dots = 0u8;
+            let (mut r_sum, mut g_sum, mut b_sum, mut samples) = (0u32, 0u32, 0u32, 0u32);
+
+            for (dy, dot_row) in BRAILLE_DOTS.iter().enumerate() {
+                for (dx, dot) in dot_row.iter().enumerate() {
+                    let idx = ((row * 4 + dy) * pixel_width + col * 2 + dx) * 3;
+                    if idx + 2 >= pixels.len() {
+                        continue;
+                    }
+
+                    let (r, g, b) = (pixels[idx], pixels[idx + 1], pixels[idx + 2]);
+                    if luminance(r, g, b) > threshold {
+                        dots |= dot;
+                    }
+
+                    r_sum += r as u32;
+                    g_sum += g as u32;
+                    b_sum += b as u32;
+                    samples += 1;
+                }
+            }
+
+            let c = char::from_u32(BRAILLE_BASE + dots as u32).unwrap_or(' ');
+
+            if color_enabled && samples > 0 {
+                let (r, g, b) = (
+                    (r_sum / samples) as u8,
+                    (g_sum / samples) as u8,
+                    (b_sum / samples) as u8,
+                );
+                write_foreground(buffer, color_depth, r, g, b);
+                buffer.push(c);
+            } else {
+                buffer.push(c);
+            }
+        }
+
+        if color_enabled {
+            buffer.push_str("\x1b[0m\n");
+        } else {
+            buffer.push('\n');
+        }
+    }
+}
+
+fn write_half_block(
+    pixels: &[u8],
+    color_depth: ColorDepth,
+    width: i32,
+    height: i32,
+    buffer: &mut String,
+) {
+    use std::fmt::Write;
+
+    let pixel_width = width as usize;
+
+    buffer.clear();
+    buffer.reserve((width * height * 42 + height) as usize);
+
+    for row in 0..height as usize {
+        for col in 0..pixel_width {
+            let top = (row * 2 * pixel_width + col) * 3;
+            let bottom = top + pixel_width * 3;
+            if bottom + 2 >= pixels.len() {
+                continue;
+            }
+
+            let (tr, tg, tb) = (pixels[top], pixels[top + 1], pixels[top + 2]);
+            let (br, bg, bb) = (pixels[bottom], pixels[bottom + 1], pixels[bottom + 2]);
+
+            match color_depth {
+                ColorDepth::TrueColor => {
+                    let _ = write!(
+                        buffer,
+                        "\x1b[38;2;{};{};{};48;2;{};{};{}m▀",
+                        tr, tg, tb, br, bg, bb
+                    );
+                }
+                ColorDepth::Ansi16 => {
+                    let _ = write!(
+                        buffer,
+                        "\x1b[{};{}m▀",
+                        rgb_to_ansi16(tr, tg, tb),
+                        rgb_to_ansi16(br, bg, bb) + 10
+                    );
+                }
+                ColorDepth::Auto | ColorDepth::Ansi256 | ColorDepth::None => {
+                    let _ = write!(
+                        buffer,
+                        "\x1b[38;5;{};48;5;{}m▀",
+                        rgb_to_ansi256_fast(tr, tg, tb),
+                        rgb_to_ansi256_fast(br, bg, bb)
+                    );
+                }
+            }
+        }
+
+        buffer.push_str("\x1b[0m\n");
+    }
+}
+
+/// Floyd–Steinberg dithers the luma of `data`, an RGB image `width` pixels wide,
+/// into one ramp index in `0..=max_index` per pixel.
+fn dither_ramp_indices(data: &[u8], width: usize, max_index: usize) -> Vec<usize> {
+    // Luma is kept in 1/16 steps so the diffused error stays integral.
+    const SCALE: i32 = 16;
+    const WHITE: i32 = 255 * SCALE;
+
+    let mut luma: Vec<i32> = data
+        .chunks_exact(3)
+        .map(|pixel| luminance(pixel[0], pixel[1], pixel[2]) as i32 * SCALE)
+        .collect();
+    let mut indices = vec![0; luma.len()];
+
+    if max_index == 0 || width == 0 {
+        return indices;
+    }
+
+    let max_index = max_index as i32;
+
+    for pixel in 0..luma.len() {
+        let col = pixel % width;
+        let value = luma[pixel].clamp(0, WHITE);
+        let index = (value * max_index + WHITE / 2) / WHITE;
+        indices[pixel] = index as usize;
+
+        let error = value - index * WHITE / max_index;
+        let mut spread = |target: usize, weight: i32| {
+            if let Some(neighbour) = luma.get_mut(target) {
+                *neighbour += error * weight / 16;
+            }
+        };
+
+        if col + 1 < width {
+            spread(pixel + 1, 7);
+            spread(pixel + width + 1, 1);
+        }
+        if col > 0 {
+            spread(pixel + width - 1, 3);
+        }
+        spread(pixel + width, 5);
+    }
+
+    indices
+}
+
+#[inline]
+fn to_grayscale(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(3)
+        .flat_map(|pixel| [luminance(pixel[0], pixel[1], pixel[2]); 3])
+        .collect()
+}
+
+fn grayscale_in_place(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(3) {
+        pixel.fill(luminance(pixel[0], pixel[1], pixel[2]));
+    }
+}
+
+fn luminance(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 77 + g as u32 * 150 + b as u32 * 29) >> 8) as u8
+}
+
 pub fn combine_frames_with_buffers(
     frames: &[Frame],
-    target_width: u16,
-    target_height: u16,
-    true_width: u16,
-    true_height: u16,
-    color_enabled: bool,
-    true_color: bool,
+    labels: &[String],
+    (target_width, target_height): (u16, u16),
+    (true_width, true_height): (u16, u16),
+    style: &AsciiStyle,
     ascii_buffer: &mut String,
     temp_buffers: &mut Vec<String>,
 ) {
@@ -262,14 +661,34 @@ pub fn combine_frames_with_buffers(
     let cell_height = available_height / rows as u16;
 
     let (frame_width, frame_height) =
-        calculate_frame_dimensions(cell_width, cell_height, aspect_ratio);
+        calculate_frame_dimensions(cell_width, cell_height.saturating_sub(1), aspect_ratio);
+
+    if cell_width < MIN_CELL_WIDTH
+        || cell_height < MIN_CELL_HEIGHT
+        || frame_width == 0
+        || frame_height == 0
+    {
+        let min_width = MIN_CELL_WIDTH * cols as u16 + total_spacing_x as u16;
+        let min_height = MIN_CELL_HEIGHT * rows as u16 + total_spacing_y as u16;
+        let message = truncate_to_width(
+            &format!(
+                "Terminal too small, resize to at least {}x{}",
+                min_width, min_height
+            ),
+            true_width as usize,
+        );
+        ascii_buffer.push_str(&center_full_grid(&message, true_width, true_height));
+        return;
+    }
 
     temp_buffers.resize(count, String::new());
 
-    let estimated_size = if color_enabled {
-        (frame_width * frame_height * 15) as usize
-    } else {
-        (frame_width * frame_height * 2) as usize
+    let estimated_size = match (style.render_mode, style.color_enabled) {
+        (RenderMode::Ascii, true) => (frame_width * frame_height * 15) as usize,
+        (RenderMode::Ascii, false) => (frame_width * frame_height * 2) as usize,
+        (RenderMode::Braille, true) => (frame_width * frame_height * 28) as usize,
+        (RenderMode::Braille, false) => (frame_width * frame_height * 4) as usize,
+        (RenderMode::HalfBlock, _) => (frame_width * frame_height * 42) as usize,
     };
 
     for buffer in temp_buffers.iter_mut().take(count) {
@@ -278,13 +697,13 @@ pub fn combine_frames_with_buffers(
 
     for (i, frame) in frames.iter().enumerate() {
         if let Ok(()) = frame.to_ascii_with_buffer(
-            color_enabled,
-            true_color,
+            style,
             frame_width as i32,
             frame_height as i32,
             &mut temp_buffers[i],
         ) {
-            let centered = center_in_cell(&temp_buffers[i], cell_width, cell_height);
+            let label = labels.get(i).map(String::as_str).unwrap_or_default();
+            let centered = center_in_cell(&temp_buffers[i], label, cell_width, cell_height);
             temp_buffers[i] = centered;
         }
     }
@@ -327,7 +746,7 @@ fn calculate_optimal_grid(
     let max_cols = max_cols.min(count).min(width as usize / 10);
 
     for cols in 1..=max_cols {
-        let rows = (count + cols - 1) / cols;
+        let rows = count.div_ceil(cols);
         let spacing_x = 2 * cols.saturating_sub(1);
         let spacing_y = rows.saturating_sub(1);
 
@@ -380,9 +799,13 @@ fn calculate_frame_dimensions(max_width: u16, max_height: u16, aspect_ratio: f64
     }
 }
 
-fn center_in_cell(frame: &str, cell_w: u16, cell_h: u16) -> String {
-    let lines = frame.lines().collect::<Vec<_>>();
-    let frame_h = lines.len();
+fn center_in_cell(frame: &str, label: &str, cell_w: u16, cell_h: u16) -> String {
+    let label = truncate_to_width(label, cell_w as usize);
+    let mut lines = frame.lines().collect::<Vec<_>>();
+    lines.truncate((cell_h as usize).saturating_sub(1));
+    lines.push(&label);
+    let frame_h = lines.len().min(cell_h as usize);
+    lines.truncate(frame_h);
     let pad_top = (cell_h as usize).saturating_sub(frame_h) / 2;
     let pad_bottom = cell_h as usize - pad_top - frame_h;
 
@@ -422,7 +845,7 @@ fn combine_into_grid(frames: &[String], cols: usize, spacing_x: usize, spacing_y
 
     for (row_idx, row) in frames.chunks(cols).enumerate() {
         if row_idx > 0 {
-            result.extend(std::iter::repeat('\n').take(spacing_y));
+            result.extend(std::iter::repeat_n('\n', spacing_y));
         }
 
         let row_lines: Vec<Vec<&str>> = row.iter().map(|f| f.lines().collect()).collect();
@@ -448,7 +871,7 @@ fn center_full_grid(grid: &str, true_w: u16, true_h: u16) -> String {
     let lines: Vec<&str> = grid.lines().collect();
     let content_h = lines.len();
     let pad_top = (true_h as usize).saturating_sub(content_h) / 2;
-    let pad_bottom = true_h as usize - pad_top - content_h;
+    let pad_bottom = (true_h as usize).saturating_sub(pad_top + content_h);
     let mut out = String::with_capacity(true_w as usize * true_h as usize);
     let blank = " ".repeat(true_w as usize);
 
@@ -460,7 +883,7 @@ fn center_full_grid(grid: &str, true_w: u16, true_h: u16) -> String {
     for &line in &lines {
         let visible = count_visible_chars_fast(line);
         let pad_left = (true_w as usize).saturating_sub(visible) / 2;
-        let pad_right = true_w as usize - pad_left - visible;
+        let pad_right = (true_w as usize).saturating_sub(pad_left + visible);
         out.push_str(&" ".repeat(pad_left));
         out.push_str(line);
         out.push_str(&" ".repeat(pad_right));
@@ -475,6 +898,61 @@ fn center_full_grid(grid: &str, true_w: u16, true_h: u16) -> String {
     out
 }
 
+pub fn composite_frames(frames: &[Frame]) -> Option<image::RgbImage> {
+    use image::{Rgb, RgbImage};
+
+    let frames: Vec<&Frame> = frames
+        .iter()
+        .filter(|frame| {
+            frame.width > 0
+                && frame.height > 0
+                && frame.data.len() == (frame.width * frame.height * 3) as usize
+        })
+        .collect();
+
+    if frames.is_empty() {
+        return None;
+    }
+
+    let count = frames.len();
+    let cols = (count as f64).sqrt().ceil() as usize;
+    let rows = count.div_ceil(cols);
+
+    let cell_width = frames.iter().map(|frame| frame.width).max().unwrap_or(0) as u32;
+    let cell_height = frames.iter().map(|frame| frame.height).max().unwrap_or(0) as u32;
+
+    let mut image = RgbImage::new(cell_width * cols as u32, cell_height * rows as u32);
+
+    for (i, frame) in frames.iter().enumerate() {
+        let width = frame.width as u32;
+        let x_offset = (i % cols) as u32 * cell_width + (cell_width - width) / 2;
+        let y_offset = (i / cols) as u32 * cell_height + (cell_height - frame.height as u32) / 2;
+
+        for (pixel_index, pixel) in frame.data.chunks_exact(3).enumerate() {
+            let x = pixel_index as u32 % width;
+            let y = pixel_index as u32 / width;
+            image.put_pixel(
+                x_offset + x,
+                y_offset + y,
+                Rgb([pixel[0], pixel[1], pixel[2]]),
+            );
+        }
+    }
+
+    Some(image)
+}
+
+pub fn save_snapshot(frames: &[Frame]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    use chrono::Local;
+
+    let image = composite_frames(frames).ok_or("No frames to snapshot")?;
+
+    let path = format!("snapshot-{}.png", Local::now().format("%Y%m%d-%H%M%S"));
+    image.save(&path)?;
+
+    Ok(path)
+}
+
 pub fn detect_true_color() -> bool {
     let is_vscode = std::env::var("TERM_PROGRAM")
         .map(|val| val.contains("vscode"))
@@ -489,6 +967,48 @@ pub fn detect_true_color() -> bool {
         .unwrap_or(false)
 }
 
+fn write_foreground(buffer: &mut String, color_depth: ColorDepth, r: u8, g: u8, b: u8) {
+    use std::fmt::Write;
+
+    let _ = match color_depth {
+        ColorDepth::TrueColor => write!(buffer, "\x1b[38;2;{};{};{}m", r, g, b),
+        ColorDepth::Ansi16 => write!(buffer, "\x1b[{}m", rgb_to_ansi16(r, g, b)),
+        ColorDepth::Auto | ColorDepth::Ansi256 | ColorDepth::None => {
+            write!(buffer, "\x1b[38;5;{}m", rgb_to_ansi256_fast(r, g, b))
+        }
+    };
+}
+
+/// Used by color fill at half the pixel's brightness, so the glyph drawn over it
+/// in the full color stays visible.
+fn write_background(buffer: &mut String, color_depth: ColorDepth, r: u8, g: u8, b: u8) {
+    use std::fmt::Write;
+
+    let _ = match color_depth {
+        ColorDepth::TrueColor => write!(buffer, "\x1b[48;2;{};{};{}m", r, g, b),
+        ColorDepth::Ansi16 => write!(buffer, "\x1b[{}m", rgb_to_ansi16(r, g, b) + 10),
+        ColorDepth::Auto | ColorDepth::Ansi256 | ColorDepth::None => {
+            write!(buffer, "\x1b[48;5;{}m", rgb_to_ansi256_fast(r, g, b))
+        }
+    };
+}
+
+fn rgb_to_ansi16(r: u8, g: u8, b: u8) -> u8 {
+    let max = r.max(g).max(b);
+    if max < 64 {
+        return 30;
+    }
+
+    let threshold = max / 2;
+    let color = (r > threshold) as u8 | ((g > threshold) as u8) << 1 | ((b > threshold) as u8) << 2;
+
+    if color == 7 && max < 128 {
+        return 90;
+    }
+
+    if max >= 192 { 90 + color } else { 30 + color }
+}
+
 #[inline]
 fn rgb_to_ansi256_fast(r: u8, g: u8, b: u8) -> u8 {
     if r == g && g == b {
@@ -509,29 +1029,356 @@ fn rgb_to_ansi256_fast(r: u8, g: u8, b: u8) -> u8 {
 }
 
 #[inline]
-fn count_visible_chars_fast(s: &str) -> usize {
-    let bytes = s.as_bytes();
+pub fn count_visible_chars_fast(s: &str) -> usize {
     let mut count = 0;
-    let mut i = 0;
+    let mut chars = s.chars();
 
-    while i < bytes.len() {
-        if bytes[i] == b'\x1b' {
-            i += 1;
-            while i < bytes.len() && bytes[i] != b'm' {
-                i += 1;
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c == 'm' {
+                    break;
+                }
             }
-            i += 1;
         } else {
-            count += 1;
-            i += match bytes[i] {
-                0..=0x7F => 1,
-                0xC0..=0xDF => 2,
-                0xE0..=0xEF => 3,
-                0xF0..=0xF7 => 4,
-                _ => 1,
-            };
+            count += c.width().unwrap_or(0);
         }
     }
 
     count
 }
+
+pub fn truncate_to_width(s: &str, max_width: usize) -> String {
+    let mut width = 0;
+
+    s.chars()
+        .take_while(|c| {
+            width += c.width().unwrap_or(0);
+            width <= max_width
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_of_one_frame_is_a_png_with_its_dimensions() {
+        let pixels: Vec<u8> = (0..36).collect();
+        let frame = Frame {
+            width: 4,
+            height: 3,
+            data: Arc::new(pixels.clone()),
+        };
+
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}.png", std::process::id()));
+        composite_frames(&[frame]).unwrap().save(&path).unwrap();
+        let decoded = image::open(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let decoded = decoded.unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (4, 3));
+        assert_eq!(decoded.into_raw(), pixels);
+    }
+
+    #[test]
+    fn tiling_two_frames_combines_their_widths_row_by_row() {
+        let left = Frame {
+            width: 1,
+            height: 2,
+            data: Arc::new(vec![1, 1, 1, 2, 2, 2]),
+        };
+        let right = Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![3, 3, 3, 4, 4, 4, 5, 5, 5, 6, 6, 6]),
+        };
+
+        let tiled = Frame::tile_horizontally(&[left, right]);
+
+        assert_eq!((tiled.width, tiled.height), (3, 2));
+        assert_eq!(
+            *tiled.data,
+            [1, 1, 1, 3, 3, 3, 4, 4, 4, 2, 2, 2, 5, 5, 5, 6, 6, 6]
+        );
+    }
+
+    #[test]
+    fn color_fill_sets_a_background_for_every_cell() {
+        let frame = Frame {
+            width: 3,
+            height: 2,
+            data: Arc::new((0..18).map(|i| i * 14).collect()),
+        };
+        let style = AsciiStyle {
+            color_enabled: true,
+            color_depth: ColorDepth::TrueColor,
+            render_mode: RenderMode::Ascii,
+            ascii_ramp: String::new(),
+            dither: false,
+            color_fill: true,
+        };
+
+        let mut buffer = String::new();
+        frame
+            .to_ascii_with_buffer(&style, frame.width, frame.height, &mut buffer)
+            .unwrap();
+
+        assert_eq!(buffer.matches("\x1b[48;2;").count(), 6);
+        assert_eq!(buffer.matches("\x1b[0m\n").count(), 2);
+    }
+
+    fn style(render_mode: RenderMode, color_depth: ColorDepth) -> AsciiStyle {
+        AsciiStyle {
+            color_enabled: color_depth != ColorDepth::None,
+            color_depth,
+            render_mode,
+            ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+            dither: false,
+            color_fill: false,
+        }
+    }
+
+    /// Renders into `width`x`height` cells. The tests size their frames to the mode's
+    /// pixels per cell, so no resize is needed.
+    fn render(frame: &Frame, style: &AsciiStyle, width: i32, height: i32) -> String {
+        let mut buffer = String::new();
+        frame
+            .to_ascii_with_buffer(style, width, height, &mut buffer)
+            .unwrap();
+        buffer
+    }
+
+    fn gray_gradient(width: i32, max_gray: u8) -> Frame {
+        let data = (0..width)
+            .flat_map(|x| [(x as u32 * max_gray as u32 / (width - 1) as u32) as u8; 3])
+            .collect();
+        Frame {
+            width,
+            height: 1,
+            data: Arc::new(data),
+        }
+    }
+
+    #[test]
+    fn braille_maps_a_2x4_block_to_its_dot_pattern() {
+        // Left column lit, right column dark: dots 1, 2, 3 and 7
+        let data = (0..8)
+            .flat_map(|pixel| if pixel % 2 == 0 { [255; 3] } else { [0; 3] })
+            .collect();
+        let frame = Frame {
+            width: 2,
+            height: 4,
+            data: Arc::new(data),
+        };
+
+        let rendered = render(&frame, &style(RenderMode::Braille, ColorDepth::None), 1, 1);
+
+        assert_eq!(rendered, "\u{2847}\n");
+    }
+
+    #[test]
+    fn half_block_colors_the_top_pixel_in_front_and_the_bottom_one_behind() {
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120]),
+        };
+
+        let rendered = render(
+            &frame,
+            &style(RenderMode::HalfBlock, ColorDepth::TrueColor),
+            2,
+            1,
+        );
+
+        assert_eq!(
+            rendered,
+            "\x1b[38;2;10;20;30;48;2;70;80;90m▀\x1b[38;2;40;50;60;48;2;100;110;120m▀\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn two_character_ramp_renders_a_gradient_with_only_its_glyphs() {
+        let frame = gray_gradient(16, 255);
+        let ramp_style = AsciiStyle {
+            ascii_ramp: ".#".to_string(),
+            ..style(RenderMode::Ascii, ColorDepth::None)
+        };
+
+        let rendered = render(&frame, &ramp_style, 16, 1);
+        let glyphs = rendered.trim_end_matches('\n');
+
+        assert!(glyphs.chars().all(|c| c == '.' || c == '#'));
+        assert!(glyphs.starts_with('.'));
+        assert!(glyphs.ends_with('#'));
+    }
+
+    #[test]
+    fn labeled_cell_shows_the_name_truncated_to_the_cell() {
+        // Half-block cells are two pixels tall, so an 8x2 frame fills an 8x1 tile as is
+        let frame = Frame {
+            width: 8,
+            height: 2,
+            data: Arc::new(vec![128; 8 * 2 * 3]),
+        };
+        let mut output = String::new();
+
+        combine_frames_with_buffers(
+            &[frame],
+            &["a-very-long-name".to_string()],
+            (8, 3),
+            (8, 3),
+            &style(RenderMode::HalfBlock, ColorDepth::TrueColor),
+            &mut output,
+            &mut Vec::new(),
+        );
+
+        assert_eq!(output.lines().count(), 3);
+        assert_eq!(output.lines().nth(1), Some("a-very-l"));
+    }
+
+    #[test]
+    fn display_width_counts_wide_and_combining_characters() {
+        assert_eq!(count_visible_chars_fast("漢字"), 4);
+        assert_eq!(count_visible_chars_fast("cafe\u{301}"), 4);
+        assert_eq!(count_visible_chars_fast("\x1b[31m漢字\x1b[0m"), 4);
+
+        // A wide character that would straddle the limit is left out whole
+        assert_eq!(truncate_to_width("漢字", 3), "漢");
+        assert_eq!(truncate_to_width("cafe\u{301}!", 4), "cafe\u{301}");
+    }
+
+    #[test]
+    fn frame_at_192x108_round_trips_with_its_dimensions() {
+        let data = (0..108)
+            .flat_map(|y| (0..192).flat_map(move |x| [x as u8, (y * 2) as u8, 128]))
+            .collect();
+        let frame = Frame {
+            width: 192,
+            height: 108,
+            data: Arc::new(data),
+        };
+
+        let decoded = Frame::from_bytes(&frame.to_bytes(90.0, FrameFormat::Rgb)).unwrap();
+
+        assert_eq!((decoded.width, decoded.height), (192, 108));
+        assert_eq!(decoded.data.len(), 192 * 108 * 3);
+    }
+
+    #[test]
+    fn mono_frame_round_trips_its_luma_within_webp_tolerance() {
+        let data: Vec<u8> = (0..16)
+            .flat_map(|y| (0..32).flat_map(move |x| [x * 8, y * 16, 255 - x * 8]))
+            .collect();
+        let frame = Frame {
+            width: 32,
+            height: 16,
+            data: Arc::new(data.clone()),
+        };
+
+        let decoded = Frame::from_bytes(&frame.to_bytes(90.0, FrameFormat::Mono)).unwrap();
+
+        let mut total_error = 0;
+        for (original, pixel) in data.chunks_exact(3).zip(decoded.data.chunks_exact(3)) {
+            assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+            let luma = luminance(original[0], original[1], original[2]);
+            total_error += (pixel[0] as i32 - luma as i32).abs();
+        }
+        assert!(total_error / (32 * 16) <= 4);
+    }
+
+    #[test]
+    fn ansi16_maps_primaries_and_emits_standard_color_codes() {
+        assert_eq!(rgb_to_ansi16(255, 0, 0), 91);
+        assert_eq!(rgb_to_ansi16(0, 255, 0), 92);
+        assert_eq!(rgb_to_ansi16(0, 0, 255), 94);
+        assert_eq!(rgb_to_ansi16(255, 255, 255), 97);
+        assert_eq!(rgb_to_ansi16(0, 0, 0), 30);
+
+        let frame = Frame {
+            width: 3,
+            height: 1,
+            data: Arc::new(vec![128, 0, 0, 0, 128, 0, 0, 0, 128]),
+        };
+        let rendered = render(&frame, &style(RenderMode::Ascii, ColorDepth::Ansi16), 3, 1);
+
+        for code in ["\x1b[31m", "\x1b[32m", "\x1b[34m"] {
+            assert!(rendered.contains(code), "{:?} lacks {:?}", rendered, code);
+        }
+    }
+
+    #[test]
+    fn tiny_terminal_gets_the_too_small_message() {
+        let frame = gray_gradient(8, 255);
+        let ascii_style = style(RenderMode::Ascii, ColorDepth::None);
+        let mut output = String::new();
+
+        combine_frames_with_buffers(
+            std::slice::from_ref(&frame),
+            &[],
+            (5, 3),
+            (5, 3),
+            &ascii_style,
+            &mut output,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "     \nTermi\n     \n");
+
+        // Only the grid is too small here, so the whole message fits
+        combine_frames_with_buffers(
+            &[frame],
+            &[],
+            (5, 3),
+            (60, 3),
+            &ascii_style,
+            &mut output,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            output.lines().nth(1).map(str::trim),
+            Some("Terminal too small, resize to at least 8x3")
+        );
+    }
+
+    #[test]
+    fn dithering_a_gentle_gradient_adds_transitions() {
+        let frame = gray_gradient(64, 64);
+        let transitions = |dither: bool| {
+            let dither_style = AsciiStyle {
+                dither,
+                ..style(RenderMode::Ascii, ColorDepth::None)
+            };
+            let rendered = render(&frame, &dither_style, 64, 1);
+            let glyphs: Vec<char> = rendered.trim_end_matches('\n').chars().collect();
+            glyphs.windows(2).filter(|pair| pair[0] != pair[1]).count()
+        };
+
+        assert!(transitions(true) > transitions(false));
+    }
+
+    #[test]
+    fn mirrored_mat_reverses_each_row() {
+        use opencv::core::{CV_8UC3, Vec3b};
+
+        let mut mat = Mat::zeros(2, 3, CV_8UC3).unwrap().to_mat().unwrap();
+        for y in 0..2 {
+            for x in 0..3 {
+                // BGR, so the red channel carries the column and green the row
+                *mat.at_2d_mut::<Vec3b>(y, x).unwrap() = Vec3b::from([0, y as u8, x as u8 * 100]);
+            }
+        }
+
+        let frame = Frame::from_mat(&mat, 3, 2, true).unwrap();
+
+        assert_eq!(
+            *frame.data,
+            [200, 0, 0, 100, 0, 0, 0, 0, 0, 200, 1, 0, 100, 1, 0, 0, 1, 0]
+        );
+        assert_eq!(
+            *frame.mirrored().data,
+            *Frame::from_mat(&mat, 3, 2, false).unwrap().data
+        );
+    }
+}
@@ -12,8 +12,27 @@ pub enum CameraTestMode {
     VortexGrid,
     #[strum(serialize = "diamond")]
     DiamondFlow,
+    #[strum(serialize = "bars")]
+    ColorBars,
+    #[strum(serialize = "checkerboard")]
+    MovingCheckerboard,
+    #[strum(serialize = "solid")]
+    SolidColorCycle,
 }
 
+const CHECKER_SIZE: i32 = 8;
+
+// 75% SMPTE bars, in BGR order
+const COLOR_BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [0, 191, 191],
+    [191, 191, 0],
+    [0, 191, 0],
+    [191, 0, 191],
+    [0, 0, 191],
+    [191, 0, 0],
+];
+
 pub struct FrameGenerator;
 
 use opencv::{
@@ -33,8 +52,7 @@ impl FrameGenerator {
         let t = time as f32;
 
         if output.channels() != 3 || output.typ() != CV_8UC3 {
-            *output =
-                Mat::zeros(TEST_FRAME_HEIGHT as i32, TEST_FRAME_WIDTH as i32, CV_8UC3)?.to_mat()?;
+            *output = Mat::zeros(TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH, CV_8UC3)?.to_mat()?;
         }
 
         for y in 0..TEST_FRAME_HEIGHT {
@@ -143,6 +161,40 @@ impl FrameGenerator {
                         let sat = 0.9;
                         hsv_to_rgb(hue, sat, val)
                     }
+
+                    CameraTestMode::ColorBars => {
+                        let bar = (x * COLOR_BARS.len() as i32 / TEST_FRAME_WIDTH) as usize;
+
+                        if y < TEST_FRAME_HEIGHT * 2 / 3 {
+                            Vec3b::from(COLOR_BARS[bar])
+                        } else if y < TEST_FRAME_HEIGHT * 3 / 4 {
+                            // Reverse castellations: blue, black, magenta, black, cyan, black, white
+                            if bar.is_multiple_of(2) {
+                                Vec3b::from(COLOR_BARS[COLOR_BARS.len() - 1 - bar])
+                            } else {
+                                Vec3b::from([0, 0, 0])
+                            }
+                        } else if bar == 1 {
+                            Vec3b::from([255, 255, 255])
+                        } else {
+                            Vec3b::from([16, 16, 16])
+                        }
+                    }
+
+                    CameraTestMode::MovingCheckerboard => {
+                        let column = (x + time).div_euclid(CHECKER_SIZE);
+                        let row = (y + time / 2).div_euclid(CHECKER_SIZE);
+
+                        if (column + row) % 2 == 0 {
+                            Vec3b::from([255, 255, 255])
+                        } else {
+                            Vec3b::from([0, 0, 0])
+                        }
+                    }
+
+                    CameraTestMode::SolidColorCycle => {
+                        hsv_to_rgb((t * 0.01).rem_euclid(1.0), 1.0, 1.0)
+                    }
                 };
 
                 *output.at_2d_mut::<Vec3b>(y, x)? = pixel;
@@ -151,6 +203,92 @@ impl FrameGenerator {
 
         Ok(())
     }
+
+    /// Fills `output` with a pattern of colored blocks and noise that depends only on
+    /// `seed`, for use as a stable input when comparing rendered or encoded output.
+    pub fn generate_static_frame(
+        seed: u64,
+        output: &mut Mat,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut rng = SplitMix64(seed);
+
+        if output.rows() != TEST_FRAME_HEIGHT
+            || output.cols() != TEST_FRAME_WIDTH
+            || output.typ() != CV_8UC3
+        {
+            *output = Mat::zeros(TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH, CV_8UC3)?.to_mat()?;
+        }
+
+        let blocks_x = (TEST_FRAME_WIDTH + CHECKER_SIZE - 1) / CHECKER_SIZE;
+        let blocks_y = (TEST_FRAME_HEIGHT + CHECKER_SIZE - 1) / CHECKER_SIZE;
+        let block_colors: Vec<[u8; 3]> = (0..blocks_x * blocks_y)
+            .map(|_| {
+                let bits = rng.next();
+                [bits as u8, (bits >> 8) as u8, (bits >> 16) as u8]
+            })
+            .collect();
+
+        for y in 0..TEST_FRAME_HEIGHT {
+            for x in 0..TEST_FRAME_WIDTH {
+                let block =
+                    block_colors[((y / CHECKER_SIZE) * blocks_x + x / CHECKER_SIZE) as usize];
+                let noise = rng.next();
+
+                let pixel = Vec3b::from([
+                    block[0].wrapping_add((noise & 0x0f) as u8),
+                    block[1].wrapping_add((noise >> 4 & 0x0f) as u8),
+                    block[2].wrapping_add((noise >> 8 & 0x0f) as u8),
+                ]);
+
+                *output.at_2d_mut::<Vec3b>(y, x)? = pixel;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn generate_offline_frame(output: &mut Mat) -> Result<(), Box<dyn Error + Send + Sync>> {
+        use opencv::{
+            core::{Point, Scalar},
+            imgproc::{FONT_HERSHEY_SIMPLEX, LINE_8, put_text},
+        };
+
+        *output = Mat::zeros(TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH, CV_8UC3)?.to_mat()?;
+
+        for (line, text) in ["CAMERA", "OFFLINE"].iter().enumerate() {
+            put_text(
+                output,
+                text,
+                Point::new(
+                    TEST_FRAME_WIDTH / 8,
+                    TEST_FRAME_HEIGHT / 2 + line as i32 * 14 - 2,
+                ),
+                FONT_HERSHEY_SIMPLEX,
+                0.45,
+                Scalar::all(255.0),
+                1,
+                LINE_8,
+                false,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// Small enough to inline and, unlike the `rand` generators, guaranteed to produce
+// the same sequence across dependency updates.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
 }
 
 fn hsv_to_rgb(h: f32, s: f32, v: f32) -> Vec3b {
@@ -167,8 +305,86 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> Vec3b {
         2 => (p, v, t),
         3 => (p, q, v),
         4 => (t, p, v),
-        5 | _ => (v, p, q),
+        _ => (v, p, q),
     };
 
     Vec3b::from([(b * 255.0) as u8, (g * 255.0) as u8, (r * 255.0) as u8])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(mode: CameraTestMode, time: i32) -> Mat {
+        let mut output = Mat::default();
+        FrameGenerator::generate_frame(&mode, time, &mut output).unwrap();
+        output
+    }
+
+    fn pixel(mat: &Mat, x: i32, y: i32) -> [u8; 3] {
+        **mat.at_2d::<Vec3b>(y, x).unwrap()
+    }
+
+    #[test]
+    fn color_bars_follow_the_smpte_layout() {
+        let bars = generate(CameraTestMode::ColorBars, 0);
+        let bar_center = |bar: i32| (2 * bar + 1) * TEST_FRAME_WIDTH / 14;
+
+        for (bar, color) in COLOR_BARS.iter().enumerate() {
+            assert_eq!(pixel(&bars, bar_center(bar as i32), 0), *color);
+        }
+
+        let castellation_y = TEST_FRAME_HEIGHT * 2 / 3;
+        assert_eq!(pixel(&bars, bar_center(0), castellation_y), [191, 0, 0]);
+        assert_eq!(pixel(&bars, bar_center(1), castellation_y), [0, 0, 0]);
+
+        let bottom_y = TEST_FRAME_HEIGHT - 1;
+        assert_eq!(pixel(&bars, bar_center(1), bottom_y), [255, 255, 255]);
+        assert_eq!(pixel(&bars, bar_center(3), bottom_y), [16, 16, 16]);
+    }
+
+    #[test]
+    fn checkerboard_moves_with_time() {
+        let board = generate(CameraTestMode::MovingCheckerboard, 0);
+        assert_eq!(pixel(&board, 0, 0), [255, 255, 255]);
+        assert_eq!(pixel(&board, CHECKER_SIZE, 0), [0, 0, 0]);
+        assert_eq!(pixel(&board, 0, CHECKER_SIZE), [0, 0, 0]);
+        assert_eq!(pixel(&board, CHECKER_SIZE, CHECKER_SIZE), [255, 255, 255]);
+
+        // Half a square later the edge has moved left by four pixels
+        let board = generate(CameraTestMode::MovingCheckerboard, CHECKER_SIZE / 2);
+        assert_eq!(pixel(&board, CHECKER_SIZE / 2 - 1, 0), [255, 255, 255]);
+        assert_eq!(pixel(&board, CHECKER_SIZE / 2, 0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn solid_color_fills_the_frame_and_cycles_hue() {
+        let red = generate(CameraTestMode::SolidColorCycle, 0);
+        let later = generate(CameraTestMode::SolidColorCycle, 25);
+
+        for (x, y) in [(0, 0), (TEST_FRAME_WIDTH / 2, TEST_FRAME_HEIGHT / 2)] {
+            assert_eq!(pixel(&red, x, y), [0, 0, 255]);
+            assert_eq!(pixel(&later, x, y), [0, 255, 127]);
+        }
+    }
+
+    #[test]
+    fn static_frame_depends_only_on_its_seed() {
+        let static_frame = |seed: u64, output: &mut Mat| {
+            FrameGenerator::generate_static_frame(seed, output).unwrap();
+            output.data_bytes().unwrap().to_vec()
+        };
+
+        // Reusing a buffer that held another frame must not leak into the result
+        let mut reused = generate(CameraTestMode::SpiralTunnel, 7);
+        let first = static_frame(42, &mut Mat::default());
+        let second = static_frame(42, &mut reused);
+
+        assert_eq!(
+            first.len(),
+            (TEST_FRAME_WIDTH * TEST_FRAME_HEIGHT * 3) as usize
+        );
+        assert_eq!(first, second);
+        assert_ne!(first, static_frame(43, &mut Mat::default()));
+    }
+}
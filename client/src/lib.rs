@@ -0,0 +1,60 @@
+//! WeSFU client library.
+//!
+//! The `client` binary is a thin CLI over this crate. Embedders can drive a whole
+//! call through [`Client`], or use the ASCII video pipeline directly:
+//! [`Frame`] and [`combine_frames_with_buffers`] turn frames into terminal output,
+//! [`Renderer`] draws it, or [`WriterRenderer`] writes it to any `io::Write`, and
+//! [`udp_handler`] moves frames over the wire.
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use client::{
+//!     Frame, RenderMode,
+//!     frame::{AsciiStyle, ColorDepth, DEFAULT_ASCII_RAMP},
+//! };
+//!
+//! let frame = Frame {
+//!     width: 2,
+//!     height: 1,
+//!     data: Arc::new(vec![0, 0, 0, 255, 255, 255]),
+//! };
+//!
+//! let style = AsciiStyle {
+//!     color_enabled: false,
+//!     color_depth: ColorDepth::None,
+//!     render_mode: RenderMode::Ascii,
+//!     ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+//!     dither: false,
+//!     color_fill: false,
+//! };
+//!
+//! let mut ascii = String::new();
+//! frame.to_ascii_with_buffer(&style, 2, 1, &mut ascii).unwrap();
+//!
+//! // Black maps to the ramp's blank and white to its densest glyph
+//! assert_eq!(ascii, " B\n");
+//! ```
+
+pub mod bench;
+mod call_interface;
+pub mod call_stats;
+pub mod camera;
+mod chat;
+mod cli_display;
+pub mod client;
+pub mod frame;
+pub mod frame_generator;
+mod loopback;
+mod media_crypto;
+mod pre_call_interface;
+#[cfg(feature = "record")]
+mod recorder;
+pub mod renderer;
+mod tls;
+pub mod udp_handler;
+
+pub use client::{Client, ClientError};
+pub use frame::{Frame, RenderMode, combine_frames_with_buffers};
+pub use renderer::{FrameSink, Renderer, WriterRenderer};
+pub use udp_handler::{udp_listener_loop, udp_send_loop};
@@ -0,0 +1,207 @@
+use core::error::Error;
+
+use shared::{
+    RoomID, StreamID, received_tcp_command::ReceivedTcpCommand, tcp_command::TcpCommand,
+    tcp_command_id::TcpCommandId,
+};
+use tokio::{io::DuplexStream, net::UdpSocket};
+
+use crate::udp_handler::{FrameType, MAX_DATAGRAM_SIZE, PACKET_HEADER_LEN, parse_packet};
+
+pub const LOOPBACK_ROOM_ID: RoomID = [0, 0, 0, 0];
+pub const LOOPBACK_OWN_SID: StreamID = [0, 0, 0, 1];
+/// Stream ID the reflected video shows up under, so it renders as a remote participant.
+pub const LOOPBACK_REMOTE_SID: StreamID = [0, 0, 0, 2];
+pub const LOOPBACK_USERNAME: &str = "loopback";
+pub const LOOPBACK_CONTROL_BUFFER_SIZE: usize = 64 * 1024;
+
+pub fn loopback_full_sid() -> Vec<u8> {
+    [LOOPBACK_ROOM_ID, LOOPBACK_OWN_SID].concat()
+}
+
+/// Stands in for the server's control channel: announces the loopback participant,
+/// echoes chat back, and drains everything else so the call never blocks on writes.
+pub async fn run_control(
+    mut stream: DuplexStream,
+    username: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let payload = [&LOOPBACK_REMOTE_SID[..], LOOPBACK_USERNAME.as_bytes()].concat();
+    TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, payload)
+        .write_to_stream(&mut stream)
+        .await?;
+
+    loop {
+        match TcpCommand::read_from_stream(&mut stream).await? {
+            ReceivedTcpCommand::EOF => return Ok(()),
+            ReceivedTcpCommand::Command(TcpCommand::StringList(
+                TcpCommandId::ChatMessage,
+                payload,
+            )) => {
+                if let [text] = payload.as_slice() {
+                    TcpCommand::StringList(
+                        TcpCommandId::ChatMessage,
+                        vec![username.clone(), text.clone()],
+                    )
+                    .write_to_stream(&mut stream)
+                    .await?;
+                }
+            }
+            ReceivedTcpCommand::Command(_) => {}
+        }
+    }
+}
+
+/// Stands in for the server's UDP forwarding: sends every packet back to the client as if
+/// it came from `LOOPBACK_REMOTE_SID`, and points keyframe and retransmit requests for
+/// that stream back at the client's own.
+pub async fn run_reflector(socket: UdpSocket) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rid_len = RoomID::default().len();
+    let sid_len = StreamID::default().len();
+    let data_offset = sid_len + PACKET_HEADER_LEN;
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (n, from_addr) = socket.recv_from(&mut buf).await?;
+        let Some(packet) = buf.get_mut(rid_len..n) else {
+            continue;
+        };
+        let frame_type = match parse_packet(packet) {
+            Ok(parsed) => parsed.frame_type,
+            Err(_) => continue,
+        };
+
+        if frame_type != FrameType::MtuProbe {
+            if matches!(
+                frame_type,
+                FrameType::KeyframeRequest | FrameType::RetransmitRequest
+            ) && let Some(target_sid) = packet.get_mut(data_offset..data_offset + sid_len)
+                && target_sid == LOOPBACK_REMOTE_SID
+            {
+                target_sid.copy_from_slice(&LOOPBACK_OWN_SID);
+            }
+            packet[..sid_len].copy_from_slice(&LOOPBACK_REMOTE_SID);
+        }
+
+        let _ = socket.send_to(packet, from_addr).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, atomic::AtomicBool},
+        time::Duration,
+    };
+
+    use tokio::sync::{Mutex, watch};
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        call_stats::CallStats,
+        camera::{Camera, MAX_USER_CAMERAS, TEST_FRAME_HEIGHT, TEST_FRAME_WIDTH},
+        frame::{
+            AsciiStyle, ColorDepth, DEFAULT_ASCII_RAMP, Frame, FrameFormat, RemoteStream,
+            RenderMode,
+        },
+        udp_handler::{
+            DEFAULT_CHUNK_SIZE, MediaStream, SendSettings, feedback_channel, udp_listener_loop,
+            udp_send_loop,
+        },
+    };
+
+    const COLOR_BARS_CAMERA: i32 = MAX_USER_CAMERAS + 3;
+    const RENDER_WIDTH: i32 = 40;
+    const RENDER_HEIGHT: i32 = 12;
+
+    #[tokio::test]
+    async fn test_pattern_camera_renders_through_the_loopback() {
+        let reflector_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reflector_addr = reflector_socket.local_addr().unwrap();
+        let reflector_task = tokio::spawn(run_reflector(reflector_socket));
+
+        let udp_stream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp_stream.connect(reflector_addr).await.unwrap();
+        let media = MediaStream {
+            udp_stream: Arc::new(udp_stream),
+            full_sid: loopback_full_sid(),
+            media_cipher: None,
+        };
+
+        let mut camera = Camera::new(COLOR_BARS_CAMERA).unwrap();
+        let (camera_tx, camera_rx) = watch::channel(Frame {
+            width: 0,
+            height: 0,
+            data: Arc::new(Vec::new()),
+        });
+
+        let sid_to_frame_map = Arc::new(Mutex::new(HashMap::<StreamID, RemoteStream>::new()));
+        let call_stats = Arc::new(Mutex::new(CallStats::new()));
+        let (feedback_tx, feedback_rx) = feedback_channel();
+        let cancel_token = CancellationToken::new();
+
+        let send_task = tokio::spawn(udp_send_loop(
+            media.clone(),
+            camera_rx,
+            SendSettings {
+                quality: 75.0,
+                fec_enabled: false,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                max_upload_kbps: None,
+                frame_format: FrameFormat::Rgb,
+            },
+            feedback_rx,
+            call_stats.clone(),
+            Arc::new(AtomicBool::new(false)),
+            cancel_token.clone(),
+        ));
+        let listen_task = tokio::spawn(udp_listener_loop(
+            media,
+            sid_to_frame_map.clone(),
+            feedback_tx,
+            call_stats,
+            cancel_token.clone(),
+        ));
+
+        // Paced like a 30 fps capture, so this waits up to a few seconds
+        let mut remote_frame = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_secs(1) / 30).await;
+            let mat = camera.get_frame().await.unwrap();
+            let camera_frame =
+                Frame::from_mat(mat, TEST_FRAME_WIDTH, TEST_FRAME_HEIGHT, false).unwrap();
+            camera_tx.send_replace(camera_frame);
+
+            remote_frame = sid_to_frame_map
+                .lock()
+                .await
+                .get(&LOOPBACK_REMOTE_SID)
+                .and_then(|remote_stream| remote_stream.frame.clone());
+            if remote_frame.is_some() {
+                break;
+            }
+        }
+        cancel_token.cancel();
+        reflector_task.abort();
+        let _ = send_task.await;
+        let _ = listen_task.await;
+
+        let remote_frame = remote_frame.expect("Loopback never delivered a frame");
+        let style = AsciiStyle {
+            color_enabled: false,
+            color_depth: ColorDepth::None,
+            render_mode: RenderMode::Ascii,
+            ascii_ramp: DEFAULT_ASCII_RAMP.to_string(),
+            dither: false,
+            color_fill: false,
+        };
+        let mut ascii = String::new();
+        remote_frame
+            .to_ascii_with_buffer(&style, RENDER_WIDTH, RENDER_HEIGHT, &mut ascii)
+            .unwrap();
+
+        assert_eq!(ascii.lines().count(), RENDER_HEIGHT as usize);
+        assert!(ascii.chars().any(|c| !c.is_whitespace()));
+    }
+}
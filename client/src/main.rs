@@ -1,67 +1,290 @@
-mod call_interface;
-mod camera;
-mod cli_display;
-mod client;
-mod frame;
-mod frame_generator;
-mod pre_call_interface;
-mod renderer;
-mod udp_handler;
-
-use clap::Parser;
-use rand::{Rng, rng, seq::IndexedRandom};
+use std::time::Duration;
 
-use crate::{camera::Camera, client::Client};
+use clap::{Parser, Subcommand};
+use client::{
+    Client,
+    bench::{BENCH_RENDER_SIZE, run_bench},
+    camera::Camera,
+    client::{parse_ascii_ramp, parse_resolution, validate_fps},
+    frame::{ColorDepth, DEFAULT_ASCII_RAMP, RenderMode},
+};
+use rand::{Rng, rng, seq::IndexedRandom};
+use shared::{TCP_PORT, UDP_PORT, validate_display_name, validate_name};
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     username: Option<String>,
 
+    #[arg(long)]
+    display_name: Option<String>,
+
     #[arg(short, long, default_value = "213.188.199.174")]
     server_address: String,
 
+    #[arg(long, default_value_t = TCP_PORT)]
+    tcp_port: u16,
+
+    #[arg(long, default_value_t = UDP_PORT)]
+    udp_port: u16,
+
     #[arg(short, long, default_value = "0")]
     camera: String,
 
+    #[arg(long, default_value = "96x54", value_parser = parse_resolution)]
+    resolution: (i32, i32),
+
+    #[arg(long, default_value_t = 30, value_parser = parse_fps)]
+    fps: u32,
+
+    #[arg(long, default_value_t = 30, value_parser = parse_fps)]
+    render_fps: u32,
+
     #[arg(long, default_value_t = false)]
     color: bool,
+
+    #[arg(long, value_enum, default_value_t = ColorDepth::Auto)]
+    color_depth: ColorDepth,
+
+    #[arg(long, value_enum, default_value_t = RenderMode::Ascii)]
+    render: RenderMode,
+
+    #[arg(long, default_value = DEFAULT_ASCII_RAMP, value_parser = parse_ascii_ramp)]
+    ascii_ramp: String,
+
+    #[arg(long, default_value_t = false)]
+    dither: bool,
+
+    #[arg(long, default_value_t = false)]
+    color_fill: bool,
+
+    #[arg(short, long, default_value_t = 75, value_parser = clap::value_parser!(u8).range(0..=100))]
+    quality: u8,
+
+    #[arg(long, default_value_t = false)]
+    fec: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u16).range(576..=9216))]
+    mtu: Option<u16>,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_upload_kbps: Option<u32>,
+
+    #[arg(long, default_value_t = false)]
+    mono_transport: bool,
+
+    #[arg(long, default_value_t = false)]
+    loopback: bool,
+
+    #[arg(long, default_value_t = false)]
+    mirror: bool,
+
+    #[arg(long, default_value_t = false, conflicts_with = "mirror")]
+    mirror_self_only: bool,
+
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    #[arg(long, default_value_t = false)]
+    tls: bool,
+
+    #[arg(long, requires = "tls")]
+    ca: Option<String>,
+
+    #[arg(long)]
+    join: Option<String>,
+
+    #[arg(long, requires = "join")]
+    room_password: Option<String>,
+
+    #[arg(long, default_value_t = false, requires = "join")]
+    create: bool,
+
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    #[cfg(feature = "record")]
+    #[arg(long)]
+    record: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Times encoding, decoding and ASCII rendering on a test pattern, without a server
+    Bench {
+        #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+
+        #[arg(long, default_value = "96x54", value_parser = parse_resolution)]
+        resolution: (i32, i32),
+
+        #[arg(short, long, default_value_t = 75, value_parser = clap::value_parser!(u8).range(0..=100))]
+        quality: u8,
+
+        #[arg(long, default_value_t = false)]
+        color: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
+    if let Some(Command::Bench {
+        iterations,
+        resolution,
+        quality,
+        color,
+    }) = args.command
+    {
+        bench(iterations, resolution, quality as f32, color);
+        return;
+    }
+
     let username = match args.username {
         Some(username) => username,
         None => generate_username(),
     };
 
-    let mut camera_index = match args.camera.parse() {
-        Ok(idx) => idx,
-        _ => {
-            eprintln!("Invalid camera");
+    if let Err(error) = validate_name(&username) {
+        eprintln!("Username {}", error);
+        return;
+    }
+
+    if let Some(display_name) = &args.display_name
+        && let Err(error) = validate_display_name(display_name)
+    {
+        eprintln!("Display name {}", error);
+        return;
+    }
+
+    let mut camera_indices = Vec::new();
+    for camera in args.camera.split(',').map(str::trim) {
+        let camera_index = match camera.parse() {
+            Ok(idx) => idx,
+            _ => {
+                eprintln!("Invalid camera");
+                return;
+            }
+        };
+
+        if !Camera::is_valid_camera_name(&camera.to_string()) {
+            eprintln!("Camera {} not found", camera);
             return;
         }
+
+        if camera_indices.contains(&camera_index) {
+            eprintln!("Camera {} is listed more than once", camera);
+            return;
+        }
+
+        camera_indices.push(camera_index);
+    }
+    let camera_index = camera_indices.remove(0);
+
+    let color_enabled = match args.color_depth {
+        ColorDepth::Auto => args.color || args.render == RenderMode::HalfBlock,
+        ColorDepth::None if args.render == RenderMode::HalfBlock => {
+            eprintln!("Half-block rendering needs color, use a --color-depth other than none");
+            return;
+        }
+        ColorDepth::None => false,
+        ColorDepth::TrueColor | ColorDepth::Ansi256 | ColorDepth::Ansi16 => true,
     };
 
-    if !Camera::is_valid_camera_name(&args.camera) {
-        eprintln!("Camera not found");
+    if args.color_fill && !color_enabled {
+        eprintln!("Color fill needs color, pass --color or a --color-depth other than none");
         return;
     }
 
-    if let Err(e) = Client::run(
-        &args.server_address,
-        &username,
-        &mut camera_index,
-        args.color,
-    )
-    .await
-    {
+    #[cfg(feature = "record")]
+    let record_path = args.record;
+    #[cfg(not(feature = "record"))]
+    let record_path = None;
+
+    let mut client = Client::new(&args.server_address, &username)
+        .display_name(args.display_name.as_deref())
+        .tcp_port(args.tcp_port)
+        .udp_port(args.udp_port)
+        .camera_index(camera_index)
+        .extra_camera_indices(camera_indices)
+        .send_resolution(args.resolution)
+        .capture_fps(args.fps)
+        .render_fps(args.render_fps)
+        .color_enabled(color_enabled)
+        .color_depth(args.color_depth)
+        .render_mode(args.render)
+        .ascii_ramp(&args.ascii_ramp)
+        .dither(args.dither)
+        .color_fill(args.color_fill)
+        .quality(args.quality as f32)
+        .fec_enabled(args.fec)
+        .mtu(args.mtu.map(usize::from))
+        .max_upload_kbps(args.max_upload_kbps)
+        .mono_transport(args.mono_transport)
+        .mirror(args.mirror)
+        .mirror_self_only(args.mirror_self_only)
+        .idle_timeout(
+            args.idle_timeout
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        )
+        .tls(args.tls, args.ca.as_deref())
+        .record_path(record_path)
+        .json_output(args.json)
+        .connect_timeout(Duration::from_secs(args.connect_timeout))
+        .connect_retries(args.retries)
+        .loopback(args.loopback);
+
+    if let Some(room_name) = &args.join {
+        client = client.join(room_name, args.room_password.as_deref(), args.create);
+    }
+
+    if let Err(e) = client.run().await {
         eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 
+fn bench(iterations: u32, resolution: (i32, i32), quality: f32, color: bool) {
+    match run_bench(iterations, resolution, quality, color) {
+        Ok(stats) => {
+            println!(
+                "{} frames at {}x{}, rendered to {}x{}",
+                stats.iterations,
+                resolution.0,
+                resolution.1,
+                BENCH_RENDER_SIZE.0,
+                BENCH_RENDER_SIZE.1
+            );
+            println!("Encode: {} us", stats.encode.as_micros());
+            println!("Decode: {} us", stats.decode.as_micros());
+            println!("Render: {} us", stats.render.as_micros());
+            println!("Frame size: {} bytes", stats.encoded_bytes);
+            println!("FPS: {:.1}", stats.fps);
+        }
+        Err(e) => {
+            eprintln!("Benchmark failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_fps(fps: &str) -> Result<u32, String> {
+    let fps = fps.parse().map_err(|_| format!("Invalid FPS '{}'", fps))?;
+
+    validate_fps(fps).map_err(|e| e.to_string())
+}
+
 fn generate_username() -> String {
     let adjectives = ["fast", "lazy", "cool", "smart", "brave"];
     let nouns = ["tiger", "eagle", "lion", "panda", "wolf"];
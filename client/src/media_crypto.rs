@@ -0,0 +1,152 @@
+use core::error::Error;
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce, Tag,
+    aead::{AeadInPlace, consts::U12},
+};
+use shared::{MediaKey, RoomID, StreamID};
+
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Bytes a sealed chunk grows by: the nonce counter up front and the GCM tag at the end.
+pub const MEDIA_CRYPTO_OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+
+/// Splits a `JoinRoomSuccess` or `ResumeSuccess` payload into the full stream ID and, when
+/// the server encrypts media, the room's media key. Returns `None` for any other length.
+pub fn split_call_payload(payload: &[u8]) -> Option<(Vec<u8>, Option<MediaKey>)> {
+    let full_sid_len = RoomID::default().len() + StreamID::default().len();
+    if payload.len() < full_sid_len {
+        return None;
+    }
+
+    let (full_sid, media_key) = payload.split_at(full_sid_len);
+    match media_key.len() {
+        0 => Some((full_sid.to_vec(), None)),
+        _ => Some((full_sid.to_vec(), Some(media_key.try_into().ok()?))),
+    }
+}
+
+/// Encrypts and decrypts the video chunks of a call with the room's media key.
+///
+/// The 96-bit nonce is the sender's stream ID followed by a 64-bit packet counter that
+/// travels in front of the ciphertext. The frame sequence can't serve as the counter on
+/// its own: it wraps, restarts when a call is resumed, and parity chunks reuse the chunk
+/// IDs of the data they cover. The counter starts at a random value for every call, so a
+/// resumed stream doesn't repeat nonces under the same key.
+#[derive(Clone)]
+pub struct MediaCipher {
+    cipher: Aes256Gcm,
+    next_counter: u64,
+}
+
+impl MediaCipher {
+    pub fn new(key: &MediaKey) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+            next_counter: rand::random(),
+        }
+    }
+
+    /// Appends `data` to `packet` encrypted under `sid`. Everything already in `packet`
+    /// from `header_start` on is authenticated along with it, so the clear header can't be
+    /// swapped onto another chunk.
+    pub fn seal_into(
+        &mut self,
+        sid: &StreamID,
+        packet: &mut Vec<u8>,
+        header_start: usize,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let counter = self.next_counter;
+        self.next_counter = self.next_counter.wrapping_add(1);
+
+        let header_end = packet.len();
+        packet.extend_from_slice(&counter.to_be_bytes());
+        let data_start = packet.len();
+        packet.extend_from_slice(data);
+
+        let (header, body) = packet.split_at_mut(data_start);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                &nonce(sid, counter),
+                &header[header_start..header_end],
+                body,
+            )
+            .map_err(|_| "Failed to encrypt video chunk")?;
+
+        packet.extend_from_slice(&tag);
+
+        Ok(())
+    }
+
+    /// Decrypts a chunk sealed by [`MediaCipher::seal_into`], or returns `None` if it was
+    /// tampered with or encrypted under another key.
+    pub fn open(&self, sid: &StreamID, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < MEDIA_CRYPTO_OVERHEAD {
+            return None;
+        }
+
+        let (counter, rest) = sealed.split_at(COUNTER_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().ok()?);
+
+        let mut data = ciphertext.to_vec();
+        self.cipher
+            .decrypt_in_place_detached(
+                &nonce(sid, counter),
+                header,
+                &mut data,
+                Tag::from_slice(tag),
+            )
+            .ok()?;
+
+        Some(data)
+    }
+}
+
+fn nonce(sid: &StreamID, counter: u64) -> Nonce<U12> {
+    let mut nonce = [0; 12];
+    nonce[..sid.len()].copy_from_slice(sid);
+    nonce[sid.len()..].copy_from_slice(&counter.to_be_bytes());
+
+    nonce.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: MediaKey = [0x42; 32];
+    const SID: StreamID = [7; 4];
+
+    #[test]
+    fn sealed_chunk_opens_with_the_same_key_and_header() {
+        let data = b"video chunk".as_slice();
+        let mut packet = b"rid-sid-header".to_vec();
+        let header_start = 4;
+        let header_end = packet.len();
+
+        MediaCipher::new(&KEY)
+            .seal_into(&SID, &mut packet, header_start, data)
+            .unwrap();
+        assert_eq!(
+            packet.len(),
+            header_end + data.len() + MEDIA_CRYPTO_OVERHEAD
+        );
+
+        let (header, sealed) = packet.split_at(header_end);
+        let header = &header[header_start..];
+        assert!(!sealed.windows(data.len()).any(|window| window == data));
+
+        let receiver = MediaCipher::new(&KEY);
+        assert_eq!(receiver.open(&SID, header, sealed).as_deref(), Some(data));
+        assert_eq!(receiver.open(&SID, b"other-header", sealed), None);
+        assert_eq!(receiver.open(&[8; 4], header, sealed), None);
+        assert_eq!(
+            MediaCipher::new(&[0x24; 32]).open(&SID, header, sealed),
+            None
+        );
+    }
+}
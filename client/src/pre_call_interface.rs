@@ -1,56 +1,153 @@
 use core::error::Error;
-use std::io::{self, BufRead};
 
+use serde_json::json;
 use shared::{
-    RoomID, StreamID, received_tcp_command::ReceivedTcpCommand, tcp_command::TcpCommand,
-    tcp_command_id::TcpCommandId,
+    control_stream::ControlStream, protocol_error::ProtocolError,
+    received_tcp_command::ReceivedTcpCommand, tcp_command::TcpCommand,
+    tcp_command_id::TcpCommandId, validate_name,
 };
-use tokio::net::TcpStream;
+use tokio::io::{AsyncBufReadExt, BufReader, stdin};
 
-use crate::{camera::Camera, cli_display::CliDisplay};
+use crate::{camera::Camera, cli_display::CliDisplay, media_crypto::split_call_payload};
+
+const CREATE_ROOM_USAGE: &str = "Usage: create room <string> [password] [--max <users>]";
 
 pub struct PreCallInterface;
 
 impl PreCallInterface {
+    /// `current_user` is how this user appears in user and member lists, so it can be
+    /// marked there. `server_addr` and `tls_enabled` are only reported by `status`.
     pub async fn run(
-        tcp_stream: &mut TcpStream,
-        current_username: &str,
+        tcp_stream: &mut dyn ControlStream,
+        server_addr: &str,
+        tls_enabled: bool,
+        current_user: &str,
         camera_index: &mut i32,
+        json_output: bool,
     ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
-        let stdin = io::stdin();
-        let mut reader = stdin.lock();
+        let mut lines = BufReader::new(stdin()).lines();
 
         loop {
-            CliDisplay::print_prompt();
-            let mut line = String::new();
-            if let Ok(n) = reader.read_line(&mut line) {
-                if n == 0 {
-                    return Ok(None);
+            if !json_output {
+                CliDisplay::print_prompt();
+            }
+
+            let line = loop {
+                tokio::select! {
+                    result = lines.next_line() => break result?,
+                    result = TcpCommand::read_from_stream(tcp_stream) => {
+                        match result? {
+                            ReceivedTcpCommand::EOF => {
+                                return Err("Server closed connection.".into());
+                            }
+                            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping)) => {
+                                TcpCommand::Simple(TcpCommandId::Pong)
+                                    .write_to_stream(tcp_stream)
+                                    .await?;
+                            }
+                            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::ServerShuttingDown)) => {
+                                if json_output {
+                                    CliDisplay::print_json(&json!({ "type": "server_shutting_down" }));
+                                } else {
+                                    println!("\nThe server is shutting down. Goodbye!");
+                                }
+                                return Ok(None);
+                            }
+                            ReceivedTcpCommand::Command(_) => {}
+                        }
+                    }
                 }
+            };
+
+            let line = match line {
+                Some(line) => line,
+                None => return Ok(None),
+            };
 
-                let line = line.trim();
+            let line = line.trim();
 
-                if line == "exit" {
+            if line == "exit" {
+                if !json_output {
                     println!("Exiting...");
-                    return Ok(None);
                 }
+                return Ok(None);
+            }
 
-                let call_info_option =
-                    Self::handle_user_input(&line, tcp_stream, current_username, camera_index)
-                        .await?;
+            let call_info_option = Self::handle_user_input(
+                line,
+                tcp_stream,
+                server_addr,
+                tls_enabled,
+                current_user,
+                camera_index,
+                json_output,
+            )
+            .await?;
+
+            if let Some(call_info) = call_info_option {
+                return Ok(Some(call_info));
+            }
+        }
+    }
 
-                if let Some(call_info) = call_info_option {
-                    return Ok(Some(call_info));
-                }
+    pub async fn join_directly(
+        tcp_stream: &mut dyn ControlStream,
+        room_name: &str,
+        password: Option<&str>,
+        create: bool,
+        json_output: bool,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if create
+            && !fetch_room_list(tcp_stream)
+                .await?
+                .iter()
+                .any(|room| room == room_name)
+            && let Err(error) =
+                create_room(tcp_stream, room_name, password, None, json_output).await?
+        {
+            return Err(report_join_error(error, json_output));
+        }
+
+        match join_room(tcp_stream, room_name, password, json_output).await? {
+            Ok(full_sid) => Ok(full_sid),
+            Err(error) => Err(report_join_error(error, json_output)),
+        }
+    }
+
+    /// Sets the name shown for this user in lists and on their video tile. Their username
+    /// stays the key for everything else, such as `kick`.
+    pub async fn set_display_name(
+        tcp_stream: &mut dyn ControlStream,
+        display_name: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        TcpCommand::String(TcpCommandId::SetDisplayName, display_name.to_string())
+            .write_to_stream(tcp_stream)
+            .await?;
+
+        let received_command = match read_response(tcp_stream).await? {
+            ReceivedTcpCommand::EOF => {
+                return Err("Unexpected EOF from server during set_display_name".into());
             }
+            ReceivedTcpCommand::Command(command) => command,
+        };
+
+        match received_command {
+            TcpCommand::Simple(TcpCommandId::SetDisplayNameSuccess) => Ok(()),
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+                Err(Box::new(ProtocolError::from_response(&error)))
+            }
+            _ => Err("Invalid command from server during set_display_name".into()),
         }
     }
 
     async fn handle_user_input(
         input: &str,
-        tcp_stream: &mut TcpStream,
-        current_username: &str,
+        tcp_stream: &mut dyn ControlStream,
+        server_addr: &str,
+        tls_enabled: bool,
+        current_user: &str,
         camera_index: &mut i32,
+        json_output: bool,
     ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
         let lowercase_input = input.to_lowercase();
 
@@ -62,16 +159,21 @@ impl PreCallInterface {
             }
 
             "create room" => {
-                eprintln!("Usage: create room <string>");
+                eprintln!("{}", CREATE_ROOM_USAGE);
             }
             command if command.starts_with("create room ") => {
                 let command_parts: Vec<&str> = input.split(" ").collect();
 
-                if command_parts.len() != 3 {
-                    eprintln!("Usage: create room <string>");
-                } else {
-                    let room_name = command_parts[2];
-                    create_room(tcp_stream, room_name).await?;
+                match parse_create_room_options(&command_parts[2..]) {
+                    Some((room_name, password, max_users)) => {
+                        if let Err(error) =
+                            create_room(tcp_stream, room_name, password, max_users, json_output)
+                                .await?
+                        {
+                            print_server_error(&error, json_output);
+                        }
+                    }
+                    None => eprintln!("{}", CREATE_ROOM_USAGE),
                 }
             }
 
@@ -85,36 +187,94 @@ impl PreCallInterface {
                     eprintln!("Usage: delete room <string>");
                 } else {
                     let room_name = command_parts[2];
-                    delete_room(tcp_stream, room_name).await?;
+                    delete_room(tcp_stream, room_name, json_output).await?;
+                }
+            }
+
+            "rename room" => {
+                eprintln!("Usage: rename room <string> <new name>");
+            }
+            command if command.starts_with("rename room ") => {
+                let command_parts: Vec<&str> = input.split(" ").collect();
+
+                if command_parts.len() != 4 {
+                    eprintln!("Usage: rename room <string> <new name>");
+                } else {
+                    let room_name = command_parts[2];
+                    let new_room_name = command_parts[3];
+                    rename_room(tcp_stream, room_name, new_room_name, json_output).await?;
                 }
             }
 
             "join room" => {
-                eprintln!("Usage: join room <string>");
+                eprintln!("Usage: join room <string> [password]");
             }
             command if command.starts_with("join room ") => {
                 let command_parts: Vec<&str> = input.split(" ").collect();
 
-                if command_parts.len() != 3 {
-                    eprintln!("Usage: join room <string>");
+                if command_parts.len() != 3 && command_parts.len() != 4 {
+                    eprintln!("Usage: join room <string> [password]");
                 } else {
                     let room_name = command_parts[2];
-                    return join_room(tcp_stream, room_name).await;
+                    let password = command_parts.get(3).copied();
+                    return match join_room(tcp_stream, room_name, password, json_output).await? {
+                        Ok(full_sid) => Ok(Some(full_sid)),
+                        Err(error) => {
+                            print_server_error(&error, json_output);
+                            Ok(None)
+                        }
+                    };
+                }
+            }
+
+            "status" => {
+                if json_output {
+                    CliDisplay::print_json(&json!({
+                        "type": "status",
+                        "user": current_user,
+                        "server": server_addr,
+                        "camera": *camera_index,
+                        "tls": tls_enabled,
+                    }));
+                } else {
+                    CliDisplay::print_status(server_addr, current_user, *camera_index, tls_enabled);
                 }
             }
 
             "list users" => {
-                list_users(tcp_stream, current_username).await?;
+                list_users(tcp_stream, current_user, json_output).await?;
             }
 
             "list rooms" => {
-                list_rooms(tcp_stream).await?;
+                list_rooms(tcp_stream, json_output).await?;
+            }
+
+            "list members" => {
+                eprintln!("Usage: list members <room>");
+            }
+            command if command.starts_with("list members ") => {
+                let command_parts: Vec<&str> = input.split(" ").collect();
+
+                if command_parts.len() != 3 {
+                    eprintln!("Usage: list members <room>");
+                } else {
+                    let room_name = command_parts[2];
+                    list_members(tcp_stream, room_name, current_user, json_output).await?;
+                }
             }
 
             "list cameras" => {
                 let available_cameras = Camera::list_available_cameras();
 
-                CliDisplay::print_camera_list(&available_cameras, *camera_index);
+                if json_output {
+                    CliDisplay::print_json(&json!({
+                        "type": "camera_list",
+                        "cameras": available_cameras,
+                        "current_camera": *camera_index,
+                    }));
+                } else {
+                    CliDisplay::print_camera_list(&available_cameras, *camera_index);
+                }
             }
 
             "switch camera" => {
@@ -131,7 +291,14 @@ impl PreCallInterface {
                     if Camera::is_valid_camera_name(&camera_name) {
                         *camera_index = camera_name.parse()?;
 
-                        println!("Successfully switched to Camera {}", *camera_index);
+                        if json_output {
+                            CliDisplay::print_json(&json!({
+                                "type": "camera_switched",
+                                "camera": *camera_index,
+                            }));
+                        } else {
+                            println!("Successfully switched to Camera {}", *camera_index);
+                        }
                     } else {
                         eprintln!("Unknown camera")
                     }
@@ -148,14 +315,15 @@ impl PreCallInterface {
 }
 
 async fn list_users(
-    tcp_stream: &mut TcpStream,
-    current_username: &str,
+    tcp_stream: &mut dyn ControlStream,
+    current_user: &str,
+    json_output: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     TcpCommand::Simple(TcpCommandId::GetUserList)
         .write_to_stream(tcp_stream)
         .await?;
 
-    let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
+    let received_command_option = read_response(tcp_stream).await?;
 
     let received_command = match received_command_option {
         ReceivedTcpCommand::EOF => {
@@ -169,17 +337,46 @@ async fn list_users(
         _ => return Err("Invalid command from server during list_users".into()),
     };
 
-    CliDisplay::print_user_list(&users, current_username);
+    if json_output {
+        CliDisplay::print_json(&user_list_json(&users, current_user));
+    } else {
+        CliDisplay::print_user_list(&users, current_user);
+    }
+
+    Ok(())
+}
+
+fn user_list_json(users: &[String], current_user: &str) -> serde_json::Value {
+    json!({
+        "type": "user_list",
+        "users": users,
+        "current_user": current_user,
+    })
+}
+
+async fn list_rooms(
+    tcp_stream: &mut dyn ControlStream,
+    json_output: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rooms = fetch_room_list(tcp_stream).await?;
+
+    if json_output {
+        CliDisplay::print_json(&json!({ "type": "room_list", "rooms": rooms }));
+    } else {
+        CliDisplay::print_room_list(&rooms);
+    }
 
     Ok(())
 }
 
-async fn list_rooms(tcp_stream: &mut TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn fetch_room_list(
+    tcp_stream: &mut dyn ControlStream,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
     TcpCommand::Simple(TcpCommandId::GetRoomList)
         .write_to_stream(tcp_stream)
         .await?;
 
-    let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
+    let received_command_option = read_response(tcp_stream).await?;
 
     let received_command = match received_command_option {
         ReceivedTcpCommand::EOF => {
@@ -188,25 +385,74 @@ async fn list_rooms(tcp_stream: &mut TcpStream) -> Result<(), Box<dyn Error + Se
         ReceivedTcpCommand::Command(command) => command,
     };
 
-    let rooms = match received_command {
-        TcpCommand::StringList(TcpCommandId::RoomList, payload) => payload,
-        _ => return Err("Invalid command from server during list_rooms".into()),
-    };
+    match received_command {
+        TcpCommand::StringList(TcpCommandId::RoomList, payload) => Ok(payload),
+        _ => Err("Invalid command from server during list_rooms".into()),
+    }
+}
+
+async fn list_members(
+    tcp_stream: &mut dyn ControlStream,
+    room_name: &str,
+    current_user: &str,
+    json_output: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    TcpCommand::String(TcpCommandId::GetRoomMembers, room_name.to_string())
+        .write_to_stream(tcp_stream)
+        .await?;
 
-    CliDisplay::print_room_list(&rooms);
+    let received_command_option = read_response(tcp_stream).await?;
 
-    Ok(())
+    let received_command = match received_command_option {
+        ReceivedTcpCommand::EOF => {
+            return Err("Unexpected EOF from server during list_members".into());
+        }
+        ReceivedTcpCommand::Command(command) => command,
+    };
+
+    match received_command {
+        TcpCommand::StringList(TcpCommandId::RoomMembers, members) => {
+            if json_output {
+                CliDisplay::print_json(&json!({
+                    "type": "room_members",
+                    "room": room_name,
+                    "members": members,
+                }));
+            } else {
+                CliDisplay::print_room_members(room_name, &members, current_user);
+            }
+            Ok(())
+        }
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            print_server_error(&ProtocolError::from_response(&error), json_output);
+            Ok(())
+        }
+        _ => Err("Invalid command from server during list_members".into()),
+    }
 }
 
 async fn create_room(
-    tcp_stream: &mut TcpStream,
+    tcp_stream: &mut dyn ControlStream,
     room_name: &str,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    TcpCommand::String(TcpCommandId::CreateRoom, room_name.to_string())
+    password: Option<&str>,
+    max_users: Option<usize>,
+    json_output: bool,
+) -> Result<Result<(), ProtocolError>, Box<dyn Error + Send + Sync>> {
+    let mut args = room_args(room_name, password);
+    if let Some(max_users) = max_users {
+        args.resize(2, String::new());
+        args.push(max_users.to_string());
+    }
+
+    if let Err(error) = validate_room_name(room_name) {
+        return Ok(Err(error));
+    }
+
+    TcpCommand::StringList(TcpCommandId::CreateRoom, args)
         .write_to_stream(tcp_stream)
         .await?;
 
-    let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
+    let received_command_option = read_response(tcp_stream).await?;
 
     let received_command = match received_command_option {
         ReceivedTcpCommand::EOF => {
@@ -217,26 +463,35 @@ async fn create_room(
 
     match received_command {
         TcpCommand::Simple(TcpCommandId::CreateRoomSuccess) => {
-            println!("Successfully created room '{}'.\n", room_name);
-            Ok(())
+            if json_output {
+                CliDisplay::print_json(&json!({ "type": "room_created", "room": room_name }));
+            } else {
+                println!("Successfully created room '{}'.\n", room_name);
+            }
+            Ok(Ok(()))
         }
-        TcpCommand::String(TcpCommandId::ErrorResponse, error) => {
-            eprintln!("{}", error);
-            Ok(())
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            Ok(Err(ProtocolError::from_response(&error)))
         }
         _ => Err("Invalid command from server during create_room".into()),
     }
 }
 
 async fn delete_room(
-    tcp_stream: &mut TcpStream,
+    tcp_stream: &mut dyn ControlStream,
     room_name: &str,
+    json_output: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Err(error) = validate_room_name(room_name) {
+        print_server_error(&error, json_output);
+        return Ok(());
+    }
+
     TcpCommand::String(TcpCommandId::DeleteRoom, room_name.to_string())
         .write_to_stream(tcp_stream)
         .await?;
 
-    let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
+    let received_command_option = read_response(tcp_stream).await?;
 
     let received_command = match received_command_option {
         ReceivedTcpCommand::EOF => {
@@ -247,26 +502,87 @@ async fn delete_room(
 
     match received_command {
         TcpCommand::Simple(TcpCommandId::DeleteRoomSuccess) => {
-            println!("Successfully deleted room '{}'.\n", room_name);
+            if json_output {
+                CliDisplay::print_json(&json!({ "type": "room_deleted", "room": room_name }));
+            } else {
+                println!("Successfully deleted room '{}'.\n", room_name);
+            }
             Ok(())
         }
-        TcpCommand::String(TcpCommandId::ErrorResponse, error) => {
-            eprintln!("{}", error);
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            print_server_error(&ProtocolError::from_response(&error), json_output);
             Ok(())
         }
         _ => Err("Invalid command from server during delete_room".into()),
     }
 }
 
+async fn rename_room(
+    tcp_stream: &mut dyn ControlStream,
+    room_name: &str,
+    new_room_name: &str,
+    json_output: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Err(error) = validate_room_name(new_room_name) {
+        print_server_error(&error, json_output);
+        return Ok(());
+    }
+
+    TcpCommand::StringList(
+        TcpCommandId::RenameRoom,
+        vec![room_name.to_string(), new_room_name.to_string()],
+    )
+    .write_to_stream(tcp_stream)
+    .await?;
+
+    let received_command_option = read_response(tcp_stream).await?;
+
+    let received_command = match received_command_option {
+        ReceivedTcpCommand::EOF => {
+            return Err("Unexpected EOF from server during rename_room".into());
+        }
+        ReceivedTcpCommand::Command(command) => command,
+    };
+
+    match received_command {
+        TcpCommand::Simple(TcpCommandId::RenameRoomSuccess) => {
+            if json_output {
+                CliDisplay::print_json(&json!({
+                    "type": "room_renamed",
+                    "room": room_name,
+                    "new_name": new_room_name,
+                }));
+            } else {
+                println!(
+                    "Successfully renamed room '{}' to '{}'.\n",
+                    room_name, new_room_name
+                );
+            }
+            Ok(())
+        }
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            print_server_error(&ProtocolError::from_response(&error), json_output);
+            Ok(())
+        }
+        _ => Err("Invalid command from server during rename_room".into()),
+    }
+}
+
 async fn join_room(
-    tcp_stream: &mut TcpStream,
+    tcp_stream: &mut dyn ControlStream,
     room_name: &str,
-) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
-    TcpCommand::String(TcpCommandId::JoinRoom, room_name.to_string())
+    password: Option<&str>,
+    json_output: bool,
+) -> Result<Result<Vec<u8>, ProtocolError>, Box<dyn Error + Send + Sync>> {
+    if let Err(error) = validate_room_name(room_name) {
+        return Ok(Err(error));
+    }
+
+    TcpCommand::StringList(TcpCommandId::JoinRoom, room_args(room_name, password))
         .write_to_stream(tcp_stream)
         .await?;
 
-    let received_command_option = TcpCommand::read_from_stream(tcp_stream).await?;
+    let received_command_option = read_response(tcp_stream).await?;
 
     let received_command = match received_command_option {
         ReceivedTcpCommand::EOF => {
@@ -277,17 +593,212 @@ async fn join_room(
 
     match received_command {
         TcpCommand::Bytes(TcpCommandId::JoinRoomSuccess, full_sid) => {
-            if full_sid.len() != RoomID::default().len() + StreamID::default().len() {
+            if split_call_payload(&full_sid).is_none() {
                 return Err("Unexpected payload length from server during join_room".into());
             }
 
-            println!("Successfully joined room '{}'.", room_name);
-            Ok(Some(full_sid))
+            if json_output {
+                CliDisplay::print_json(&json!({ "type": "room_joined", "room": room_name }));
+            } else {
+                println!("Successfully joined room '{}'.", room_name);
+            }
+            Ok(Ok(full_sid))
         }
-        TcpCommand::String(TcpCommandId::ErrorResponse, error) => {
-            eprintln!("{}", error);
-            Ok(None)
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => {
+            Ok(Err(ProtocolError::from_response(&error)))
         }
         _ => Err("Invalid command from server during join_room".into()),
     }
 }
+
+fn parse_create_room_options<'a>(
+    parts: &[&'a str],
+) -> Option<(&'a str, Option<&'a str>, Option<usize>)> {
+    let (room_name, options) = parts.split_first()?;
+
+    let mut password = None;
+    let mut max_users = None;
+    let mut options = options.iter();
+
+    while let Some(option) = options.next() {
+        if *option == "--max" {
+            max_users = Some(options.next()?.parse().ok()?);
+        } else if password.is_none() {
+            password = Some(*option);
+        } else {
+            return None;
+        }
+    }
+
+    Some((room_name, password, max_users))
+}
+
+/// Mirrors the server's room name checks so obviously bad names are refused
+/// without a round trip. The server still validates every request.
+fn validate_room_name(room_name: &str) -> Result<(), ProtocolError> {
+    validate_name(room_name)
+        .map_err(|error| ProtocolError::rejected(format!("Room name {}", error)))
+}
+
+fn print_server_error(error: &ProtocolError, json_output: bool) {
+    if json_output {
+        CliDisplay::print_json(&json!({ "type": "error", "message": error.to_string() }));
+    } else {
+        eprintln!("{}", error);
+    }
+}
+
+/// In JSON mode the refusal is also printed as an error object, since the caller
+/// only reports the returned error on stderr.
+fn report_join_error(error: ProtocolError, json_output: bool) -> Box<dyn Error + Send + Sync> {
+    if json_output {
+        print_server_error(&error, json_output);
+    }
+
+    Box::new(error)
+}
+
+fn room_args(room_name: &str, password: Option<&str>) -> Vec<String> {
+    let mut args = vec![room_name.to_string()];
+    args.extend(password.map(str::to_string));
+    args
+}
+
+async fn read_response(
+    tcp_stream: &mut dyn ControlStream,
+) -> Result<ReceivedTcpCommand, Box<dyn Error + Send + Sync>> {
+    loop {
+        match TcpCommand::read_from_stream(tcp_stream).await? {
+            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping)) => {
+                TcpCommand::Simple(TcpCommandId::Pong)
+                    .write_to_stream(tcp_stream)
+                    .await?;
+            }
+            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::ServerShuttingDown)) => {
+                return Err("The server is shutting down.".into());
+            }
+            received_command => return Ok(received_command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde_json::Value;
+    use server::wes_sfu::{ServerConfig, WeSFU};
+    use shared::RoomID;
+    use tokio::{
+        io::{AsyncReadExt, duplex},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::client::perform_handshake;
+
+    async fn start_server() -> SocketAddr {
+        let server = WeSFU::bind(
+            "127.0.0.1:0".to_string(),
+            "127.0.0.1:0".to_string(),
+            ServerConfig {
+                stream_budget_bytes_per_second: usize::MAX,
+                ..ServerConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let tcp_addr = server.tcp_local_addr().unwrap();
+        tokio::spawn(server.listen());
+
+        tcp_addr
+    }
+
+    async fn connect(tcp_addr: SocketAddr, username: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+        perform_handshake(&mut stream, username, false)
+            .await
+            .unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn join_flag_lands_in_a_call_creating_the_room_if_asked() {
+        let tcp_addr = start_server().await;
+
+        let mut alice = connect(tcp_addr, "alice").await;
+        let alice_sid = PreCallInterface::join_directly(&mut alice, "lobby", None, true, false)
+            .await
+            .expect("--join --create should create the room and enter it");
+
+        // The room exists now, so --create joins it instead of failing
+        let mut bob = connect(tcp_addr, "bob").await;
+        let bob_sid = PreCallInterface::join_directly(&mut bob, "lobby", None, true, false)
+            .await
+            .expect("--join --create should join the existing room");
+
+        let rid_len = RoomID::default().len();
+        assert_eq!(alice_sid[..rid_len], bob_sid[..rid_len]);
+        assert_ne!(alice_sid[rid_len..], bob_sid[rid_len..]);
+    }
+
+    #[tokio::test]
+    async fn join_flag_without_create_fails_for_a_missing_room() {
+        let tcp_addr = start_server().await;
+
+        let mut alice = connect(tcp_addr, "alice").await;
+        let error = PreCallInterface::join_directly(&mut alice, "lobby", None, false, false)
+            .await
+            .expect_err("Joining a missing room should fail");
+
+        assert_eq!(
+            error.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::RoomNotFound("lobby".to_string()))
+        );
+    }
+
+    #[test]
+    fn user_list_prints_as_one_line_of_valid_json() {
+        let users = [
+            "alice".to_string(),
+            "bob (Bobby \"B\")".to_string(),
+            "zoë\\".to_string(),
+        ];
+
+        let line = user_list_json(&users, "alice").to_string();
+        assert!(!line.contains('\n'));
+
+        let parsed: Value = serde_json::from_str(&line).expect("Output should be valid JSON");
+        assert_eq!(parsed["type"], "user_list");
+        assert_eq!(parsed["current_user"], "alice");
+        assert_eq!(parsed["users"], json!(users));
+    }
+
+    #[tokio::test]
+    async fn overlong_room_name_is_refused_before_anything_is_sent() {
+        let (mut client_end, mut server_end) = duplex(1024);
+        let room_name = "a".repeat(20);
+
+        for result in [
+            create_room(&mut client_end, &room_name, None, None, false)
+                .await
+                .map(|result| result.err()),
+            join_room(&mut client_end, &room_name, None, false)
+                .await
+                .map(|result| result.err()),
+        ] {
+            match result.unwrap() {
+                Some(ProtocolError::Rejected(message)) => {
+                    assert!(message.starts_with("Room name must be"), "{}", message);
+                }
+                error => panic!("Expected a local rejection, got {:?}", error),
+            }
+        }
+
+        drop(client_end);
+        let mut sent = Vec::new();
+        server_end.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use core::error::Error;
+use std::path::{Path, PathBuf};
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::frame::{Frame, composite_frames};
+
+/// Writes each batch of frames from `frames_rx` to `output_dir` as a numbered PNG until
+/// cancelled, then flushes what is still queued. Encoding and file writes run on the
+/// blocking pool so they never stall the call's other tasks.
+pub async fn record_loop(
+    output_dir: String,
+    mut frames_rx: mpsc::Receiver<Vec<Frame>>,
+    cancel_token: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let output_dir = PathBuf::from(output_dir);
+    tokio::fs::create_dir_all(&output_dir).await?;
+
+    let mut frame_number = 0;
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            frames = frames_rx.recv() => {
+                match frames {
+                    Some(frames) => write_frame(&output_dir, frames, &mut frame_number).await?,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    while let Ok(frames) = frames_rx.try_recv() {
+        write_frame(&output_dir, frames, &mut frame_number).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_frame(
+    output_dir: &Path,
+    frames: Vec<Frame>,
+    frame_number: &mut u32,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let path = output_dir.join(format!("frame-{:06}.png", frame_number));
+
+    let written =
+        tokio::task::spawn_blocking(move || -> Result<bool, Box<dyn Error + Send + Sync>> {
+            match composite_frames(&frames) {
+                Some(image) => {
+                    image.save(path)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+        .await??;
+
+    if written {
+        *frame_number += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_one_png_per_composited_frame() {
+        let output_dir = std::env::temp_dir().join(format!("record-test-{}", std::process::id()));
+        let (frames_tx, frames_rx) = mpsc::channel(16);
+        let recording = tokio::spawn(record_loop(
+            output_dir.to_string_lossy().into_owned(),
+            frames_rx,
+            CancellationToken::new(),
+        ));
+
+        for i in 0..10 {
+            let frame = Frame {
+                width: 4,
+                height: 3,
+                data: Arc::new(vec![i * 20; 4 * 3 * 3]),
+            };
+            frames_tx.send(vec![frame]).await.unwrap();
+        }
+        drop(frames_tx);
+        recording.await.unwrap().unwrap();
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        let dimensions: Vec<(u32, u32)> = files
+            .iter()
+            .map(|path| image::image_dimensions(path).unwrap())
+            .collect();
+        let _ = std::fs::remove_dir_all(&output_dir);
+
+        assert_eq!(files.len(), 10);
+        assert_eq!(files[9].file_name().unwrap(), "frame-000009.png");
+        assert!(dimensions.iter().all(|&size| size == (4, 3)));
+    }
+
+    #[tokio::test]
+    async fn write_failures_end_the_recording_with_an_error() {
+        // A file where the output directory should be makes every write fail
+        let output_path = std::env::temp_dir().join(format!("record-file-{}", std::process::id()));
+        std::fs::write(&output_path, b"").unwrap();
+        let (frames_tx, frames_rx) = mpsc::channel(1);
+
+        let recording = tokio::spawn(record_loop(
+            output_path.to_string_lossy().into_owned(),
+            frames_rx,
+            CancellationToken::new(),
+        ));
+        let _ = frames_tx
+            .send(vec![Frame {
+                width: 1,
+                height: 1,
+                data: Arc::new(vec![0; 3]),
+            }])
+            .await;
+        let result = recording.await.unwrap();
+        let _ = std::fs::remove_file(&output_path);
+
+        assert!(result.is_err());
+    }
+}
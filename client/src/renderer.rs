@@ -3,21 +3,145 @@ use crossterm::{
     cursor::{Hide, MoveTo, Show},
     style::Print,
 };
-use std::io::{BufWriter, Write, stdout};
+use std::{
+    io::{BufWriter, Stdout, Write, stdout},
+    time::{Duration, Instant},
+};
+
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(150);
 
-pub struct Renderer {
+/// Draws frames on the terminal, rewriting only the lines that changed. Writes to stdout
+/// unless built with [`Renderer::with_writer`].
+pub struct Renderer<W: Write = BufWriter<Stdout>> {
     last_frame: Option<Vec<String>>,
     terminal_size: Option<(u16, u16)>,
-    writer: BufWriter<std::io::Stdout>,
+    writer: W,
     cursor_hidden: bool,
 }
 
+/// Writes each composited frame to any writer in full, without cursor movement or diffing,
+/// so output can go to a file, a pipe, or a network bridge instead of the terminal.
+///
+/// ```
+/// use client::renderer::WriterRenderer;
+///
+/// let mut renderer = WriterRenderer::new(Vec::new());
+/// renderer.render_frame("ab\ncd").unwrap();
+/// renderer.render_frame("ef\ngh\n").unwrap();
+///
+/// assert_eq!(renderer.into_inner(), b"ab\ncd\nef\ngh\n");
+/// ```
+pub struct WriterRenderer<W: Write> {
+    writer: W,
+}
+
+/// Where a call's render loop sends each composited frame.
+pub trait FrameSink {
+    fn draw(
+        &mut self,
+        content: &str,
+        width: u16,
+        height: u16,
+        color_enabled: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+pub struct ResizeDebouncer {
+    size: (u16, u16),
+    pending: Option<((u16, u16), Instant)>,
+}
+
+impl ResizeDebouncer {
+    pub fn new(size: (u16, u16)) -> Self {
+        Self {
+            size,
+            pending: None,
+        }
+    }
+
+    pub fn resize(&mut self, size: (u16, u16)) {
+        if size != self.size || self.pending.is_some() {
+            self.pending = Some((size, Instant::now()));
+        }
+    }
+
+    pub fn is_resizing(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// When the pending resize, if any, will be adopted by `size`.
+    pub fn settles_at(&self) -> Option<Instant> {
+        self.pending
+            .map(|(_, resized_at)| resized_at + RESIZE_DEBOUNCE)
+    }
+
+    /// Returns the last settled size, adopting a pending one once no resize has
+    /// arrived for `RESIZE_DEBOUNCE`.
+    pub fn size(&mut self) -> (u16, u16) {
+        if let Some((size, resized_at)) = self.pending
+            && resized_at.elapsed() >= RESIZE_DEBOUNCE
+        {
+            self.size = size;
+            self.pending = None;
+        }
+
+        self.size
+    }
+}
+
+impl<W: Write> WriterRenderer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Writes `content` followed by a newline if it doesn't already end in one, then flushes.
+    pub fn render_frame(
+        &mut self,
+        content: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.writer.write_all(content.as_bytes())?;
+        if !content.ends_with('\n') {
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> FrameSink for WriterRenderer<W> {
+    fn draw(
+        &mut self,
+        content: &str,
+        _width: u16,
+        _height: u16,
+        _color_enabled: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.render_frame(content)
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Renderer {
     pub fn new() -> Self {
+        Self::with_writer(BufWriter::with_capacity(32768, stdout()))
+    }
+}
+
+impl<W: Write> Renderer<W> {
+    pub fn with_writer(writer: W) -> Self {
         Self {
             last_frame: None,
             terminal_size: None,
-            writer: BufWriter::with_capacity(32768, stdout()),
+            writer,
             cursor_hidden: false,
         }
     }
@@ -84,18 +208,18 @@ impl Renderer {
                 .queue(MoveTo(0, line_num as u16))?
                 .queue(Print(line))?;
 
-            if let Some(old_line) = last_lines.get(line_num) {
-                if line.len() < old_line.len() {
-                    self.clear_to_end_of_line(line.len())?;
-                }
+            if let Some(old_line) = last_lines.get(line_num)
+                && line.len() < old_line.len()
+            {
+                self.clear_to_end_of_line(line.len())?;
             }
         }
 
         if new_lines.len() > last_lines.len() {
-            for line_num in last_lines.len()..new_lines.len() {
+            for (line_num, line) in new_lines.iter().enumerate().skip(last_lines.len()) {
                 self.writer
                     .queue(MoveTo(0, line_num as u16))?
-                    .queue(Print(&new_lines[line_num]))?;
+                    .queue(Print(line))?;
             }
         }
 
@@ -148,13 +272,13 @@ impl Renderer {
             self.clear_to_end_of_line(line.len())?;
         }
 
-        if let Some(last_lines) = &self.last_frame {
-            if last_lines.len() > lines.len() {
-                for line_num in lines.len()..last_lines.len() {
-                    self.writer
-                        .queue(MoveTo(0, line_num as u16))?
-                        .queue(Print(" ".repeat(last_lines[line_num].len())))?;
-                }
+        if let Some(last_lines) = &self.last_frame
+            && last_lines.len() > lines.len()
+        {
+            for (line_num, line) in last_lines.iter().enumerate().skip(lines.len()) {
+                self.writer
+                    .queue(MoveTo(0, line_num as u16))?
+                    .queue(Print(" ".repeat(line.len())))?;
             }
         }
 
@@ -165,11 +289,11 @@ impl Renderer {
         &mut self,
         pos: usize,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if let Some((width, _)) = self.terminal_size {
-            if pos < width as usize {
-                self.writer
-                    .queue(crossterm::style::Print(" ".repeat(width as usize - pos)))?;
-            }
+        if let Some((width, _)) = self.terminal_size
+            && pos < width as usize
+        {
+            self.writer
+                .queue(crossterm::style::Print(" ".repeat(width as usize - pos)))?;
         }
         Ok(())
     }
@@ -198,3 +322,68 @@ impl Renderer {
         Ok(())
     }
 }
+
+impl<W: Write> FrameSink for Renderer<W> {
+    fn draw(
+        &mut self,
+        content: &str,
+        width: u16,
+        height: u16,
+        color_enabled: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.update_terminal(content, width, height, color_enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn resize_burst_settles_once_on_the_last_size() {
+        let mut debouncer = ResizeDebouncer::new((80, 24));
+        let mut settled_sizes = Vec::new();
+        let mut last_size = debouncer.size();
+
+        let mut poll = |debouncer: &mut ResizeDebouncer| {
+            let size = debouncer.size();
+            if size != last_size {
+                settled_sizes.push(size);
+                last_size = size;
+            }
+        };
+
+        // Dragging a window edge: a new size every 10ms, well inside the debounce
+        for width in 81..=100 {
+            debouncer.resize((width, 24));
+            poll(&mut debouncer);
+            sleep(Duration::from_millis(10));
+        }
+        assert!(debouncer.is_resizing());
+
+        sleep(RESIZE_DEBOUNCE + Duration::from_millis(50));
+        poll(&mut debouncer);
+        poll(&mut debouncer);
+
+        assert_eq!(settled_sizes, [(100, 24)]);
+        assert!(!debouncer.is_resizing());
+    }
+
+    #[test]
+    fn writer_renderer_writes_each_frame_as_rows_of_a_grid() {
+        let frames = ["@@@\n. .", "#.#\n.#.\n"];
+        let mut renderer = WriterRenderer::new(Vec::new());
+        for frame in frames {
+            renderer.render_frame(frame).unwrap();
+        }
+
+        let output = String::from_utf8(renderer.into_inner()).unwrap();
+        assert!(output.ends_with('\n'));
+
+        let rows: Vec<&str> = output.lines().collect();
+        assert_eq!(rows, ["@@@", ". .", "#.#", ".#."]);
+        assert!(rows.iter().all(|row| row.chars().count() == 3));
+    }
+}
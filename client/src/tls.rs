@@ -0,0 +1,47 @@
+use core::error::Error;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+};
+
+pub async fn connect_tls(
+    tcp_stream: TcpStream,
+    server_addr: &str,
+    ca_path: Option<&str>,
+) -> Result<TlsStream<TcpStream>, Box<dyn Error + Send + Sync>> {
+    let mut root_store = RootCertStore::empty();
+
+    match ca_path {
+        Some(ca_path) => {
+            for cert in CertificateDer::pem_file_iter(ca_path)
+                .map_err(|e| format!("Failed to read CA certificate '{}': {}", ca_path, e))?
+            {
+                let cert =
+                    cert.map_err(|e| format!("Invalid CA certificate '{}': {}", ca_path, e))?;
+                root_store.add(cert)?;
+            }
+        }
+        None => root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    let host = server_addr.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| format!("Invalid server name for TLS: {}", server_addr))?;
+
+    let tls_stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp_stream)
+        .await?;
+
+    Ok(tls_stream)
+}
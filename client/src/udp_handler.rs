@@ -1,32 +1,80 @@
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, HashMap, VecDeque},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Duration,
 };
 
 use core::error::Error;
-use shared::StreamID;
+use shared::{RoomID, StreamID};
 use tokio::{
     net::UdpSocket,
-    sync::{Mutex, watch},
-    time::Instant,
+    sync::{Mutex, mpsc, watch},
+    time::{Instant, interval, timeout_at},
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::frame::Frame;
+use crate::{
+    call_stats::CallStats,
+    frame::{Frame, FrameFormat, RemoteStream},
+    media_crypto::MediaCipher,
+};
 
-const CHUNK_SIZE: usize = 1350;
-const CHUNK_TIMEOUT: Duration = Duration::from_millis(50);
+pub const DEFAULT_CHUNK_SIZE: usize = 1350;
+pub const MAX_DATAGRAM_SIZE: usize = 9216;
+/// Frame type, sequence, chunk ID and flags, following the stream ID of every media packet.
+pub(crate) const PACKET_HEADER_LEN: usize = 10;
+/// Link MTUs tried at call start, from Ethernet down to the IPv6 minimum. Larger MTUs
+/// are only used when given with `--mtu`, since the probe only covers the path to the
+/// server and not the paths from it to other participants.
+const MTU_PROBE_SIZES: [usize; 5] = [1500, 1492, 1420, 1400, 1280];
+const MTU_PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+const IPV4_UDP_HEADER_LEN: usize = 28;
+const IPV6_UDP_HEADER_LEN: usize = 48;
+// Full SID and packet header, plus the length prefix parity chunks add
+const CHUNK_OVERHEAD: usize = 8 + 10 + 3;
+const DEFAULT_CHUNK_TIMEOUT: Duration = Duration::from_millis(50);
+const MIN_CHUNK_TIMEOUT: Duration = Duration::from_millis(20);
+const MAX_CHUNK_TIMEOUT: Duration = Duration::from_millis(500);
+const CHUNK_TIMEOUT_RTT_MULTIPLIER: u32 = 3;
+const RTT_PING_INTERVAL: Duration = Duration::from_secs(1);
+// Echoed like MTU probes, told apart from them by a sequence no probe index reaches
+const RTT_PING_SEQUENCE: u32 = u32::MAX;
 const DELTA_THRESHOLD: f32 = 0.3;
 const MIN_BLOCK_SIZE: usize = 64;
+const DELTA_HEADER_LEN: usize = 8;
 const SEQUENCE_WRAP: u32 = 1000000;
+const SEQUENCE_RESET_TIMEOUT: Duration = Duration::from_secs(1);
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const STREAM_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 const BUFFER_POOL_SIZE: usize = 10;
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+const DECODE_ERROR_THRESHOLD: u32 = 3;
+const MAX_RETRANSMIT_REQUESTS: u32 = 2;
+const MAX_RETRANSMIT_CHUNKS: usize = 64;
+const FEC_GROUP_SIZE: u32 = 4;
+const LAST_CHUNK_FLAG: u8 = 1;
+const PARITY_FLAG: u8 = 2;
+const MIN_QUALITY: f32 = 20.0;
+const QUALITY_STEP: f32 = 10.0;
+const MAX_DELTA_THRESHOLD: f32 = 0.6;
+const DELTA_THRESHOLD_STEP: f32 = 0.1;
+const HIGH_LOSS_RATIO: f32 = 0.05;
+const LOW_LOSS_RATIO: f32 = 0.01;
+const QUALITY_WINDOW: Duration = Duration::from_secs(2);
+const UPLOAD_WINDOW: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug, PartialEq)]
-enum FrameType {
+pub(crate) enum FrameType {
     Full = 0,
     Delta = 1,
     Heartbeat = 2,
+    KeyframeRequest = 3,
+    RetransmitRequest = 4,
+    MtuProbe = 5,
 }
 
 #[derive(Clone)]
@@ -39,34 +87,75 @@ struct FragmentBuffer {
     chunks: BTreeMap<u32, Vec<u8>>,
     last_update: Instant,
     frame_type: FrameType,
-    expected_chunks: u32,
+    expected_chunks: Option<u32>,
     sequence: u32,
+    retransmit_requests: u32,
+    parity_chunks: BTreeMap<u32, Vec<u8>>,
 }
 
 struct FrameCache {
-    last_frame: Option<Vec<u8>>,
     reconstructed_frame: Option<Vec<u8>>,
     last_sequence: u32,
     corrupted: bool,
+    last_keyframe_request: Option<Instant>,
+    last_assembled_sequence: Option<u32>,
+    last_assembled_at: Instant,
+    last_heard: Instant,
+    decode_failures: u32,
+    decoding_sequence: Option<u32>,
+    decode_pending: bool,
+}
+
+enum ReceivedFrame {
+    Decoded(Frame),
+    DecodeError,
+}
+
+/// A copy of a stream's reconstructed frame, handed to a blocking task for WebP decoding.
+struct DecodeJob {
+    sid: StreamID,
+    sequence: u32,
+    data: Vec<u8>,
+    output: Vec<u8>,
+}
+
+struct DecodeResult {
+    sid: StreamID,
+    sequence: u32,
+    data: Vec<u8>,
+    frame: Option<Frame>,
 }
 
 struct BufferPool {
     buffers: VecDeque<Vec<u8>>,
 }
 
+struct QualityController {
+    max_quality: f32,
+    quality: f32,
+    delta_threshold: f32,
+    sent_chunks: u32,
+    lost_chunks: u32,
+    window_start: Instant,
+}
+
+/// Keeps upload under the user's `--max-upload-kbps` ceiling over a sliding window.
+/// Unlike `QualityController` it doesn't react to loss, it only refuses frames.
+struct UploadBudget {
+    bytes_per_window: usize,
+    sent: VecDeque<(Instant, usize)>,
+    sent_bytes: usize,
+}
+
 impl BufferPool {
     fn new() -> Self {
-        let mut buffers = VecDeque::with_capacity(BUFFER_POOL_SIZE);
-        for _ in 0..BUFFER_POOL_SIZE {
-            buffers.push_back(Vec::with_capacity(1920 * 1080 * 3));
+        Self {
+            buffers: VecDeque::with_capacity(BUFFER_POOL_SIZE),
         }
-        Self { buffers }
     }
 
     fn get_buffer(&mut self) -> Vec<u8> {
-        self.buffers
-            .pop_front()
-            .unwrap_or_else(|| Vec::with_capacity(1920 * 1080 * 3))
+        self.buffers.pop_front().unwrap_or_default()
     }
 
     fn return_buffer(&mut self, mut buffer: Vec<u8>) {
@@ -75,38 +164,290 @@ impl BufferPool {
             self.buffers.push_back(buffer);
         }
     }
+
+    /// Takes back a replaced frame's pixels, unless the renderer or recorder still holds it.
+    fn return_frame(&mut self, frame: Frame) {
+        if let Ok(buffer) = Arc::try_unwrap(frame.data) {
+            self.return_buffer(buffer);
+        }
+    }
+}
+
+impl FragmentBuffer {
+    fn new(frame_type: FrameType, sequence: u32) -> Self {
+        Self {
+            chunks: BTreeMap::new(),
+            last_update: Instant::now(),
+            frame_type,
+            expected_chunks: None,
+            sequence,
+            retransmit_requests: 0,
+            parity_chunks: BTreeMap::new(),
+        }
+    }
+
+    fn reset(&mut self, frame_type: FrameType, sequence: u32) {
+        self.chunks.clear();
+        self.frame_type = frame_type;
+        self.expected_chunks = None;
+        self.sequence = sequence;
+        self.retransmit_requests = 0;
+        self.parity_chunks.clear();
+    }
+
+    fn set_expected_chunks(&mut self, expected_chunks: u32) {
+        self.chunks.split_off(&expected_chunks);
+        self.expected_chunks = Some(expected_chunks);
+    }
+
+    fn insert_chunk(&mut self, chunk_id: u32, chunk_data: &[u8], is_last: bool) {
+        if is_last {
+            self.set_expected_chunks(chunk_id.saturating_add(1));
+        }
+
+        if self
+            .expected_chunks
+            .is_some_and(|expected_chunks| chunk_id >= expected_chunks)
+        {
+            return;
+        }
+
+        self.chunks
+            .entry(chunk_id)
+            .or_insert_with(|| chunk_data.to_vec());
+        self.last_update = Instant::now();
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.expected_chunks {
+            Some(expected_chunks) => {
+                self.chunks.len() == expected_chunks as usize
+                    && self
+                        .chunks
+                        .last_key_value()
+                        .is_some_and(|(chunk_id, _)| chunk_id + 1 == expected_chunks)
+            }
+            None => false,
+        }
+    }
+
+    fn insert_parity(&mut self, group_id: u32, parity_data: &[u8], is_last: bool) {
+        if parity_data.len() < 3 || parity_data[0] as u32 > FEC_GROUP_SIZE {
+            return;
+        }
+
+        if let Some(first_chunk_id) = group_id.checked_mul(FEC_GROUP_SIZE) {
+            if is_last {
+                self.set_expected_chunks(first_chunk_id.saturating_add(parity_data[0] as u32));
+            }
+
+            self.parity_chunks
+                .entry(group_id)
+                .or_insert_with(|| parity_data.to_vec());
+            self.last_update = Instant::now();
+        }
+    }
+
+    fn recover_chunks(&mut self) {
+        let mut recovered_chunks = Vec::new();
+
+        for (group_id, parity_data) in &self.parity_chunks {
+            let first_chunk_id = group_id * FEC_GROUP_SIZE;
+            let group = first_chunk_id..first_chunk_id.saturating_add(parity_data[0] as u32);
+
+            let mut missing = group
+                .clone()
+                .filter(|chunk_id| !self.chunks.contains_key(chunk_id));
+            let missing_chunk_id = match (missing.next(), missing.next()) {
+                (Some(chunk_id), None) => chunk_id,
+                _ => continue,
+            };
+
+            let mut chunk_len = u16::from_be_bytes([parity_data[1], parity_data[2]]);
+            let mut chunk_data = parity_data[3..].to_vec();
+            for chunk in group.filter_map(|chunk_id| self.chunks.get(&chunk_id)) {
+                chunk_len ^= chunk.len() as u16;
+                for (byte, chunk_byte) in chunk_data.iter_mut().zip(chunk) {
+                    *byte ^= chunk_byte;
+                }
+            }
+            chunk_data.truncate(chunk_len as usize);
+
+            recovered_chunks.push((missing_chunk_id, chunk_data));
+        }
+
+        for (chunk_id, chunk_data) in recovered_chunks {
+            if self
+                .expected_chunks
+                .is_none_or(|expected_chunks| chunk_id < expected_chunks)
+            {
+                self.chunks.insert(chunk_id, chunk_data);
+            }
+        }
+    }
+
+    fn missing_chunks(&self) -> Vec<u32> {
+        match self.expected_chunks {
+            Some(expected_chunks) => (0..expected_chunks)
+                .filter(|chunk_id| !self.chunks.contains_key(chunk_id))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
 }
 
 impl FrameCache {
     fn new() -> Self {
         Self {
-            last_frame: None,
             reconstructed_frame: None,
             last_sequence: 0,
             corrupted: false,
+            last_keyframe_request: None,
+            last_assembled_sequence: None,
+            last_assembled_at: Instant::now(),
+            last_heard: Instant::now(),
+            decode_failures: 0,
+            decoding_sequence: None,
+            decode_pending: false,
         }
     }
 
+    fn is_stale(&self, sequence: u32) -> bool {
+        match self.last_assembled_sequence {
+            Some(last_sequence) => {
+                !sequence_is_newer(sequence, last_sequence)
+                    && self.last_assembled_at.elapsed() < SEQUENCE_RESET_TIMEOUT
+            }
+            None => false,
+        }
+    }
+
+    fn mark_assembled(&mut self, sequence: u32) {
+        self.last_assembled_sequence = Some(sequence);
+        self.last_assembled_at = Instant::now();
+    }
+
     fn mark_corrupted(&mut self) {
         self.corrupted = true;
     }
 
-    fn reset(&mut self, frame: Vec<u8>, sequence: u32) {
-        self.last_frame = Some(frame.clone());
-        self.reconstructed_frame = Some(frame);
+    fn should_request_keyframe(&mut self) -> bool {
+        let due = self
+            .last_keyframe_request
+            .is_none_or(|last_request| last_request.elapsed() >= KEYFRAME_REQUEST_INTERVAL);
+
+        if due {
+            self.last_keyframe_request = Some(Instant::now());
+        }
+
+        due
+    }
+
+    fn reset(&mut self, frame: Vec<u8>, sequence: u32) -> Option<Vec<u8>> {
         self.last_sequence = sequence;
         self.corrupted = false;
+        self.last_keyframe_request = None;
+        self.reconstructed_frame.replace(frame)
+    }
+}
+
+impl UploadBudget {
+    fn new(max_upload_kbps: u32) -> Self {
+        Self {
+            bytes_per_window: (max_upload_kbps as f64 * 1000.0 / 8.0 * UPLOAD_WINDOW.as_secs_f64())
+                as usize,
+            sent: VecDeque::new(),
+            sent_bytes: 0,
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.sent.push_back((Instant::now(), bytes));
+        self.sent_bytes += bytes;
+    }
+
+    /// Deltas are dropped once they would go over budget. Receivers can't recover
+    /// without full frames, so those are only held back while the budget is used up.
+    fn allows(&mut self, frame_type: &FrameType, bytes: usize) -> bool {
+        while let Some((sent_at, sent_bytes)) = self.sent.front() {
+            if sent_at.elapsed() < UPLOAD_WINDOW {
+                break;
+            }
+            self.sent_bytes -= sent_bytes;
+            self.sent.pop_front();
+        }
+
+        match frame_type {
+            FrameType::Full => self.sent_bytes < self.bytes_per_window,
+            _ => self.sent_bytes + bytes <= self.bytes_per_window,
+        }
     }
 }
 
-fn create_delta_optimized(old_frame: &[u8], new_frame: &[u8]) -> Option<Vec<DeltaChunk>> {
+impl QualityController {
+    fn new(max_quality: f32) -> Self {
+        Self {
+            max_quality,
+            quality: max_quality,
+            delta_threshold: DELTA_THRESHOLD,
+            sent_chunks: 0,
+            lost_chunks: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    fn record_sent(&mut self, chunks: u32) {
+        self.sent_chunks += chunks;
+    }
+
+    fn record_lost(&mut self, chunks: u32) {
+        self.lost_chunks += chunks;
+    }
+
+    fn update(&mut self) -> bool {
+        if self.window_start.elapsed() < QUALITY_WINDOW || self.sent_chunks == 0 {
+            return false;
+        }
+
+        let loss_ratio = self.lost_chunks as f32 / self.sent_chunks as f32;
+        let previous = (self.quality, self.delta_threshold);
+
+        if loss_ratio > HIGH_LOSS_RATIO {
+            self.quality = (self.quality - QUALITY_STEP).max(MIN_QUALITY.min(self.max_quality));
+            self.delta_threshold =
+                (self.delta_threshold + DELTA_THRESHOLD_STEP).min(MAX_DELTA_THRESHOLD);
+        } else if loss_ratio < LOW_LOSS_RATIO {
+            self.quality = (self.quality + QUALITY_STEP).min(self.max_quality);
+            self.delta_threshold =
+                (self.delta_threshold - DELTA_THRESHOLD_STEP).max(DELTA_THRESHOLD);
+        }
+
+        self.sent_chunks = 0;
+        self.lost_chunks = 0;
+        self.window_start = Instant::now();
+
+        previous != (self.quality, self.delta_threshold)
+    }
+}
+
+fn sequence_is_newer(sequence: u32, other: u32) -> bool {
+    let distance =
+        (sequence % SEQUENCE_WRAP + SEQUENCE_WRAP - other % SEQUENCE_WRAP) % SEQUENCE_WRAP;
+    distance != 0 && distance < SEQUENCE_WRAP / 2
+}
+
+fn create_delta_optimized(
+    old_frame: &[u8],
+    new_frame: &[u8],
+    delta_threshold: f32,
+) -> Option<Vec<DeltaChunk>> {
     if old_frame.len() != new_frame.len() {
         return None;
     }
 
-    let mut deltas = Vec::new();
+    let mut deltas: Vec<DeltaChunk> = Vec::new();
     let mut total_delta_size = 0;
-    let threshold_size = (new_frame.len() as f32 * DELTA_THRESHOLD) as usize;
+    let threshold_size = (new_frame.len() as f32 * delta_threshold) as usize;
 
     let mut i = 0;
     let len = new_frame.len();
@@ -143,8 +484,22 @@ fn create_delta_optimized(old_frame: &[u8], new_frame: &[u8]) -> Option<Vec<Delt
             }
         }
 
+        if let Some(last_delta) = deltas.last_mut() {
+            let last_end = last_delta.offset as usize + last_delta.data.len();
+            if start - last_end < DELTA_HEADER_LEN {
+                total_delta_size += i - last_end;
+
+                if total_delta_size >= threshold_size {
+                    return None;
+                }
+
+                last_delta.data.extend_from_slice(&new_frame[last_end..i]);
+                continue;
+            }
+        }
+
         let chunk_size = i - start;
-        total_delta_size += chunk_size + 8;
+        total_delta_size += chunk_size + DELTA_HEADER_LEN;
 
         if total_delta_size >= threshold_size {
             return None;
@@ -159,6 +514,43 @@ fn create_delta_optimized(old_frame: &[u8], new_frame: &[u8]) -> Option<Vec<Delt
     Some(deltas)
 }
 
+fn create_parity_chunk(group: &[&[u8]]) -> Vec<u8> {
+    let max_len = group.iter().map(|chunk| chunk.len()).max().unwrap_or(0);
+    let mut parity_data = vec![0u8; 3 + max_len];
+    let mut len_parity = 0u16;
+
+    for chunk in group {
+        len_parity ^= chunk.len() as u16;
+        for (byte, chunk_byte) in parity_data[3..].iter_mut().zip(chunk.iter()) {
+            *byte ^= chunk_byte;
+        }
+    }
+
+    parity_data[0] = group.len() as u8;
+    parity_data[1..3].copy_from_slice(&len_parity.to_be_bytes());
+    parity_data
+}
+
+/// Appends a chunk's data after the packet header already in `packet`, sealed with the
+/// room's media key when the call is encrypted. The header from `header_start` on, which
+/// follows the full stream ID, is what receivers see after forwarding, so it's what gets
+/// authenticated.
+fn push_chunk_data(
+    packet: &mut Vec<u8>,
+    data: &[u8],
+    own_sid: &StreamID,
+    header_start: usize,
+    media_cipher: Option<&mut MediaCipher>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match media_cipher {
+        Some(cipher) => cipher.seal_into(own_sid, packet, header_start, data),
+        None => {
+            packet.extend_from_slice(data);
+            Ok(())
+        }
+    }
+}
+
 fn serialize_deltas_optimized(deltas: &[DeltaChunk]) -> Vec<u8> {
     let capacity = 4 + deltas.iter().map(|d| 8 + d.data.len()).sum::<usize>();
     let mut result = Vec::with_capacity(capacity);
@@ -236,129 +628,802 @@ fn apply_delta_safe(
     Ok(())
 }
 
+async fn send_keyframe_request(udp_stream: &UdpSocket, full_sid: &[u8], target_sid: &StreamID) {
+    let mut packet = Vec::with_capacity(full_sid.len() + 10 + target_sid.len());
+    packet.extend_from_slice(full_sid);
+    packet.push(FrameType::KeyframeRequest as u8);
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.push(1);
+    packet.extend_from_slice(target_sid);
+
+    let _ = udp_stream.send(&packet).await;
+}
+
+async fn send_rtt_ping(udp_stream: &UdpSocket, full_sid: &[u8], sent_micros: u64) {
+    let mut packet = Vec::with_capacity(full_sid.len() + 18);
+    packet.extend_from_slice(full_sid);
+    packet.push(FrameType::MtuProbe as u8);
+    packet.extend_from_slice(&RTT_PING_SEQUENCE.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(&sent_micros.to_be_bytes());
+
+    let _ = udp_stream.send(&packet).await;
+}
+
+async fn send_heartbeat(udp_stream: &UdpSocket, full_sid: &[u8], sequence: u32) -> usize {
+    let mut packet = Vec::with_capacity(full_sid.len() + 10);
+    packet.extend_from_slice(full_sid);
+    packet.push(FrameType::Heartbeat as u8);
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.push(1);
+
+    let _ = udp_stream.send(&packet).await;
+    packet.len()
+}
+
+async fn send_retransmit_request(
+    udp_stream: &UdpSocket,
+    full_sid: &[u8],
+    target_sid: &StreamID,
+    sequence: u32,
+    missing_chunks: &[u32],
+) {
+    let mut packet =
+        Vec::with_capacity(full_sid.len() + 10 + target_sid.len() + missing_chunks.len() * 4);
+    packet.extend_from_slice(full_sid);
+    packet.push(FrameType::RetransmitRequest as u8);
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.push(1);
+    packet.extend_from_slice(target_sid);
+    for chunk_id in missing_chunks {
+        packet.extend_from_slice(&chunk_id.to_be_bytes());
+    }
+
+    let _ = udp_stream.send(&packet).await;
+}
+
+pub(crate) struct ParsedPacket<'a> {
+    pub(crate) sid: StreamID,
+    pub(crate) frame_type: FrameType,
+    pub(crate) sequence: u32,
+    pub(crate) chunk_id: u32,
+    pub(crate) flags: u8,
+    pub(crate) data: &'a [u8],
+}
+
+enum OutgoingRequest {
+    Retransmit {
+        sid: StreamID,
+        sequence: u32,
+        missing_chunks: Vec<u32>,
+    },
+    Keyframe(StreamID),
+}
+
+struct ReceiveState {
+    fragment_buffers: HashMap<StreamID, FragmentBuffer>,
+    frame_caches: HashMap<StreamID, FrameCache>,
+    buffer_pool: BufferPool,
+    decoded_frame_pool: BufferPool,
+    outgoing_requests: Vec<OutgoingRequest>,
+    rtt_estimate: Option<Duration>,
+}
+
+impl ReceiveState {
+    fn new() -> Self {
+        Self {
+            fragment_buffers: HashMap::new(),
+            frame_caches: HashMap::new(),
+            buffer_pool: BufferPool::new(),
+            decoded_frame_pool: BufferPool::new(),
+            outgoing_requests: Vec::new(),
+            rtt_estimate: None,
+        }
+    }
+
+    /// Smooths RTT samples the way TCP does, weighting each new sample by an eighth.
+    fn record_rtt(&mut self, sample: Duration) {
+        self.rtt_estimate = Some(match self.rtt_estimate {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    /// How long a partial frame may go without new chunks before it counts as lost. It
+    /// scales with the RTT so retransmits have time to arrive on slow links.
+    fn chunk_timeout(&self) -> Duration {
+        match self.rtt_estimate {
+            Some(rtt) => {
+                (rtt * CHUNK_TIMEOUT_RTT_MULTIPLIER).clamp(MIN_CHUNK_TIMEOUT, MAX_CHUNK_TIMEOUT)
+            }
+            None => DEFAULT_CHUNK_TIMEOUT,
+        }
+    }
+
+    fn process_heartbeat(&mut self, sid: StreamID, sequence: u32) {
+        let cache = self.frame_caches.entry(sid).or_insert_with(FrameCache::new);
+        cache.last_heard = Instant::now();
+
+        let dropped_partial_frame = self
+            .fragment_buffers
+            .get(&sid)
+            .is_some_and(|fb| sequence_is_newer(sequence, fb.sequence));
+        if dropped_partial_frame {
+            self.fragment_buffers.remove(&sid);
+        }
+
+        // The lost frame may be the base of the next delta, so ask for a keyframe, but keep
+        // the last frame that did arrive in the meantime
+        if (dropped_partial_frame || cache.corrupted || cache.reconstructed_frame.is_none())
+            && cache.should_request_keyframe()
+        {
+            self.outgoing_requests.push(OutgoingRequest::Keyframe(sid));
+        }
+    }
+
+    /// Drops partial frames that stopped receiving chunks, and the caches of streams that
+    /// sent neither chunks nor heartbeats within `STREAM_TIMEOUT`, which also means they
+    /// completed no frame. A static scene only sends heartbeats, so it keeps its cache.
+    /// Returns the evicted streams.
+    fn expire_stale_fragments(&mut self) -> Vec<StreamID> {
+        let mut evicted = Vec::new();
+        let buffer_pool = &mut self.buffer_pool;
+        self.frame_caches.retain(|sid, cache| {
+            let expired = cache.last_heard.elapsed() >= STREAM_TIMEOUT;
+            if expired {
+                evicted.push(*sid);
+                if let Some(frame) = cache.reconstructed_frame.take() {
+                    buffer_pool.return_buffer(frame);
+                }
+            }
+            !expired
+        });
+
+        for sid in &evicted {
+            self.fragment_buffers.remove(sid);
+        }
+
+        let chunk_timeout = self.chunk_timeout();
+        let frame_caches = &mut self.frame_caches;
+        self.fragment_buffers.retain(|sid, fb| {
+            let expired = fb.last_update.elapsed() >= chunk_timeout;
+            if expired && let Some(cache) = frame_caches.get_mut(sid) {
+                cache.mark_corrupted();
+            }
+            !expired
+        });
+
+        evicted
+    }
+
+    /// Copies the stream's current frame into a decode job, unless a decode is already in
+    /// flight. Then the frame is decoded once that one finishes, so at most one decode per
+    /// stream runs at a time and results can't arrive out of order.
+    fn start_decode(&mut self, sid: StreamID) -> Option<DecodeJob> {
+        let cache = self.frame_caches.get_mut(&sid)?;
+
+        if cache.decoding_sequence.is_some() {
+            cache.decode_pending = true;
+            return None;
+        }
+
+        let frame = cache.reconstructed_frame.as_ref()?;
+        let mut data = self.buffer_pool.get_buffer();
+        data.extend_from_slice(frame);
+
+        cache.decoding_sequence = Some(cache.last_sequence);
+        cache.decode_pending = false;
+
+        Some(DecodeJob {
+            sid,
+            sequence: cache.last_sequence,
+            data,
+            output: self.decoded_frame_pool.get_buffer(),
+        })
+    }
+
+    fn finish_decode(
+        &mut self,
+        result: DecodeResult,
+    ) -> (Option<ReceivedFrame>, Option<DecodeJob>) {
+        let DecodeResult {
+            sid,
+            sequence,
+            data,
+            frame,
+        } = result;
+        self.buffer_pool.return_buffer(data);
+
+        // The stream may have been evicted, and possibly recreated, while decoding
+        let cache = match self.frame_caches.get_mut(&sid) {
+            Some(cache) if cache.decoding_sequence == Some(sequence) => cache,
+            _ => return (None, None),
+        };
+        cache.decoding_sequence = None;
+
+        let received = match frame {
+            Some(frame) => {
+                cache.decode_failures = 0;
+                Some(ReceivedFrame::Decoded(frame))
+            }
+            // A newer frame is already waiting; if it built on this one it will fail too
+            None if cache.decode_pending => None,
+            None => {
+                // Deltas built on an undecodable frame can't decode either, so wait for a keyframe
+                cache.decode_failures += 1;
+                cache.mark_corrupted();
+                if cache.should_request_keyframe() {
+                    self.outgoing_requests.push(OutgoingRequest::Keyframe(sid));
+                }
+
+                (cache.decode_failures >= DECODE_ERROR_THRESHOLD)
+                    .then_some(ReceivedFrame::DecodeError)
+            }
+        };
+
+        let next_job = if cache.decode_pending {
+            self.start_decode(sid)
+        } else {
+            None
+        };
+
+        (received, next_job)
+    }
+}
+
+pub(crate) fn parse_packet(
+    packet: &[u8],
+) -> Result<ParsedPacket<'_>, Box<dyn Error + Send + Sync>> {
+    let sid_len = StreamID::default().len();
+    if packet.len() < sid_len + PACKET_HEADER_LEN {
+        return Err("Packet too short".into());
+    }
+
+    let sid = StreamID::try_from(&packet[..sid_len])?;
+    let frame_type = match packet[sid_len] {
+        0 => FrameType::Full,
+        1 => FrameType::Delta,
+        2 => FrameType::Heartbeat,
+        3 => FrameType::KeyframeRequest,
+        4 => FrameType::RetransmitRequest,
+        5 => FrameType::MtuProbe,
+        frame_type => return Err(format!("Unknown frame type {}", frame_type).into()),
+    };
+
+    Ok(ParsedPacket {
+        sid,
+        frame_type,
+        sequence: u32::from_be_bytes(packet[sid_len + 1..sid_len + 5].try_into()?),
+        chunk_id: u32::from_be_bytes(packet[sid_len + 5..sid_len + 9].try_into()?),
+        flags: packet[sid_len + 9],
+        data: &packet[sid_len + PACKET_HEADER_LEN..],
+    })
+}
+
+fn process_chunk(state: &mut ReceiveState, packet: ParsedPacket) -> Option<DecodeJob> {
+    let ParsedPacket {
+        sid,
+        frame_type,
+        sequence,
+        chunk_id,
+        flags,
+        data: chunk_data,
+    } = packet;
+    let is_last = flags & LAST_CHUNK_FLAG != 0;
+
+    if let Some(cache) = state.frame_caches.get_mut(&sid) {
+        cache.last_heard = Instant::now();
+    }
+
+    if state
+        .frame_caches
+        .get(&sid)
+        .is_some_and(|cache| cache.is_stale(sequence))
+    {
+        return None;
+    }
+
+    let entry = state
+        .fragment_buffers
+        .entry(sid)
+        .or_insert_with(|| FragmentBuffer::new(frame_type.clone(), sequence));
+
+    if entry.sequence != sequence {
+        if !sequence_is_newer(sequence, entry.sequence) {
+            return None;
+        }
+        entry.reset(frame_type, sequence);
+    }
+
+    if flags & PARITY_FLAG != 0 {
+        entry.insert_parity(chunk_id, chunk_data, is_last);
+    } else {
+        entry.insert_chunk(chunk_id, chunk_data, is_last);
+    }
+    entry.recover_chunks();
+
+    if is_last && !entry.is_complete() && entry.retransmit_requests < MAX_RETRANSMIT_REQUESTS {
+        let missing_chunks = entry.missing_chunks();
+        if missing_chunks.len() <= MAX_RETRANSMIT_CHUNKS {
+            entry.retransmit_requests += 1;
+            state.outgoing_requests.push(OutgoingRequest::Retransmit {
+                sid,
+                sequence,
+                missing_chunks,
+            });
+        }
+    }
+
+    if !entry.is_complete() {
+        return None;
+    }
+
+    let mut frame_data = state.buffer_pool.get_buffer();
+    for chunk in entry.chunks.values() {
+        frame_data.extend(chunk);
+    }
+
+    let cache = state
+        .frame_caches
+        .entry(sid)
+        .or_insert_with(FrameCache::new);
+    cache.mark_assembled(sequence);
+
+    let reconstructed = match entry.frame_type {
+        FrameType::Full => {
+            if let Some(previous_frame) = cache.reset(frame_data, sequence) {
+                state.buffer_pool.return_buffer(previous_frame);
+            }
+            true
+        }
+        FrameType::Delta => {
+            let applied = !cache.corrupted
+                && cache
+                    .reconstructed_frame
+                    .as_mut()
+                    .is_some_and(|base_frame| {
+                        deserialize_deltas(&frame_data)
+                            .and_then(|deltas| apply_delta_safe(base_frame, &deltas))
+                            .is_ok()
+                    });
+            state.buffer_pool.return_buffer(frame_data);
+
+            if applied {
+                cache.last_sequence = sequence;
+            } else {
+                cache.mark_corrupted();
+            }
+            applied
+        }
+        FrameType::Heartbeat
+        | FrameType::KeyframeRequest
+        | FrameType::RetransmitRequest
+        | FrameType::MtuProbe => {
+            state.buffer_pool.return_buffer(frame_data);
+            false
+        }
+    };
+
+    if entry.frame_type == FrameType::Delta && !reconstructed && cache.should_request_keyframe() {
+        state.outgoing_requests.push(OutgoingRequest::Keyframe(sid));
+    }
+
+    state.fragment_buffers.remove(&sid);
+
+    if !reconstructed {
+        return None;
+    }
+
+    state.start_decode(sid)
+}
+
+fn spawn_decode(job: DecodeJob, decoded_tx: mpsc::UnboundedSender<DecodeResult>) {
+    tokio::task::spawn_blocking(move || {
+        let _ = decoded_tx.send(decode(job));
+    });
+}
+
+fn decode(job: DecodeJob) -> DecodeResult {
+    let frame = Frame::from_bytes_into(&job.data, job.output).ok();
+
+    DecodeResult {
+        sid: job.sid,
+        sequence: job.sequence,
+        data: job.data,
+        frame,
+    }
+}
+
+async fn show_received_frame(
+    sid: StreamID,
+    received: ReceivedFrame,
+    sid_to_frame_map: &Mutex<HashMap<StreamID, RemoteStream>>,
+    call_stats: &Mutex<CallStats>,
+    decoded_frame_pool: &mut BufferPool,
+) {
+    match received {
+        ReceivedFrame::Decoded(frame) => {
+            call_stats.lock().await.record_frame_received(sid);
+
+            let mut frame_map = sid_to_frame_map.lock().await;
+            let remote_stream = frame_map.entry(sid).or_default();
+            if let Some(previous_frame) = remote_stream.frame.replace(frame) {
+                decoded_frame_pool.return_frame(previous_frame);
+            }
+            remote_stream.decode_error = false;
+        }
+        ReceivedFrame::DecodeError => {
+            sid_to_frame_map
+                .lock()
+                .await
+                .entry(sid)
+                .or_default()
+                .decode_error = true;
+        }
+    }
+}
+
+async fn send_outgoing_requests(udp_stream: &UdpSocket, full_sid: &[u8], state: &mut ReceiveState) {
+    for request in std::mem::take(&mut state.outgoing_requests) {
+        match request {
+            OutgoingRequest::Retransmit {
+                sid,
+                sequence,
+                missing_chunks,
+            } => {
+                send_retransmit_request(udp_stream, full_sid, &sid, sequence, &missing_chunks)
+                    .await;
+            }
+            OutgoingRequest::Keyframe(sid) => {
+                send_keyframe_request(udp_stream, full_sid, &sid).await;
+            }
+        }
+    }
+}
+
+async fn expire_streams(
+    state: &mut ReceiveState,
+    sid_to_frame_map: &Mutex<HashMap<StreamID, RemoteStream>>,
+) {
+    let evicted = state.expire_stale_fragments();
+    if evicted.is_empty() {
+        return;
+    }
+
+    // Streams the server announced keep their tile entry so the username survives
+    // until they leave; anything else was only created by stray packets.
+    sid_to_frame_map.lock().await.retain(|sid, remote_stream| {
+        if !evicted.contains(sid) {
+            return true;
+        }
+
+        remote_stream.frame = None;
+        remote_stream.decode_error = false;
+        !remote_stream.username.is_empty()
+    });
+}
+
+/// Picks the chunk size for the call. An explicit `mtu` is trusted as is; otherwise MTU
+/// probes are sent with fragmentation disabled and the largest one the server echoes
+/// back decides. Falls back to `DEFAULT_CHUNK_SIZE` if nothing comes back, e.g. from an
+/// older server.
+pub async fn negotiate_chunk_size(
+    udp_stream: &UdpSocket,
+    full_sid: &[u8],
+    mtu: Option<usize>,
+) -> usize {
+    let ipv6 = udp_stream
+        .local_addr()
+        .is_ok_and(|local_addr| local_addr.is_ipv6());
+
+    if let Some(mtu) = mtu {
+        return chunk_size_for_mtu(mtu, ipv6);
+    }
+
+    match probe_path_mtu(udp_stream, full_sid, ipv6).await {
+        Some(mtu) => chunk_size_for_mtu(mtu, ipv6),
+        None => DEFAULT_CHUNK_SIZE,
+    }
+}
+
+/// Splits a frame into the payloads of its chunk packets, each at most `chunk_size` bytes.
+fn split_frame(data: &[u8], chunk_size: usize) -> Vec<&[u8]> {
+    data.chunks(chunk_size).collect()
+}
+
+fn last_chunk_flag(chunk_index: usize, total_chunks: usize) -> u8 {
+    if chunk_index + 1 == total_chunks {
+        LAST_CHUNK_FLAG
+    } else {
+        0
+    }
+}
+
+fn chunk_size_for_mtu(mtu: usize, ipv6: bool) -> usize {
+    let header_len = if ipv6 {
+        IPV6_UDP_HEADER_LEN
+    } else {
+        IPV4_UDP_HEADER_LEN
+    };
+
+    mtu.saturating_sub(header_len + CHUNK_OVERHEAD).max(1)
+}
+
+async fn probe_path_mtu(udp_stream: &UdpSocket, full_sid: &[u8], ipv6: bool) -> Option<usize> {
+    set_dont_fragment(udp_stream, ipv6, true).ok()?;
+
+    let header_len = if ipv6 {
+        IPV6_UDP_HEADER_LEN
+    } else {
+        IPV4_UDP_HEADER_LEN
+    };
+
+    // The probe index travels in the sequence field so echoes map back to an MTU
+    for (probe_index, mtu) in MTU_PROBE_SIZES.iter().enumerate() {
+        let mut probe = full_sid.to_vec();
+        probe.push(FrameType::MtuProbe as u8);
+        probe.extend_from_slice(&(probe_index as u32).to_be_bytes());
+        probe.extend_from_slice(&0u32.to_be_bytes());
+        probe.push(0);
+        probe.resize(mtu - header_len, 0);
+
+        // Probes larger than a known local MTU fail right away, which is fine
+        let _ = udp_stream.send(&probe).await;
+    }
+
+    let own_sid = &full_sid[RoomID::default().len()..];
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let mut largest_mtu = None;
+    let deadline = Instant::now() + MTU_PROBE_TIMEOUT;
+
+    while let Ok(Ok(n)) = timeout_at(deadline, udp_stream.recv(&mut buf)).await {
+        if let Ok(packet) = parse_packet(&buf[..n])
+            && packet.frame_type == FrameType::MtuProbe
+            && packet.sid == *own_sid
+            && let Some(mtu) = MTU_PROBE_SIZES.get(packet.sequence as usize)
+        {
+            largest_mtu = largest_mtu.max(Some(*mtu));
+        }
+    }
+
+    let _ = set_dont_fragment(udp_stream, ipv6, false);
+
+    largest_mtu
+}
+
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(udp_stream: &UdpSocket, ipv6: bool, enabled: bool) -> std::io::Result<()> {
+    // PMTUDISC_PROBE sets DF without letting the kernel's cached path MTU reject probes
+    let (level, name, value) = match (ipv6, enabled) {
+        (false, true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        (false, false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_WANT,
+        ),
+        (true, true) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+        (true, false) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_WANT,
+        ),
+    };
+
+    set_socket_option(udp_stream, level, name, value)
+}
+
+#[cfg(target_os = "macos")]
+fn set_dont_fragment(udp_stream: &UdpSocket, ipv6: bool, enabled: bool) -> std::io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_DONTFRAG)
+    };
+
+    set_socket_option(udp_stream, level, name, enabled as libc::c_int)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_dont_fragment(_udp_stream: &UdpSocket, _ipv6: bool, _enabled: bool) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_socket_option(
+    udp_stream: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            udp_stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// The UDP socket of a call, the room and stream id prefixed to every packet, and the
+/// cipher for media chunks when the room encrypts them.
+#[derive(Clone)]
+pub struct MediaStream {
+    pub udp_stream: Arc<UdpSocket>,
+    pub full_sid: Vec<u8>,
+    pub media_cipher: Option<MediaCipher>,
+}
+
+/// Encoding and pacing options for [`udp_send_loop`].
+pub struct SendSettings {
+    pub quality: f32,
+    pub fec_enabled: bool,
+    pub chunk_size: usize,
+    pub max_upload_kbps: Option<u32>,
+    pub frame_format: FrameFormat,
+}
+
+/// Keyframe and retransmit requests from receivers, passed from [`udp_listener_loop`],
+/// which reads them off the socket, to [`udp_send_loop`], which answers them.
+pub struct FeedbackTx {
+    keyframe_requested: Arc<AtomicBool>,
+    retransmit_tx: mpsc::UnboundedSender<(u32, Vec<u32>)>,
+}
+
+/// The [`udp_send_loop`] end of [`feedback_channel`].
+pub struct FeedbackRx {
+    keyframe_requested: Arc<AtomicBool>,
+    retransmit_rx: mpsc::UnboundedReceiver<(u32, Vec<u32>)>,
+}
+
+pub fn feedback_channel() -> (FeedbackTx, FeedbackRx) {
+    let keyframe_requested = Arc::new(AtomicBool::new(false));
+    let (retransmit_tx, retransmit_rx) = mpsc::unbounded_channel();
+
+    (
+        FeedbackTx {
+            keyframe_requested: keyframe_requested.clone(),
+            retransmit_tx,
+        },
+        FeedbackRx {
+            keyframe_requested,
+            retransmit_rx,
+        },
+    )
+}
+
 pub async fn udp_listener_loop(
-    udp_stream: Arc<UdpSocket>,
-    sid_to_frame_map: Arc<Mutex<HashMap<StreamID, Option<Frame>>>>,
+    media: MediaStream,
+    sid_to_frame_map: Arc<Mutex<HashMap<StreamID, RemoteStream>>>,
+    feedback_tx: FeedbackTx,
+    call_stats: Arc<Mutex<CallStats>>,
     udp_listener_loop_cancel_token: CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut buf = [0; 1500];
-    let mut fragment_buffers: HashMap<StreamID, FragmentBuffer> = HashMap::new();
-    let mut frame_caches: HashMap<StreamID, FrameCache> = HashMap::new();
-    let mut buffer_pool = BufferPool::new();
+    let MediaStream {
+        udp_stream,
+        full_sid,
+        media_cipher,
+    } = media;
+    let FeedbackTx {
+        keyframe_requested,
+        retransmit_tx,
+    } = feedback_tx;
+    let own_sid = &full_sid[RoomID::default().len()..];
+    let sid_len = StreamID::default().len();
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let mut state = ReceiveState::new();
+    let mut stream_sweep_interval = interval(STREAM_SWEEP_INTERVAL);
+    let mut rtt_ping_interval = interval(RTT_PING_INTERVAL);
+    let rtt_epoch = Instant::now();
+    let (decoded_tx, mut decoded_rx) = mpsc::unbounded_channel();
 
     loop {
         tokio::select! {
             result = udp_stream.recv(&mut buf) => {
                 if let Ok(n) = result {
-                    let sid_len = StreamID::default().len();
-                    if n > sid_len + 10 {
-                        if let Ok(sid) = StreamID::try_from(&buf[..sid_len]) {
-                            let frame_type = match buf[sid_len] {
-                                0 => FrameType::Full,
-                                1 => FrameType::Delta,
-                                2 => FrameType::Heartbeat,
-                                _ => continue,
-                            };
-
-                            let sequence = u32::from_be_bytes(buf[sid_len + 1..sid_len + 5].try_into()?);
-                            let chunk_id = u32::from_be_bytes(buf[sid_len + 5..sid_len + 9].try_into()?);
-                            let is_last = buf[sid_len + 9] == 1;
-                            let chunk_data = &buf[sid_len + 10..n];
-
-                            if frame_type == FrameType::Heartbeat {
-                                continue;
+                    call_stats.lock().await.record_received(n);
+
+                    if let Ok(packet) = parse_packet(&buf[..n]) {
+                        match packet.frame_type {
+                            FrameType::Heartbeat => state.process_heartbeat(packet.sid, packet.sequence),
+                            FrameType::KeyframeRequest => {
+                                if packet.data == own_sid {
+                                    keyframe_requested.store(true, Ordering::Relaxed);
+                                }
                             }
-
-                            let entry = fragment_buffers.entry(sid.clone()).or_insert(FragmentBuffer {
-                                chunks: BTreeMap::new(),
-                                last_update: Instant::now(),
-                                frame_type: frame_type.clone(),
-                                expected_chunks: 0,
-                                sequence,
-                            });
-
-                            if entry.sequence != sequence {
-                                entry.chunks.clear();
-                                entry.frame_type = frame_type;
-                                entry.sequence = sequence;
+                            FrameType::RetransmitRequest => {
+                                if packet.data.len() > sid_len && packet.data[..sid_len] == *own_sid {
+                                    let missing_chunks: Vec<u32> = packet.data[sid_len..]
+                                        .chunks_exact(4)
+                                        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                                        .collect();
+                                    let _ = retransmit_tx.send((packet.sequence, missing_chunks));
+                                }
                             }
-
-                            entry.chunks.insert(chunk_id, chunk_data.to_vec());
-                            entry.last_update = Instant::now();
-
-                            if is_last {
-                                entry.expected_chunks = chunk_id + 1;
-
-                                if entry.chunks.len() == entry.expected_chunks as usize {
-                                    let mut frame_data = buffer_pool.get_buffer();
-                                    for chunk in entry.chunks.values() {
-                                        frame_data.extend(chunk);
+                            FrameType::MtuProbe => {
+                                // Anything else is a late echo of a probe from the start of the call
+                                if packet.sequence == RTT_PING_SEQUENCE
+                                    && packet.sid == *own_sid
+                                    && let Ok(sent_micros) = <[u8; 8]>::try_from(packet.data)
+                                {
+                                    let sent = Duration::from_micros(u64::from_be_bytes(sent_micros));
+                                    state.record_rtt(rtt_epoch.elapsed().saturating_sub(sent));
+                                }
+                            }
+                            FrameType::Full | FrameType::Delta => {
+                                let data = match media_cipher.as_ref() {
+                                    Some(cipher) => {
+                                        let header = &buf[sid_len..n - packet.data.len()];
+                                        cipher.open(&packet.sid, header, packet.data).map(Cow::Owned)
                                     }
+                                    None => Some(Cow::Borrowed(packet.data)),
+                                };
 
-                                    let cache = frame_caches.entry(sid.clone()).or_insert_with(FrameCache::new);
-
-                                    let final_frame_data = match entry.frame_type {
-                                        FrameType::Full => {
-                                            cache.reset(frame_data.clone(), sequence);
-                                            Some(frame_data.clone())
-                                        },
-                                        FrameType::Delta => {
-                                            if cache.corrupted {
-                                                None
-                                            } else if let Some(ref mut base_frame) = cache.reconstructed_frame {
-                                                match deserialize_deltas(&frame_data) {
-                                                    Ok(deltas) => {
-                                                        let mut new_frame = base_frame.clone();
-                                                        match apply_delta_safe(&mut new_frame, &deltas) {
-                                                            Ok(()) => {
-                                                                cache.reconstructed_frame = Some(new_frame.clone());
-                                                                cache.last_sequence = sequence;
-                                                                Some(new_frame)
-                                                            },
-                                                            Err(_) => {
-                                                                cache.mark_corrupted();
-                                                                None
-                                                            }
-                                                        }
-                                                    },
-                                                    Err(_) => {
-                                                        cache.mark_corrupted();
-                                                        None
-                                                    }
-                                                }
-                                            } else {
-                                                cache.mark_corrupted();
-                                                None
-                                            }
-                                        },
-                                        FrameType::Heartbeat => None,
-                                    };
-
-                                    if let Some(final_data) = final_frame_data {
-                                        if let Ok(frame) = Frame::from_bytes(&final_data) {
-                                            if let Ok(mut guard) = sid_to_frame_map.try_lock() {
-                                                guard.insert(sid.clone(), Some(frame));
-                                            }
-                                        }
+                                if let Some(data) = data {
+                                    let packet = ParsedPacket { data: &data, ..packet };
+                                    if let Some(job) = process_chunk(&mut state, packet) {
+                                        spawn_decode(job, decoded_tx.clone());
                                     }
-
-                                    buffer_pool.return_buffer(frame_data);
-                                    fragment_buffers.remove(&sid);
                                 }
                             }
                         }
+
+                        send_outgoing_requests(&udp_stream, &full_sid, &mut state).await;
                     }
                 }
 
-                fragment_buffers.retain(|sid, fb| {
-                    let expired = fb.last_update.elapsed() >= CHUNK_TIMEOUT;
-                    if expired {
-                        if let Some(cache) = frame_caches.get_mut(sid) {
-                            cache.mark_corrupted();
-                        }
-                    }
-                    !expired
-                });
+                expire_streams(&mut state, &sid_to_frame_map).await;
+            }
+
+            Some(result) = decoded_rx.recv() => {
+                let sid = result.sid;
+                let (received, next_job) = state.finish_decode(result);
+
+                if let Some(job) = next_job {
+                    spawn_decode(job, decoded_tx.clone());
+                }
+
+                if let Some(received) = received {
+                    show_received_frame(
+                        sid,
+                        received,
+                        &sid_to_frame_map,
+                        &call_stats,
+                        &mut state.decoded_frame_pool,
+                    )
+                    .await;
+                }
+
+                send_outgoing_requests(&udp_stream, &full_sid, &mut state).await;
+            }
+
+            _ = rtt_ping_interval.tick() => {
+                send_rtt_ping(&udp_stream, &full_sid, rtt_epoch.elapsed().as_micros() as u64).await;
+            }
+
+            _ = stream_sweep_interval.tick() => {
+                expire_streams(&mut state, &sid_to_frame_map).await;
             }
 
             _ = udp_listener_loop_cancel_token.cancelled() => break,
@@ -369,26 +1434,88 @@ pub async fn udp_listener_loop(
 }
 
 pub async fn udp_send_loop(
-    udp_stream: Arc<UdpSocket>,
+    media: MediaStream,
     mut camera_frame_channel_rx: watch::Receiver<Frame>,
-    full_sid: Vec<u8>,
+    settings: SendSettings,
+    feedback_rx: FeedbackRx,
+    call_stats: Arc<Mutex<CallStats>>,
+    paused: Arc<AtomicBool>,
     udp_send_loop_cancel_token: CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let MediaStream {
+        udp_stream,
+        full_sid,
+        mut media_cipher,
+    } = media;
+    let SendSettings {
+        quality,
+        fec_enabled,
+        chunk_size,
+        max_upload_kbps,
+        frame_format,
+    } = settings;
+    let FeedbackRx {
+        keyframe_requested,
+        mut retransmit_rx,
+    } = feedback_rx;
     let mut last_frame: Option<Vec<u8>> = None;
     let mut sequence: u32 = 0;
+    let mut sent_sequence: u32 = 0;
+    let mut sent_packets: Vec<Vec<u8>> = Vec::new();
     let mut heartbeat_counter = 0;
-    let mut packet_buffer = Vec::with_capacity(CHUNK_SIZE + 100);
+    let mut resume_with_full_frame = false;
+    let mut quality_controller = QualityController::new(quality);
+    let mut upload_budget = max_upload_kbps.map(UploadBudget::new);
+    let mut packet_buffer = Vec::with_capacity(chunk_size + 100);
+    let own_sid = StreamID::try_from(&full_sid[RoomID::default().len()..])?;
     const HEARTBEAT_INTERVAL: u32 = 30;
 
     loop {
         tokio::select! {
             _ = udp_send_loop_cancel_token.cancelled() => break,
+            Some((requested_sequence, missing_chunks)) = retransmit_rx.recv() => {
+                quality_controller.record_lost(missing_chunks.len() as u32);
+
+                if requested_sequence == sent_sequence {
+                    for chunk_id in missing_chunks {
+                        if let Some(packet) = sent_packets.get(chunk_id as usize) {
+                            let _ = udp_stream.send(packet).await;
+                            call_stats.lock().await.record_sent(packet.len());
+                            if let Some(budget) = upload_budget.as_mut() {
+                                budget.record(packet.len());
+                            }
+                        }
+                    }
+                }
+            }
             _ = camera_frame_channel_rx.changed() => {
-                let frame = camera_frame_channel_rx.borrow().to_bytes();
+                if quality_controller.update() {
+                    call_stats.lock().await.send_quality = Some(quality_controller.quality);
+                }
+
+                if paused.load(Ordering::Relaxed) {
+                    resume_with_full_frame = true;
+                    heartbeat_counter += 1;
+                    if heartbeat_counter >= HEARTBEAT_INTERVAL {
+                        heartbeat_counter = 0;
+                        sequence = (sequence + 1) % SEQUENCE_WRAP;
+                        let bytes_sent = send_heartbeat(&udp_stream, &full_sid, sequence).await;
+                        call_stats.lock().await.record_sent(bytes_sent);
+                        if let Some(budget) = upload_budget.as_mut() {
+                            budget.record(bytes_sent);
+                        }
+                    }
+                    continue;
+                }
+
+                let frame = camera_frame_channel_rx.borrow().to_bytes(quality_controller.quality, frame_format);
                 sequence = (sequence + 1) % SEQUENCE_WRAP;
 
-                let (frame_type, data_to_send) = if let Some(ref prev_frame) = last_frame {
-                    if let Some(deltas) = create_delta_optimized(prev_frame, &frame) {
+                let force_full_frame = keyframe_requested.swap(false, Ordering::Relaxed)
+                    || std::mem::take(&mut resume_with_full_frame);
+
+                let (frame_type, data_to_send) = if let Some(prev_frame) = last_frame.as_ref().filter(|_| !force_full_frame) {
+                    if let Some(deltas) = create_delta_optimized(prev_frame, &frame, quality_controller.delta_threshold) {
                         if deltas.is_empty() {
                             heartbeat_counter += 1;
                             if heartbeat_counter >= HEARTBEAT_INTERVAL {
@@ -410,32 +1537,79 @@ pub async fn udp_send_loop(
                     (FrameType::Full, frame.clone())
                 };
 
+                if let Some(budget) = upload_budget.as_mut() {
+                    // A dropped frame leaves last_frame alone, so the next delta still
+                    // applies to what receivers actually have
+                    if frame_type != FrameType::Heartbeat && !budget.allows(&frame_type, data_to_send.len()) {
+                        if frame_type == FrameType::Full {
+                            resume_with_full_frame = true;
+                        }
+                        call_stats.lock().await.record_upload_drop();
+                        continue;
+                    }
+                }
+
                 last_frame = Some(frame);
 
                 if frame_type == FrameType::Heartbeat {
-                    packet_buffer.clear();
-                    packet_buffer.extend_from_slice(&full_sid);
-                    packet_buffer.push(FrameType::Heartbeat as u8);
-                    packet_buffer.extend_from_slice(&sequence.to_be_bytes());
-                    packet_buffer.extend_from_slice(&0u32.to_be_bytes());
-                    packet_buffer.push(1);
-                    let _ = udp_stream.send(&packet_buffer).await;
+                    let bytes_sent = send_heartbeat(&udp_stream, &full_sid, sequence).await;
+                    call_stats.lock().await.record_sent(bytes_sent);
+                    if let Some(budget) = upload_budget.as_mut() {
+                        budget.record(bytes_sent);
+                    }
                     continue;
                 }
 
-                let chunks: Vec<_> = data_to_send.chunks(CHUNK_SIZE).collect();
+                let chunks = split_frame(&data_to_send, chunk_size);
                 let total_chunks = chunks.len();
 
+                sent_sequence = sequence;
+                sent_packets.clear();
+                quality_controller.record_sent(total_chunks as u32);
+
                 for (i, chunk) in chunks.iter().enumerate() {
+                    let group_end = (i as u32 + 1).is_multiple_of(FEC_GROUP_SIZE) || i + 1 == total_chunks;
+                    if fec_enabled && group_end {
+                        let group_id = i as u32 / FEC_GROUP_SIZE;
+                        let group_start = (group_id * FEC_GROUP_SIZE) as usize;
+
+                        packet_buffer.clear();
+                        packet_buffer.extend_from_slice(&full_sid);
+                        packet_buffer.push(frame_type.clone() as u8);
+                        packet_buffer.extend_from_slice(&sequence.to_be_bytes());
+                        packet_buffer.extend_from_slice(&group_id.to_be_bytes());
+                        packet_buffer.push(PARITY_FLAG | last_chunk_flag(i, total_chunks));
+                        push_chunk_data(
+                            &mut packet_buffer,
+                            &create_parity_chunk(&chunks[group_start..=i]),
+                            &own_sid,
+                            full_sid.len(),
+                            media_cipher.as_mut(),
+                        )?;
+
+                        let _ = udp_stream.send(&packet_buffer).await;
+                        call_stats.lock().await.record_sent(packet_buffer.len());
+                        if let Some(budget) = upload_budget.as_mut() {
+                            budget.record(packet_buffer.len());
+                        }
+                    }
+
                     packet_buffer.clear();
                     packet_buffer.extend_from_slice(&full_sid);
                     packet_buffer.push(frame_type.clone() as u8);
                     packet_buffer.extend_from_slice(&sequence.to_be_bytes());
                     packet_buffer.extend_from_slice(&(i as u32).to_be_bytes());
-                    packet_buffer.push((i + 1 == total_chunks) as u8);
-                    packet_buffer.extend_from_slice(chunk);
+                    packet_buffer.push(last_chunk_flag(i, total_chunks));
+                    push_chunk_data(&mut packet_buffer, chunk, &own_sid, full_sid.len(), media_cipher.as_mut())?;
 
                     let _ = udp_stream.send(&packet_buffer).await;
+                    sent_packets.push(packet_buffer.clone());
+                }
+
+                let bytes_sent: usize = sent_packets.iter().map(Vec::len).sum();
+                call_stats.lock().await.record_sent(bytes_sent);
+                if let Some(budget) = upload_budget.as_mut() {
+                    budget.record(bytes_sent);
                 }
             }
         }
@@ -443,3 +1617,731 @@ pub async fn udp_send_loop(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const SID: StreamID = [7; 4];
+
+    fn chunk(sequence: u32, chunk_id: u32, flags: u8, data: &[u8]) -> ParsedPacket<'_> {
+        typed_chunk(FrameType::Full, sequence, chunk_id, flags, data)
+    }
+
+    fn typed_chunk(
+        frame_type: FrameType,
+        sequence: u32,
+        chunk_id: u32,
+        flags: u8,
+        data: &[u8],
+    ) -> ParsedPacket<'_> {
+        ParsedPacket {
+            sid: SID,
+            frame_type,
+            sequence,
+            chunk_id,
+            flags,
+            data,
+        }
+    }
+
+    /// Reports `job` as decoded, so the stream's next frame can start decoding.
+    fn finish_decoding(state: &mut ReceiveState, job: DecodeJob) {
+        let frame = Frame {
+            width: 1,
+            height: 1,
+            data: Arc::new(job.output),
+        };
+        state.finish_decode(DecodeResult {
+            sid: job.sid,
+            sequence: job.sequence,
+            data: job.data,
+            frame: Some(frame),
+        });
+    }
+
+    #[test]
+    fn reconstructs_frame_from_shuffled_and_duplicated_chunks() {
+        let chunks: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 3]).collect();
+        let flags = |chunk_id: u32| if chunk_id == 4 { LAST_CHUNK_FLAG } else { 0 };
+        let mut state = ReceiveState::new();
+
+        for chunk_id in [4, 1, 1, 3, 4, 0] {
+            let packet = chunk(1, chunk_id, flags(chunk_id), &chunks[chunk_id as usize]);
+            assert!(process_chunk(&mut state, packet).is_none());
+        }
+
+        let job = process_chunk(&mut state, chunk(1, 2, 0, &chunks[2])).expect("Frame is complete");
+        assert_eq!(job.data, chunks.concat());
+
+        // A duplicate arriving after assembly must not start the frame over
+        assert!(process_chunk(&mut state, chunk(1, 2, 0, &chunks[2])).is_none());
+        assert!(!state.fragment_buffers.contains_key(&SID));
+    }
+
+    #[test]
+    fn parity_recovers_one_dropped_chunk_per_group() {
+        // Two full groups and a short last one, with uneven chunk lengths
+        let chunks: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i + 1; 4 + i as usize]).collect();
+        let total_chunks = chunks.len() as u32;
+        let dropped = [1, 6, 9];
+        let mut state = ReceiveState::new();
+        let mut job = None;
+
+        for (group_id, group_chunks) in chunks.chunks(FEC_GROUP_SIZE as usize).enumerate() {
+            let first_chunk_id = group_id as u32 * FEC_GROUP_SIZE;
+            let last_flag = |chunk_id: u32| {
+                if chunk_id + 1 == total_chunks {
+                    LAST_CHUNK_FLAG
+                } else {
+                    0
+                }
+            };
+
+            for (i, data) in group_chunks.iter().enumerate() {
+                let chunk_id = first_chunk_id + i as u32;
+                if !dropped.contains(&chunk_id) {
+                    job = job.or(process_chunk(
+                        &mut state,
+                        chunk(1, chunk_id, last_flag(chunk_id), data),
+                    ));
+                }
+            }
+
+            let group_refs: Vec<&[u8]> = group_chunks.iter().map(Vec::as_slice).collect();
+            let parity = create_parity_chunk(&group_refs);
+            let last_chunk_id = first_chunk_id + group_chunks.len() as u32 - 1;
+            let flags = PARITY_FLAG | last_flag(last_chunk_id);
+            job = job.or(process_chunk(
+                &mut state,
+                chunk(1, group_id as u32, flags, &parity),
+            ));
+        }
+
+        let job = job.expect("Parity should fill in the dropped chunks");
+        assert_eq!(job.data, chunks.concat());
+        assert!(state.outgoing_requests.is_empty());
+    }
+
+    #[test]
+    fn applies_delta_on_top_of_full_frame() {
+        let full_frame = vec![0u8; 64];
+        let mut state = ReceiveState::new();
+
+        let job = process_chunk(&mut state, chunk(1, 0, LAST_CHUNK_FLAG, &full_frame))
+            .expect("Full frame is complete");
+        assert_eq!(job.data, full_frame);
+        finish_decoding(&mut state, job);
+
+        let mut next_frame = full_frame.clone();
+        next_frame[4..6].copy_from_slice(&[9, 9]);
+        next_frame[40] = 5;
+        let deltas = create_delta_optimized(&full_frame, &next_frame, 1.0).unwrap();
+        let delta = serialize_deltas_optimized(&deltas);
+
+        let packet = typed_chunk(FrameType::Delta, 2, 0, LAST_CHUNK_FLAG, &delta);
+        let job = process_chunk(&mut state, packet).expect("Delta frame is complete");
+        assert_eq!(job.data, next_frame);
+        assert!(!state.frame_caches[&SID].corrupted);
+    }
+
+    #[test]
+    fn corrupted_delta_chain_requests_a_keyframe_and_recovers() {
+        let full_frame = vec![0u8; 64];
+        let mut state = ReceiveState::new();
+
+        let job = process_chunk(&mut state, chunk(1, 0, LAST_CHUNK_FLAG, &full_frame))
+            .expect("Full frame is complete");
+        finish_decoding(&mut state, job);
+
+        // Reaches past the end of the frame, as if it was built on a frame we never got
+        let broken_delta = serialize_deltas_optimized(&[DeltaChunk {
+            offset: 100,
+            data: vec![1],
+        }]);
+        let packet = typed_chunk(FrameType::Delta, 2, 0, LAST_CHUNK_FLAG, &broken_delta);
+        assert!(process_chunk(&mut state, packet).is_none());
+        assert!(state.frame_caches[&SID].corrupted);
+        assert!(matches!(
+            state.outgoing_requests.drain(..).as_slice(),
+            [OutgoingRequest::Keyframe(SID)]
+        ));
+
+        // Deltas stay dropped until the keyframe arrives, without asking again right away
+        let mut next_frame = full_frame.clone();
+        next_frame[0] = 1;
+        let delta = serialize_deltas_optimized(
+            &create_delta_optimized(&full_frame, &next_frame, 1.0).unwrap(),
+        );
+        let packet = typed_chunk(FrameType::Delta, 3, 0, LAST_CHUNK_FLAG, &delta);
+        assert!(process_chunk(&mut state, packet).is_none());
+        assert!(state.outgoing_requests.is_empty());
+
+        let job = process_chunk(&mut state, chunk(4, 0, LAST_CHUNK_FLAG, &full_frame))
+            .expect("Keyframe is complete");
+        finish_decoding(&mut state, job);
+        assert!(!state.frame_caches[&SID].corrupted);
+
+        let packet = typed_chunk(FrameType::Delta, 5, 0, LAST_CHUNK_FLAG, &delta);
+        let job = process_chunk(&mut state, packet).expect("Deltas apply again");
+        assert_eq!(job.data, next_frame);
+    }
+
+    #[test]
+    fn retransmit_request_recovers_a_lost_chunk() {
+        let chunks: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 8]).collect();
+        let mut state = ReceiveState::new();
+
+        for chunk_id in [0, 2, 3] {
+            let flags = if chunk_id == 3 { LAST_CHUNK_FLAG } else { 0 };
+            let packet = chunk(1, chunk_id, flags, &chunks[chunk_id as usize]);
+            assert!(process_chunk(&mut state, packet).is_none());
+        }
+
+        match state.outgoing_requests.drain(..).as_slice() {
+            [
+                OutgoingRequest::Retransmit {
+                    sid: SID,
+                    sequence: 1,
+                    missing_chunks,
+                },
+            ] => assert_eq!(missing_chunks, &[1]),
+            _ => panic!("Expected one retransmit request"),
+        }
+
+        let job = process_chunk(&mut state, chunk(1, 1, 0, &chunks[1]))
+            .expect("Retransmitted chunk completes the frame");
+        assert_eq!(job.data, chunks.concat());
+        assert!(state.outgoing_requests.is_empty());
+        assert!(!state.frame_caches[&SID].corrupted);
+    }
+
+    #[test]
+    fn accepts_frames_after_sequence_wrap_and_rejects_stale_ones() {
+        let last_before_wrap = SEQUENCE_WRAP - 1;
+        assert!(sequence_is_newer(0, last_before_wrap));
+        assert!(!sequence_is_newer(last_before_wrap, 0));
+
+        let mut state = ReceiveState::new();
+        let job = process_chunk(
+            &mut state,
+            chunk(last_before_wrap - 1, 0, LAST_CHUNK_FLAG, &[1]),
+        )
+        .expect("Frame before the wrap is complete");
+        finish_decoding(&mut state, job);
+
+        // The first frame after the wrap replaces a partial one from just before it
+        assert!(process_chunk(&mut state, chunk(last_before_wrap, 0, 0, &[2])).is_none());
+        let job = process_chunk(&mut state, chunk(0, 0, LAST_CHUNK_FLAG, &[3]))
+            .expect("Frame after the wrap is accepted");
+        assert_eq!(job.data, [3]);
+        finish_decoding(&mut state, job);
+
+        let stale = chunk(last_before_wrap, 0, LAST_CHUNK_FLAG, &[2]);
+        assert!(process_chunk(&mut state, stale).is_none());
+        assert!(!state.fragment_buffers.contains_key(&SID));
+        assert_eq!(state.frame_caches[&SID].last_sequence, 0);
+    }
+
+    #[test]
+    fn heartbeat_drops_partial_frame_without_marking_corruption() {
+        let mut state = ReceiveState::new();
+        let job = process_chunk(&mut state, chunk(1, 0, LAST_CHUNK_FLAG, &[1]))
+            .expect("First frame is complete");
+        finish_decoding(&mut state, job);
+
+        assert!(process_chunk(&mut state, chunk(2, 0, 0, &[2])).is_none());
+        assert!(state.fragment_buffers.contains_key(&SID));
+
+        state.process_heartbeat(SID, 3);
+
+        assert!(!state.fragment_buffers.contains_key(&SID));
+        assert!(!state.frame_caches[&SID].corrupted);
+        assert!(matches!(
+            state.outgoing_requests.as_slice(),
+            [OutgoingRequest::Keyframe(SID)]
+        ));
+    }
+
+    #[test]
+    fn coalescing_scattered_changes_shrinks_the_delta() {
+        let old_frame = vec![0u8; 4096];
+        let mut new_frame = old_frame.clone();
+        let changed_offsets: Vec<usize> = (0..300).step_by(3).collect();
+        for &offset in &changed_offsets {
+            new_frame[offset] = 1;
+        }
+
+        let deltas = create_delta_optimized(&old_frame, &new_frame, DELTA_THRESHOLD).unwrap();
+        let coalesced_size = serialize_deltas_optimized(&deltas).len();
+
+        // Every gap is shorter than a chunk header, so all the changes share one chunk
+        assert_eq!(deltas.len(), 1);
+
+        // The same changes, one chunk each
+        let uncoalesced: Vec<DeltaChunk> = changed_offsets
+            .iter()
+            .map(|&offset| DeltaChunk {
+                offset: offset as u32,
+                data: vec![1],
+            })
+            .collect();
+        let uncoalesced_size = serialize_deltas_optimized(&uncoalesced).len();
+
+        assert!(coalesced_size < uncoalesced_size);
+
+        let mut patched_frame = old_frame.clone();
+        apply_delta_safe(&mut patched_frame, &deltas).unwrap();
+        assert_eq!(patched_frame, new_frame);
+    }
+
+    #[test]
+    fn slow_decode_of_one_stream_does_not_hold_up_another() {
+        const OTHER_SID: StreamID = [8; 4];
+        let mut state = ReceiveState::new();
+
+        // The first job stays unfinished, as if its decode were still running
+        let slow_job = process_chunk(&mut state, chunk(1, 0, LAST_CHUNK_FLAG, &[1; 64]))
+            .expect("Large frame is complete");
+        assert!(process_chunk(&mut state, chunk(2, 0, LAST_CHUNK_FLAG, &[2; 64])).is_none());
+
+        let mut packet = chunk(1, 0, LAST_CHUNK_FLAG, &[3]);
+        packet.sid = OTHER_SID;
+        let other_job = process_chunk(&mut state, packet).expect("Other stream decodes meanwhile");
+        assert_eq!(other_job.sid, OTHER_SID);
+
+        // Once the slow decode finishes, the frame that arrived during it goes next
+        let frame = Frame {
+            width: 1,
+            height: 1,
+            data: Arc::new(slow_job.output),
+        };
+        let (_, next_job) = state.finish_decode(DecodeResult {
+            sid: SID,
+            sequence: slow_job.sequence,
+            data: slow_job.data,
+            frame: Some(frame),
+        });
+        let next_job = next_job.expect("Pending frame starts decoding");
+        assert_eq!((next_job.sequence, next_job.data), (2, vec![2; 64]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_keep_a_stream_that_a_silent_one_loses() {
+        const SILENT_SID: StreamID = [8; 4];
+        let mut state = ReceiveState::new();
+
+        for sid in [SID, SILENT_SID] {
+            let mut packet = chunk(1, 0, LAST_CHUNK_FLAG, &[1]);
+            packet.sid = sid;
+            let job = process_chunk(&mut state, packet).expect("Frame is complete");
+            finish_decoding(&mut state, job);
+        }
+
+        let mut evicted = Vec::new();
+        for sequence in 2..2 + STREAM_TIMEOUT.as_secs() as u32 {
+            tokio::time::advance(Duration::from_secs(1)).await;
+            state.process_heartbeat(SID, sequence);
+            evicted.extend(state.expire_stale_fragments());
+        }
+
+        assert_eq!(evicted, [SILENT_SID]);
+        assert!(state.frame_caches.contains_key(&SID));
+        assert!(!state.frame_caches.contains_key(&SILENT_SID));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_budget_holds_a_frame_stream_to_the_cap() {
+        const FRAME_BYTES: usize = 1000;
+        const FRAMES_PER_SECOND: u32 = 30;
+        const SECONDS: u32 = 3;
+
+        // 80 kbps is 10 KB per window, room for 10 of the 30 deltas sent each second
+        let mut budget = UploadBudget::new(80);
+        let mut sent_at = Vec::new();
+
+        for _ in 0..FRAMES_PER_SECOND * SECONDS {
+            if budget.allows(&FrameType::Delta, FRAME_BYTES) {
+                budget.record(FRAME_BYTES);
+                sent_at.push(Instant::now());
+            }
+            tokio::time::advance(Duration::from_secs(1) / FRAMES_PER_SECOND).await;
+        }
+
+        let frames_per_window = budget.bytes_per_window / FRAME_BYTES;
+        assert_eq!(sent_at.len(), frames_per_window * SECONDS as usize);
+        for (i, window_start) in sent_at.iter().enumerate() {
+            let in_window = sent_at[i..]
+                .iter()
+                .take_while(|sent| sent.duration_since(*window_start) < UPLOAD_WINDOW)
+                .count();
+            assert!(in_window <= frames_per_window);
+        }
+    }
+
+    #[test]
+    fn chunk_timeout_scales_with_rtt_within_bounds() {
+        let mut state = ReceiveState::new();
+        assert_eq!(state.chunk_timeout(), DEFAULT_CHUNK_TIMEOUT);
+
+        state.record_rtt(Duration::from_millis(40));
+        assert_eq!(state.chunk_timeout(), Duration::from_millis(120));
+
+        // Later samples only move the estimate by an eighth of the difference
+        state.record_rtt(Duration::from_millis(120));
+        assert_eq!(state.chunk_timeout(), Duration::from_millis(150));
+
+        let mut fast = ReceiveState::new();
+        fast.record_rtt(Duration::from_millis(1));
+        assert_eq!(fast.chunk_timeout(), MIN_CHUNK_TIMEOUT);
+
+        let mut slow = ReceiveState::new();
+        slow.record_rtt(Duration::from_secs(2));
+        assert_eq!(slow.chunk_timeout(), MAX_CHUNK_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quality_ramps_down_under_loss_and_recovers_without_it() {
+        let mut controller = QualityController::new(75.0);
+
+        for _ in 0..3 {
+            controller.record_sent(100);
+            controller.record_lost(20);
+            tokio::time::advance(QUALITY_WINDOW).await;
+            assert!(controller.update());
+        }
+        assert_eq!(controller.quality, 75.0 - 3.0 * QUALITY_STEP);
+        assert!(controller.delta_threshold > DELTA_THRESHOLD);
+
+        for _ in 0..3 {
+            controller.record_sent(100);
+            tokio::time::advance(QUALITY_WINDOW).await;
+            assert!(controller.update());
+        }
+        assert_eq!(controller.quality, 75.0);
+        assert!((controller.delta_threshold - DELTA_THRESHOLD).abs() < 1e-6);
+
+        controller.record_sent(100);
+        tokio::time::advance(QUALITY_WINDOW).await;
+        assert!(!controller.update());
+    }
+
+    #[tokio::test]
+    async fn paused_send_loop_only_sends_heartbeats() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let full_sid = [[1; 4], SID].concat();
+        let media = MediaStream {
+            udp_stream: Arc::new(sender),
+            full_sid: full_sid.clone(),
+            media_cipher: None,
+        };
+        let settings = SendSettings {
+            quality: 75.0,
+            fec_enabled: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_upload_kbps: None,
+            frame_format: FrameFormat::Rgb,
+        };
+        let (camera_tx, camera_rx) = watch::channel(Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![128; 12]),
+        });
+        let (_feedback_tx, feedback_rx) = feedback_channel();
+        let cancel_token = CancellationToken::new();
+
+        let send_loop = tokio::spawn(udp_send_loop(
+            media,
+            camera_rx,
+            settings,
+            feedback_rx,
+            Arc::new(Mutex::new(CallStats::new())),
+            Arc::new(AtomicBool::new(true)),
+            cancel_token.clone(),
+        ));
+
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let mut heartbeats = 0;
+        for _ in 0..5000 {
+            if heartbeats == 2 {
+                break;
+            }
+
+            camera_tx.send_modify(|_| {});
+            if let Ok(result) = timeout(Duration::from_millis(1), receiver.recv(&mut buf)).await {
+                result.unwrap();
+                assert_eq!(buf[full_sid.len()], FrameType::Heartbeat as u8);
+                heartbeats += 1;
+            }
+        }
+        assert_eq!(heartbeats, 2);
+
+        cancel_token.cancel();
+        send_loop.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconstructed_frame_waits_out_a_held_frame_map() {
+        let sid_to_frame_map = Arc::new(Mutex::new(HashMap::<StreamID, RemoteStream>::new()));
+        let frame = Frame {
+            width: 1,
+            height: 1,
+            data: Arc::new(vec![1, 2, 3]),
+        };
+
+        // The render loop holds the map while the frame finishes decoding
+        let render_guard = sid_to_frame_map.lock().await;
+        let show = tokio::spawn({
+            let sid_to_frame_map = sid_to_frame_map.clone();
+            async move {
+                show_received_frame(
+                    SID,
+                    ReceivedFrame::Decoded(frame),
+                    &sid_to_frame_map,
+                    &Mutex::new(CallStats::new()),
+                    &mut BufferPool::new(),
+                )
+                .await;
+            }
+        });
+
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!show.is_finished());
+        drop(render_guard);
+
+        timeout(Duration::from_secs(1), show)
+            .await
+            .expect("Frame was not stored once the map was free")
+            .unwrap();
+        let frame_map = sid_to_frame_map.lock().await;
+        let stored = frame_map[&SID].frame.as_ref().expect("Frame was dropped");
+        assert_eq!(*stored.data, [1, 2, 3]);
+    }
+
+    /// Swaps in a counting global allocator, so it only builds with
+    /// `--features client/alloc-count` and leaves the rest of the suite on the system one.
+    #[cfg(feature = "alloc-count")]
+    mod allocation_counting {
+        use super::*;
+
+        /// Counts this thread's allocations of at least `LARGE_ALLOCATION` bytes, so a test can
+        /// see frame-sized copies without other tests' threads adding to the count.
+        struct CountingAllocator;
+
+        const LARGE_ALLOCATION: usize = 64 * 1024;
+
+        thread_local! {
+            static LARGE_ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+        }
+
+        fn count_large(size: usize) {
+            if size >= LARGE_ALLOCATION {
+                let _ = LARGE_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            }
+        }
+
+        unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+            unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+                count_large(layout.size());
+                unsafe { std::alloc::System.alloc(layout) }
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+                unsafe { std::alloc::System.dealloc(ptr, layout) }
+            }
+
+            unsafe fn realloc(
+                &self,
+                ptr: *mut u8,
+                layout: std::alloc::Layout,
+                new_size: usize,
+            ) -> *mut u8 {
+                count_large(new_size);
+                unsafe { std::alloc::System.realloc(ptr, layout, new_size) }
+            }
+        }
+
+        #[global_allocator]
+        static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+        #[test]
+        fn delta_frames_allocate_about_one_frame_copy_each() {
+            const DELTA_FRAMES: u32 = 20;
+            let mut frame = vec![0u8; LARGE_ALLOCATION];
+            let mut state = ReceiveState::new();
+
+            // The first full frame fills the buffer pools, so it does count
+            let before = LARGE_ALLOCATIONS.with(|count| count.get());
+            let job = process_chunk(&mut state, chunk(1, 0, LAST_CHUNK_FLAG, &frame))
+                .expect("Full frame is complete");
+            finish_decoding(&mut state, job);
+            assert!(LARGE_ALLOCATIONS.with(|count| count.get()) > before);
+
+            let mut large_allocations = 0;
+            for sequence in 2..2 + DELTA_FRAMES {
+                let previous_frame = frame.clone();
+                frame[sequence as usize * 100] = sequence as u8;
+                let delta = serialize_deltas_optimized(
+                    &create_delta_optimized(&previous_frame, &frame, 1.0).unwrap(),
+                );
+
+                let before = LARGE_ALLOCATIONS.with(|count| count.get());
+                let packet = typed_chunk(FrameType::Delta, sequence, 0, LAST_CHUNK_FLAG, &delta);
+                let job = process_chunk(&mut state, packet).expect("Delta frame is complete");
+                assert_eq!(job.data, frame);
+                finish_decoding(&mut state, job);
+                large_allocations += LARGE_ALLOCATIONS.with(|count| count.get()) - before;
+            }
+
+            assert!(
+                large_allocations <= DELTA_FRAMES as usize,
+                "{} frame-sized allocations over {} delta frames",
+                large_allocations,
+                DELTA_FRAMES
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_decode_failures_show_the_placeholder_until_a_frame_decodes() {
+        let sid_to_frame_map = Mutex::new(HashMap::<StreamID, RemoteStream>::new());
+        let call_stats = Mutex::new(CallStats::new());
+        let mut state = ReceiveState::new();
+        let valid_frame = Frame {
+            width: 4,
+            height: 2,
+            data: Arc::new(vec![128; 4 * 2 * 3]),
+        }
+        .to_bytes(90.0, FrameFormat::Rgb);
+
+        let mut receive = async |sequence: u32, payload: &[u8]| {
+            let job = process_chunk(&mut state, chunk(sequence, 0, LAST_CHUNK_FLAG, payload))
+                .expect("Frame is complete");
+            let (received, _) = state.finish_decode(decode(job));
+            if let Some(received) = received {
+                show_received_frame(
+                    SID,
+                    received,
+                    &sid_to_frame_map,
+                    &call_stats,
+                    &mut state.decoded_frame_pool,
+                )
+                .await;
+            }
+            sid_to_frame_map
+                .lock()
+                .await
+                .get(&SID)
+                .is_some_and(|remote_stream| remote_stream.decode_error)
+        };
+
+        // A glitch or two is not worth a placeholder, a run of them is
+        for sequence in 1..DECODE_ERROR_THRESHOLD {
+            assert!(!receive(sequence, b"not a webp image").await);
+        }
+        assert!(receive(DECODE_ERROR_THRESHOLD, b"not a webp image").await);
+
+        assert!(!receive(DECODE_ERROR_THRESHOLD + 1, &valid_frame).await);
+        let frame_map = sid_to_frame_map.lock().await;
+        let frame = frame_map[&SID]
+            .frame
+            .as_ref()
+            .expect("Valid frame is shown");
+        assert_eq!((frame.width, frame.height), (4, 2));
+    }
+
+    #[test]
+    fn frames_split_into_configured_chunks_with_one_last_flag() {
+        const CHUNK_SIZE: usize = 500;
+
+        for frame_len in [1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let frame: Vec<u8> = (0..frame_len).map(|i| i as u8).collect();
+            let chunks = split_frame(&frame, CHUNK_SIZE);
+
+            assert_eq!(chunks.len(), frame_len.div_ceil(CHUNK_SIZE));
+            assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+
+            let flags: Vec<u8> = (0..chunks.len())
+                .map(|i| last_chunk_flag(i, chunks.len()))
+                .collect();
+            assert_eq!(
+                flags
+                    .iter()
+                    .filter(|&&flag| flag == LAST_CHUNK_FLAG)
+                    .count(),
+                1
+            );
+            assert_eq!(flags.last(), Some(&LAST_CHUNK_FLAG));
+
+            // The receiver can put the frame back together from them
+            let mut state = ReceiveState::new();
+            let mut job = None;
+            for (i, chunk_data) in chunks.iter().enumerate() {
+                job = process_chunk(&mut state, chunk(1, i as u32, flags[i], chunk_data));
+            }
+            assert_eq!(job.expect("Frame is complete").data, frame);
+        }
+    }
+
+    #[tokio::test]
+    async fn replaced_frames_give_their_buffers_back_to_the_pool() {
+        let sid_to_frame_map = Mutex::new(HashMap::<StreamID, RemoteStream>::new());
+        let call_stats = Mutex::new(CallStats::new());
+        let mut state = ReceiveState::new();
+        let encoded = Frame {
+            width: 8,
+            height: 8,
+            data: Arc::new(vec![200; 8 * 8 * 3]),
+        }
+        .to_bytes(90.0, FrameFormat::Rgb);
+
+        let mut receive = async |sequence: u32| {
+            let job = process_chunk(&mut state, chunk(sequence, 0, LAST_CHUNK_FLAG, &encoded))
+                .expect("Frame is complete");
+            let (received, _) = state.finish_decode(decode(job));
+            show_received_frame(
+                SID,
+                received.expect("Frame decodes"),
+                &sid_to_frame_map,
+                &call_stats,
+                &mut state.decoded_frame_pool,
+            )
+            .await;
+            sid_to_frame_map.lock().await[&SID]
+                .frame
+                .clone()
+                .expect("Frame is shown")
+        };
+
+        // Once a replaced frame's buffer is back in the pool, the next decode reuses it
+        let mut buffers = Vec::new();
+        for sequence in 1..=20 {
+            let frame = receive(sequence).await;
+            buffers.push(frame.data.as_ptr());
+        }
+        buffers.sort();
+        buffers.dedup();
+        assert!(
+            buffers.len() <= 2,
+            "{} buffers for 20 frames",
+            buffers.len()
+        );
+
+        // A frame the renderer still holds is left alone
+        let held = receive(21).await;
+        let next = receive(22).await;
+        let after_next = receive(23).await;
+        assert_ne!(after_next.data.as_ptr(), held.data.as_ptr());
+        assert_eq!(*held.data, *next.data);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+pub async fn send_batch(
+    socket: &UdpSocket,
+    by_destination: HashMap<SocketAddr, Vec<Vec<u8>>>,
+) -> (u64, u64) {
+    let packets: Vec<(SocketAddr, Vec<u8>)> = by_destination
+        .into_iter()
+        .flat_map(|(dest, payloads)| payloads.into_iter().map(move |payload| (dest, payload)))
+        .collect();
+
+    #[cfg(target_os = "linux")]
+    {
+        linux::send_batch(socket, &packets).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        send_individually(socket, &packets).await
+    }
+}
+
+async fn send_individually(socket: &UdpSocket, packets: &[(SocketAddr, Vec<u8>)]) -> (u64, u64) {
+    let mut forwarded = 0;
+    let mut dropped = 0;
+
+    for (dest, payload) in packets {
+        match socket.send_to(payload, dest).await {
+            Ok(_) => forwarded += 1,
+            Err(e) => {
+                dropped += 1;
+                if dropped % 100 == 0 {
+                    log::warn!("Failed to send to {}: {}", dest, e);
+                }
+            }
+        }
+    }
+
+    (forwarded, dropped)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io::{self, ErrorKind},
+        mem,
+        net::SocketAddr,
+        os::fd::AsRawFd,
+    };
+
+    use tokio::{io::Interest, net::UdpSocket};
+
+    use super::send_individually;
+
+    pub async fn send_batch(socket: &UdpSocket, packets: &[(SocketAddr, Vec<u8>)]) -> (u64, u64) {
+        let addresses: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            packets.iter().map(|(dest, _)| to_sockaddr(dest)).collect();
+
+        let mut forwarded = 0;
+        let mut offset = 0;
+
+        while offset < packets.len() {
+            if let Err(e) = socket.writable().await {
+                log::warn!("UDP socket not writable: {}", e);
+                break;
+            }
+
+            let result = socket.try_io(Interest::WRITABLE, || {
+                sendmmsg(socket, &packets[offset..], &addresses[offset..])
+            });
+
+            match result {
+                // Retrying would spin forever, so leave the rest to send_to
+                Ok(0) => {
+                    log::debug!("sendmmsg sent nothing, falling back to send_to");
+                    break;
+                }
+                Ok(sent) => {
+                    forwarded += sent as u64;
+                    offset += sent;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    log::debug!("sendmmsg failed, falling back to send_to: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let (fallback_forwarded, dropped) = send_individually(socket, &packets[offset..]).await;
+
+        (forwarded + fallback_forwarded, dropped)
+    }
+
+    fn sendmmsg(
+        socket: &UdpSocket,
+        packets: &[(SocketAddr, Vec<u8>)],
+        addresses: &[(libc::sockaddr_storage, libc::socklen_t)],
+    ) -> io::Result<usize> {
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|(_, payload)| libc::iovec {
+                iov_base: payload.as_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            })
+            .collect();
+
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addresses)
+            .map(|(iovec, (address, address_len))| {
+                // SAFETY: msghdr is plain old data, all-zero is a valid empty header.
+                let mut header: libc::msghdr = unsafe { mem::zeroed() };
+                header.msg_name = address as *const libc::sockaddr_storage as *mut libc::c_void;
+                header.msg_namelen = *address_len;
+                header.msg_iov = iovec as *mut libc::iovec;
+                header.msg_iovlen = 1;
+
+                libc::mmsghdr {
+                    msg_hdr: header,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+
+        // SAFETY: every header points into `iovecs`, `addresses` and `packets`, which all
+        // outlive this call, and `messages.len()` matches the buffer passed in.
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+            )
+        };
+
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(sent as usize)
+    }
+
+    fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: sockaddr_storage is plain old data, all-zero is a valid value.
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+        let len = match addr {
+            SocketAddr::V4(v4) => {
+                let sockaddr = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: v4.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(v4.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+
+                // SAFETY: sockaddr_storage is large enough and suitably aligned for sockaddr_in.
+                unsafe {
+                    (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in)
+                        .write(sockaddr)
+                };
+
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(v6) => {
+                let sockaddr = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: v6.port().to_be(),
+                    sin6_flowinfo: v6.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: v6.ip().octets(),
+                    },
+                    sin6_scope_id: v6.scope_id(),
+                };
+
+                // SAFETY: sockaddr_storage is large enough and suitably aligned for sockaddr_in6.
+                unsafe {
+                    (&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6)
+                        .write(sockaddr)
+                };
+
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, len as libc::socklen_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn every_destination_receives_its_payloads() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut receivers = Vec::new();
+        let mut by_destination = HashMap::new();
+
+        for i in 0..3u8 {
+            let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let payloads = vec![vec![i; 10], vec![i; 20]];
+            by_destination.insert(receiver.local_addr().unwrap(), payloads.clone());
+            receivers.push((receiver, payloads));
+        }
+
+        assert_eq!(send_batch(&socket, by_destination).await, (6, 0));
+
+        for (receiver, payloads) in receivers {
+            let mut buf = [0; 64];
+            for payload in payloads {
+                let n = timeout(Duration::from_secs(5), receiver.recv(&mut buf))
+                    .await
+                    .expect("Payload never arrived")
+                    .unwrap();
+                assert_eq!(&buf[..n], payload.as_slice());
+            }
+        }
+    }
+}
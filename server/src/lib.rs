@@ -0,0 +1,12 @@
+mod batch_sender;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod room;
+mod room_store;
+mod server_state;
+mod session;
+mod tcp_command_handler;
+mod tcp_handler;
+pub mod tls;
+mod udp_handler;
+pub mod wes_sfu;
@@ -1,15 +1,14 @@
-mod room;
-mod tcp_command_handler;
-mod tcp_handler;
-mod udp_handler;
-mod wes_sfu;
+use std::time::Duration;
 
 use log::{error, info};
-use shared::{TCP_PORT, UDP_PORT};
+use shared::{TCP_PORT, UDP_PORT, format_host_port};
 
 use clap::Parser;
 
-use crate::wes_sfu::WeSFU;
+use server::{
+    tls::load_tls_acceptor,
+    wes_sfu::{DEFAULT_STREAM_BUDGET_KBPS, DEFAULT_STREAM_LOG_SECS, ServerConfig, WeSFU},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -19,6 +18,40 @@ struct Args {
 
     #[arg(short, long, default_value = "0.0.0.0")]
     udp: String,
+
+    #[arg(long, default_value_t = TCP_PORT)]
+    tcp_port: u16,
+
+    #[arg(long, default_value_t = UDP_PORT)]
+    udp_port: u16,
+
+    #[arg(long, default_value_t = false)]
+    keep_empty_rooms: bool,
+
+    #[arg(long, default_value_t = DEFAULT_STREAM_BUDGET_KBPS)]
+    stream_budget_kbps: usize,
+
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_forwarded_streams: Option<u64>,
+
+    #[arg(long, default_value_t = DEFAULT_STREAM_LOG_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    stream_log_secs: u64,
+
+    #[arg(long, default_value_t = false)]
+    encrypt_media: bool,
+
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    #[arg(long)]
+    rooms_file: Option<String>,
+
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<String>,
 }
 
 #[tokio::main]
@@ -27,10 +60,45 @@ async fn main() {
 
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let tcp_addr = format!("{}:{}", args.tcp, TCP_PORT);
-    let udp_addr = format!("{}:{}", args.udp, UDP_PORT);
+    let tcp_addr = format_host_port(&args.tcp, args.tcp_port);
+    let udp_addr = format_host_port(&args.udp, args.udp_port);
+
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => match load_tls_acceptor(cert_path, key_path) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("Error loading TLS configuration: {}", e);
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    #[cfg(feature = "metrics")]
+    let metrics_addr = args.metrics_addr;
+    #[cfg(not(feature = "metrics"))]
+    let metrics_addr = None;
+
+    if let Some(metrics_addr) = &metrics_addr {
+        info!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
 
-    let server = match WeSFU::bind(tcp_addr.clone(), udp_addr.clone()).await {
+    let server = match WeSFU::bind(
+        tcp_addr.clone(),
+        udp_addr.clone(),
+        ServerConfig {
+            keep_empty_rooms: args.keep_empty_rooms,
+            stream_budget_bytes_per_second: args.stream_budget_kbps * 1000 / 8,
+            max_forwarded_streams: args.max_forwarded_streams.map(|max| max as usize),
+            stream_log_interval: Duration::from_secs(args.stream_log_secs),
+            encrypt_media: args.encrypt_media,
+            tls_acceptor,
+            metrics_addr,
+            rooms_file: args.rooms_file,
+        },
+    )
+    .await
+    {
         Ok(wes_sfu_server) => wes_sfu_server,
         Err(e) => {
             error!("Error binding: {}", e);
@@ -38,6 +106,12 @@ async fn main() {
         }
     };
 
+    let tcp_addr = server
+        .tcp_local_addr()
+        .map_or(tcp_addr, |addr| addr.to_string());
+    let udp_addr = server
+        .udp_local_addr()
+        .map_or(udp_addr, |addr| addr.to_string());
     info!("WeSFU listening on TCP: {}, UDP: {}", tcp_addr, udp_addr);
 
     match server.listen().await {
@@ -0,0 +1,172 @@
+use core::error::Error;
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use log::error;
+use shared::RoomID;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, RwLock},
+};
+
+use crate::{room::Room, udp_handler::ServerStats};
+
+const MAX_REQUEST_SIZE: usize = 1024;
+
+pub async fn serve_metrics(
+    listener: TcpListener,
+    stats: Arc<Mutex<ServerStats>>,
+    users: Arc<RwLock<Vec<String>>>,
+    room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+
+        let stats = stats.clone();
+        let users = users.clone();
+        let room_map = room_map.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, stats, users, room_map).await {
+                error!("Error serving metrics to {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    stats: Arc<Mutex<ServerStats>>,
+    users: Arc<RwLock<Vec<String>>>,
+    room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut buf = [0; MAX_REQUEST_SIZE];
+    let n = stream.read(&mut buf).await?;
+
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render_metrics(&stats, &users, &room_map).await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn render_metrics(
+    stats: &Mutex<ServerStats>,
+    users: &RwLock<Vec<String>>,
+    room_map: &RwLock<HashMap<RoomID, Room>>,
+) -> String {
+    let stats = stats.lock().await.clone();
+    let connected_users = users.read().await.len();
+    let rooms = room_map.read().await.len();
+
+    let metrics: [(&str, &str, &str, u64); 8] = [
+        (
+            "wesfu_packets_received_total",
+            "counter",
+            "UDP packets received from clients",
+            stats.packets_received,
+        ),
+        (
+            "wesfu_packets_forwarded_total",
+            "counter",
+            "UDP packets forwarded to clients",
+            stats.packets_forwarded,
+        ),
+        (
+            "wesfu_packets_dropped_total",
+            "counter",
+            "UDP packets dropped by rate limiting, backpressure or send errors",
+            stats.packets_dropped,
+        ),
+        (
+            "wesfu_packets_malformed_total",
+            "counter",
+            "UDP packets dropped because they were truncated",
+            stats.packets_malformed,
+        ),
+        (
+            "wesfu_packets_unknown_room_total",
+            "counter",
+            "UDP packets dropped because their room does not exist",
+            stats.packets_unknown_room,
+        ),
+        (
+            "wesfu_packets_unknown_stream_total",
+            "counter",
+            "UDP packets dropped because their stream is not in the room",
+            stats.packets_unknown_stream,
+        ),
+        ("wesfu_rooms", "gauge", "Rooms currently open", rooms as u64),
+        (
+            "wesfu_connected_users",
+            "gauge",
+            "Users currently connected",
+            connected_users as u64,
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, metric_type, help, value) in metrics {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, metric_type);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(listener_addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(listener_addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_reports_forwarded_packets() {
+        let stats = Arc::new(Mutex::new(ServerStats {
+            packets_forwarded: 42,
+            ..ServerStats::default()
+        }));
+        let users = Arc::new(RwLock::new(vec!["alice".to_string()]));
+        let room_map = Arc::new(RwLock::new(HashMap::new()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, stats, users, room_map));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nwesfu_packets_forwarded_total 42\n"));
+        assert!(response.contains("\nwesfu_connected_users 1\n"));
+
+        assert!(get(addr, "/other").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -1,20 +1,235 @@
-use shared::StreamID;
+use log::info;
+use rand::fill;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::{MediaKey, RoomID, StreamID, tcp_command::TcpCommand, tcp_command_id::TcpCommandId};
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock, broadcast};
+
+pub const DEFAULT_MAX_ROOM_USERS: usize = 8;
+pub const MAX_ROOM_USERS: usize = 16;
 
 #[derive(Clone)]
 pub struct Room {
     pub name: String,
     pub stream_id_to_socket_addr: Arc<Mutex<HashMap<StreamID, Option<std::net::SocketAddr>>>>,
     pub users: Vec<String>,
+    pub stream_id_to_username: HashMap<StreamID, String>,
+    pub password_hash: Option<PasswordHash>,
+    pub max_users: usize,
+    pub creator: String,
+    pub media_key: MediaKey,
 }
 
 impl Room {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, password: Option<&str>, max_users: usize, creator: &str) -> Self {
+        let mut media_key = MediaKey::default();
+        fill(&mut media_key);
+
         Self {
             name: name.to_string(),
             stream_id_to_socket_addr: Arc::new(Mutex::new(HashMap::new())),
             users: vec![],
+            stream_id_to_username: HashMap::new(),
+            password_hash: password.map(PasswordHash::new),
+            max_users,
+            creator: creator.to_string(),
+            media_key,
+        }
+    }
+
+    pub fn from_config(config: RoomConfig) -> (RoomID, Self) {
+        let mut room = Self::new(&config.name, None, config.max_users, &config.creator);
+        room.password_hash = config.password_hash;
+
+        (config.room_id, room)
+    }
+
+    pub fn to_config(&self, room_id: RoomID) -> RoomConfig {
+        RoomConfig {
+            room_id,
+            name: self.name.clone(),
+            password_hash: self.password_hash.clone(),
+            max_users: self.max_users,
+            creator: self.creator.clone(),
+        }
+    }
+
+    pub fn accepts_password(&self, password: Option<&str>) -> bool {
+        match &self.password_hash {
+            Some(password_hash) => password.is_some_and(|password| password_hash.matches(password)),
+            None => true,
+        }
+    }
+}
+
+/// Removes `sid` from whichever room holds it and tells the remaining users it left.
+/// Both an explicit `LeaveRoom` and a dropped connection end up here, so a stream that
+/// is already gone is a no-op and `OtherUserLeftRoom` goes out at most once.
+/// Returns whether the stream was removed.
+pub async fn leave_room(
+    username: &str,
+    sid: StreamID,
+    room_map: &RwLock<HashMap<RoomID, Room>>,
+    username_to_tcp_command_tx: &Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>,
+    keep_empty_rooms: bool,
+) -> bool {
+    let remaining_users = {
+        let mut room_map_guard = room_map.write().await;
+        let mut left_room = None;
+
+        for (room_id, room) in room_map_guard.iter_mut() {
+            if room
+                .stream_id_to_socket_addr
+                .lock()
+                .await
+                .remove(&sid)
+                .is_some()
+            {
+                room.stream_id_to_username.remove(&sid);
+                room.users.retain(|user| user != username);
+                left_room = Some((*room_id, room.name.clone(), room.users.clone()));
+                break;
+            }
+        }
+
+        let (room_id, room_name, remaining_users) = match left_room {
+            Some(left_room) => left_room,
+            None => return false,
+        };
+
+        if remaining_users.is_empty() && !keep_empty_rooms {
+            room_map_guard.remove(&room_id);
+            info!("Removed empty room '{}' with ID {:?}", room_name, room_id);
+        }
+
+        remaining_users
+    };
+
+    let tx_map = username_to_tcp_command_tx.lock().await;
+    let cmd = TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid.to_vec());
+
+    for user in remaining_users {
+        if let Some(tx) = tx_map.get(&user) {
+            let _ = tx.send(cmd.clone());
+        }
+    }
+
+    true
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomConfig {
+    pub room_id: RoomID,
+    pub name: String,
+    pub password_hash: Option<PasswordHash>,
+    pub max_users: usize,
+    pub creator: String,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasswordHash {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Self {
+        let mut salt = [0; 16];
+        fill(&mut salt);
+
+        Self {
+            salt,
+            hash: Self::hash(&salt, password),
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let hash = Self::hash(&self.salt, password);
+
+        hash.iter()
+            .zip(self.hash.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    fn hash(salt: &[u8], password: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(password.as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM_ID: RoomID = [1; 4];
+
+    async fn room_map_with_members(members: &[(&str, StreamID)]) -> RwLock<HashMap<RoomID, Room>> {
+        let mut room = Room::new("lobby", None, DEFAULT_MAX_ROOM_USERS, "alice");
+
+        for (username, sid) in members {
+            room.users.push(username.to_string());
+            room.stream_id_to_username
+                .insert(*sid, username.to_string());
+            room.stream_id_to_socket_addr
+                .lock()
+                .await
+                .insert(*sid, None);
         }
+
+        RwLock::new(HashMap::from([(ROOM_ID, room)]))
+    }
+
+    #[tokio::test]
+    async fn last_member_leaving_removes_room() {
+        let room_map = room_map_with_members(&[("alice", [1; 4]), ("bob", [2; 4])]).await;
+        let tx_map = Mutex::new(HashMap::new());
+
+        assert!(leave_room("alice", [1; 4], &room_map, &tx_map, false).await);
+        assert!(room_map.read().await.contains_key(&ROOM_ID));
+
+        assert!(leave_room("bob", [2; 4], &room_map, &tx_map, false).await);
+        assert!(!room_map.read().await.contains_key(&ROOM_ID));
+    }
+
+    #[tokio::test]
+    async fn keep_empty_rooms_keeps_room_after_last_member_leaves() {
+        let room_map = room_map_with_members(&[("alice", [1; 4])]).await;
+        let tx_map = Mutex::new(HashMap::new());
+
+        assert!(leave_room("alice", [1; 4], &room_map, &tx_map, true).await);
+        assert!(room_map.read().await[&ROOM_ID].users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn leaving_then_disconnecting_removes_and_announces_once() {
+        let room_map = room_map_with_members(&[("alice", [1; 4]), ("bob", [2; 4])]).await;
+        let (bob_tx, mut bob_rx) = broadcast::channel(16);
+        let tx_map = Mutex::new(HashMap::from([("bob".to_string(), bob_tx)]));
+
+        // LeaveRoom on Ctrl+C, then the dropped connection leaves with the same stream
+        assert!(leave_room("alice", [1; 4], &room_map, &tx_map, false).await);
+        assert!(!leave_room("alice", [1; 4], &room_map, &tx_map, false).await);
+
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid)) if sid == [1; 4]
+        ));
+        assert!(bob_rx.try_recv().is_err());
+
+        let room_map = room_map.read().await;
+        let room = &room_map[&ROOM_ID];
+        assert_eq!(room.users, ["bob"]);
+        assert!(!room.stream_id_to_username.contains_key(&[1; 4]));
+        assert!(
+            !room
+                .stream_id_to_socket_addr
+                .lock()
+                .await
+                .contains_key(&[1; 4])
+        );
     }
 }
@@ -0,0 +1,133 @@
+use core::error::Error;
+use std::{collections::HashMap, io::ErrorKind, sync::Arc};
+
+use log::error;
+use shared::RoomID;
+use tokio::sync::{Notify, RwLock};
+
+use crate::room::{Room, RoomConfig};
+
+pub fn load_rooms(path: &str) -> Result<HashMap<RoomID, Room>, Box<dyn Error + Send + Sync>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(format!("Failed to read rooms file '{}': {}", path, e).into()),
+    };
+
+    let configs: Vec<RoomConfig> = serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid rooms file '{}': {}", path, e))?;
+
+    Ok(configs.into_iter().map(Room::from_config).collect())
+}
+
+/// Saves the rooms each time `rooms_changed` is notified, skipping saves that would not change the file.
+pub async fn persist_rooms(
+    path: String,
+    room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+    rooms_changed: Arc<Notify>,
+) {
+    let mut saved_configs = room_configs(&*room_map.read().await);
+
+    loop {
+        rooms_changed.notified().await;
+
+        let configs = room_configs(&*room_map.read().await);
+        if configs == saved_configs {
+            continue;
+        }
+
+        match save_rooms(&path, &configs).await {
+            Ok(()) => saved_configs = configs,
+            Err(e) => error!("Failed to save rooms to '{}': {}", path, e),
+        }
+    }
+}
+
+pub async fn save_room_map(
+    path: &str,
+    room_map: &RwLock<HashMap<RoomID, Room>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let configs = room_configs(&*room_map.read().await);
+
+    save_rooms(path, &configs).await
+}
+
+fn room_configs(room_map: &HashMap<RoomID, Room>) -> Vec<RoomConfig> {
+    let mut configs: Vec<RoomConfig> = room_map
+        .iter()
+        .map(|(room_id, room)| room.to_config(*room_id))
+        .collect();
+    configs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    configs
+}
+
+async fn save_rooms(
+    path: &str,
+    configs: &[RoomConfig],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tmp_path = format!("{}.tmp", path);
+
+    tokio::fs::write(&tmp_path, serde_json::to_string_pretty(configs)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::room::DEFAULT_MAX_ROOM_USERS;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn persisted_rooms_load_back_with_their_names() {
+        let path = std::env::temp_dir()
+            .join(format!("wesfu_rooms_{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+
+        let room_map = Arc::new(RwLock::new(HashMap::new()));
+        let rooms_changed = Arc::new(Notify::new());
+        let persist_task = tokio::spawn(persist_rooms(
+            path.clone(),
+            room_map.clone(),
+            rooms_changed.clone(),
+        ));
+        // Let it take its snapshot of the empty map, so the rooms below count as changes
+        tokio::task::yield_now().await;
+
+        {
+            let mut room_map_guard = room_map.write().await;
+            room_map_guard.insert(
+                [1; 4],
+                Room::new("lobby", None, DEFAULT_MAX_ROOM_USERS, "alice"),
+            );
+            room_map_guard.insert([2; 4], Room::new("studio", Some("secret"), 4, "bob"));
+        }
+        rooms_changed.notify_one();
+
+        let mut loaded = HashMap::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            loaded = load_rooms(&path).unwrap();
+            if loaded.len() == 2 {
+                break;
+            }
+        }
+        persist_task.abort();
+        let _ = std::fs::remove_file(&path);
+
+        let mut names = loaded
+            .iter()
+            .map(|(room_id, room)| (*room_id, room.name.as_str()))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [([1; 4], "lobby"), ([2; 4], "studio")]);
+        // Passwords, capacity and creator come back too
+        assert!(room_configs(&loaded) == room_configs(&*room_map.read().await));
+    }
+}
@@ -0,0 +1,39 @@
+use std::{collections::HashMap, sync::Arc};
+
+use shared::{RoomID, SessionToken, tcp_command::TcpCommand};
+use tokio::sync::{Mutex, Notify, RwLock, broadcast};
+
+use crate::{room::Room, session::Session};
+
+/// State shared by every control connection, cloned into each connection's task.
+#[derive(Clone)]
+pub struct ServerState {
+    pub users: Arc<RwLock<Vec<String>>>,
+    pub display_names: Arc<RwLock<HashMap<String, String>>>,
+    pub room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+    pub sessions: Arc<Mutex<HashMap<SessionToken, Session>>>,
+    pub username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+    /// Notified whenever a room may have been created, renamed or deleted.
+    pub rooms_changed: Arc<Notify>,
+    pub keep_empty_rooms: bool,
+    pub encrypt_media: bool,
+}
+
+impl ServerState {
+    pub fn new(
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        keep_empty_rooms: bool,
+        encrypt_media: bool,
+    ) -> Self {
+        Self {
+            users: Arc::new(RwLock::new(Vec::new())),
+            display_names: Arc::new(RwLock::new(HashMap::new())),
+            room_map,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            username_to_tcp_command_tx: Arc::new(Mutex::new(HashMap::new())),
+            rooms_changed: Arc::new(Notify::new()),
+            keep_empty_rooms,
+            encrypt_media,
+        }
+    }
+}
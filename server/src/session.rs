@@ -0,0 +1,28 @@
+use std::time::{Duration, Instant};
+
+use shared::StreamID;
+
+pub const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+pub struct Session {
+    pub username: String,
+    pub sid: Option<StreamID>,
+    pub disconnected_at: Option<Instant>,
+    pub compress_lists: bool,
+}
+
+impl Session {
+    pub fn new(username: &str, compress_lists: bool) -> Self {
+        Self {
+            username: username.to_string(),
+            sid: None,
+            disconnected_at: None,
+            compress_lists,
+        }
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        self.disconnected_at
+            .is_some_and(|disconnected_at| disconnected_at.elapsed() < SESSION_GRACE_PERIOD)
+    }
+}
@@ -4,49 +4,156 @@ use std::{collections::HashMap, sync::Arc, vec};
 use log::{error, info, warn};
 use rand::fill;
 use shared::{
-    MAX_NAME_LENGTH, RoomID, StreamID, is_valid_name, tcp_command::TcpCommand,
-    tcp_command_id::TcpCommandId,
-};
-use tokio::{
-    net::TcpStream,
-    sync::{Mutex, RwLock, broadcast},
+    MAX_CHAT_MESSAGE_LENGTH, NameError, RoomID, StreamID, control_stream::ControlStream,
+    protocol_error::ProtocolError, sanitize_chat_message, tcp_command::TcpCommand,
+    tcp_command_id::TcpCommandId, user_list_label, validate_display_name, validate_name,
 };
+use tokio::sync::{Mutex, RwLock, broadcast};
 
-use crate::room::Room;
+use crate::{
+    room::{DEFAULT_MAX_ROOM_USERS, MAX_ROOM_USERS, Room, leave_room},
+    server_state::ServerState,
+};
 
 pub struct TcpCommandHandler;
 
 impl TcpCommandHandler {
     pub async fn handle_command(
         incoming_command: &TcpCommand,
-        stream: &mut TcpStream,
+        stream: &mut dyn ControlStream,
         current_username: &str,
         current_sid_option: &mut Option<StreamID>,
-        users: Arc<RwLock<Vec<String>>>,
-        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
-        username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+        state: &ServerState,
+        compress_lists: bool,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let users = state.users.clone();
+        let display_names = state.display_names.clone();
+        let room_map = state.room_map.clone();
+        let username_to_tcp_command_tx = state.username_to_tcp_command_tx.clone();
+
         let result = match incoming_command {
             TcpCommand::Simple(TcpCommandId::GetUserList) => {
-                Self::handle_get_user_list(stream, users).await
+                Self::handle_get_user_list(stream, users, display_names, compress_lists).await
             }
             TcpCommand::Simple(TcpCommandId::GetRoomList) => {
-                Self::handle_get_room_list(stream, room_map).await
+                Self::handle_get_room_list(stream, room_map, compress_lists).await
             }
-            TcpCommand::String(TcpCommandId::CreateRoom, room_name) => {
-                Self::handle_create_room(stream, room_map, room_name).await
+            TcpCommand::Simple(TcpCommandId::GetMyStreamStatus) => {
+                Self::handle_get_my_stream_status(stream, current_username, room_map).await
+            }
+            TcpCommand::String(TcpCommandId::GetRoomMembers, room_name) => {
+                Self::handle_get_room_members(
+                    stream,
+                    room_map,
+                    display_names,
+                    room_name,
+                    compress_lists,
+                )
+                .await
+            }
+            TcpCommand::String(TcpCommandId::SetDisplayName, display_name) => {
+                Self::handle_set_display_name(stream, current_username, display_names, display_name)
+                    .await
+            }
+            TcpCommand::StringList(TcpCommandId::CreateRoom, room_args) => {
+                match parse_create_room_args(room_args) {
+                    Some((room_name, password, max_users)) => {
+                        Self::handle_create_room(
+                            stream,
+                            current_username,
+                            room_map,
+                            room_name,
+                            password,
+                            max_users,
+                        )
+                        .await
+                    }
+                    None => {
+                        Self::send_error_response(
+                            stream,
+                            ProtocolError::rejected("Invalid create room request"),
+                        )
+                        .await
+                    }
+                }
             }
             TcpCommand::String(TcpCommandId::DeleteRoom, room_name) => {
                 Self::handle_delete_room(stream, room_map, room_name).await
             }
-            TcpCommand::String(TcpCommandId::JoinRoom, room_name) => {
-                Self::handle_join_room(
+            TcpCommand::StringList(TcpCommandId::RenameRoom, room_args) => {
+                match room_args.as_slice() {
+                    [room_name, new_room_name] => {
+                        Self::handle_rename_room(
+                            stream,
+                            current_username,
+                            room_map,
+                            room_name,
+                            new_room_name,
+                            username_to_tcp_command_tx,
+                        )
+                        .await
+                    }
+                    _ => {
+                        Self::send_error_response(
+                            stream,
+                            ProtocolError::rejected("Invalid rename room request"),
+                        )
+                        .await
+                    }
+                }
+            }
+            TcpCommand::StringList(TcpCommandId::JoinRoom, room_args) => {
+                match parse_room_args(room_args) {
+                    Some((room_name, password)) => {
+                        Self::handle_join_room(
+                            stream,
+                            current_username,
+                            current_sid_option,
+                            state,
+                            room_name,
+                            password,
+                        )
+                        .await
+                    }
+                    None => {
+                        Self::send_error_response(
+                            stream,
+                            ProtocolError::rejected("Invalid join room request"),
+                        )
+                        .await
+                    }
+                }
+            }
+            TcpCommand::StringList(TcpCommandId::ChatMessage, payload) => {
+                match payload.as_slice() {
+                    [text] => {
+                        Self::handle_chat_message(
+                            stream,
+                            current_username,
+                            current_sid_option,
+                            room_map,
+                            text,
+                            username_to_tcp_command_tx,
+                        )
+                        .await
+                    }
+                    _ => {
+                        Self::send_error_response(
+                            stream,
+                            ProtocolError::rejected("Invalid chat message"),
+                        )
+                        .await
+                    }
+                }
+            }
+            TcpCommand::String(TcpCommandId::KickUser, target_username) => {
+                Self::handle_kick_user(
                     stream,
                     current_username,
-                    current_sid_option,
                     room_map,
-                    room_name,
+                    target_username,
                     username_to_tcp_command_tx,
+                    state.keep_empty_rooms,
                 )
                 .await
             }
@@ -56,6 +163,7 @@ impl TcpCommandHandler {
                     current_sid_option,
                     room_map,
                     username_to_tcp_command_tx,
+                    state.keep_empty_rooms,
                 )
                 .await
             }
@@ -63,7 +171,7 @@ impl TcpCommandHandler {
                 warn!("Unhandled command received: {:?}", incoming_command);
                 Self::send_error_response(
                     stream,
-                    &format!("Command not supported: {:?}", incoming_command),
+                    ProtocolError::UnsupportedCommand(format!("{:?}", incoming_command)),
                 )
                 .await
             }
@@ -73,27 +181,40 @@ impl TcpCommandHandler {
             error!("Error handling command {:?}: {}", incoming_command, e);
         }
 
+        // Leaving or kicking can delete a room once it is empty
+        if matches!(
+            incoming_command,
+            TcpCommand::StringList(TcpCommandId::CreateRoom | TcpCommandId::RenameRoom, _)
+                | TcpCommand::String(TcpCommandId::DeleteRoom | TcpCommandId::KickUser, _)
+                | TcpCommand::Simple(TcpCommandId::LeaveRoom)
+        ) {
+            state.rooms_changed.notify_one();
+        }
+
         result
     }
 
     async fn handle_get_user_list(
-        stream: &mut TcpStream,
+        stream: &mut dyn ControlStream,
         users: Arc<RwLock<Vec<String>>>,
+        display_names: Arc<RwLock<HashMap<String, String>>>,
+        compress_lists: bool,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let users_snapshot = {
             let guard = users.read().await;
-            guard.clone()
+            list_labels(&guard, &*display_names.read().await)
         };
 
-        TcpCommand::StringList(TcpCommandId::UserList, users_snapshot)
-            .write_to_stream(stream)
+        let command = TcpCommand::StringList(TcpCommandId::UserList, users_snapshot);
+        Self::write_list(stream, &command, compress_lists)
             .await
             .map_err(|e| format!("Failed to send user list: {}", e).into())
     }
 
     async fn handle_get_room_list(
-        stream: &mut TcpStream,
+        stream: &mut dyn ControlStream,
         room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        compress_lists: bool,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let room_names = {
             let guard = room_map.read().await;
@@ -103,36 +224,143 @@ impl TcpCommandHandler {
                 .collect::<Vec<_>>()
         };
 
-        TcpCommand::StringList(TcpCommandId::RoomList, room_names)
-            .write_to_stream(stream)
+        let command = TcpCommand::StringList(TcpCommandId::RoomList, room_names);
+        Self::write_list(stream, &command, compress_lists)
             .await
             .map_err(|e| format!("Failed to send room list: {}", e).into())
     }
 
+    async fn handle_get_room_members(
+        stream: &mut dyn ControlStream,
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        display_names: Arc<RwLock<HashMap<String, String>>>,
+        room_name: &str,
+        compress_lists: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let display_names_guard = display_names.read().await;
+        let members = room_map
+            .read()
+            .await
+            .values()
+            .find(|room| room.name == room_name)
+            .map(|room| list_labels(&room.users, &display_names_guard));
+        drop(display_names_guard);
+
+        match members {
+            Some(members) => Self::write_list(
+                stream,
+                &TcpCommand::StringList(TcpCommandId::RoomMembers, members),
+                compress_lists,
+            )
+            .await
+            .map_err(|e| format!("Failed to send room members: {}", e).into()),
+            None => {
+                Self::send_error_response(
+                    stream,
+                    ProtocolError::RoomNotFound(room_name.to_string()),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Reports, for each of the user's streams, whether a UDP packet from it has arrived so
+    /// the server knows where to forward to. A stream that stays unbound usually means the
+    /// client's UDP traffic isn't reaching the server.
+    async fn handle_get_my_stream_status(
+        stream: &mut dyn ControlStream,
+        current_username: &str,
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut payload = Vec::new();
+
+        {
+            let room_map_guard = room_map.read().await;
+            for room in room_map_guard.values() {
+                let sid_map = room.stream_id_to_socket_addr.lock().await;
+                for (sid, username) in &room.stream_id_to_username {
+                    if username == current_username {
+                        let bound = sid_map.get(sid).is_some_and(|addr| addr.is_some());
+                        payload.extend_from_slice(sid);
+                        payload.push(bound as u8);
+                    }
+                }
+            }
+        }
+
+        TcpCommand::Bytes(TcpCommandId::MyStreamStatus, payload)
+            .write_to_stream(stream)
+            .await
+    }
+
+    async fn handle_set_display_name(
+        stream: &mut dyn ControlStream,
+        current_username: &str,
+        display_names: Arc<RwLock<HashMap<String, String>>>,
+        display_name: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Err(error) = validate_display_name(display_name) {
+            return Self::send_error_response(
+                stream,
+                ProtocolError::rejected(format!("Display name {}", error)),
+            )
+            .await;
+        }
+
+        display_names
+            .write()
+            .await
+            .insert(current_username.to_string(), display_name.to_string());
+
+        info!(
+            "User '{}' set their display name to '{}'",
+            current_username, display_name
+        );
+
+        TcpCommand::Simple(TcpCommandId::SetDisplayNameSuccess)
+            .write_to_stream(stream)
+            .await
+    }
+
+    async fn write_list(
+        stream: &mut dyn ControlStream,
+        command: &TcpCommand,
+        compress_lists: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if compress_lists {
+            command.write_to_stream_compressed(stream).await
+        } else {
+            command.write_to_stream(stream).await
+        }
+    }
+
     async fn handle_create_room(
-        stream: &mut TcpStream,
+        stream: &mut dyn ControlStream,
+        current_username: &str,
         room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
         room_name: &str,
+        password: Option<&str>,
+        max_users: usize,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        if room_name.trim().is_empty() {
-            return Self::send_error_response(stream, "Room name cannot be empty").await;
+        if let Err(error) = validate_name(room_name) {
+            return Self::send_error_response(stream, room_name_error(error)).await;
         }
 
-        if room_name.len() > MAX_NAME_LENGTH {
+        if password.is_some_and(|password| password.is_empty()) {
             return Self::send_error_response(
                 stream,
-                &format!(
-                    "Room name must be less than or equal to {} characters.",
-                    MAX_NAME_LENGTH
-                ),
+                ProtocolError::rejected("Room password cannot be empty"),
             )
             .await;
         }
 
-        if !is_valid_name(room_name) {
+        if !(1..=MAX_ROOM_USERS).contains(&max_users) {
             return Self::send_error_response(
                 stream,
-                "Room name must contain only letters, numbers, underscores (_), or hyphens (-).",
+                ProtocolError::rejected(format!(
+                    "Room capacity must be between 1 and {}.",
+                    MAX_ROOM_USERS
+                )),
             )
             .await;
         }
@@ -140,11 +368,11 @@ impl TcpCommandHandler {
         let insert_result = {
             let mut room_map_guard = room_map.write().await;
             if room_map_guard.values().any(|room| room.name == room_name) {
-                Err(format!("Room '{}' already exists", room_name))
+                Err(ProtocolError::RoomAlreadyExists(room_name.to_string()))
             } else {
                 let mut room_id = RoomID::default();
                 fill(&mut room_id);
-                let new_room = Room::new(room_name);
+                let new_room = Room::new(room_name, password, max_users, current_username);
                 room_map_guard.insert(room_id, new_room);
                 Ok(room_id)
             }
@@ -164,17 +392,17 @@ impl TcpCommandHandler {
                         format!("Failed to send create room success response: {}", e).into()
                     })
             }
-            Err(msg) => Self::send_error_response(stream, &msg).await,
+            Err(error) => Self::send_error_response(stream, error).await,
         }
     }
 
     async fn handle_delete_room(
-        stream: &mut TcpStream,
+        stream: &mut dyn ControlStream,
         room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
         room_name: &str,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         if room_name.trim().is_empty() {
-            return Self::send_error_response(stream, "Room name cannot be empty").await;
+            return Self::send_error_response(stream, room_name_error(NameError::Empty)).await;
         }
 
         let room_id_result = {
@@ -188,16 +416,16 @@ impl TcpCommandHandler {
             match room_entry {
                 Some((room_id, user_count)) => {
                     if user_count > 0 {
-                        Err(format!(
-                            "Room '{}' cannot be deleted because it still has {} active user(s).",
-                            room_name, user_count
-                        ))
+                        Err(ProtocolError::RoomNotEmpty {
+                            room: room_name.to_string(),
+                            user_count,
+                        })
                     } else {
                         room_map_guard.remove(&room_id);
                         Ok(room_id)
                     }
                 }
-                None => Err(format!("Room '{}' does not exist", room_name)),
+                None => Err(ProtocolError::RoomNotFound(room_name.to_string())),
             }
         };
 
@@ -215,139 +443,823 @@ impl TcpCommandHandler {
                         format!("Failed to send delete room success response: {}", e).into()
                     })
             }
-            Err(msg) => Self::send_error_response(stream, &msg).await,
+            Err(error) => Self::send_error_response(stream, error).await,
         }
     }
 
-    async fn handle_join_room(
-        stream: &mut TcpStream,
+    async fn handle_rename_room(
+        stream: &mut dyn ControlStream,
         current_username: &str,
-        current_sid_option: &mut Option<StreamID>,
         room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
         room_name: &str,
+        new_room_name: &str,
         username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Err(error) = validate_name(new_room_name) {
+            return Self::send_error_response(stream, room_name_error(error)).await;
+        }
+
+        let rename_result = {
+            let mut room_map_guard = room_map.write().await;
+
+            if room_map_guard
+                .values()
+                .any(|room| room.name == new_room_name)
+            {
+                Err(ProtocolError::RoomAlreadyExists(new_room_name.to_string()))
+            } else {
+                match room_map_guard
+                    .values_mut()
+                    .find(|room| room.name == room_name)
+                {
+                    Some(room) if room.creator != current_username => {
+                        Err(ProtocolError::NotRoomCreator {
+                            room: room.name.clone(),
+                            action: "rename it".to_string(),
+                        })
+                    }
+                    Some(room) => {
+                        room.name = new_room_name.to_string();
+                        Ok(room.users.clone())
+                    }
+                    None => Err(ProtocolError::RoomNotFound(room_name.to_string())),
+                }
+            }
+        };
+
+        let room_users = match rename_result {
+            Ok(room_users) => room_users,
+            Err(error) => return Self::send_error_response(stream, error).await,
+        };
+
+        info!(
+            "Room '{}' was renamed to '{}' by '{}'",
+            room_name, new_room_name, current_username
+        );
+
+        let command = TcpCommand::StringList(
+            TcpCommandId::RoomRenamed,
+            vec![room_name.to_string(), new_room_name.to_string()],
+        );
+
+        {
+            let tx_map = username_to_tcp_command_tx.lock().await;
+            for user in &room_users {
+                if let Some(tx) = tx_map.get(user) {
+                    let _ = tx.send(command.clone());
+                }
+            }
+        }
+
+        TcpCommand::Simple(TcpCommandId::RenameRoomSuccess)
+            .write_to_stream(stream)
+            .await
+            .map_err(|e| format!("Failed to send rename room success response: {}", e).into())
+    }
+
+    async fn handle_join_room(
+        stream: &mut dyn ControlStream,
+        current_username: &str,
+        current_sid_option: &mut Option<StreamID>,
+        state: &ServerState,
+        room_name: &str,
+        password: Option<&str>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         if room_name.trim().is_empty() {
-            return Self::send_error_response(stream, "Room name cannot be empty").await;
+            return Self::send_error_response(stream, room_name_error(NameError::Empty)).await;
         }
 
         let mut sid = StreamID::default();
         fill(&mut sid);
 
-        let (room_id_opt, other_users, other_sids) = {
-            let mut room_map_guard = room_map.write().await;
+        let join_result = {
+            let mut room_map_guard = state.room_map.write().await;
 
             if let Some((room_id, room)) = room_map_guard
                 .iter_mut()
                 .find(|(_, room)| room.name == room_name)
             {
-                let mut sid_map = room.stream_id_to_socket_addr.lock().await;
-                let other_sids = sid_map.keys().cloned().collect::<Vec<_>>();
+                if !room.accepts_password(password) {
+                    Err(ProtocolError::IncorrectPassword(room_name.to_string()))
+                } else if room.users.len() >= room.max_users {
+                    Err(ProtocolError::RoomFull {
+                        room: room_name.to_string(),
+                        max_users: room.max_users,
+                    })
+                } else {
+                    let mut sid_map = room.stream_id_to_socket_addr.lock().await;
+                    let other_sids = sid_map
+                        .keys()
+                        .map(|other_sid| {
+                            let username = room
+                                .stream_id_to_username
+                                .get(other_sid)
+                                .cloned()
+                                .unwrap_or_default();
+                            (*other_sid, username)
+                        })
+                        .collect::<Vec<_>>();
 
-                sid_map.insert(sid, None);
+                    sid_map.insert(sid, None);
 
-                drop(sid_map);
+                    drop(sid_map);
 
-                let other_users = room.users.clone();
-                room.users.push(current_username.to_string());
+                    room.stream_id_to_username
+                        .insert(sid, current_username.to_string());
 
-                *current_sid_option = Some(sid);
+                    let other_users = room.users.clone();
+                    room.users.push(current_username.to_string());
 
-                (Some(*room_id), other_users, other_sids)
+                    *current_sid_option = Some(sid);
+
+                    Ok((*room_id, room.media_key, other_users, other_sids))
+                }
             } else {
-                (None, vec![], vec![])
+                Err(ProtocolError::RoomNotFound(room_name.to_string()))
             }
         };
 
-        match room_id_opt {
-            Some(rid) => {
+        match join_result {
+            Ok((rid, media_key, other_users, other_sids)) => {
                 let mut payload = Vec::from(rid);
                 payload.extend_from_slice(&sid);
+                if state.encrypt_media {
+                    payload.extend_from_slice(&media_key);
+                }
 
                 TcpCommand::Bytes(TcpCommandId::JoinRoomSuccess, payload)
                     .write_to_stream(stream)
                     .await?;
 
+                let display_names_guard = state.display_names.read().await;
+
+                let mut joined_payload = sid.to_vec();
+                joined_payload.extend_from_slice(
+                    tile_name(current_username, &display_names_guard).as_bytes(),
+                );
+
                 for user in other_users {
-                    let cmd = TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, sid.to_vec());
+                    let cmd = TcpCommand::Bytes(
+                        TcpCommandId::OtherUserJoinedRoom,
+                        joined_payload.clone(),
+                    );
 
-                    if let Some(tx) = username_to_tcp_command_tx.lock().await.get(&user) {
+                    if let Some(tx) = state.username_to_tcp_command_tx.lock().await.get(&user) {
                         tx.send(cmd)?;
                     }
                 }
 
-                for sid in other_sids {
-                    TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, sid.to_vec())
+                let other_sids = other_sids
+                    .into_iter()
+                    .map(|(sid, username)| (sid, tile_name(&username, &display_names_guard)))
+                    .collect::<Vec<_>>();
+                drop(display_names_guard);
+
+                for (sid, username) in other_sids {
+                    let mut payload = sid.to_vec();
+                    payload.extend_from_slice(username.as_bytes());
+
+                    TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, payload)
                         .write_to_stream(stream)
                         .await?;
                 }
             }
-            None => {
-                Self::send_error_response(stream, &format!("Room '{}' does not exist", room_name))
-                    .await?;
+            Err(error) => {
+                Self::send_error_response(stream, error).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn handle_leave_room(
+    async fn handle_chat_message(
+        stream: &mut dyn ControlStream,
         current_username: &str,
-        current_sid_option: &mut Option<StreamID>,
+        current_sid_option: &Option<StreamID>,
         room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        text: &str,
         username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut leaving_sid: Option<StreamID> = None;
-        let mut affected_users: Vec<String> = vec![];
-
-        let current_sid = match current_sid_option {
-            Some(sid) => *sid,
-            None => return Err("No sid found".into()),
+        let sid = match current_sid_option {
+            Some(sid) => sid,
+            None => {
+                return Self::send_error_response(
+                    stream,
+                    ProtocolError::rejected("You must be in a room to chat"),
+                )
+                .await;
+            }
         };
 
-        {
-            let mut map_guard = room_map.write().await;
+        let text = sanitize_chat_message(text);
+        if text.is_empty() {
+            return Ok(());
+        }
 
-            for room in map_guard.values_mut() {
-                if let Some(_) = room
-                    .stream_id_to_socket_addr
-                    .lock()
-                    .await
-                    .remove(&current_sid)
-                {
-                    leaving_sid = Some(current_sid);
+        if text.chars().count() > MAX_CHAT_MESSAGE_LENGTH {
+            return Self::send_error_response(
+                stream,
+                ProtocolError::rejected(format!(
+                    "Chat message must be less than or equal to {} characters.",
+                    MAX_CHAT_MESSAGE_LENGTH
+                )),
+            )
+            .await;
+        }
 
-                    room.users.retain(|user| user != &current_username);
-                    affected_users = room.users.clone();
+        let room_users = room_map
+            .read()
+            .await
+            .values()
+            .find(|room| room.stream_id_to_username.contains_key(sid))
+            .map(|room| room.users.clone())
+            .unwrap_or_default();
 
-                    *current_sid_option = None;
+        let command = TcpCommand::StringList(
+            TcpCommandId::ChatMessage,
+            vec![current_username.to_string(), text],
+        );
 
-                    break;
-                }
+        let username_to_tcp_command_tx_guard = username_to_tcp_command_tx.lock().await;
+        for user in room_users {
+            if let Some(tx) = username_to_tcp_command_tx_guard.get(&user) {
+                let _ = tx.send(command.clone());
             }
         }
 
-        if let Some(sid) = leaving_sid {
-            let tx_map = username_to_tcp_command_tx.lock().await;
-            let cmd = TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid.to_vec());
+        Ok(())
+    }
+
+    async fn handle_kick_user(
+        stream: &mut dyn ControlStream,
+        current_username: &str,
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        target_username: &str,
+        username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+        keep_empty_rooms: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if target_username == current_username {
+            return Self::send_error_response(
+                stream,
+                ProtocolError::rejected("You cannot kick yourself"),
+            )
+            .await;
+        }
+
+        let kick_result = {
+            let room_map_guard = room_map.read().await;
 
-            for user in affected_users {
-                if let Some(tx) = tx_map.get(&user) {
-                    let _ = tx.send(cmd.clone());
+            match room_map_guard
+                .values()
+                .find(|room| room.users.iter().any(|user| user == target_username))
+            {
+                Some(room) if room.creator != current_username => {
+                    Err(ProtocolError::NotRoomCreator {
+                        room: room.name.clone(),
+                        action: "kick users".to_string(),
+                    })
                 }
+                Some(room) => {
+                    let kicked_sids = room
+                        .stream_id_to_username
+                        .iter()
+                        .filter(|(_, username)| *username == target_username)
+                        .map(|(sid, _)| *sid)
+                        .collect::<Vec<_>>();
+
+                    Ok((kicked_sids, room.name.clone()))
+                }
+                None => Err(ProtocolError::UserNotInRoom(target_username.to_string())),
             }
+        };
+
+        let (kicked_sids, room_name) = match kick_result {
+            Ok(kick) => kick,
+            Err(error) => return Self::send_error_response(stream, error).await,
+        };
+
+        // Leaving the same way a LeaveRoom does tells the others once and drops an emptied room
+        for sid in kicked_sids {
+            leave_room(
+                target_username,
+                sid,
+                &room_map,
+                &username_to_tcp_command_tx,
+                keep_empty_rooms,
+            )
+            .await;
+        }
+
+        info!(
+            "User '{}' was kicked from room '{}' by '{}'",
+            target_username, room_name, current_username
+        );
+
+        if let Some(tx) = username_to_tcp_command_tx.lock().await.get(target_username) {
+            let _ = tx.send(TcpCommand::Simple(TcpCommandId::YouWereKicked));
+        }
+
+        Ok(())
+    }
+
+    async fn handle_leave_room(
+        current_username: &str,
+        current_sid_option: &mut Option<StreamID>,
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+        username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+        keep_empty_rooms: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Taking the sid makes a later disconnect skip the room, so leaving happens once
+        if let Some(sid) = current_sid_option.take() {
+            leave_room(
+                current_username,
+                sid,
+                &room_map,
+                &username_to_tcp_command_tx,
+                keep_empty_rooms,
+            )
+            .await;
         }
 
         Ok(())
     }
 
     async fn send_error_response(
-        stream: &mut TcpStream,
-        error_message: &str,
+        stream: &mut dyn ControlStream,
+        error: ProtocolError,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        TcpCommand::String(TcpCommandId::ErrorResponse, error_message.to_string())
+        error
+            .to_response()
             .write_to_stream(stream)
             .await
             .map_err(|e| format!("Failed to send error response: {}", e).into())
     }
 }
+
+/// Name shown on `username`'s video tile: their display name if they set one.
+pub fn tile_name(username: &str, display_names: &HashMap<String, String>) -> String {
+    display_names
+        .get(username)
+        .cloned()
+        .unwrap_or_else(|| username.to_string())
+}
+
+fn list_labels(usernames: &[String], display_names: &HashMap<String, String>) -> Vec<String> {
+    usernames
+        .iter()
+        .map(|username| user_list_label(username, display_names.get(username).map(String::as_str)))
+        .collect()
+}
+
+fn room_name_error(error: NameError) -> ProtocolError {
+    ProtocolError::rejected(format!("Room name {}", error))
+}
+
+fn parse_create_room_args(room_args: &[String]) -> Option<(&str, Option<&str>, usize)> {
+    match room_args {
+        [room_name, password, max_users] => {
+            let password = Some(password.as_str()).filter(|password| !password.is_empty());
+            Some((room_name, password, max_users.parse().ok()?))
+        }
+        _ => parse_room_args(room_args)
+            .map(|(room_name, password)| (room_name, password, DEFAULT_MAX_ROOM_USERS)),
+    }
+}
+
+fn parse_room_args(room_args: &[String]) -> Option<(&str, Option<&str>)> {
+    match room_args {
+        [room_name] => Some((room_name, None)),
+        [room_name, password] => Some((room_name, Some(password))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::received_tcp_command::ReceivedTcpCommand;
+    use tokio::io::{DuplexStream, duplex};
+
+    use super::*;
+
+    const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+    struct TestUser {
+        username: String,
+        sid: Option<StreamID>,
+        server_end: DuplexStream,
+        client_end: DuplexStream,
+        notifications: broadcast::Receiver<TcpCommand>,
+    }
+
+    impl TestUser {
+        async fn connect(state: &ServerState, username: &str) -> Self {
+            let (client_end, server_end) = duplex(DUPLEX_BUFFER_SIZE);
+            let (tx, notifications) = broadcast::channel(16);
+
+            state.users.write().await.push(username.to_string());
+            state
+                .username_to_tcp_command_tx
+                .lock()
+                .await
+                .insert(username.to_string(), tx);
+
+            Self {
+                username: username.to_string(),
+                sid: None,
+                server_end,
+                client_end,
+                notifications,
+            }
+        }
+
+        async fn send(&mut self, state: &ServerState, command: TcpCommand) {
+            TcpCommandHandler::handle_command(
+                &command,
+                &mut self.server_end,
+                &self.username,
+                &mut self.sid,
+                state,
+                false,
+            )
+            .await
+            .unwrap();
+        }
+
+        /// Reads the next response, skipping announcements of members already in the room.
+        async fn response(&mut self) -> TcpCommand {
+            loop {
+                match TcpCommand::read_from_stream(&mut self.client_end)
+                    .await
+                    .unwrap()
+                {
+                    ReceivedTcpCommand::Command(TcpCommand::Bytes(
+                        TcpCommandId::OtherUserJoinedRoom,
+                        _,
+                    )) => continue,
+                    ReceivedTcpCommand::Command(command) => return command,
+                    ReceivedTcpCommand::EOF => panic!("Stream closed before a response"),
+                }
+            }
+        }
+
+        async fn request(&mut self, state: &ServerState, command: TcpCommand) -> TcpCommand {
+            self.send(state, command).await;
+            self.response().await
+        }
+
+        async fn join(&mut self, state: &ServerState, room_args: &[&str]) -> TcpCommand {
+            let room_args = room_args.iter().map(|arg| arg.to_string()).collect();
+            self.request(
+                state,
+                TcpCommand::StringList(TcpCommandId::JoinRoom, room_args),
+            )
+            .await
+        }
+    }
+
+    fn test_state() -> ServerState {
+        ServerState::new(Arc::new(RwLock::new(HashMap::new())), false, false)
+    }
+
+    async fn create_room(state: &ServerState, room_args: &[&str]) {
+        let mut creator = TestUser::connect(state, "creator").await;
+        let room_args = room_args.iter().map(|arg| arg.to_string()).collect();
+        let response = creator
+            .request(
+                state,
+                TcpCommand::StringList(TcpCommandId::CreateRoom, room_args),
+            )
+            .await;
+        assert!(
+            matches!(
+                response,
+                TcpCommand::Simple(TcpCommandId::CreateRoomSuccess)
+            ),
+            "Unexpected create room response: {:?}",
+            response
+        );
+    }
+
+    fn protocol_error(response: TcpCommand) -> ProtocolError {
+        match response {
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, message) => {
+                ProtocolError::from_response(&message)
+            }
+            command => panic!("Expected an error response, got {:?}", command),
+        }
+    }
+
+    fn is_join_success(response: &TcpCommand) -> bool {
+        matches!(
+            response,
+            TcpCommand::Bytes(TcpCommandId::JoinRoomSuccess, _)
+        )
+    }
+
+    #[tokio::test]
+    async fn correct_password_joins_room() {
+        let state = test_state();
+        create_room(&state, &["lobby", "secret", "8"]).await;
+
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(
+            &bob.join(&state, &["lobby", "secret"]).await
+        ));
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(
+            &alice.join(&state, &["lobby", "secret"]).await
+        ));
+        assert!(matches!(
+            bob.notifications.try_recv(),
+            Ok(TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_or_missing_password_is_rejected() {
+        let state = test_state();
+        create_room(&state, &["lobby", "secret", "8"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert_eq!(
+            protocol_error(alice.join(&state, &["lobby", "guess"]).await),
+            ProtocolError::IncorrectPassword("lobby".to_string())
+        );
+        assert_eq!(
+            protocol_error(alice.join(&state, &["lobby"]).await),
+            ProtocolError::IncorrectPassword("lobby".to_string())
+        );
+        assert!(alice.sid.is_none());
+    }
+
+    #[tokio::test]
+    async fn room_without_password_is_joinable() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+    }
+
+    #[tokio::test]
+    async fn join_beyond_capacity_is_rejected() {
+        let state = test_state();
+        create_room(&state, &["lobby", "", "2"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+
+        let mut carol = TestUser::connect(&state, "carol").await;
+        assert_eq!(
+            protocol_error(carol.join(&state, &["lobby"]).await),
+            ProtocolError::RoomFull {
+                room: "lobby".to_string(),
+                max_users: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_reaches_room_members_but_not_other_rooms() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+        create_room(&state, &["studio"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+        let mut carol = TestUser::connect(&state, "carol").await;
+        assert!(is_join_success(&carol.join(&state, &["studio"]).await));
+
+        alice
+            .send(
+                &state,
+                TcpCommand::StringList(TcpCommandId::ChatMessage, vec!["hi".to_string()]),
+            )
+            .await;
+
+        match bob.notifications.try_recv() {
+            Ok(TcpCommand::StringList(TcpCommandId::ChatMessage, payload)) => {
+                assert_eq!(payload, ["alice", "hi"]);
+            }
+            received => panic!("Expected a chat message, got {:?}", received),
+        }
+        assert!(matches!(
+            carol.notifications.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn member_list_follows_room_users() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+
+        let get_members = || TcpCommand::String(TcpCommandId::GetRoomMembers, "lobby".to_string());
+
+        match alice.request(&state, get_members()).await {
+            TcpCommand::StringList(TcpCommandId::RoomMembers, members) => {
+                assert_eq!(members, ["alice", "bob"]);
+            }
+            command => panic!("Unexpected members response: {:?}", command),
+        }
+
+        bob.send(&state, TcpCommand::Simple(TcpCommandId::LeaveRoom))
+            .await;
+
+        match alice.request(&state, get_members()).await {
+            TcpCommand::StringList(TcpCommandId::RoomMembers, members) => {
+                assert_eq!(members, ["alice"]);
+            }
+            command => panic!("Unexpected members response: {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn join_and_create_failures_arrive_as_their_variants() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        let create_lobby =
+            TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()]);
+        assert_eq!(
+            protocol_error(alice.request(&state, create_lobby).await),
+            ProtocolError::RoomAlreadyExists("lobby".to_string())
+        );
+        assert_eq!(
+            protocol_error(alice.join(&state, &["studio"]).await),
+            ProtocolError::RoomNotFound("studio".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn creator_kicks_a_member_who_is_announced_once() {
+        let state = test_state();
+        let mut alice = TestUser::connect(&state, "alice").await;
+        let create_lobby =
+            TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()]);
+        alice.request(&state, create_lobby).await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+        let bob_sid = bob.sid.unwrap();
+        let _ = alice.notifications.try_recv();
+
+        alice
+            .send(
+                &state,
+                TcpCommand::String(TcpCommandId::KickUser, "bob".to_string()),
+            )
+            .await;
+
+        assert!(matches!(
+            bob.notifications.try_recv(),
+            Ok(TcpCommand::Simple(TcpCommandId::YouWereKicked))
+        ));
+        match alice.notifications.try_recv() {
+            Ok(TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid)) => {
+                assert_eq!(sid, bob_sid);
+            }
+            received => panic!("Expected a leave notification, got {:?}", received),
+        }
+        assert!(matches!(
+            alice.notifications.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+
+        let room_map = state.room_map.read().await;
+        let lobby = room_map.values().next().unwrap();
+        assert_eq!(lobby.users, ["alice"]);
+        assert!(!lobby.stream_id_to_username.contains_key(&bob_sid));
+    }
+
+    #[tokio::test]
+    async fn kicking_the_last_member_removes_the_empty_room() {
+        let state = test_state();
+        let mut alice = TestUser::connect(&state, "alice").await;
+        let create_lobby =
+            TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()]);
+        alice.request(&state, create_lobby).await;
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+
+        alice
+            .send(
+                &state,
+                TcpCommand::String(TcpCommandId::KickUser, "bob".to_string()),
+            )
+            .await;
+
+        assert!(state.room_map.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_creator_may_kick() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert!(is_join_success(&bob.join(&state, &["lobby"]).await));
+
+        let kick_alice = TcpCommand::String(TcpCommandId::KickUser, "alice".to_string());
+        assert_eq!(
+            protocol_error(bob.request(&state, kick_alice).await),
+            ProtocolError::NotRoomCreator {
+                room: "lobby".to_string(),
+                action: "kick users".to_string(),
+            }
+        );
+        assert!(matches!(
+            alice.notifications.try_recv(),
+            Ok(TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, _))
+        ));
+        assert!(matches!(
+            alice.notifications.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    fn rename(room_name: &str, new_room_name: &str) -> TcpCommand {
+        TcpCommand::StringList(
+            TcpCommandId::RenameRoom,
+            vec![room_name.to_string(), new_room_name.to_string()],
+        )
+    }
+
+    #[tokio::test]
+    async fn creator_renames_a_room_and_members_are_told() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+
+        let mut alice = TestUser::connect(&state, "alice").await;
+        assert!(is_join_success(&alice.join(&state, &["lobby"]).await));
+
+        let mut creator = TestUser::connect(&state, "creator").await;
+        assert!(matches!(
+            creator.request(&state, rename("lobby", "studio")).await,
+            TcpCommand::Simple(TcpCommandId::RenameRoomSuccess)
+        ));
+
+        let room_names = state
+            .room_map
+            .read()
+            .await
+            .values()
+            .map(|room| room.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(room_names, ["studio"]);
+
+        assert!(matches!(
+            alice.notifications.try_recv(),
+            Ok(TcpCommand::StringList(TcpCommandId::RoomRenamed, names)) if names == ["lobby", "studio"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn rename_is_refused_for_taken_or_invalid_names_and_non_creators() {
+        let state = test_state();
+        create_room(&state, &["lobby"]).await;
+        create_room(&state, &["studio"]).await;
+
+        let mut creator = TestUser::connect(&state, "creator").await;
+        assert_eq!(
+            protocol_error(creator.request(&state, rename("lobby", "studio")).await),
+            ProtocolError::RoomAlreadyExists("studio".to_string())
+        );
+        assert_eq!(
+            protocol_error(creator.request(&state, rename("lobby", "big lobby")).await),
+            room_name_error(NameError::InvalidChar(' '))
+        );
+
+        let mut bob = TestUser::connect(&state, "bob").await;
+        assert_eq!(
+            protocol_error(bob.request(&state, rename("lobby", "bobs_room")).await),
+            ProtocolError::NotRoomCreator {
+                room: "lobby".to_string(),
+                action: "rename it".to_string(),
+            }
+        );
+
+        let mut room_names = state
+            .room_map
+            .read()
+            .await
+            .values()
+            .map(|room| room.name.clone())
+            .collect::<Vec<_>>();
+        room_names.sort();
+        assert_eq!(room_names, ["lobby", "studio"]);
+    }
+}
@@ -1,66 +1,83 @@
 use core::error::Error;
-use std::{collections::HashMap, sync::Arc};
+use std::time::Duration;
 
 use log::info;
+use rand::fill;
 use shared::{
-    MAX_NAME_LENGTH, RoomID, StreamID, is_valid_name, received_tcp_command::ReceivedTcpCommand,
-    tcp_command::TcpCommand, tcp_command_id::TcpCommandId,
+    LIST_COMPRESSION_FEATURE, SessionToken, StreamID, control_stream::ControlStream,
+    protocol_error::ProtocolError, received_tcp_command::ReceivedTcpCommand,
+    tcp_command::TcpCommand, tcp_command_id::TcpCommandId, tcp_command_reader::TcpCommandReader,
+    validate_name,
 };
 use tokio::{
-    net::TcpStream,
-    sync::{Mutex, RwLock, broadcast},
+    sync::{RwLock, broadcast},
+    time::{Instant, interval},
 };
 
-use crate::{room::Room, tcp_command_handler::TcpCommandHandler};
+use crate::{
+    server_state::ServerState,
+    session::Session,
+    tcp_command_handler::{TcpCommandHandler, tile_name},
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PONG_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct TcpHandler;
 
 impl TcpHandler {
     pub async fn handle_stream(
-        mut stream: TcpStream,
+        stream: &mut dyn ControlStream,
         current_username_option: &mut Option<String>,
         current_sid_option: &mut Option<StreamID>,
-        users: Arc<RwLock<Vec<String>>>,
-        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
-        username_to_tcp_command_tx: Arc<Mutex<HashMap<String, broadcast::Sender<TcpCommand>>>>,
+        current_session_token_option: &mut Option<SessionToken>,
+        state: &ServerState,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let current_username = match Self::handle_handshake(&mut stream, users.clone()).await? {
-            Some(username) => username,
-            None => return Ok(()),
-        };
-
-        let current_username = current_username.clone();
+        let (current_username, session_token, compress_lists) =
+            match Self::handle_handshake(stream, current_sid_option, state).await? {
+                Some(handshake) => handshake,
+                None => return Ok(()),
+            };
 
         *current_username_option = Some(current_username.clone());
-        users.write().await.push(current_username.clone());
+        *current_session_token_option = Some(session_token);
 
         let (tcp_command_channel_tx, mut tcp_command_channel_rx) = broadcast::channel(16);
 
-        username_to_tcp_command_tx
+        state
+            .username_to_tcp_command_tx
             .lock()
             .await
             .insert(current_username.clone(), tcp_command_channel_tx);
 
         info!("User '{}' has connected", current_username);
 
+        let mut ping_interval = interval(PING_INTERVAL);
+        let mut last_pong_time = Instant::now();
+        let mut command_reader = TcpCommandReader::new();
+
         loop {
             tokio::select! {
 
-                result = TcpCommand::read_from_stream(&mut stream) => {
+                result = command_reader.read_from_stream(stream) => {
 
                     let incoming_command = match result? {
                         ReceivedTcpCommand::EOF => return Ok(()),
                         ReceivedTcpCommand::Command(command) => command,
                     };
 
+                    if let TcpCommand::Simple(TcpCommandId::Pong) = incoming_command {
+                        last_pong_time = Instant::now();
+                        continue;
+                    }
+
                     TcpCommandHandler::handle_command(
                         &incoming_command,
-                        &mut stream,
+                        stream,
                         &current_username,
                         current_sid_option,
-                        users.clone(),
-                        room_map.clone(),
-                        username_to_tcp_command_tx.clone(),
+                        state,
+                        compress_lists,
                     )
                     .await?;
                 }
@@ -68,62 +85,485 @@ impl TcpHandler {
                 result = tcp_command_channel_rx.recv() => {
                     let outgoing_command = result?;
 
-                    outgoing_command.write_to_stream(&mut stream).await?;
+                    // The kick already took our stream out of the room, so don't leave it again
+                    if let TcpCommand::Simple(TcpCommandId::YouWereKicked) = outgoing_command {
+                        *current_sid_option = None;
+                    }
+
+                    outgoing_command.write_to_stream(stream).await?;
 
                 }
+
+                _ = ping_interval.tick() => {
+                    if last_pong_time.elapsed() > PONG_TIMEOUT {
+                        info!("User '{}' timed out waiting for pong", current_username);
+                        return Ok(());
+                    }
+
+                    TcpCommand::Simple(TcpCommandId::Ping)
+                        .write_to_stream(stream)
+                        .await?;
+                }
             }
         }
     }
 
     async fn handle_handshake(
-        stream: &mut TcpStream,
-        users: Arc<RwLock<Vec<String>>>,
-    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        stream: &mut dyn ControlStream,
+        current_sid_option: &mut Option<StreamID>,
+        state: &ServerState,
+    ) -> Result<Option<(String, SessionToken, bool)>, Box<dyn Error + Send + Sync>> {
         let received_command = match TcpCommand::read_from_stream(stream).await? {
             ReceivedTcpCommand::EOF => return Ok(None),
             ReceivedTcpCommand::Command(cmd) => cmd,
         };
 
-        let received_username = match received_command {
-            TcpCommand::String(TcpCommandId::HelloFromClient, username) => username,
-            _ => return Err("Invalid hello command from client".into()),
+        let (received_username, compress_lists) = match received_command {
+            TcpCommand::String(TcpCommandId::HelloFromClient, username) => (username, false),
+            TcpCommand::StringList(TcpCommandId::HelloWithFeatures, mut payload)
+                if !payload.is_empty() =>
+            {
+                let features = payload.split_off(1);
+                let compress_lists = features
+                    .iter()
+                    .any(|feature| feature == LIST_COMPRESSION_FEATURE);
+                (payload.remove(0), compress_lists)
+            }
+            TcpCommand::Bytes(TcpCommandId::Resume, token) => {
+                return Self::handle_resume(stream, &token, current_sid_option, state).await;
+            }
+            command => {
+                // Say so explicitly, so a client knows to retry with a hello we understand
+                // rather than mistaking the hang-up for a dropped connection
+                ProtocolError::UnsupportedCommand(format!("{:?}", command))
+                    .to_response()
+                    .write_to_stream(stream)
+                    .await?;
+
+                return Err("Invalid hello command from client".into());
+            }
         };
 
-        if received_username.len() > MAX_NAME_LENGTH {
-            let error_message = format!(
-                "Username must be less than or equal to {} characters.",
-                MAX_NAME_LENGTH
-            );
-            TcpCommand::String(TcpCommandId::ErrorResponse, error_message)
+        if let Err(error) = validate_name(&received_username) {
+            ProtocolError::rejected(format!("Username {}", error))
+                .to_response()
                 .write_to_stream(stream)
                 .await?;
 
             return Ok(None);
         }
 
-        if !is_valid_name(&received_username) {
-            let error_message =
-                "Username must contain only letters, numbers, underscores (_), or hyphens (-).";
-            TcpCommand::String(TcpCommandId::ErrorResponse, error_message.to_string())
+        if !Self::claim_username(&state.users, &received_username).await {
+            ProtocolError::UsernameTaken
+                .to_response()
                 .write_to_stream(stream)
                 .await?;
 
             return Ok(None);
         }
 
-        if users.read().await.contains(&received_username) {
-            let error_message = "Username is already taken.";
-            TcpCommand::String(TcpCommandId::ErrorResponse, error_message.to_string())
-                .write_to_stream(stream)
-                .await?;
+        let mut session_token = SessionToken::default();
+        fill(&mut session_token);
 
-            return Ok(None);
+        state.sessions.lock().await.insert(
+            session_token,
+            Session::new(&received_username, compress_lists),
+        );
+
+        if let Err(e) = TcpCommand::Bytes(TcpCommandId::HelloFromServer, session_token.to_vec())
+            .write_to_stream(stream)
+            .await
+        {
+            state.sessions.lock().await.remove(&session_token);
+            state
+                .users
+                .write()
+                .await
+                .retain(|user| user != &received_username);
+            return Err(e);
         }
 
-        TcpCommand::Simple(TcpCommandId::HelloFromServer)
+        Ok(Some((received_username, session_token, compress_lists)))
+    }
+
+    async fn handle_resume(
+        stream: &mut dyn ControlStream,
+        token: &[u8],
+        current_sid_option: &mut Option<StreamID>,
+        state: &ServerState,
+    ) -> Result<Option<(String, SessionToken, bool)>, Box<dyn Error + Send + Sync>> {
+        let session_token: SessionToken = token
+            .try_into()
+            .map_err(|_| "Invalid session token from client")?;
+
+        let resumed = {
+            let mut sessions_guard = state.sessions.lock().await;
+            let room_map_guard = state.room_map.read().await;
+
+            match sessions_guard.get_mut(&session_token) {
+                Some(session) if session.is_resumable() => {
+                    let room_entry = session.sid.and_then(|sid| {
+                        room_map_guard
+                            .iter()
+                            .find(|(_, room)| room.stream_id_to_username.contains_key(&sid))
+                            .map(|(room_id, room)| (*room_id, sid, room))
+                    });
+
+                    match room_entry {
+                        Some((room_id, sid, room)) => {
+                            let mut sid_map = room.stream_id_to_socket_addr.lock().await;
+
+                            // The client reconnects with a fresh UDP socket, so forget the old
+                            // address and learn the new one from its next packet.
+                            sid_map.insert(sid, None);
+
+                            let other_sids = room
+                                .stream_id_to_username
+                                .iter()
+                                .filter(|(other_sid, _)| **other_sid != sid)
+                                .map(|(other_sid, username)| (*other_sid, username.clone()))
+                                .collect::<Vec<_>>();
+
+                            session.disconnected_at = None;
+
+                            Some((
+                                session.username.clone(),
+                                session.compress_lists,
+                                room_id,
+                                sid,
+                                room.media_key,
+                                other_sids,
+                            ))
+                        }
+                        None => None,
+                    }
+                }
+                _ => None,
+            }
+        };
+
+        let (username, compress_lists, room_id, sid, media_key, other_sids) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                ProtocolError::rejected("Session has expired and cannot be resumed.")
+                    .to_response()
+                    .write_to_stream(stream)
+                    .await?;
+
+                return Ok(None);
+            }
+        };
+
+        *current_sid_option = Some(sid);
+
+        let mut payload = Vec::from(room_id);
+        payload.extend_from_slice(&sid);
+        if state.encrypt_media {
+            payload.extend_from_slice(&media_key);
+        }
+
+        TcpCommand::Bytes(TcpCommandId::ResumeSuccess, payload)
             .write_to_stream(stream)
             .await?;
 
-        return Ok(Some(received_username));
+        let other_sids = {
+            let display_names_guard = state.display_names.read().await;
+            other_sids
+                .into_iter()
+                .map(|(other_sid, other_username)| {
+                    (other_sid, tile_name(&other_username, &display_names_guard))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (other_sid, other_username) in other_sids {
+            let mut payload = other_sid.to_vec();
+            payload.extend_from_slice(other_username.as_bytes());
+
+            TcpCommand::Bytes(TcpCommandId::OtherUserJoinedRoom, payload)
+                .write_to_stream(stream)
+                .await?;
+        }
+
+        info!("User '{}' resumed their session", username);
+
+        Ok(Some((username, session_token, compress_lists)))
+    }
+
+    async fn claim_username(users: &RwLock<Vec<String>>, username: &str) -> bool {
+        let mut users_guard = users.write().await;
+
+        if users_guard.iter().any(|user| user == username) {
+            return false;
+        }
+
+        users_guard.push(username.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use shared::RoomID;
+
+    use tokio::{
+        io::{DuplexStream, duplex},
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::{
+        room::{DEFAULT_MAX_ROOM_USERS, Room},
+        session::SESSION_GRACE_PERIOD,
+    };
+
+    const ROOM_ID: RoomID = [1; 4];
+    const SID: StreamID = [2; 4];
+
+    type HandlerResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+    fn test_state() -> ServerState {
+        ServerState::new(Arc::new(RwLock::new(HashMap::new())), false, false)
+    }
+
+    fn spawn_handler(state: ServerState) -> (DuplexStream, JoinHandle<HandlerResult>) {
+        let (client, mut server) = duplex(4096);
+
+        let handler = tokio::spawn(async move {
+            let (mut username, mut sid, mut session_token) = (None, None, None);
+            TcpHandler::handle_stream(
+                &mut server,
+                &mut username,
+                &mut sid,
+                &mut session_token,
+                &state,
+            )
+            .await
+        });
+
+        (client, handler)
+    }
+
+    /// State in which alice's session, which was in a call, dropped `disconnected_for` ago.
+    async fn state_with_dropped_session(disconnected_for: Duration) -> (ServerState, SessionToken) {
+        let mut room = Room::new("lobby", None, DEFAULT_MAX_ROOM_USERS, "alice");
+        room.users.push("alice".to_string());
+        room.stream_id_to_username.insert(SID, "alice".to_string());
+        room.stream_id_to_socket_addr.lock().await.insert(SID, None);
+
+        let room_map = HashMap::from([(ROOM_ID, room)]);
+        let state = ServerState::new(Arc::new(RwLock::new(room_map)), false, false);
+
+        let mut session = Session::new("alice", false);
+        session.sid = Some(SID);
+        session.disconnected_at = std::time::Instant::now().checked_sub(disconnected_for);
+
+        let mut session_token = SessionToken::default();
+        fill(&mut session_token);
+        state.sessions.lock().await.insert(session_token, session);
+
+        (state, session_token)
+    }
+
+    /// Sends `command` and returns the response, skipping the pings sent meanwhile.
+    async fn request(client: &mut DuplexStream, command: TcpCommand) -> TcpCommand {
+        command.write_to_stream(client).await.unwrap();
+
+        loop {
+            match TcpCommand::read_from_stream(client).await.unwrap() {
+                ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping)) => continue,
+                ReceivedTcpCommand::Command(command) => return command,
+                ReceivedTcpCommand::EOF => panic!("Handler closed the stream before responding"),
+            }
+        }
+    }
+
+    async fn hello(client: &mut DuplexStream, username: &str) -> TcpCommand {
+        request(
+            client,
+            TcpCommand::String(TcpCommandId::HelloFromClient, username.to_string()),
+        )
+        .await
+    }
+
+    async fn connect() -> (DuplexStream, JoinHandle<HandlerResult>) {
+        let (mut client, handler) = spawn_handler(test_state());
+
+        assert!(matches!(
+            hello(&mut client, "alice").await,
+            TcpCommand::Bytes(TcpCommandId::HelloFromServer, _)
+        ));
+
+        (client, handler)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_client_that_stops_answering_pings() {
+        let (_client, handler) = connect().await;
+
+        let result = timeout(PONG_TIMEOUT + PING_INTERVAL * 2, handler)
+            .await
+            .expect("Handler kept a silent client connected");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_client_that_answers_pings() {
+        let (mut client, handler) = connect().await;
+
+        let answer_pings = async {
+            loop {
+                match TcpCommand::read_from_stream(&mut client).await.unwrap() {
+                    ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping)) => {
+                        TcpCommand::Simple(TcpCommandId::Pong)
+                            .write_to_stream(&mut client)
+                            .await
+                            .unwrap();
+                    }
+                    ReceivedTcpCommand::EOF => panic!("Handler disconnected a live client"),
+                    _ => {}
+                }
+            }
+        };
+
+        assert!(timeout(PONG_TIMEOUT * 2, answer_pings).await.is_err());
+        assert!(!handler.is_finished());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn only_one_of_two_concurrent_handshakes_claims_a_username() {
+        let state = test_state();
+
+        let handshakes = (0..2).map(|_| {
+            let (mut client, _handler) = spawn_handler(state.clone());
+            tokio::spawn(async move { hello(&mut client, "alice").await })
+        });
+
+        let mut accepted = 0;
+        for handshake in handshakes.collect::<Vec<_>>() {
+            match handshake.await.unwrap() {
+                TcpCommand::Bytes(TcpCommandId::HelloFromServer, _) => accepted += 1,
+                TcpCommand::StringList(TcpCommandId::ErrorResponse, message) => assert_eq!(
+                    ProtocolError::from_response(&message),
+                    ProtocolError::UsernameTaken
+                ),
+                command => panic!("Unexpected handshake response: {:?}", command),
+            }
+        }
+
+        assert_eq!(accepted, 1);
+        assert_eq!(*state.users.read().await, vec!["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn resumes_session_within_grace_period() {
+        let (state, session_token) = state_with_dropped_session(Duration::from_secs(1)).await;
+        let (mut client, _handler) = spawn_handler(state);
+
+        let resume = TcpCommand::Bytes(TcpCommandId::Resume, session_token.to_vec());
+        match request(&mut client, resume).await {
+            TcpCommand::Bytes(TcpCommandId::ResumeSuccess, payload) => {
+                assert_eq!(payload, [&ROOM_ID[..], &SID[..]].concat());
+            }
+            command => panic!("Unexpected resume response: {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_resume_after_grace_period() {
+        let (state, session_token) =
+            state_with_dropped_session(SESSION_GRACE_PERIOD + Duration::from_secs(1)).await;
+        let (mut client, _handler) = spawn_handler(state);
+
+        let resume = TcpCommand::Bytes(TcpCommandId::Resume, session_token.to_vec());
+        assert!(matches!(
+            request(&mut client, resume).await,
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, _)
+        ));
+    }
+
+    #[tokio::test]
+    async fn display_names_may_be_shared_but_usernames_may_not() {
+        let state = test_state();
+        let mut clients = Vec::new();
+
+        for username in ["alice", "bob"] {
+            let (mut client, handler) = spawn_handler(state.clone());
+            assert!(matches!(
+                hello(&mut client, username).await,
+                TcpCommand::Bytes(TcpCommandId::HelloFromServer, _)
+            ));
+
+            let set_display_name =
+                TcpCommand::String(TcpCommandId::SetDisplayName, "Sam".to_string());
+            assert!(matches!(
+                request(&mut client, set_display_name).await,
+                TcpCommand::Simple(TcpCommandId::SetDisplayNameSuccess)
+            ));
+            clients.push((client, handler));
+        }
+
+        let (mut client, _handler) = spawn_handler(state.clone());
+        match hello(&mut client, "alice").await {
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, payload) => {
+                assert_eq!(
+                    ProtocolError::from_response(&payload),
+                    ProtocolError::UsernameTaken
+                )
+            }
+            command => panic!("Unexpected handshake response: {:?}", command),
+        }
+
+        let display_names = state.display_names.read().await;
+        assert_eq!(display_names["alice"], "Sam");
+        assert_eq!(display_names["bob"], "Sam");
+    }
+
+    #[tokio::test]
+    async fn kick_notification_forgets_the_stream_id() {
+        let state = test_state();
+        let room = Room::new("lobby", None, DEFAULT_MAX_ROOM_USERS, "alice");
+        state.room_map.write().await.insert(ROOM_ID, room);
+
+        let (mut client, mut server) = duplex(4096);
+        let handler_state = state.clone();
+        let handler = tokio::spawn(async move {
+            let (mut username, mut sid, mut session_token) = (None, None, None);
+            let _ = TcpHandler::handle_stream(
+                &mut server,
+                &mut username,
+                &mut sid,
+                &mut session_token,
+                &handler_state,
+            )
+            .await;
+            sid
+        });
+
+        hello(&mut client, "bob").await;
+        let join_lobby = TcpCommand::StringList(TcpCommandId::JoinRoom, vec!["lobby".to_string()]);
+        assert!(matches!(
+            request(&mut client, join_lobby).await,
+            TcpCommand::Bytes(TcpCommandId::JoinRoomSuccess, _)
+        ));
+
+        state.username_to_tcp_command_tx.lock().await["bob"]
+            .send(TcpCommand::Simple(TcpCommandId::YouWereKicked))
+            .unwrap();
+        loop {
+            match TcpCommand::read_from_stream(&mut client).await.unwrap() {
+                ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::YouWereKicked)) => {
+                    break;
+                }
+                ReceivedTcpCommand::EOF => panic!("Handler closed the stream before the kick"),
+                _ => continue,
+            }
+        }
+        drop(client);
+
+        assert_eq!(handler.await.unwrap(), None);
     }
 }
@@ -0,0 +1,33 @@
+use core::error::Error;
+use std::sync::Arc;
+
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+pub fn load_tls_acceptor(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| format!("Failed to read TLS certificate '{}': {}", cert_path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid TLS certificate '{}': {}", cert_path, e))?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in '{}'", cert_path).into());
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to read TLS key '{}': {}", key_path, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -6,6 +6,7 @@ use std::{
     time::{Duration, Instant},
 };
 
+use serde_json::{Value, json};
 use shared::{RoomID, StreamID};
 use tokio::{
     net::UdpSocket,
@@ -13,13 +14,28 @@ use tokio::{
     time::interval,
 };
 
-use crate::room::Room;
+use crate::{batch_sender::send_batch, room::Room};
 
 const BATCH_SIZE: usize = 32;
 const BATCH_TIMEOUT: Duration = Duration::from_millis(1);
 const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
 const MAX_PACKETS_PER_SECOND: usize = 5000;
 const BACKPRESSURE_THRESHOLD: usize = 500;
+const FORWARDING_BUDGET_WINDOW: Duration = Duration::from_secs(1);
+const STREAM_ACTIVITY_WINDOW: Duration = Duration::from_secs(1);
+const RID_LEN: usize = size_of::<RoomID>();
+const SID_LEN: usize = size_of::<StreamID>();
+const MIN_PACKET_SIZE: usize = RID_LEN + SID_LEN + 1;
+
+// Media header fields after the room and stream ids, as written by the client
+const FULL_FRAME_TYPE: u8 = 0;
+const DELTA_FRAME_TYPE: u8 = 1;
+const HEARTBEAT_FRAME_TYPE: u8 = 2;
+const MTU_PROBE_FRAME_TYPE: u8 = 5;
+const MAX_DATAGRAM_SIZE: usize = 9216;
+const FLAGS_OFFSET: usize = 9;
+const LAST_CHUNK_FLAG: u8 = 1;
+const PARITY_FLAG: u8 = 2;
 
 #[derive(Clone)]
 struct ClientStats {
@@ -28,6 +44,118 @@ struct ClientStats {
     rate_window_start: Instant,
 }
 
+struct StreamUsage {
+    forwarded_bytes: usize,
+    window_start: Instant,
+}
+
+struct ForwardingBudget {
+    bytes_per_second: usize,
+    streams: HashMap<StreamID, StreamUsage>,
+}
+
+impl ForwardingBudget {
+    fn new(bytes_per_second: usize) -> Self {
+        Self {
+            bytes_per_second,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Charges `bytes` to `sid` if they fit in what is left of its budget this window.
+    fn try_charge(&mut self, sid: StreamID, bytes: usize) -> bool {
+        let now = Instant::now();
+
+        let usage = self.streams.entry(sid).or_insert_with(|| StreamUsage {
+            forwarded_bytes: 0,
+            window_start: now,
+        });
+
+        if usage.window_start.elapsed() >= FORWARDING_BUDGET_WINDOW {
+            usage.forwarded_bytes = 0;
+            usage.window_start = now;
+        }
+
+        if usage.forwarded_bytes.saturating_add(bytes) > self.bytes_per_second {
+            return false;
+        }
+
+        usage.forwarded_bytes += bytes;
+
+        true
+    }
+}
+
+/// Picks the `max_streams` most active streams of each room, by frames completed in the
+/// last `STREAM_ACTIVITY_WINDOW`, so receivers in large rooms get only those.
+struct StreamSelector {
+    max_streams: Option<usize>,
+    frame_counts: HashMap<(RoomID, StreamID), u32>,
+    ranks: HashMap<(RoomID, StreamID), usize>,
+    window_start: Instant,
+}
+
+impl StreamSelector {
+    fn new(max_streams: Option<usize>) -> Self {
+        Self {
+            max_streams,
+            frame_counts: HashMap::new(),
+            ranks: HashMap::new(),
+            window_start: Instant::now(),
+        }
+    }
+
+    fn record_frame(&mut self, rid: RoomID, sid: StreamID) {
+        if self.max_streams.is_none() {
+            return;
+        }
+
+        if self.window_start.elapsed() >= STREAM_ACTIVITY_WINDOW {
+            self.update_ranks();
+        }
+
+        *self.frame_counts.entry((rid, sid)).or_default() += 1;
+    }
+
+    fn update_ranks(&mut self) {
+        let mut by_room: HashMap<RoomID, Vec<(StreamID, u32)>> = HashMap::new();
+        for ((rid, sid), frames) in self.frame_counts.drain() {
+            by_room.entry(rid).or_default().push((sid, frames));
+        }
+
+        self.ranks.clear();
+        for (rid, mut streams) in by_room {
+            streams.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+            for (rank, (sid, _)) in streams.into_iter().enumerate() {
+                self.ranks.insert((rid, sid), rank);
+            }
+        }
+
+        self.window_start = Instant::now();
+    }
+
+    fn max_streams_for_room(&self, room_streams: usize) -> Option<usize> {
+        // Every receiver in a room this small already gets at most max_streams others
+        self.max_streams
+            .filter(|&max_streams| room_streams > max_streams + 1)
+    }
+
+    fn forwards(
+        &self,
+        max_streams: usize,
+        rid: RoomID,
+        from_sid: StreamID,
+        to_sid: StreamID,
+    ) -> bool {
+        let rank = |sid| self.ranks.get(&(rid, sid)).copied().unwrap_or(usize::MAX);
+        let from_rank = rank(from_sid);
+
+        // A receiver that is itself among the top streams frees a slot for the next one
+        from_rank < max_streams || (from_rank == max_streams && rank(to_sid) < max_streams)
+    }
+}
+
 struct PacketBatch {
     packets: Vec<(Vec<u8>, Vec<SocketAddr>)>,
     last_flush: Instant,
@@ -60,26 +188,48 @@ pub struct UdpHandler {
     client_stats: Arc<Mutex<HashMap<SocketAddr, ClientStats>>>,
     packet_batch: Arc<Mutex<PacketBatch>>,
     stats: Arc<Mutex<ServerStats>>,
+    forwarding_budget: Arc<Mutex<ForwardingBudget>>,
+    stream_selector: Arc<Mutex<StreamSelector>>,
+    forwarded_by_stream: Arc<Mutex<HashMap<(RoomID, StreamID), u64>>>,
+    stream_log_interval: Duration,
     socket: Option<Arc<UdpSocket>>,
 }
 
 #[derive(Default, Clone)]
-struct ServerStats {
-    packets_received: u64,
-    packets_forwarded: u64,
-    packets_dropped: u64,
+pub struct ServerStats {
+    pub packets_received: u64,
+    pub packets_forwarded: u64,
+    pub packets_dropped: u64,
+    pub packets_malformed: u64,
+    pub packets_unknown_room: u64,
+    pub packets_unknown_stream: u64,
 }
 
 impl UdpHandler {
-    pub fn new() -> Self {
+    pub fn new(
+        stream_budget_bytes_per_second: usize,
+        max_forwarded_streams: Option<usize>,
+        stream_log_interval: Duration,
+    ) -> Self {
         Self {
             client_stats: Arc::new(Mutex::new(HashMap::new())),
             packet_batch: Arc::new(Mutex::new(PacketBatch::new())),
             stats: Arc::new(Mutex::new(ServerStats::default())),
+            forwarding_budget: Arc::new(Mutex::new(ForwardingBudget::new(
+                stream_budget_bytes_per_second,
+            ))),
+            stream_selector: Arc::new(Mutex::new(StreamSelector::new(max_forwarded_streams))),
+            forwarded_by_stream: Arc::new(Mutex::new(HashMap::new())),
+            stream_log_interval,
             socket: None,
         }
     }
 
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> Arc<Mutex<ServerStats>> {
+        Arc::clone(&self.stats)
+    }
+
     pub async fn handle_socket(
         mut self,
         socket: UdpSocket,
@@ -90,23 +240,17 @@ impl UdpHandler {
 
         let cleanup_task = self.spawn_cleanup_task();
         let batch_flush_task = self.spawn_batch_flush_task(Arc::clone(&socket));
+        let stream_log_task = self.spawn_stream_log_task(Arc::clone(&room_map));
 
-        let mut buf = [0u8; 1500];
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
         let mut to_addrs = Vec::with_capacity(64);
 
-        let rid_len = RoomID::default().len();
-        let sid_len = StreamID::default().len();
-        let min_packet_size = rid_len + sid_len + 1;
-
         tokio::select! {
             result = self.run_packet_loop(
                 &socket,
                 &room_map,
                 &mut buf,
-                &mut to_addrs,
-                rid_len,
-                sid_len,
-                min_packet_size
+                &mut to_addrs
             ) => {
                 if let Err(e) = result {
                     log::error!("Packet loop error: {}", e);
@@ -118,6 +262,9 @@ impl UdpHandler {
             _ = batch_flush_task => {
                 log::info!("Batch flush task completed");
             }
+            _ = stream_log_task => {
+                log::info!("Stream log task completed");
+            }
         }
 
         Ok(())
@@ -129,24 +276,12 @@ impl UdpHandler {
         room_map: &Arc<RwLock<HashMap<RoomID, Room>>>,
         buf: &mut [u8],
         to_addrs: &mut Vec<SocketAddr>,
-        rid_len: usize,
-        sid_len: usize,
-        min_packet_size: usize,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         loop {
             match socket.recv_from(buf).await {
                 Ok((n, from_addr)) => {
-                    self.handle_packet(
-                        &buf[..n],
-                        from_addr,
-                        room_map,
-                        to_addrs,
-                        socket,
-                        rid_len,
-                        sid_len,
-                        min_packet_size,
-                    )
-                    .await;
+                    self.handle_packet(&buf[..n], from_addr, room_map, to_addrs, socket)
+                        .await;
                 }
                 Err(e) => {
                     log::error!("Error receiving UDP packet: {}", e);
@@ -163,18 +298,26 @@ impl UdpHandler {
         room_map: &Arc<RwLock<HashMap<RoomID, Room>>>,
         to_addrs: &mut Vec<SocketAddr>,
         socket: &UdpSocket,
-        rid_len: usize,
-        sid_len: usize,
-        min_packet_size: usize,
     ) {
         {
             let mut stats = self.stats.lock().await;
             stats.packets_received += 1;
         }
 
-        if buf.len() < min_packet_size {
-            return;
-        }
+        let (rid, sid) = match (
+            buf.get(..RID_LEN)
+                .and_then(|rid| RoomID::try_from(rid).ok()),
+            buf.get(RID_LEN..RID_LEN + SID_LEN)
+                .and_then(|sid| StreamID::try_from(sid).ok()),
+        ) {
+            (Some(rid), Some(sid)) if buf.len() >= MIN_PACKET_SIZE => (rid, sid),
+            _ => {
+                let mut stats = self.stats.lock().await;
+                stats.packets_malformed += 1;
+                stats.packets_dropped += 1;
+                return;
+            }
+        };
 
         if !self.check_rate_limit(from_addr).await {
             let mut stats = self.stats.lock().await;
@@ -182,54 +325,85 @@ impl UdpHandler {
             return;
         }
 
-        let rid: RoomID = match buf[..rid_len].try_into() {
-            Ok(rid) => rid,
-            Err(_) => return,
-        };
-
-        let sid: StreamID = match buf[rid_len..rid_len + sid_len].try_into() {
-            Ok(sid) => sid,
-            Err(_) => return,
-        };
-
         to_addrs.clear();
 
-        let needs_update = {
+        let frame_type = buf[RID_LEN + SID_LEN];
+        let is_video = matches!(
+            frame_type,
+            FULL_FRAME_TYPE | DELTA_FRAME_TYPE | HEARTBEAT_FRAME_TYPE
+        );
+        let is_frame_end = frame_type != HEARTBEAT_FRAME_TYPE
+            && is_video
+            && buf
+                .get(RID_LEN + SID_LEN + FLAGS_OFFSET)
+                .is_some_and(|flags| flags & LAST_CHUNK_FLAG != 0 && flags & PARITY_FLAG == 0);
+
+        {
             let room_map_read = room_map.read().await;
             if let Some(room) = room_map_read.get(&rid) {
-                let stream_map = room.stream_id_to_socket_addr.lock().await;
+                // Binding the sender's address only needs this lock, not a write lock on the room map
+                let mut stream_map = room.stream_id_to_socket_addr.lock().await;
+                let mut stream_selector = self.stream_selector.lock().await;
+
+                // Keyframe and retransmit requests still go to everyone so they reach their sender
+                let max_streams = stream_selector
+                    .max_streams_for_room(stream_map.len())
+                    .filter(|_| is_video);
 
                 for (to_sid, to_addr_option) in stream_map.iter() {
-                    if to_sid != &sid {
-                        if let Some(to_addr) = to_addr_option {
-                            to_addrs.push(*to_addr);
+                    if to_sid != &sid
+                        && max_streams.is_none_or(|max_streams| {
+                            stream_selector.forwards(max_streams, rid, sid, *to_sid)
+                        })
+                    {
+                        // Two streams bound to one address would otherwise get their own media back
+                        if let Some(to_addr) = to_addr_option.filter(|addr| *addr != from_addr) {
+                            to_addrs.push(to_addr);
                         }
                     }
                 }
 
-                stream_map.get(&sid).map_or(true, |entry| entry.is_none())
+                match stream_map.get_mut(&sid) {
+                    Some(entry) => {
+                        if is_frame_end {
+                            stream_selector.record_frame(rid, sid);
+                        }
+                        if entry.is_none() {
+                            *entry = Some(from_addr);
+                        }
+                    }
+                    None => {
+                        drop(stream_selector);
+                        drop(stream_map);
+                        drop(room_map_read);
+                        let mut stats = self.stats.lock().await;
+                        stats.packets_unknown_stream += 1;
+                        stats.packets_dropped += 1;
+                        return;
+                    }
+                }
             } else {
+                drop(room_map_read);
+                let mut stats = self.stats.lock().await;
+                stats.packets_unknown_room += 1;
+                stats.packets_dropped += 1;
                 return;
             }
-        };
+        }
 
-        if needs_update {
-            let mut room_map_write = room_map.write().await;
-            if let Some(room) = room_map_write.get_mut(&rid) {
-                let mut stream_map = room.stream_id_to_socket_addr.lock().await;
-                if let Some(entry) = stream_map.get_mut(&sid) {
-                    if entry.is_none() {
-                        *entry = Some(from_addr);
-                    }
-                }
+        // Probes are echoed to their sender in the forwarded layout so it can size its chunks
+        if frame_type == MTU_PROBE_FRAME_TYPE {
+            if let Err(e) = socket.send_to(&buf[RID_LEN..], from_addr).await {
+                log::debug!("Failed to echo MTU probe to {}: {}", from_addr, e);
             }
+            return;
         }
 
         if to_addrs.is_empty() {
             return;
         }
 
-        let payload = [&buf[rid_len..rid_len + sid_len], &buf[rid_len + sid_len..]].concat();
+        let payload = [&buf[RID_LEN..RID_LEN + SID_LEN], &buf[RID_LEN + SID_LEN..]].concat();
 
         {
             let batch = self.packet_batch.lock().await;
@@ -240,6 +414,25 @@ impl UdpHandler {
             }
         }
 
+        // Applies to immediate sends as well as batched ones, and only charges what is forwarded
+        if !self
+            .forwarding_budget
+            .lock()
+            .await
+            .try_charge(sid, payload.len() * to_addrs.len())
+        {
+            let mut stats = self.stats.lock().await;
+            stats.packets_dropped += 1;
+            return;
+        }
+
+        *self
+            .forwarded_by_stream
+            .lock()
+            .await
+            .entry((rid, sid))
+            .or_insert(0) += to_addrs.len() as u64;
+
         if to_addrs.len() <= 3 {
             self.send_immediate(socket, &payload, to_addrs).await;
         } else {
@@ -307,22 +500,7 @@ impl UdpHandler {
             }
         }
 
-        let mut forwarded = 0;
-        let mut dropped = 0;
-
-        for (dest, payloads) in by_destination {
-            for payload in payloads {
-                match socket.send_to(&payload, dest).await {
-                    Ok(_) => forwarded += 1,
-                    Err(e) => {
-                        dropped += 1;
-                        if dropped % 100 == 0 {
-                            log::warn!("Failed to send to {}: {}", dest, e);
-                        }
-                    }
-                }
-            }
-        }
+        let (forwarded, dropped) = send_batch(&socket, by_destination).await;
 
         if forwarded > 0 || dropped > 0 {
             let mut stats = self.stats.lock().await;
@@ -354,6 +532,7 @@ impl UdpHandler {
 
     fn spawn_cleanup_task(&self) -> tokio::task::JoinHandle<()> {
         let client_stats = Arc::clone(&self.client_stats);
+        let forwarding_budget = Arc::clone(&self.forwarding_budget);
 
         tokio::spawn(async move {
             let mut cleanup_interval = interval(Duration::from_secs(60));
@@ -370,6 +549,54 @@ impl UdpHandler {
                 if removed > 0 {
                     log::info!("Cleaned up {} inactive clients", removed);
                 }
+                drop(clients);
+
+                forwarding_budget
+                    .lock()
+                    .await
+                    .streams
+                    .retain(|_, usage| usage.window_start.elapsed() < Duration::from_secs(300));
+            }
+        })
+    }
+
+    /// Logs one JSON line per interval with the packets forwarded for each stream since the
+    /// last one. Nothing is logged for intervals without traffic.
+    fn spawn_stream_log_task(
+        &self,
+        room_map: Arc<RwLock<HashMap<RoomID, Room>>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let forwarded_by_stream = Arc::clone(&self.forwarded_by_stream);
+        let stream_log_interval = self.stream_log_interval;
+
+        tokio::spawn(async move {
+            let mut log_interval = interval(stream_log_interval);
+            log_interval.tick().await;
+
+            loop {
+                log_interval.tick().await;
+
+                let forwarded = std::mem::take(&mut *forwarded_by_stream.lock().await);
+                if forwarded.is_empty() {
+                    continue;
+                }
+
+                let room_names: HashMap<RoomID, String> = {
+                    let room_map_guard = room_map.read().await;
+                    forwarded
+                        .keys()
+                        .filter_map(|(rid, _)| {
+                            room_map_guard
+                                .get(rid)
+                                .map(|room| (*rid, room.name.clone()))
+                        })
+                        .collect()
+                };
+
+                log::info!(
+                    "Stream summary: {}",
+                    stream_summary(forwarded, &room_names, stream_log_interval)
+                );
             }
         })
     }
@@ -404,22 +631,7 @@ impl UdpHandler {
                     }
                 }
 
-                let mut forwarded = 0;
-                let mut dropped = 0;
-
-                for (dest, payloads) in by_destination {
-                    for payload in payloads {
-                        match socket.send_to(&payload, dest).await {
-                            Ok(_) => forwarded += 1,
-                            Err(e) => {
-                                dropped += 1;
-                                if dropped % 100 == 0 {
-                                    log::warn!("Failed to send to {}: {}", dest, e);
-                                }
-                            }
-                        }
-                    }
-                }
+                let (forwarded, dropped) = send_batch(&socket, by_destination).await;
 
                 if forwarded > 0 || dropped > 0 {
                     let mut stats_guard = stats.lock().await;
@@ -430,3 +642,273 @@ impl UdpHandler {
         })
     }
 }
+
+/// Forwarded packet counts per stream, sorted by room and stream, with the room names that
+/// were still known.
+fn stream_summary(
+    forwarded: HashMap<(RoomID, StreamID), u64>,
+    room_names: &HashMap<RoomID, String>,
+    stream_log_interval: Duration,
+) -> Value {
+    let mut streams: Vec<_> = forwarded.into_iter().collect();
+    streams.sort();
+
+    let mut active_rooms: Vec<RoomID> = streams.iter().map(|((rid, _), _)| *rid).collect();
+    active_rooms.dedup();
+
+    let stream_entries: Vec<_> = streams
+        .iter()
+        .map(|((rid, sid), packets)| {
+            json!({
+                "room": room_names.get(rid),
+                "stream": hex(sid),
+                "packets": packets,
+            })
+        })
+        .collect();
+
+    json!({
+        "interval_secs": stream_log_interval.as_secs(),
+        "rooms": active_rooms.len(),
+        "streams": stream_entries.len(),
+        "forwarded": stream_entries,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_stream_over_budget_is_refused() {
+        let mut budget = ForwardingBudget::new(1000);
+
+        assert!(budget.try_charge([1; 4], 600));
+        assert!(budget.try_charge([2; 4], 500));
+        assert!(!budget.try_charge([1; 4], 600));
+        assert!(budget.try_charge([2; 4], 500));
+        assert!(!budget.try_charge([2; 4], 1));
+        // The refused 600 bytes were not charged
+        assert!(budget.try_charge([1; 4], 400));
+    }
+
+    #[test]
+    fn each_receiver_gets_the_top_streams_of_a_large_room() {
+        const MAX_STREAMS: usize = 3;
+        let rid = [1; 4];
+        let sids: Vec<StreamID> = (0..6u8).map(|i| [i; 4]).collect();
+        let mut selector = StreamSelector::new(Some(MAX_STREAMS));
+
+        for (i, sid) in sids.iter().enumerate() {
+            for _ in 0..=i {
+                selector.record_frame(rid, *sid);
+            }
+        }
+        selector.update_ranks();
+
+        let max_streams = selector.max_streams_for_room(sids.len()).unwrap();
+        assert_eq!(max_streams, MAX_STREAMS);
+
+        for to_sid in &sids {
+            let forwarded = sids
+                .iter()
+                .filter(|from_sid| *from_sid != to_sid)
+                .filter(|from_sid| selector.forwards(max_streams, rid, **from_sid, *to_sid))
+                .count();
+            assert_eq!(forwarded, MAX_STREAMS, "Receiver {:?}", to_sid);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_and_unknown_room_packets_are_counted() {
+        let handler = UdpHandler::new(usize::MAX, None, Duration::from_secs(60));
+        let room_map = Arc::new(RwLock::new(HashMap::new()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from_addr = socket.local_addr().unwrap();
+        let mut to_addrs = Vec::new();
+
+        let unknown_room = [
+            [9; RID_LEN].as_slice(),
+            &[1; SID_LEN],
+            &[HEARTBEAT_FRAME_TYPE],
+        ]
+        .concat();
+        let packets = [
+            &unknown_room[..RID_LEN - 1],
+            &unknown_room[..RID_LEN + SID_LEN],
+            &unknown_room[..],
+        ];
+        for packet in packets {
+            handler
+                .handle_packet(packet, from_addr, &room_map, &mut to_addrs, &socket)
+                .await;
+        }
+
+        let stats = handler.stats.lock().await;
+        assert_eq!(stats.packets_received, 3);
+        assert_eq!(stats.packets_malformed, 2);
+        assert_eq!(stats.packets_unknown_room, 1);
+        assert_eq!(stats.packets_dropped, 3);
+        assert!(to_addrs.is_empty());
+    }
+
+    async fn room_with_streams(name: &str, streams: &[(StreamID, SocketAddr)]) -> Room {
+        let room = Room::new(name, None, 8, "creator");
+        room.stream_id_to_socket_addr
+            .lock()
+            .await
+            .extend(streams.iter().map(|(sid, addr)| (*sid, Some(*addr))));
+        room
+    }
+
+    fn packet(rid: RoomID, sid: StreamID) -> Vec<u8> {
+        [&rid[..], &sid[..], &[HEARTBEAT_FRAME_TYPE]].concat()
+    }
+
+    #[tokio::test]
+    async fn summary_counts_forwarded_packets_per_stream_and_room() {
+        let handler = UdpHandler::new(usize::MAX, None, Duration::from_secs(60));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from_addr = socket.local_addr().unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to_addr = receiver.local_addr().unwrap();
+
+        let (lobby, studio) = ([1; RID_LEN], [2; RID_LEN]);
+        let (alice, bob, carol, dave) = ([1; SID_LEN], [2; SID_LEN], [3; SID_LEN], [4; SID_LEN]);
+        let room_map = Arc::new(RwLock::new(HashMap::from([
+            (
+                lobby,
+                room_with_streams("lobby", &[(alice, from_addr), (bob, to_addr)]).await,
+            ),
+            (
+                studio,
+                room_with_streams("studio", &[(carol, from_addr), (dave, to_addr)]).await,
+            ),
+        ])));
+
+        let mut to_addrs = Vec::new();
+        let packets = [
+            packet(lobby, alice),
+            packet(lobby, alice),
+            packet(lobby, alice),
+            packet(studio, carol),
+        ];
+        for packet in &packets {
+            handler
+                .handle_packet(packet, from_addr, &room_map, &mut to_addrs, &socket)
+                .await;
+        }
+
+        let forwarded = std::mem::take(&mut *handler.forwarded_by_stream.lock().await);
+        // Studio was deleted before the summary, so its stream is logged without a name
+        let room_names = HashMap::from([(lobby, "lobby".to_string())]);
+
+        assert_eq!(
+            stream_summary(forwarded, &room_names, Duration::from_secs(60)),
+            json!({
+                "interval_secs": 60,
+                "rooms": 2,
+                "streams": 2,
+                "forwarded": [
+                    { "room": "lobby", "stream": "01010101", "packets": 3 },
+                    { "room": null, "stream": "03030303", "packets": 1 },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_sharing_an_address_never_get_each_others_packets() {
+        let handler = UdpHandler::new(usize::MAX, None, Duration::from_secs(60));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let shared_addr = socket.local_addr().unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other_addr = other.local_addr().unwrap();
+
+        let rid = [1; RID_LEN];
+        let (camera, screen, viewer) = ([1; SID_LEN], [2; SID_LEN], [3; SID_LEN]);
+        let room = room_with_streams(
+            "lobby",
+            &[
+                (camera, shared_addr),
+                (screen, shared_addr),
+                (viewer, other_addr),
+            ],
+        )
+        .await;
+        let room_map = Arc::new(RwLock::new(HashMap::from([(rid, room)])));
+
+        let mut to_addrs = Vec::new();
+        for sid in [camera, screen] {
+            handler
+                .handle_packet(
+                    &packet(rid, sid),
+                    shared_addr,
+                    &room_map,
+                    &mut to_addrs,
+                    &socket,
+                )
+                .await;
+            assert_eq!(to_addrs, [other_addr]);
+        }
+    }
+
+    #[tokio::test]
+    async fn over_budget_stream_is_dropped_while_others_are_forwarded() {
+        // Each forwarded payload is 5 bytes, so two receivers cost 10 per packet
+        let handler = UdpHandler::new(25, None, Duration::from_secs(60));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let noisy_addr = socket.local_addr().unwrap();
+        let quiet_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let quiet_addr = quiet_socket.local_addr().unwrap();
+        let viewer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let viewer_addr = viewer.local_addr().unwrap();
+
+        let rid = [1; RID_LEN];
+        let (noisy, quiet, watcher) = ([1; SID_LEN], [2; SID_LEN], [3; SID_LEN]);
+        let room = room_with_streams(
+            "lobby",
+            &[
+                (noisy, noisy_addr),
+                (quiet, quiet_addr),
+                (watcher, viewer_addr),
+            ],
+        )
+        .await;
+        let room_map = Arc::new(RwLock::new(HashMap::from([(rid, room)])));
+
+        let mut to_addrs = Vec::new();
+        for _ in 0..3 {
+            handler
+                .handle_packet(
+                    &packet(rid, noisy),
+                    noisy_addr,
+                    &room_map,
+                    &mut to_addrs,
+                    &socket,
+                )
+                .await;
+        }
+        handler
+            .handle_packet(
+                &packet(rid, quiet),
+                quiet_addr,
+                &room_map,
+                &mut to_addrs,
+                &socket,
+            )
+            .await;
+
+        let forwarded_by_stream = handler.forwarded_by_stream.lock().await;
+        assert_eq!(forwarded_by_stream[&(rid, noisy)], 4);
+        assert_eq!(forwarded_by_stream[&(rid, quiet)], 2);
+
+        let stats = handler.stats.lock().await;
+        assert_eq!(stats.packets_dropped, 1);
+        assert_eq!(stats.packets_forwarded, 6);
+    }
+}
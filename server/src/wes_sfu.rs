@@ -1,59 +1,205 @@
 use core::error::Error;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use log::{error, info};
-use shared::{RoomID, tcp_command::TcpCommand, tcp_command_id::TcpCommandId};
+use shared::{
+    RoomID, StreamID, control_stream::ControlStream, tcp_command::TcpCommand,
+    tcp_command_id::TcpCommandId,
+};
 use tokio::{
     net::{TcpListener, UdpSocket},
-    sync::{Mutex, RwLock},
+    sync::RwLock,
 };
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::serve_metrics;
+use crate::{
+    room::{Room, leave_room},
+    room_store::{load_rooms, persist_rooms, save_room_map},
+    server_state::ServerState,
+    session::SESSION_GRACE_PERIOD,
+    tcp_handler::TcpHandler,
+    udp_handler::UdpHandler,
+};
+
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_millis(500);
+pub const DEFAULT_STREAM_BUDGET_KBPS: usize = 4096;
+pub const DEFAULT_STREAM_LOG_SECS: u64 = 60;
+
+/// Options for [`WeSFU::bind`], one per server command-line flag.
+pub struct ServerConfig {
+    pub keep_empty_rooms: bool,
+    pub stream_budget_bytes_per_second: usize,
+    pub max_forwarded_streams: Option<usize>,
+    pub stream_log_interval: Duration,
+    pub encrypt_media: bool,
+    pub tls_acceptor: Option<TlsAcceptor>,
+    pub metrics_addr: Option<String>,
+    pub rooms_file: Option<String>,
+}
 
-use crate::{room::Room, tcp_handler::TcpHandler, udp_handler::UdpHandler};
+/// Matches the command-line defaults: no TLS, metrics or rooms file.
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            keep_empty_rooms: false,
+            stream_budget_bytes_per_second: DEFAULT_STREAM_BUDGET_KBPS * 1000 / 8,
+            max_forwarded_streams: None,
+            stream_log_interval: Duration::from_secs(DEFAULT_STREAM_LOG_SECS),
+            encrypt_media: false,
+            tls_acceptor: None,
+            metrics_addr: None,
+            rooms_file: None,
+        }
+    }
+}
 
 pub struct WeSFU {
     tcp_listener: TcpListener,
     udp_socket: UdpSocket,
     room_map_for_tcp: Arc<RwLock<HashMap<RoomID, Room>>>,
     room_map_for_udp: Arc<RwLock<HashMap<RoomID, Room>>>,
+    keep_empty_rooms: bool,
+    stream_budget_bytes_per_second: usize,
+    max_forwarded_streams: Option<usize>,
+    stream_log_interval: Duration,
+    encrypt_media: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    rooms_file: Option<String>,
+    #[cfg(feature = "metrics")]
+    metrics_listener: Option<TcpListener>,
 }
 
 impl WeSFU {
     pub async fn bind(
         tcp_addr: String,
         udp_addr: String,
+        config: ServerConfig,
     ) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let room_map_for_tcp = Arc::new(RwLock::new(HashMap::new()));
+        let ServerConfig {
+            keep_empty_rooms,
+            stream_budget_bytes_per_second,
+            max_forwarded_streams,
+            stream_log_interval,
+            encrypt_media,
+            tls_acceptor,
+            metrics_addr,
+            rooms_file,
+        } = config;
+
+        let rooms = match &rooms_file {
+            Some(rooms_file) => {
+                let rooms = load_rooms(rooms_file)?;
+                info!("Restored {} rooms from '{}'", rooms.len(), rooms_file);
+                rooms
+            }
+            None => HashMap::new(),
+        };
+
+        let room_map_for_tcp = Arc::new(RwLock::new(rooms));
         let room_map_for_udp = room_map_for_tcp.clone();
 
+        #[cfg(feature = "metrics")]
+        let metrics_listener = match metrics_addr {
+            Some(metrics_addr) => Some(TcpListener::bind(metrics_addr).await?),
+            None => None,
+        };
+        #[cfg(not(feature = "metrics"))]
+        let _ = metrics_addr;
+
         Ok(Self {
             tcp_listener: TcpListener::bind(tcp_addr).await?,
             udp_socket: UdpSocket::bind(udp_addr).await?,
             room_map_for_tcp,
             room_map_for_udp,
+            keep_empty_rooms,
+            stream_budget_bytes_per_second,
+            max_forwarded_streams,
+            stream_log_interval,
+            encrypt_media,
+            tls_acceptor,
+            rooms_file,
+            #[cfg(feature = "metrics")]
+            metrics_listener,
         })
     }
 
+    /// Address the control listener is bound to, with the real port when bound to port 0.
+    pub fn tcp_local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+
+    /// Address the media socket is bound to, with the real port when bound to port 0.
+    pub fn udp_local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp_socket.local_addr()
+    }
+
     pub async fn listen(self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut udp_task: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> =
+        self.listen_until(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+    }
+
+    /// Serves until `shutdown` completes, then announces the shutdown to every client.
+    pub async fn listen_until(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        tokio::pin!(shutdown);
+
+        let state = ServerState::new(
+            self.room_map_for_tcp.clone(),
+            self.keep_empty_rooms,
+            self.encrypt_media,
+        );
+
+        let persist_task = self.rooms_file.clone().map(|rooms_file| {
+            tokio::spawn(persist_rooms(
+                rooms_file,
+                self.room_map_for_tcp.clone(),
+                state.rooms_changed.clone(),
+            ))
+        });
+
+        let handler = UdpHandler::new(
+            self.stream_budget_bytes_per_second,
+            self.max_forwarded_streams,
+            self.stream_log_interval,
+        );
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_listener) = self.metrics_listener {
+            let stats = handler.stats();
+            let users = state.users.clone();
+            let room_map = state.room_map.clone();
+
             tokio::spawn(async move {
-                let handler = UdpHandler::new();
+                if let Err(e) = serve_metrics(metrics_listener, stats, users, room_map).await {
+                    error!("Metrics server error: {}", e);
+                }
+            });
+        }
 
+        let mut udp_task: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> =
+            tokio::spawn(async move {
                 handler
                     .handle_socket(self.udp_socket, self.room_map_for_udp)
                     .await?;
 
-                return Ok(());
+                Ok(())
             });
 
-        let users = Arc::new(RwLock::new(Vec::new()));
-
-        let username_to_tcp_command_tx = Arc::new(Mutex::new(HashMap::new()));
-
         loop {
-            let username_to_tcp_command_tx = username_to_tcp_command_tx.clone();
-
-            let users = users.clone();
-            let room_map = self.room_map_for_tcp.clone();
+            let state = state.clone();
+            let tls_acceptor = self.tls_acceptor.clone();
 
             tokio::select! {
 
@@ -62,6 +208,28 @@ impl WeSFU {
                     return result?;
                 }
 
+                _ = &mut shutdown => {
+
+                    info!("Shutting down WeSFU");
+
+                    for tx in state.username_to_tcp_command_tx.lock().await.values() {
+                        let _ = tx.send(TcpCommand::Simple(TcpCommandId::ServerShuttingDown));
+                    }
+
+                    tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+
+                    if let (Some(persist_task), Some(rooms_file)) = (&persist_task, &self.rooms_file) {
+
+                        persist_task.abort();
+
+                        if let Err(e) = save_room_map(rooms_file, &state.room_map).await {
+                            error!("Failed to save rooms to '{}': {}", rooms_file, e);
+                        }
+                    }
+
+                    return Ok(());
+                }
+
                 result = self.tcp_listener.accept() => {
 
                     let (stream, addr) = result?;
@@ -70,47 +238,79 @@ impl WeSFU {
 
                     tokio::spawn(async move {
 
-                        let users = users.clone();
+                        let mut stream: Box<dyn ControlStream> = match tls_acceptor {
+                            Some(acceptor) => match acceptor.accept(stream).await {
+                                Ok(tls_stream) => Box::new(tls_stream),
+                                Err(e) => {
+                                    error!("TLS handshake with {} failed: {}", addr, e);
+                                    return;
+                                }
+                            },
+                            None => Box::new(stream),
+                        };
 
                         let mut current_username_option = None;
                         let mut current_sid_option = None;
+                        let mut current_session_token_option = None;
 
-                        if let Err(e) = TcpHandler::handle_stream(stream, &mut current_username_option, &mut current_sid_option, users.clone(), room_map.clone(), username_to_tcp_command_tx.clone()).await {
+                        if let Err(e) = TcpHandler::handle_stream(stream.as_mut(), &mut current_username_option, &mut current_sid_option, &mut current_session_token_option, &state).await {
 
                             error!("Error handling TcpSocket: {}", e);
                         }
 
                         if let Some(current_username) = current_username_option.take() {
 
-                            users.write().await.retain(|user| user != &current_username);
-                            username_to_tcp_command_tx
+                            state.username_to_tcp_command_tx
                             .lock()
                             .await
                             .remove(&current_username);
 
-                            if let Some(sid) = current_sid_option {
+                            match (current_session_token_option, current_sid_option) {
 
-                                for room in room_map.write().await.values_mut() {
+                                (Some(session_token), Some(sid)) => {
 
-                                    let mut stream_id_to_socket_addr_guard = room.stream_id_to_socket_addr.lock().await;
-                                    if stream_id_to_socket_addr_guard.contains_key(&sid) {
+                                    let disconnected_at = Instant::now();
 
-                                        stream_id_to_socket_addr_guard.remove(&sid);
+                                    if let Some(session) = state.sessions.lock().await.get_mut(&session_token) {
+
+                                        session.sid = Some(sid);
+                                        session.disconnected_at = Some(disconnected_at);
+                                    }
 
-                                        room.users.retain(|user| user != &current_username);
+                                    info!("User '{}' dropped mid-call (address: {}), holding their session for {:?}", current_username, addr, SESSION_GRACE_PERIOD);
 
-                                        for user in room.users.clone() {
+                                    tokio::time::sleep(SESSION_GRACE_PERIOD).await;
 
-                                            if let Some(tx) = username_to_tcp_command_tx.lock().await.get(&user) {
+                                    let expired = {
 
-                                                let _ = tx.send(TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid.to_vec()));
-                                            }
+                                        let mut sessions_guard = state.sessions.lock().await;
+                                        let expired = sessions_guard
+                                            .get(&session_token)
+                                            .is_some_and(|session| session.disconnected_at == Some(disconnected_at));
+
+                                        if expired {
+                                            sessions_guard.remove(&session_token);
                                         }
+
+                                        expired
+                                    };
+
+                                    if expired {
+
+                                        remove_user(&current_username, Some(sid), &state).await;
+                                        info!("User '{}' has disconnected (session expired)", current_username);
                                     }
                                 }
-                            }
+                                (session_token_option, sid_option) => {
+
+                                    if let Some(session_token) = session_token_option {
+                                        state.sessions.lock().await.remove(&session_token);
+                                    }
 
-                            info!("User '{}' has disconnected (address: {})", current_username, addr);
+                                    remove_user(&current_username, sid_option, &state).await;
+                                    info!("User '{}' has disconnected (address: {})", current_username, addr);
+                                }
+                            }
                         }
 
                     });
@@ -119,3 +319,90 @@ impl WeSFU {
         }
     }
 }
+
+async fn remove_user(username: &str, sid_option: Option<StreamID>, state: &ServerState) {
+    state.users.write().await.retain(|user| user != username);
+    state.display_names.write().await.remove(username);
+
+    if let Some(sid) = sid_option {
+        leave_room(
+            username,
+            sid,
+            &state.room_map,
+            &state.username_to_tcp_command_tx,
+            state.keep_empty_rooms,
+        )
+        .await;
+        state.rooms_changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dropped_user_leaves_no_stream_ids_behind() {
+        let (alice_sid, bob_sid) = ([1; 4], [2; 4]);
+        let mut room = Room::new("lobby", None, 8, "alice");
+        for (username, sid, port) in [("alice", alice_sid, 5000), ("bob", bob_sid, 5001)] {
+            room.users.push(username.to_string());
+            room.stream_id_to_username.insert(sid, username.to_string());
+            room.stream_id_to_socket_addr
+                .lock()
+                .await
+                .insert(sid, Some(SocketAddr::from(([127, 0, 0, 1], port))));
+        }
+
+        let state = ServerState::new(
+            Arc::new(RwLock::new(HashMap::from([([1; 4], room)]))),
+            false,
+            false,
+        );
+        state
+            .users
+            .write()
+            .await
+            .extend(["alice".to_string(), "bob".to_string()]);
+        state
+            .display_names
+            .write()
+            .await
+            .insert("alice".to_string(), "Alice".to_string());
+        let (bob_tx, mut bob_rx) = broadcast::channel(16);
+        state
+            .username_to_tcp_command_tx
+            .lock()
+            .await
+            .insert("bob".to_string(), bob_tx);
+
+        remove_user("alice", Some(alice_sid), &state).await;
+
+        assert_eq!(*state.users.read().await, ["bob"]);
+        assert!(state.display_names.read().await.is_empty());
+
+        let room_map = state.room_map.read().await;
+        let room = &room_map[&[1; 4]];
+        assert_eq!(room.users, ["bob"]);
+        assert_eq!(
+            room.stream_id_to_username.keys().collect::<Vec<_>>(),
+            [&bob_sid]
+        );
+        assert_eq!(
+            room.stream_id_to_socket_addr
+                .lock()
+                .await
+                .keys()
+                .collect::<Vec<_>>(),
+            [&bob_sid]
+        );
+
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(TcpCommand::Bytes(TcpCommandId::OtherUserLeftRoom, sid)) if sid == alice_sid
+        ));
+        assert!(bob_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,427 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use rcgen::CertifiedKey;
+use server::{
+    tls::load_tls_acceptor,
+    wes_sfu::{ServerConfig, WeSFU},
+};
+use shared::{
+    RoomID, StreamID, TCP_PORT, UDP_PORT, format_host_port, protocol_error::ProtocolError,
+    received_tcp_command::ReceivedTcpCommand, tcp_command::TcpCommand,
+    tcp_command_id::TcpCommandId,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UdpSocket},
+    sync::oneshot,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const HEARTBEAT_FRAME_TYPE: u8 = 2;
+
+struct TestUser {
+    stream: TcpStream,
+    udp_socket: UdpSocket,
+    rid: RoomID,
+    sid: StreamID,
+}
+
+fn command_id(command: &TcpCommand) -> TcpCommandId {
+    match command {
+        TcpCommand::Simple(id)
+        | TcpCommand::String(id, _)
+        | TcpCommand::Bytes(id, _)
+        | TcpCommand::StringList(id, _) => *id,
+    }
+}
+
+/// Reads commands until one with `id` arrives, skipping pings and other notifications.
+async fn read_until<S>(stream: &mut S, id: TcpCommandId) -> TcpCommand
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let received = timeout(READ_TIMEOUT, TcpCommand::read_from_stream(stream))
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for {:?}", id))
+            .expect("Failed to read command");
+
+        let command = match received {
+            ReceivedTcpCommand::Command(command) => command,
+            ReceivedTcpCommand::EOF => panic!("Server closed the stream waiting for {:?}", id),
+        };
+
+        if command_id(&command) == TcpCommandId::ErrorResponse {
+            panic!("Server responded with an error: {:?}", command);
+        }
+
+        if command_id(&command) == id {
+            return command;
+        }
+    }
+}
+
+async fn start_server() -> (SocketAddr, SocketAddr) {
+    start_server_on("127.0.0.1:0", "127.0.0.1:0").await
+}
+
+async fn start_server_on(tcp_addr: &str, udp_addr: &str) -> (SocketAddr, SocketAddr) {
+    let server = bind_server(tcp_addr, udp_addr, test_config()).await;
+    let addrs = (
+        server.tcp_local_addr().unwrap(),
+        server.udp_local_addr().unwrap(),
+    );
+    tokio::spawn(server.listen());
+
+    addrs
+}
+
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        stream_budget_bytes_per_second: usize::MAX,
+        ..ServerConfig::default()
+    }
+}
+
+async fn bind_server(tcp_addr: &str, udp_addr: &str, config: ServerConfig) -> WeSFU {
+    WeSFU::bind(tcp_addr.to_string(), udp_addr.to_string(), config)
+        .await
+        .expect("Failed to bind server")
+}
+
+async fn connect(tcp_addr: SocketAddr, username: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(tcp_addr)
+        .await
+        .expect("Failed to connect");
+
+    hello(&mut stream, username).await;
+
+    stream
+}
+
+async fn hello<S>(stream: &mut S, username: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    TcpCommand::String(TcpCommandId::HelloFromClient, username.to_string())
+        .write_to_stream(stream)
+        .await
+        .unwrap();
+    read_until(stream, TcpCommandId::HelloFromServer).await;
+}
+
+async fn join(mut stream: TcpStream, udp_addr: SocketAddr, room_name: &str) -> TestUser {
+    TcpCommand::StringList(TcpCommandId::JoinRoom, vec![room_name.to_string()])
+        .write_to_stream(&mut stream)
+        .await
+        .unwrap();
+
+    let payload = match read_until(&mut stream, TcpCommandId::JoinRoomSuccess).await {
+        TcpCommand::Bytes(_, payload) => payload,
+        command => panic!("Unexpected join response: {:?}", command),
+    };
+    let (rid, sid) = payload.split_at(RoomID::default().len());
+
+    // Bind on the server's address family so both loopbacks work
+    let udp_socket = UdpSocket::bind(SocketAddr::new(udp_addr.ip(), 0))
+        .await
+        .unwrap();
+    udp_socket.connect(udp_addr).await.unwrap();
+
+    TestUser {
+        stream,
+        udp_socket,
+        rid: rid.try_into().unwrap(),
+        sid: sid.try_into().unwrap(),
+    }
+}
+
+async fn send_video(user: &TestUser, data: &[u8]) {
+    let packet = [&user.rid[..], &user.sid[..], &[HEARTBEAT_FRAME_TYPE], data].concat();
+    user.udp_socket.send(&packet).await.unwrap();
+}
+
+async fn stream_status(user: &mut TestUser) -> Vec<u8> {
+    TcpCommand::Simple(TcpCommandId::GetMyStreamStatus)
+        .write_to_stream(&mut user.stream)
+        .await
+        .unwrap();
+
+    match read_until(&mut user.stream, TcpCommandId::MyStreamStatus).await {
+        TcpCommand::Bytes(_, payload) => payload,
+        command => panic!("Unexpected stream status: {:?}", command),
+    }
+}
+
+#[tokio::test]
+async fn forwards_video_between_room_members_and_announces_leave() {
+    let (tcp_addr, udp_addr) = start_server().await;
+
+    let mut alice_stream = connect(tcp_addr, "alice").await;
+    let bob_stream = connect(tcp_addr, "bob").await;
+
+    TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()])
+        .write_to_stream(&mut alice_stream)
+        .await
+        .unwrap();
+    read_until(&mut alice_stream, TcpCommandId::CreateRoomSuccess).await;
+
+    let mut alice = join(alice_stream, udp_addr, "lobby").await;
+    let mut bob = join(bob_stream, udp_addr, "lobby").await;
+    assert_eq!(alice.rid, bob.rid);
+
+    // The server learns each stream's UDP address from its first packet
+    send_video(&bob, b"bind").await;
+
+    let expected = [&alice.sid[..], &[HEARTBEAT_FRAME_TYPE], b"hello"].concat();
+    let mut buf = [0; 1500];
+    let mut forwarded = None;
+
+    for _ in 0..50 {
+        send_video(&alice, b"hello").await;
+
+        if let Ok(result) = timeout(Duration::from_millis(100), bob.udp_socket.recv(&mut buf)).await
+        {
+            let n = result.unwrap();
+            forwarded = Some(buf[..n].to_vec());
+            break;
+        }
+
+        send_video(&bob, b"bind").await;
+    }
+
+    assert_eq!(forwarded.as_deref(), Some(&expected[..]));
+
+    TcpCommand::Simple(TcpCommandId::LeaveRoom)
+        .write_to_stream(&mut alice.stream)
+        .await
+        .unwrap();
+
+    match read_until(&mut bob.stream, TcpCommandId::OtherUserLeftRoom).await {
+        TcpCommand::Bytes(_, sid) => assert_eq!(sid, alice.sid),
+        command => panic!("Unexpected leave notification: {:?}", command),
+    }
+}
+
+#[tokio::test]
+async fn handshakes_and_joins_over_ipv6_loopback() {
+    let (tcp_addr, udp_addr) = start_server_on("[::1]:0", "[::1]:0").await;
+    assert!(tcp_addr.is_ipv6() && udp_addr.is_ipv6());
+
+    let mut stream = connect(tcp_addr, "alice").await;
+    assert!(stream.local_addr().unwrap().is_ipv6());
+
+    TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()])
+        .write_to_stream(&mut stream)
+        .await
+        .unwrap();
+    read_until(&mut stream, TcpCommandId::CreateRoomSuccess).await;
+
+    let alice = join(stream, udp_addr, "lobby").await;
+    assert!(alice.udp_socket.local_addr().unwrap().is_ipv6());
+}
+
+#[tokio::test]
+async fn connected_clients_are_told_about_shutdown() {
+    let server = bind_server("127.0.0.1:0", "127.0.0.1:0", test_config()).await;
+    let tcp_addr = server.tcp_local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let listen_task = tokio::spawn(server.listen_until(async {
+        let _ = shutdown_rx.await;
+    }));
+
+    let mut alice = connect(tcp_addr, "alice").await;
+    let mut bob = connect(tcp_addr, "bob").await;
+
+    shutdown_tx.send(()).unwrap();
+
+    read_until(&mut alice, TcpCommandId::ServerShuttingDown).await;
+    read_until(&mut bob, TcpCommandId::ServerShuttingDown).await;
+
+    timeout(READ_TIMEOUT, listen_task)
+        .await
+        .expect("Server did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn rooms_created_just_before_shutdown_are_saved() {
+    let rooms_file = std::env::temp_dir()
+        .join(format!("wesfu_shutdown_rooms_{}.json", std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    let _ = std::fs::remove_file(&rooms_file);
+
+    let server = bind_server(
+        "127.0.0.1:0",
+        "127.0.0.1:0",
+        ServerConfig {
+            rooms_file: Some(rooms_file.clone()),
+            ..test_config()
+        },
+    )
+    .await;
+    let tcp_addr = server.tcp_local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let listen_task = tokio::spawn(server.listen_until(async {
+        let _ = shutdown_rx.await;
+    }));
+
+    let mut alice = connect(tcp_addr, "alice").await;
+    TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()])
+        .write_to_stream(&mut alice)
+        .await
+        .unwrap();
+    read_until(&mut alice, TcpCommandId::CreateRoomSuccess).await;
+
+    shutdown_tx.send(()).unwrap();
+    timeout(READ_TIMEOUT, listen_task)
+        .await
+        .expect("Server did not stop after shutdown")
+        .unwrap()
+        .unwrap();
+
+    let contents = std::fs::read_to_string(&rooms_file).expect("Rooms file was not written");
+    let _ = std::fs::remove_file(&rooms_file);
+    assert!(contents.contains("\"lobby\""), "{}", contents);
+}
+
+#[tokio::test]
+async fn handshakes_over_tls_with_a_self_signed_certificate() {
+    let CertifiedKey { cert, signing_key } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("wes_sfu_test_cert_{}.pem", std::process::id()));
+    let key_path = dir.join(format!("wes_sfu_test_key_{}.pem", std::process::id()));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+
+    let tls_acceptor = load_tls_acceptor(cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+    let _ = std::fs::remove_file(&cert_path);
+    let _ = std::fs::remove_file(&key_path);
+
+    let server = bind_server(
+        "127.0.0.1:0",
+        "127.0.0.1:0",
+        ServerConfig {
+            tls_acceptor: Some(tls_acceptor.expect("Failed to load TLS acceptor")),
+            ..test_config()
+        },
+    )
+    .await;
+    let tcp_addr = server.tcp_local_addr().unwrap();
+    tokio::spawn(server.listen());
+
+    let mut root_store = RootCertStore::empty();
+    root_store.add(cert.der().clone()).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    let tcp_stream = TcpStream::connect(tcp_addr).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp_stream)
+        .await
+        .expect("TLS handshake failed");
+
+    hello(&mut stream, "alice").await;
+}
+
+#[tokio::test]
+async fn handshakes_on_non_default_ports() {
+    // Let the OS pick free ports, then hand them to the server as explicit ports
+    let tcp_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let udp_port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    assert_ne!(tcp_port, TCP_PORT);
+    assert_ne!(udp_port, UDP_PORT);
+
+    let (tcp_addr, udp_addr) = start_server_on(
+        &format_host_port("127.0.0.1", tcp_port),
+        &format_host_port("127.0.0.1", udp_port),
+    )
+    .await;
+    assert_eq!(tcp_addr.port(), tcp_port);
+    assert_eq!(udp_addr.port(), udp_port);
+
+    let mut stream = connect(tcp_addr, "alice").await;
+
+    TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()])
+        .write_to_stream(&mut stream)
+        .await
+        .unwrap();
+    read_until(&mut stream, TcpCommandId::CreateRoomSuccess).await;
+
+    join(stream, udp_addr, "lobby").await;
+}
+
+#[tokio::test]
+async fn unknown_hello_gets_an_unsupported_command_error() {
+    let (tcp_addr, _) = start_server().await;
+    let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+
+    TcpCommand::Simple(TcpCommandId::LeaveRoom)
+        .write_to_stream(&mut stream)
+        .await
+        .unwrap();
+
+    let received = timeout(READ_TIMEOUT, TcpCommand::read_from_stream(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    let command = match received {
+        ReceivedTcpCommand::Command(command) => command,
+        ReceivedTcpCommand::EOF => panic!("Server hung up without an error response"),
+    };
+    match command {
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, error) => assert!(matches!(
+            ProtocolError::from_response(&error),
+            ProtocolError::UnsupportedCommand(_)
+        )),
+        command => panic!("Expected an error response, got {:?}", command),
+    }
+}
+
+#[tokio::test]
+async fn stream_status_turns_bound_after_a_udp_packet() {
+    let (tcp_addr, udp_addr) = start_server().await;
+    let mut stream = connect(tcp_addr, "alice").await;
+
+    TcpCommand::StringList(TcpCommandId::CreateRoom, vec!["lobby".to_string()])
+        .write_to_stream(&mut stream)
+        .await
+        .unwrap();
+    read_until(&mut stream, TcpCommandId::CreateRoomSuccess).await;
+
+    let mut alice = join(stream, udp_addr, "lobby").await;
+    let unbound = [&alice.sid[..], &[0]].concat();
+    let bound = [&alice.sid[..], &[1]].concat();
+    assert_eq!(stream_status(&mut alice).await, unbound);
+
+    send_video(&alice, b"bind").await;
+
+    // The UDP handler binds the stream on its own task, so poll until it has
+    let mut status = Vec::new();
+    for _ in 0..50 {
+        status = stream_status(&mut alice).await;
+        if status == bound {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(status, bound);
+}
@@ -0,0 +1,5 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub trait ControlStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> ControlStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
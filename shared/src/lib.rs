@@ -1,17 +1,135 @@
+use thiserror::Error;
+
+pub mod control_stream;
+pub mod protocol_error;
 pub mod received_tcp_command;
 pub mod tcp_command;
 pub mod tcp_command_id;
 pub mod tcp_command_payload_type;
+pub mod tcp_command_reader;
 
 pub const TCP_PORT: u16 = 8040;
 pub const UDP_PORT: u16 = 8039;
 
 pub type RoomID = [u8; 4];
 pub type StreamID = [u8; 4];
+pub type SessionToken = [u8; 16];
+/// AES-256-GCM key a room's clients share for encrypting video, handed out on join.
+pub type MediaKey = [u8; 32];
 
 pub const MAX_NAME_LENGTH: usize = 15;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 256;
+
+/// Sent in `HelloWithFeatures` by clients that can read zlib-compressed StringLists.
+pub const LIST_COMPRESSION_FEATURE: &str = "zlib-lists";
+
+/// Why a username or room name was refused.
+///
+/// The `Display` text is a predicate, meant to follow what was being named,
+/// e.g. `format!("Room name {}", error)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum NameError {
+    #[error("cannot be empty")]
+    Empty,
+
+    #[error("must be less than or equal to {MAX_NAME_LENGTH} characters.")]
+    TooLong,
+
+    #[error("must contain only letters, numbers, underscores (_), or hyphens (-), found {0:?}.")]
+    InvalidChar(char),
+
+    #[error("must be less than or equal to {MAX_DISPLAY_NAME_LENGTH} characters.")]
+    DisplayNameTooLong,
+
+    #[error("cannot start or end with whitespace.")]
+    SurroundingWhitespace,
+
+    #[error("cannot contain control characters.")]
+    ControlChar,
+}
+
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong);
+    }
+
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        Some(c) => Err(NameError::InvalidChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// Display names are only shown, never used as a key, so unlike usernames they may contain
+/// spaces and any printable character. Surrounding whitespace is not allowed.
+pub fn validate_display_name(name: &str) -> Result<(), NameError> {
+    if name.trim().is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(NameError::DisplayNameTooLong);
+    }
+
+    if name.trim() != name {
+        return Err(NameError::SurroundingWhitespace);
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(NameError::ControlChar);
+    }
+
+    Ok(())
+}
+
+/// How a user appears in user and room member lists. Commands like `kick` take the
+/// username, so it stays visible next to a display name.
+pub fn user_list_label(username: &str, display_name: Option<&str>) -> String {
+    match display_name {
+        Some(display_name) => format!("{} ({})", display_name, username),
+        None => username.to_string(),
+    }
+}
 
 pub fn is_valid_name(name: &str) -> bool {
-    name.chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    validate_name(name).is_ok()
+}
+
+pub fn sanitize_chat_message(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_name_reports_each_reason() {
+        assert_eq!(validate_name("alice_01-b"), Ok(()));
+        assert_eq!(validate_name(""), Err(NameError::Empty));
+        assert_eq!(
+            validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)),
+            Err(NameError::TooLong)
+        );
+        assert_eq!(validate_name("bob smith"), Err(NameError::InvalidChar(' ')));
+    }
 }
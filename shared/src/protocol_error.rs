@@ -0,0 +1,202 @@
+use thiserror::Error;
+
+use crate::{tcp_command::TcpCommand, tcp_command_id::TcpCommandId};
+
+const ROOM_NOT_FOUND: &str = "room_not_found";
+const ROOM_ALREADY_EXISTS: &str = "room_already_exists";
+const INCORRECT_PASSWORD: &str = "incorrect_password";
+const ROOM_FULL: &str = "room_full";
+const ROOM_NOT_EMPTY: &str = "room_not_empty";
+const NOT_ROOM_CREATOR: &str = "not_room_creator";
+const USER_NOT_IN_ROOM: &str = "user_not_in_room";
+const USERNAME_TAKEN: &str = "username_taken";
+const UNSUPPORTED_COMMAND: &str = "unsupported_command";
+const REJECTED: &str = "rejected";
+
+/// A request the server refused, as carried by an `ErrorResponse`.
+///
+/// The response lists a code naming the variant, the `Display` message, and then the
+/// variant's fields, so [`ProtocolError::from_response`] rebuilds the variant without
+/// parsing the message. A code it doesn't know becomes `Rejected` with the message.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProtocolError {
+    #[error("Room '{0}' does not exist")]
+    RoomNotFound(String),
+
+    #[error("Room '{0}' already exists")]
+    RoomAlreadyExists(String),
+
+    #[error("Incorrect password for room '{0}'")]
+    IncorrectPassword(String),
+
+    #[error("Room '{room}' is full ({max_users} users).")]
+    RoomFull { room: String, max_users: usize },
+
+    #[error("Room '{room}' cannot be deleted because it still has {user_count} active user(s).")]
+    RoomNotEmpty { room: String, user_count: usize },
+
+    #[error("Only the creator of room '{room}' can {action}")]
+    NotRoomCreator { room: String, action: String },
+
+    #[error("User '{0}' is not in a room")]
+    UserNotInRoom(String),
+
+    #[error("Username is already taken.")]
+    UsernameTaken,
+
+    /// The server doesn't understand the command, e.g. a hello variant it predates.
+    #[error("Command not supported: {0}")]
+    UnsupportedCommand(String),
+
+    /// Any other refusal, such as a request that failed validation.
+    #[error("{0}")]
+    Rejected(String),
+}
+
+impl ProtocolError {
+    pub fn rejected(message: impl Into<String>) -> Self {
+        ProtocolError::Rejected(message.into())
+    }
+
+    pub fn to_response(&self) -> TcpCommand {
+        let (code, fields) = match self {
+            ProtocolError::RoomNotFound(room) => (ROOM_NOT_FOUND, vec![room.clone()]),
+            ProtocolError::RoomAlreadyExists(room) => (ROOM_ALREADY_EXISTS, vec![room.clone()]),
+            ProtocolError::IncorrectPassword(room) => (INCORRECT_PASSWORD, vec![room.clone()]),
+            ProtocolError::RoomFull { room, max_users } => {
+                (ROOM_FULL, vec![room.clone(), max_users.to_string()])
+            }
+            ProtocolError::RoomNotEmpty { room, user_count } => {
+                (ROOM_NOT_EMPTY, vec![room.clone(), user_count.to_string()])
+            }
+            ProtocolError::NotRoomCreator { room, action } => {
+                (NOT_ROOM_CREATOR, vec![room.clone(), action.clone()])
+            }
+            ProtocolError::UserNotInRoom(username) => (USER_NOT_IN_ROOM, vec![username.clone()]),
+            ProtocolError::UsernameTaken => (USERNAME_TAKEN, Vec::new()),
+            ProtocolError::UnsupportedCommand(command) => {
+                (UNSUPPORTED_COMMAND, vec![command.clone()])
+            }
+            ProtocolError::Rejected(_) => (REJECTED, Vec::new()),
+        };
+
+        let mut payload = vec![code.to_string(), self.to_string()];
+        payload.extend(fields);
+
+        TcpCommand::StringList(TcpCommandId::ErrorResponse, payload)
+    }
+
+    pub fn from_response(payload: &[String]) -> Self {
+        let [code, message, fields @ ..] = payload else {
+            return ProtocolError::rejected(payload.concat());
+        };
+
+        let error = match (code.as_str(), fields) {
+            (ROOM_NOT_FOUND, [room]) => Some(ProtocolError::RoomNotFound(room.clone())),
+            (ROOM_ALREADY_EXISTS, [room]) => Some(ProtocolError::RoomAlreadyExists(room.clone())),
+            (INCORRECT_PASSWORD, [room]) => Some(ProtocolError::IncorrectPassword(room.clone())),
+            (ROOM_FULL, [room, max_users]) => {
+                max_users
+                    .parse()
+                    .ok()
+                    .map(|max_users| ProtocolError::RoomFull {
+                        room: room.clone(),
+                        max_users,
+                    })
+            }
+            (ROOM_NOT_EMPTY, [room, user_count]) => {
+                user_count
+                    .parse()
+                    .ok()
+                    .map(|user_count| ProtocolError::RoomNotEmpty {
+                        room: room.clone(),
+                        user_count,
+                    })
+            }
+            (NOT_ROOM_CREATOR, [room, action]) => Some(ProtocolError::NotRoomCreator {
+                room: room.clone(),
+                action: action.clone(),
+            }),
+            (USER_NOT_IN_ROOM, [username]) => Some(ProtocolError::UserNotInRoom(username.clone())),
+            (USERNAME_TAKEN, []) => Some(ProtocolError::UsernameTaken),
+            (UNSUPPORTED_COMMAND, [command]) => {
+                Some(ProtocolError::UnsupportedCommand(command.clone()))
+            }
+            _ => None,
+        };
+
+        error.unwrap_or_else(|| ProtocolError::Rejected(message.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(error: &ProtocolError) -> ProtocolError {
+        match error.to_response() {
+            TcpCommand::StringList(TcpCommandId::ErrorResponse, payload) => {
+                ProtocolError::from_response(&payload)
+            }
+            command => panic!("Unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn join_and_create_errors_round_trip_to_their_variants() {
+        // Quotes in a room name used to throw off matching the message
+        let room = "bob's room".to_string();
+        let errors = [
+            ProtocolError::RoomNotFound(room.clone()),
+            ProtocolError::RoomAlreadyExists(room.clone()),
+            ProtocolError::IncorrectPassword(room.clone()),
+            ProtocolError::RoomFull {
+                room: room.clone(),
+                max_users: 8,
+            },
+            ProtocolError::rejected("Room name must not be empty"),
+        ];
+
+        for error in errors {
+            assert_eq!(round_trip(&error), error);
+        }
+    }
+
+    #[test]
+    fn unsupported_command_round_trips() {
+        let error = ProtocolError::UnsupportedCommand("HelloWithFeatures".to_string());
+
+        assert_eq!(
+            error.to_string(),
+            "Command not supported: HelloWithFeatures"
+        );
+        assert_eq!(round_trip(&error), error);
+    }
+
+    #[test]
+    fn unknown_code_keeps_the_message() {
+        let payload = [
+            "room_on_fire".to_string(),
+            "Room 'lobby' is on fire".to_string(),
+        ];
+
+        assert_eq!(
+            ProtocolError::from_response(&payload),
+            ProtocolError::rejected("Room 'lobby' is on fire")
+        );
+    }
+
+    #[test]
+    fn not_room_creator_round_trips_with_its_action() {
+        let error = ProtocolError::NotRoomCreator {
+            room: "lobby".to_string(),
+            action: "rename it".to_string(),
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "Only the creator of room 'lobby' can rename it"
+        );
+        assert_eq!(round_trip(&error), error);
+    }
+}
@@ -1,6 +1,10 @@
 use core::error::Error;
-use std::str::from_utf8;
+use std::{
+    io::{ErrorKind, Read, Write},
+    str::from_utf8,
+};
 
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
@@ -8,6 +12,11 @@ use crate::{
     tcp_command_payload_type::TcpCommandPayloadType,
 };
 
+pub(crate) const MAX_PAYLOAD_LENGTH: usize = 16 * 1024 * 1024;
+// Set in a StringList's entry count when the entries that follow are zlib-compressed
+pub(crate) const COMPRESSED_LIST_FLAG: u32 = 1 << 31;
+const COMPRESSION_THRESHOLD: usize = 1024;
+
 #[derive(Debug, Clone)]
 pub enum TcpCommand {
     Simple(TcpCommandId),
@@ -22,66 +31,90 @@ impl TcpCommand {
         stream: &mut W,
     ) -> Result<(), Box<dyn Error + Send + Sync>>
     where
-        W: AsyncWrite + Unpin,
+        W: AsyncWrite + Unpin + ?Sized,
     {
         match &self {
             TcpCommand::Simple(id) => {
                 stream.write_all(&[id.to_byte()]).await?;
             }
             TcpCommand::String(id, payload) => {
-                if payload.len() > u8::MAX as usize {
+                if payload.len() > MAX_PAYLOAD_LENGTH {
                     return Err("String payload too large".into());
                 }
 
-                let mut bytes = vec![id.to_byte(), payload.len() as u8];
+                let mut bytes = vec![id.to_byte()];
+                bytes.extend((payload.len() as u32).to_be_bytes());
                 bytes.extend(payload.as_bytes());
 
                 stream.write_all(&bytes).await?;
             }
             TcpCommand::Bytes(id, payload) => {
-                if payload.len() > u8::MAX as usize {
+                if payload.len() > MAX_PAYLOAD_LENGTH {
                     return Err("Bytes payload too large".into());
                 }
 
-                let mut bytes = vec![id.to_byte(), payload.len() as u8];
+                let mut bytes = vec![id.to_byte()];
+                bytes.extend((payload.len() as u32).to_be_bytes());
                 bytes.extend(payload);
 
                 stream.write_all(&bytes).await?;
             }
             TcpCommand::StringList(id, payload) => {
-                if payload.len() > u8::MAX as usize {
-                    return Err("StringList payload too large".into());
-                }
+                let mut bytes = vec![id.to_byte()];
+                bytes.extend((payload.len() as u32).to_be_bytes());
+                bytes.extend(encode_string_list(payload)?);
 
-                let mut bytes = vec![id.to_byte(), payload.len() as u8];
+                stream.write_all(&bytes).await?;
+            }
+        }
 
-                for str in payload {
-                    if str.len() > u8::MAX as usize {
-                        return Err("String in StringList payload too large".into());
-                    }
+        Ok(())
+    }
 
-                    bytes.push(str.len() as u8);
-                    bytes.extend(str.as_bytes());
-                }
+    /// Like `write_to_stream`, but zlib-compresses large StringList payloads. Only use this
+    /// towards peers that advertised `LIST_COMPRESSION_FEATURE`.
+    pub async fn write_to_stream_compressed<W>(
+        &self,
+        stream: &mut W,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if let TcpCommand::StringList(id, payload) = &self {
+            let entries = encode_string_list(payload)?;
 
-                stream.write_all(&bytes).await?;
+            if entries.len() >= COMPRESSION_THRESHOLD {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&entries)?;
+                let compressed = encoder.finish()?;
+
+                if compressed.len() < entries.len() {
+                    let mut bytes = vec![id.to_byte()];
+                    bytes.extend((payload.len() as u32 | COMPRESSED_LIST_FLAG).to_be_bytes());
+                    bytes.extend((compressed.len() as u32).to_be_bytes());
+                    bytes.extend(compressed);
+
+                    stream.write_all(&bytes).await?;
+                    return Ok(());
+                }
             }
         }
 
-        Ok(())
+        self.write_to_stream(stream).await
     }
 
     pub async fn read_from_stream<R>(
         stream: &mut R,
     ) -> Result<ReceivedTcpCommand, Box<dyn Error + Send + Sync>>
     where
-        R: AsyncRead + Unpin,
+        R: AsyncRead + Unpin + ?Sized,
     {
         let mut buf = [0; 1];
 
         let first_byte = match stream.read(&mut buf).await {
             Ok(0) => return Ok(ReceivedTcpCommand::EOF),
             Ok(_) => buf[0],
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(ReceivedTcpCommand::EOF),
             Err(e) => return Err(e.into()),
         };
 
@@ -92,9 +125,7 @@ impl TcpCommand {
                 Ok(ReceivedTcpCommand::Command(TcpCommand::Simple(command_id)))
             }
             TcpCommandPayloadType::String => {
-                let mut payload_len_buf = [0];
-                stream.read_exact(&mut payload_len_buf).await?;
-                let payload_len = payload_len_buf[0] as usize;
+                let payload_len = read_len(stream).await?;
 
                 let mut payload_buf = vec![0; payload_len];
                 stream.read_exact(&mut payload_buf).await?;
@@ -105,9 +136,7 @@ impl TcpCommand {
                 )))
             }
             TcpCommandPayloadType::Bytes => {
-                let mut payload_len_buf = [0];
-                stream.read_exact(&mut payload_len_buf).await?;
-                let payload_len = payload_len_buf[0] as usize;
+                let payload_len = read_len(stream).await?;
 
                 let mut payload = vec![0; payload_len];
                 stream.read_exact(&mut payload).await?;
@@ -117,16 +146,45 @@ impl TcpCommand {
                 )))
             }
             TcpCommandPayloadType::StringList => {
-                let mut list_len_buf = [0];
-                stream.read_exact(&mut list_len_buf).await?;
-                let list_len = list_len_buf[0] as usize;
+                let header = read_u32(stream).await?;
+
+                if header & COMPRESSED_LIST_FLAG != 0 {
+                    let list_len = (header & !COMPRESSED_LIST_FLAG) as usize;
+                    let compressed_len = read_len(stream).await?;
 
-                let mut result = Vec::with_capacity(list_len);
+                    let mut compressed = vec![0; compressed_len];
+                    stream.read_exact(&mut compressed).await?;
+
+                    let mut entries = Vec::new();
+                    ZlibDecoder::new(compressed.as_slice())
+                        .take(MAX_PAYLOAD_LENGTH as u64 + 1)
+                        .read_to_end(&mut entries)?;
+                    if entries.len() > MAX_PAYLOAD_LENGTH {
+                        return Err("Decompressed StringList exceeds maximum".into());
+                    }
+
+                    return Ok(ReceivedTcpCommand::Command(TcpCommand::StringList(
+                        command_id,
+                        decode_string_list(&entries, list_len)?,
+                    )));
+                }
+
+                let list_len = header as usize;
+                if list_len > MAX_PAYLOAD_LENGTH {
+                    return Err("Payload length exceeds maximum".into());
+                }
+
+                // list_len comes from the peer, so grow with the entries that actually arrive
+                let mut result = Vec::new();
+                let mut entries_len = 0;
 
                 for _ in 0..list_len {
-                    let mut str_len_buf = [0];
-                    stream.read_exact(&mut str_len_buf).await?;
-                    let str_len = str_len_buf[0] as usize;
+                    let str_len = read_len(stream).await?;
+
+                    entries_len += 4 + str_len;
+                    if entries_len > MAX_PAYLOAD_LENGTH {
+                        return Err("StringList payload exceeds maximum".into());
+                    }
 
                     let mut str_buf = vec![0; str_len];
                     stream.read_exact(&mut str_buf).await?;
@@ -142,3 +200,197 @@ impl TcpCommand {
         }
     }
 }
+
+fn encode_string_list(payload: &[String]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if payload.len() > MAX_PAYLOAD_LENGTH {
+        return Err("StringList payload too large".into());
+    }
+
+    let mut bytes = Vec::new();
+
+    for str in payload {
+        if str.len() > MAX_PAYLOAD_LENGTH {
+            return Err("String in StringList payload too large".into());
+        }
+
+        bytes.extend((str.len() as u32).to_be_bytes());
+        bytes.extend(str.as_bytes());
+    }
+
+    if bytes.len() > MAX_PAYLOAD_LENGTH {
+        return Err("StringList payload too large".into());
+    }
+
+    Ok(bytes)
+}
+
+fn decode_string_list(
+    mut entries: &[u8],
+    list_len: usize,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    if list_len > entries.len() / 4 {
+        return Err("StringList entry count exceeds payload".into());
+    }
+
+    let mut result = Vec::new();
+
+    for _ in 0..list_len {
+        let (len_bytes, rest) = entries
+            .split_first_chunk::<4>()
+            .ok_or("Truncated StringList entry")?;
+        let str_len = u32::from_be_bytes(*len_bytes) as usize;
+
+        let str_bytes = rest.get(..str_len).ok_or("Truncated StringList entry")?;
+        result.push(from_utf8(str_bytes)?.to_string());
+
+        entries = &rest[str_len..];
+    }
+
+    if !entries.is_empty() {
+        return Err("Trailing bytes after StringList entries".into());
+    }
+
+    Ok(result)
+}
+
+async fn read_u32<R>(stream: &mut R) -> Result<u32, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(u32::from_be_bytes(buf))
+}
+
+async fn read_len<R>(stream: &mut R) -> Result<usize, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let len = read_u32(stream).await? as usize;
+    if len > MAX_PAYLOAD_LENGTH {
+        return Err("Payload length exceeds maximum".into());
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    // Smaller than the payloads below, so reads and writes have to interleave
+    const DUPLEX_BUFFER_SIZE: usize = 4096;
+
+    async fn round_trip(command: &TcpCommand) -> TcpCommand {
+        let (mut writer, mut reader) = duplex(DUPLEX_BUFFER_SIZE);
+
+        let (written, received) = tokio::join!(
+            command.write_to_stream(&mut writer),
+            TcpCommand::read_from_stream(&mut reader)
+        );
+        written.unwrap();
+
+        match received.unwrap() {
+            ReceivedTcpCommand::Command(command) => command,
+            ReceivedTcpCommand::EOF => panic!("Unexpected EOF"),
+        }
+    }
+
+    #[tokio::test]
+    async fn large_bytes_payload_round_trips() {
+        let payload: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+
+        match round_trip(&TcpCommand::Bytes(
+            TcpCommandId::MyStreamStatus,
+            payload.clone(),
+        ))
+        .await
+        {
+            TcpCommand::Bytes(TcpCommandId::MyStreamStatus, received) => {
+                assert_eq!(received, payload)
+            }
+            command => panic!("Unexpected command: {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn long_string_list_round_trips() {
+        let payload: Vec<String> = (0..1000).map(|i| format!("user-{}", i)).collect();
+
+        match round_trip(&TcpCommand::StringList(
+            TcpCommandId::UserList,
+            payload.clone(),
+        ))
+        .await
+        {
+            TcpCommand::StringList(TcpCommandId::UserList, received) => {
+                assert_eq!(received, payload)
+            }
+            command => panic!("Unexpected command: {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_string_list_round_trips_smaller() {
+        let payload: Vec<String> = (0..1000).map(|i| format!("user-{}", i)).collect();
+        let command = TcpCommand::StringList(TcpCommandId::UserList, payload.clone());
+
+        let mut uncompressed = Vec::new();
+        command.write_to_stream(&mut uncompressed).await.unwrap();
+        let mut compressed = Vec::new();
+        command
+            .write_to_stream_compressed(&mut compressed)
+            .await
+            .unwrap();
+        assert!(compressed.len() < uncompressed.len());
+
+        match TcpCommand::read_from_stream(&mut compressed.as_slice())
+            .await
+            .unwrap()
+        {
+            ReceivedTcpCommand::Command(TcpCommand::StringList(
+                TcpCommandId::UserList,
+                received,
+            )) => {
+                assert_eq!(received, payload)
+            }
+            _ => panic!("Unexpected command"),
+        }
+    }
+
+    fn string_list_header(list_len: u32) -> Vec<u8> {
+        let mut bytes = vec![TcpCommandId::UserList.to_byte()];
+        bytes.extend(list_len.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn huge_entry_count_with_short_body_is_rejected() {
+        let mut bytes = string_list_header(MAX_PAYLOAD_LENGTH as u32);
+        bytes.extend(3u32.to_be_bytes());
+        bytes.extend(b"bob");
+
+        assert!(
+            TcpCommand::read_from_stream(&mut bytes.as_slice())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_adding_up_past_the_payload_cap_are_rejected() {
+        let mut bytes = string_list_header(2);
+        bytes.extend(3u32.to_be_bytes());
+        bytes.extend(b"bob");
+        // Within the per-entry limit, but not once the first entry is counted
+        bytes.extend((MAX_PAYLOAD_LENGTH as u32).to_be_bytes());
+
+        match TcpCommand::read_from_stream(&mut bytes.as_slice()).await {
+            Err(error) => assert_eq!(error.to_string(), "StringList payload exceeds maximum"),
+            Ok(_) => panic!("Oversized StringList was accepted"),
+        }
+    }
+}
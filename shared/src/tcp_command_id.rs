@@ -50,32 +50,68 @@ tcp_command_id_enum! {
         JoinRoomSuccess,
         LeaveRoom,
         OtherUserJoinedRoom,
-        OtherUserLeftRoom
+        OtherUserLeftRoom,
+        Ping,
+        Pong,
+        ServerShuttingDown,
+        ChatMessage,
+        KickUser,
+        YouWereKicked,
+        GetRoomMembers,
+        RoomMembers,
+        Resume,
+        ResumeSuccess,
+        HelloWithFeatures,
+        RenameRoom,
+        RenameRoomSuccess,
+        RoomRenamed,
+        GetMyStreamStatus,
+        MyStreamStatus,
+        SetDisplayName,
+        SetDisplayNameSuccess
     }
 }
 
 impl TcpCommandId {
     pub fn get_payload_type(&self) -> TcpCommandPayloadType {
         match &self {
-            TcpCommandId::HelloFromServer => TcpCommandPayloadType::Simple,
             TcpCommandId::GetUserList => TcpCommandPayloadType::Simple,
             TcpCommandId::GetRoomList => TcpCommandPayloadType::Simple,
             TcpCommandId::CreateRoomSuccess => TcpCommandPayloadType::Simple,
-            TcpCommandId::CreateRoom => TcpCommandPayloadType::String,
             TcpCommandId::DeleteRoomSuccess => TcpCommandPayloadType::Simple,
             TcpCommandId::LeaveRoom => TcpCommandPayloadType::Simple,
+            TcpCommandId::Ping => TcpCommandPayloadType::Simple,
+            TcpCommandId::Pong => TcpCommandPayloadType::Simple,
+            TcpCommandId::ServerShuttingDown => TcpCommandPayloadType::Simple,
+            TcpCommandId::YouWereKicked => TcpCommandPayloadType::Simple,
+            TcpCommandId::RenameRoomSuccess => TcpCommandPayloadType::Simple,
+            TcpCommandId::GetMyStreamStatus => TcpCommandPayloadType::Simple,
+            TcpCommandId::SetDisplayNameSuccess => TcpCommandPayloadType::Simple,
 
             TcpCommandId::HelloFromClient => TcpCommandPayloadType::String,
-            TcpCommandId::ErrorResponse => TcpCommandPayloadType::String,
             TcpCommandId::DeleteRoom => TcpCommandPayloadType::String,
-            TcpCommandId::JoinRoom => TcpCommandPayloadType::String,
+            TcpCommandId::KickUser => TcpCommandPayloadType::String,
+            TcpCommandId::GetRoomMembers => TcpCommandPayloadType::String,
+            TcpCommandId::SetDisplayName => TcpCommandPayloadType::String,
 
             TcpCommandId::UserList => TcpCommandPayloadType::StringList,
             TcpCommandId::RoomList => TcpCommandPayloadType::StringList,
+            TcpCommandId::CreateRoom => TcpCommandPayloadType::StringList,
+            TcpCommandId::JoinRoom => TcpCommandPayloadType::StringList,
+            TcpCommandId::ChatMessage => TcpCommandPayloadType::StringList,
+            TcpCommandId::RoomMembers => TcpCommandPayloadType::StringList,
+            TcpCommandId::HelloWithFeatures => TcpCommandPayloadType::StringList,
+            TcpCommandId::RenameRoom => TcpCommandPayloadType::StringList,
+            TcpCommandId::RoomRenamed => TcpCommandPayloadType::StringList,
+            TcpCommandId::ErrorResponse => TcpCommandPayloadType::StringList,
 
+            TcpCommandId::HelloFromServer => TcpCommandPayloadType::Bytes,
             TcpCommandId::JoinRoomSuccess => TcpCommandPayloadType::Bytes,
             TcpCommandId::OtherUserJoinedRoom => TcpCommandPayloadType::Bytes,
             TcpCommandId::OtherUserLeftRoom => TcpCommandPayloadType::Bytes,
+            TcpCommandId::Resume => TcpCommandPayloadType::Bytes,
+            TcpCommandId::ResumeSuccess => TcpCommandPayloadType::Bytes,
+            TcpCommandId::MyStreamStatus => TcpCommandPayloadType::Bytes,
         }
     }
 }
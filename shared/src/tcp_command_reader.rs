@@ -0,0 +1,289 @@
+use core::error::Error;
+use std::io::ErrorKind;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    received_tcp_command::ReceivedTcpCommand,
+    tcp_command::{COMPRESSED_LIST_FLAG, MAX_PAYLOAD_LENGTH, TcpCommand},
+    tcp_command_id::TcpCommandId,
+    tcp_command_payload_type::TcpCommandPayloadType,
+};
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Reads commands like [`TcpCommand::read_from_stream`], but is safe to use as a
+/// `tokio::select!` branch.
+///
+/// `read_from_stream` reads a command in several steps, so dropping it halfway leaves the
+/// stream in the middle of a command. This keeps whatever it has read so far buffered
+/// until the whole command is in, and the next call picks up where the dropped one left off.
+/// It never reads past the end of a command, so once a read returns, the stream can be
+/// handed back to plain `read_from_stream` calls.
+#[derive(Default)]
+pub struct TcpCommandReader {
+    buffer: Vec<u8>,
+    // How far the StringList at the start of the buffer has been sized, so each read only
+    // looks at the entries that are new since the last one
+    sized_entries: SizedEntries,
+}
+
+#[derive(Default)]
+struct SizedEntries {
+    count: usize,
+    end: usize,
+}
+
+impl TcpCommandReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn read_from_stream<R>(
+        &mut self,
+        stream: &mut R,
+    ) -> Result<ReceivedTcpCommand, Box<dyn Error + Send + Sync>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        loop {
+            let missing_len = match command_progress(&self.buffer, &mut self.sized_entries)? {
+                CommandProgress::Complete(command_len) => {
+                    // Decoding from memory finishes in one poll, so this can't be cancelled
+                    // halfway
+                    let mut command_bytes = &self.buffer[..command_len];
+                    let received = TcpCommand::read_from_stream(&mut command_bytes).await?;
+                    self.buffer.drain(..command_len);
+                    self.sized_entries = SizedEntries::default();
+
+                    return Ok(received);
+                }
+                CommandProgress::Missing(missing_len) => missing_len,
+            };
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let n = match stream
+                .read(&mut chunk[..missing_len.min(READ_CHUNK_SIZE)])
+                .await
+            {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+                Err(e) => return Err(e.into()),
+            };
+
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(ReceivedTcpCommand::EOF);
+                }
+
+                return Err("Stream closed in the middle of a command".into());
+            }
+
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+enum CommandProgress {
+    /// The command at the start of the buffer has arrived and is this long.
+    Complete(usize),
+    /// At least this many more bytes belong to the command at the start of the buffer.
+    Missing(usize),
+}
+
+fn command_progress(
+    buffer: &[u8],
+    sized_entries: &mut SizedEntries,
+) -> Result<CommandProgress, Box<dyn Error + Send + Sync>> {
+    let Some(&first_byte) = buffer.first() else {
+        return Ok(CommandProgress::Missing(1));
+    };
+
+    // Until a length field has arrived, count only up to its end
+    let command_len = match TcpCommandId::from_byte(first_byte)?.get_payload_type() {
+        TcpCommandPayloadType::Simple => 1,
+        TcpCommandPayloadType::String | TcpCommandPayloadType::Bytes => {
+            peek_len(buffer, 1)?.map_or(5, |payload_len| 5 + payload_len)
+        }
+        TcpCommandPayloadType::StringList => match peek_u32(buffer, 1) {
+            None => 5,
+            Some(header) if header & COMPRESSED_LIST_FLAG != 0 => {
+                peek_len(buffer, 5)?.map_or(9, |compressed_len| 9 + compressed_len)
+            }
+            Some(header) => {
+                let list_len = header as usize;
+                if list_len > MAX_PAYLOAD_LENGTH {
+                    return Err("Payload length exceeds maximum".into());
+                }
+
+                let mut offset = sized_entries.end.max(5);
+                while sized_entries.count < list_len {
+                    let Some(str_len) = peek_len(buffer, offset)? else {
+                        break;
+                    };
+
+                    offset += 4 + str_len;
+                    if offset - 5 > MAX_PAYLOAD_LENGTH {
+                        return Err("StringList payload exceeds maximum".into());
+                    }
+
+                    sized_entries.count += 1;
+                    sized_entries.end = offset;
+                }
+
+                if sized_entries.count < list_len {
+                    offset + 4
+                } else {
+                    offset
+                }
+            }
+        },
+    };
+
+    if command_len <= buffer.len() {
+        Ok(CommandProgress::Complete(command_len))
+    } else {
+        Ok(CommandProgress::Missing(command_len - buffer.len()))
+    }
+}
+
+fn peek_u32(buffer: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buffer.get(offset..offset + 4)?;
+
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn peek_len(buffer: &[u8], offset: usize) -> Result<Option<usize>, Box<dyn Error + Send + Sync>> {
+    match peek_u32(buffer, offset) {
+        Some(len) if len as usize > MAX_PAYLOAD_LENGTH => {
+            Err("Payload length exceeds maximum".into())
+        }
+        len => Ok(len.map(|len| len as usize)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncWriteExt, duplex},
+        time::timeout,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_read_keeps_partial_command() {
+        let (mut writer, mut reader_stream) = duplex(1024);
+        let mut reader = TcpCommandReader::new();
+
+        let mut bytes = Vec::new();
+        TcpCommand::StringList(
+            TcpCommandId::ChatMessage,
+            vec!["alice".to_string(), "hello".to_string()],
+        )
+        .write_to_stream(&mut bytes)
+        .await
+        .unwrap();
+        let (first_half, second_half) = bytes.split_at(bytes.len() / 2);
+
+        writer.write_all(first_half).await.unwrap();
+        let cancelled = timeout(
+            Duration::from_millis(50),
+            reader.read_from_stream(&mut reader_stream),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        writer.write_all(second_half).await.unwrap();
+        match reader.read_from_stream(&mut reader_stream).await.unwrap() {
+            ReceivedTcpCommand::Command(TcpCommand::StringList(
+                TcpCommandId::ChatMessage,
+                payload,
+            )) => assert_eq!(payload, ["alice", "hello"]),
+            _ => panic!("Unexpected command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_back_to_back_commands_from_one_chunk() {
+        let (mut writer, mut reader_stream) = duplex(1024);
+        let mut reader = TcpCommandReader::new();
+
+        TcpCommand::Simple(TcpCommandId::Ping)
+            .write_to_stream(&mut writer)
+            .await
+            .unwrap();
+        TcpCommand::String(TcpCommandId::KickUser, "bob".to_string())
+            .write_to_stream(&mut writer)
+            .await
+            .unwrap();
+        drop(writer);
+
+        assert!(matches!(
+            reader.read_from_stream(&mut reader_stream).await.unwrap(),
+            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping))
+        ));
+        assert!(matches!(
+            reader.read_from_stream(&mut reader_stream).await.unwrap(),
+            ReceivedTcpCommand::Command(TcpCommand::String(TcpCommandId::KickUser, name)) if name == "bob"
+        ));
+        assert!(matches!(
+            reader.read_from_stream(&mut reader_stream).await.unwrap(),
+            ReceivedTcpCommand::EOF
+        ));
+    }
+
+    #[tokio::test]
+    async fn leaves_the_next_command_in_the_stream() {
+        let (mut writer, mut reader_stream) = duplex(1024);
+        let mut reader = TcpCommandReader::new();
+
+        TcpCommand::Simple(TcpCommandId::Ping)
+            .write_to_stream(&mut writer)
+            .await
+            .unwrap();
+        TcpCommand::String(TcpCommandId::KickUser, "bob".to_string())
+            .write_to_stream(&mut writer)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            reader.read_from_stream(&mut reader_stream).await.unwrap(),
+            ReceivedTcpCommand::Command(TcpCommand::Simple(TcpCommandId::Ping))
+        ));
+        assert!(matches!(
+            TcpCommand::read_from_stream(&mut reader_stream).await.unwrap(),
+            ReceivedTcpCommand::Command(TcpCommand::String(TcpCommandId::KickUser, name)) if name == "bob"
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_a_long_list_arriving_one_byte_at_a_time() {
+        // A one-byte buffer hands the reader a single byte per read
+        let (mut writer, mut reader_stream) = duplex(1);
+        let mut reader = TcpCommandReader::new();
+
+        let payload: Vec<String> = (0..1000).map(|i| format!("user-{}", i)).collect();
+        let command = TcpCommand::StringList(TcpCommandId::UserList, payload.clone());
+
+        let (written, received) = tokio::join!(
+            command.write_to_stream(&mut writer),
+            reader.read_from_stream(&mut reader_stream)
+        );
+        written.unwrap();
+
+        match received.unwrap() {
+            ReceivedTcpCommand::Command(TcpCommand::StringList(
+                TcpCommandId::UserList,
+                received,
+            )) => {
+                assert_eq!(received, payload)
+            }
+            _ => panic!("Unexpected command"),
+        }
+        assert_eq!(reader.sized_entries.count, 0);
+        assert!(reader.buffer.is_empty());
+    }
+}